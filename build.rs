@@ -20,7 +20,7 @@ fn download_configs() -> io::Result<Vec<u8>> {
     );
 
     // Try to download using curl
-    let output = Command::new("curl").args(&["-L", "-o", "-", &url]).output();
+    let output = Command::new("curl").args(["-L", "-o", "-", &url]).output();
 
     match output {
         Ok(output) if output.status.success() => Ok(output.stdout),
@@ -69,6 +69,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut f = File::create(&dest_path)?;
 
     // Try to download and parse chip configs
+    let mut version = None;
     let chip_data = match download_configs() {
         Ok(compressed_data) => {
             println!("cargo:warning=Downloaded chip configs, decompressing...");
@@ -93,6 +94,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     if let Some((vendor, name)) = parse_chip_name(&data) {
                         chips.push((vendor, name, data));
                     }
+                } else if path.file_name().and_then(|s| s.to_str()) == Some("VERSION") {
+                    let mut data = String::new();
+                    entry.read_to_string(&mut data)?;
+                    version = Some(data.trim().to_string());
                 }
             }
 
@@ -109,6 +114,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     writeln!(f, "// Generated chip definitions")?;
     writeln!(f, "// Automatically generated at build time")?;
     writeln!(f)?;
+    // Archives without a VERSION file report "unknown", like ChipDatabase::load()
+    writeln!(
+        f,
+        "const EMBEDDED_CHIP_DB_VERSION: &str = {:?};",
+        version.as_deref().unwrap_or("unknown")
+    )?;
+    writeln!(f)?;
     writeln!(f, "// Embedded chip configuration data")?;
     writeln!(f, "const EMBEDDED_CHIP_CONFIGS: &[(&str, &[u8])] = &[")?;
 
@@ -0,0 +1,73 @@
+//! Load an image into an EM100Pro, verify it and start emulation
+//!
+//! The device is a scripted stand-in (`rem100::mock`), so the example runs
+//! without hardware. With a real EM100Pro, open it with
+//! `Em100::open(None, None, None)` instead of `scripted_em100`.
+//!
+//! Usage: cargo run --example flash_image
+
+use rem100::mock::{MockTransport, Step};
+use rem100::protocol::{self, HwVersion};
+use rem100::{ChipDesc, FlashOptions};
+
+/// A 1MB 3.3V chip: the voltage entry is 0x11 0x04 followed by the millivolts
+fn chip() -> ChipDesc {
+    let mut chip = ChipDesc {
+        vendor: "Winbond".to_string(),
+        name: "W25Q80".to_string(),
+        size: 1024 * 1024,
+        ..Default::default()
+    };
+    chip.init[0] = [0x11, 0x04, 0x0c, 0xe4];
+    chip.init[1] = [0x23, 0xef, 0x40, 0x14];
+    chip.init_len = 2;
+    chip
+}
+
+/// The conversation `flash_image` has with an EM100Pro holding `image`
+fn scripted_em100(chip: &ChipDesc, image: &[u8]) -> MockTransport {
+    let state = protocol::FPGA_REG_STATE;
+    MockTransport::em100pro(0x0227, 0x0034, 123456, HwVersion::Em100Pro)
+        // Stop emulation, set up the chip in 3-byte mode
+        .write_register(state, 0)
+        .read_register(state, 0)
+        .chip_setup(chip, 3)
+        // Download the image and read it back
+        .command(protocol::write_sdram_cmd(0, image.len()))
+        .step(Step::BulkWrite(image.to_vec()))
+        .command(protocol::read_sdram_cmd(0, image.len()))
+        .step(Step::BulkRead(image.to_vec()))
+        // Start emulation
+        .write_register(state, 1)
+        .read_register(state, 1)
+}
+
+fn main() -> rem100::Result<()> {
+    let chip = chip();
+    let image: Vec<u8> = (0..chip.size).map(|i| (i % 251) as u8).collect();
+
+    let mock = scripted_em100(&chip, &image);
+    let mut em100 = mock.open()?;
+    println!("Using {}", em100.get_info().serial);
+
+    let opts = FlashOptions {
+        verify: true,
+        start: true,
+        ..Default::default()
+    };
+    let report = em100.flash_image(Some(&chip), &image, opts)?;
+    if report.verified != Some(true) {
+        eprintln!("Verify failed");
+        std::process::exit(1);
+    }
+    mock.assert_done();
+
+    println!(
+        "Emulating {} {} with a {} byte image (loaded in {:.1}s)",
+        chip.vendor,
+        chip.name,
+        image.len(),
+        report.elapsed.as_secs_f32()
+    );
+    Ok(())
+}
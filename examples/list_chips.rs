@@ -0,0 +1,23 @@
+//! List the chips known to the embedded chip database
+//!
+//! Usage: cargo run --example list_chips [FILTER]
+
+use rem100::ChipDatabase;
+
+fn main() {
+    let filter = std::env::args().nth(1).map(|f| f.to_lowercase());
+
+    let db = ChipDatabase::load_embedded();
+    println!("Chip database: {}", db.version);
+
+    for chip in db.list_chips() {
+        let label = format!("{} {}", chip.vendor, chip.name);
+        if filter
+            .as_ref()
+            .is_some_and(|f| !label.to_lowercase().contains(f))
+        {
+            continue;
+        }
+        println!("  {:<40} {:>6} KB", label, chip.size / 1024);
+    }
+}
@@ -0,0 +1,111 @@
+//! Trace SPI accesses and write every decoded transaction to a CSV file
+//!
+//! The device is a scripted stand-in (`rem100::mock`) whose trace holds a
+//! few transactions of a booting host, so the example runs without
+//! hardware. With a real EM100Pro, open it with `Em100::open(None, None,
+//! None)` instead of `scripted_em100` and trace for as long as needed.
+//!
+//! Usage: cargo run --example trace_to_csv OUT.csv
+
+use rem100::mock::{MockTransport, Step};
+use rem100::protocol::{self, HwVersion};
+use rem100::trace::{self, TraceFormat, TraceSink, TraceState, REPORT_BUFFER_LENGTH};
+use rem100::HoldPinState;
+use std::path::Path;
+
+/// Report buffers read per trace poll
+const REPORT_BUFFERS: usize = 8;
+
+/// A trace packet; `bits` is the transaction's bit count up to the end of
+/// `payload`, plus 0x10 per earlier packet of it
+fn packet(id: u8, bits: u8, payload: &[u8]) -> [u8; 8] {
+    let mut packet = [0u8; 8];
+    packet[0] = id;
+    packet[1] = bits;
+    packet[2..2 + payload.len()].copy_from_slice(payload);
+    packet
+}
+
+/// The first report buffer of the trace: a JEDEC ID query, a status read
+/// and a 12 byte read of the reset vector page
+fn boot_report() -> Vec<u8> {
+    let packets = [
+        packet(0xff, 0, &[0, 0, 0, 0, 0x12, 0x34]),
+        packet(1, 32, &[0x9f, 0xef, 0x40, 0x14]),
+        packet(2, 16, &[0x05, 0x00]),
+        packet(3, 48, &[0x03, 0x0f, 0xff, 0xf0, 0x90, 0x90]),
+        packet(3, 64 + 48, &[0xe9, 0x5b, 0xe0, 0x00, 0xf0, 0xea]),
+        packet(3, 128 + 32, &[0x5b, 0xe0, 0x00, 0xf0]),
+    ];
+    let mut report = vec![0u8; REPORT_BUFFER_LENGTH];
+    report[..2].copy_from_slice(&(packets.len() as u16).to_be_bytes());
+    for (i, packet) in packets.iter().enumerate() {
+        report[2 + i * 8..10 + i * 8].copy_from_slice(packet);
+    }
+    report
+}
+
+/// The conversation a short trace has with an EM100Pro
+fn scripted_em100() -> MockTransport {
+    let hold = protocol::FPGA_REG_HOLD_PIN;
+    let state = protocol::FPGA_REG_STATE;
+    let float = HoldPinState::Float as u16;
+    let input = HoldPinState::Input as u16;
+    let mut mock = MockTransport::em100pro(0x0227, 0x0034, 123456, HwVersion::Em100Pro)
+        // Hold pin to input, start emulation, clear the trace
+        .read_register(hold, float)
+        .write_register(hold, 4 | float)
+        .read_register(hold, float)
+        .write_register(hold, input)
+        .read_register(hold, input)
+        .write_register(state, 1)
+        .read_register(state, 1)
+        .command(protocol::command(0xbd, &[]))
+        // One poll of the trace buffers
+        .command(protocol::command(
+            0xbc,
+            &[0, 0, 0, REPORT_BUFFERS as u8, 0, 0, 0, 0, 0x15],
+        ))
+        .step(Step::Response(boot_report()));
+    for _ in 1..REPORT_BUFFERS {
+        mock = mock.step(Step::Response(vec![0; REPORT_BUFFER_LENGTH]));
+    }
+    // Stop emulation, release the hold pin
+    mock.write_register(state, 0)
+        .read_register(state, 0)
+        .read_register(hold, input)
+        .write_register(hold, 4 | input)
+        .read_register(hold, input)
+        .write_register(hold, float)
+        .read_register(hold, float)
+}
+
+fn main() -> rem100::Result<()> {
+    let Some(out) = std::env::args().nth(1) else {
+        eprintln!("Usage: trace_to_csv OUT.csv");
+        std::process::exit(1);
+    };
+
+    let mock = scripted_em100();
+    let em100 = mock.open()?;
+    em100.set_hold_pin_state(HoldPinState::Input)?;
+    em100.set_state(true)?;
+    trace::reset_spi_trace(&em100)?;
+
+    // Every transaction becomes a CSV row, data bytes included
+    let mut state = TraceState::new(false, 3)
+        .with_hw_version(em100.hw_version)
+        .with_format(TraceFormat::Csv, true)
+        .with_sink(TraceSink::file(Path::new(&out), false)?);
+    trace::read_spi_trace(&em100, &mut state, false, 0)?;
+    state.finish()?;
+
+    em100.set_state(false)?;
+    em100.set_hold_pin_state(HoldPinState::Float)?;
+    mock.assert_done();
+
+    drop(state);
+    let rows = std::fs::read_to_string(&out)?.lines().count() - 1;
+    println!("Wrote {} transactions to {}", rows, out);
+    Ok(())
+}
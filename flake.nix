@@ -44,8 +44,13 @@
             # Web development tools
             pkgs.trunk
             pkgs.wasm-bindgen-cli
+            # Browser unit tests (wasm-pack test --firefox --headless)
+            pkgs.wasm-pack
           ]
           ++ pkgs.lib.optionals pkgs.stdenv.isLinux [
+            # Headless browser for wasm-pack test
+            pkgs.firefox
+            pkgs.geckodriver
             # For native GUI on Linux (xdg-portal)
             pkgs.dbus
             pkgs.wayland
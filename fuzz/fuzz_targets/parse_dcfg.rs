@@ -0,0 +1,22 @@
+//! Fuzz the Dediprog chip config parser
+//!
+//! Run with `cargo +nightly fuzz run parse_dcfg` from the repository root.
+//! Any input must be rejected with an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rem100::chips::parse_dcfg;
+
+/// 'Dcfg' magic followed by version 1.1
+const HEADER: [u8; 8] = [0x44, 0x63, 0x66, 0x67, 0x01, 0x00, 0x01, 0x00];
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_dcfg(data);
+
+    // Most random inputs fail the magic check, so also parse the input behind
+    // a valid header to reach the offset, init sequence and SFDP/SRST code.
+    let mut cfg = HEADER.to_vec();
+    cfg.extend_from_slice(data);
+    let _ = parse_dcfg(&cfg);
+});
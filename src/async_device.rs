@@ -0,0 +1,279 @@
+//! Async EM100 device operations
+//!
+//! The same device API as the blocking `Em100`, for callers that drive
+//! several devices from one async runtime, and for the browser, where
+//! WebUSB only offers async transfers. Available on wasm32, and natively
+//! with the "async" feature. The command sequences are shared with the
+//! blocking device through [`crate::commands`].
+
+use crate::async_usb::UsbTransport;
+use crate::chips::{AddressModePolicy, ChipDesc};
+use crate::commands;
+use crate::error::{Error, Result};
+use crate::protocol;
+use nusb::transfer::{Bulk, In, Out};
+use nusb::Interface;
+
+pub use crate::protocol::{
+    HoldPinState, HwVersion, PostInitReadback, Quirks, PRODUCT_ID, VENDOR_ID,
+};
+
+/// Device information structure
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub mcu_version: String,
+    pub fpga_version: String,
+    pub hw_version: HwVersion,
+    pub serial: String,
+    pub fpga_voltage: u16,
+}
+
+/// Async EM100 device structure
+pub struct Em100Async {
+    /// USB interface (held to keep the device claim alive)
+    _interface: Interface,
+    /// USB bulk endpoints
+    pub transport: UsbTransport,
+    /// MCU firmware version
+    pub mcu: u16,
+    /// FPGA firmware version
+    pub fpga: u16,
+    /// Device serial number
+    pub serial_no: u32,
+    /// Hardware version
+    pub hw_version: HwVersion,
+    /// Protocol quirks of the installed firmware
+    pub quirks: Quirks,
+}
+
+/// USB endpoint addresses
+const ENDPOINT_OUT: u8 = 0x01;
+const ENDPOINT_IN: u8 = 0x82;
+
+impl Em100Async {
+    /// List available EM100 devices
+    pub async fn list_devices() -> Result<Vec<nusb::DeviceInfo>> {
+        let devices: Vec<_> = nusb::list_devices()
+            .await?
+            .filter(|d| d.vendor_id() == VENDOR_ID && d.product_id() == PRODUCT_ID)
+            .collect();
+        Ok(devices)
+    }
+
+    /// Whether this browser exposes WebUSB (`navigator.usb`)
+    ///
+    /// Firefox and Safari don't, and neither does any browser outside a
+    /// secure (https or localhost) context.
+    #[cfg(target_arch = "wasm32")]
+    pub fn webusb_available() -> bool {
+        web_sys::window().is_some_and(|window| {
+            js_sys::Reflect::has(&window.navigator(), &"usb".into()).unwrap_or(false)
+        })
+    }
+
+    /// Request access to an EM100 device via WebUSB permission prompt
+    ///
+    /// This must be called from a user gesture (e.g., button click) in the browser.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn request_device() -> Result<nusb::DeviceInfo> {
+        use wasm_bindgen::JsCast;
+        use wasm_bindgen_futures::JsFuture;
+        use web_sys::{UsbDevice, UsbDeviceFilter, UsbDeviceRequestOptions};
+
+        web_sys::console::log_1(&"request_device: starting...".into());
+
+        if !Self::webusb_available() {
+            return Err(Error::WebUsbUnavailable);
+        }
+        let usb = web_sys::window()
+            .ok_or(Error::DeviceNotFound)?
+            .navigator()
+            .usb();
+
+        // Create filter for EM100 devices
+        let filter = UsbDeviceFilter::new();
+        filter.set_vendor_id(VENDOR_ID);
+        filter.set_product_id(PRODUCT_ID);
+
+        let filters = js_sys::Array::new();
+        filters.push(&filter);
+
+        let options = UsbDeviceRequestOptions::new(&filters);
+
+        web_sys::console::log_1(&"request_device: calling usb.request_device()...".into());
+
+        // request_device returns a Promise that resolves to a UsbDevice
+        let device_promise = usb.request_device(&options);
+
+        let device_js = JsFuture::from(device_promise).await.map_err(|e| {
+            let err = format!(
+                "WebUSB request failed: {}",
+                crate::async_usb::js_error_message(&e)
+            );
+            web_sys::console::error_1(&err.clone().into());
+            Error::Communication(err)
+        })?;
+
+        web_sys::console::log_1(&"request_device: got device from picker".into());
+
+        // Cast to UsbDevice
+        let device: UsbDevice = device_js
+            .dyn_into()
+            .map_err(|_| Error::Communication("Failed to get USB device".to_string()))?;
+
+        web_sys::console::log_1(
+            &format!(
+                "request_device: device vid=0x{:04x} pid=0x{:04x} opened={}",
+                device.vendor_id(),
+                device.product_id(),
+                device.opened()
+            )
+            .into(),
+        );
+
+        // Use nusb's function to create DeviceInfo from the already-granted device
+        web_sys::console::log_1(&"request_device: calling device_info_from_webusb...".into());
+
+        let device_info = nusb::device_info_from_webusb(device).await.map_err(|e| {
+            let err = format!("Failed to get device info: {}", e);
+            web_sys::console::error_1(&err.clone().into());
+            Error::Communication(err)
+        })?;
+
+        web_sys::console::log_1(&"request_device: success!".into());
+        Ok(device_info)
+    }
+
+    /// Open an EM100 device from a DeviceInfo
+    pub async fn open(device_info: nusb::DeviceInfo) -> Result<Self> {
+        let device = device_info.open().await?;
+        let interface = device.claim_interface(0).await?;
+        let transport = UsbTransport {
+            endpoint_out: interface.endpoint::<Bulk, Out>(ENDPOINT_OUT)?,
+            endpoint_in: interface.endpoint::<Bulk, In>(ENDPOINT_IN)?,
+        };
+
+        let mut em100 = Em100Async {
+            _interface: interface,
+            transport,
+            mcu: 0,
+            fpga: 0,
+            serial_no: 0,
+            hw_version: HwVersion::Unknown,
+            quirks: Quirks::default(),
+        };
+
+        em100.init().await?;
+        Ok(em100)
+    }
+
+    /// Open the first available EM100 device
+    pub async fn open_first() -> Result<Self> {
+        let devices = Self::list_devices().await?;
+        let device_info = devices.into_iter().next().ok_or(Error::DeviceNotFound)?;
+        Self::open(device_info).await
+    }
+
+    /// Initialize the device
+    async fn init(&mut self) -> Result<()> {
+        let identity = commands::identify(&mut self.transport).await?;
+        self.mcu = identity.mcu;
+        self.fpga = identity.fpga;
+        self.serial_no = identity.serial_no;
+        self.hw_version = identity.hw_version;
+        self.quirks = identity.quirks;
+        Ok(())
+    }
+
+    /// Read FPGA register
+    pub async fn read_fpga_register(&mut self, reg: u8) -> Result<u16> {
+        commands::read_fpga_register(&mut self.transport, reg).await
+    }
+
+    /// Write FPGA register
+    pub async fn write_fpga_register(&mut self, reg: u8, val: u16) -> Result<()> {
+        commands::write_fpga_register(&mut self.transport, reg, val).await
+    }
+
+    /// Start or stop emulation, checking that the device followed
+    pub async fn set_state(&mut self, run: bool) -> Result<()> {
+        commands::set_state(&mut self.transport, run).await
+    }
+
+    /// Get current emulation state
+    pub async fn get_state(&mut self) -> Result<bool> {
+        commands::get_state(&mut self.transport).await
+    }
+
+    /// Set address mode (3 or 4 byte)
+    pub async fn set_address_mode(&mut self, mode: u8) -> Result<()> {
+        commands::set_address_mode(&mut self.transport, mode).await
+    }
+
+    /// Get the address mode (3 or 4 byte) the emulation is configured for
+    pub async fn get_address_mode(&mut self) -> Result<u8> {
+        commands::get_address_mode(&mut self.transport).await
+    }
+
+    /// Get current hold pin state
+    pub async fn get_hold_pin_state(&mut self) -> Result<HoldPinState> {
+        commands::get_hold_pin_state(&mut self.transport).await
+    }
+
+    /// Set hold pin state
+    pub async fn set_hold_pin_state(&mut self, state: HoldPinState) -> Result<()> {
+        commands::set_hold_pin_state(&mut self.transport, state).await
+    }
+
+    /// Stop emulation and set the chip type for emulation
+    ///
+    /// The address mode is programmed afterwards according to `policy`.
+    /// The FPGA registers written after the init sequence are read back and
+    /// written once more if they didn't stick; their final values are returned.
+    pub async fn set_chip_type(
+        &mut self,
+        chip: &ChipDesc,
+        policy: AddressModePolicy,
+    ) -> Result<PostInitReadback> {
+        commands::set_chip_type(
+            &mut self.transport,
+            &mut self.fpga,
+            self.hw_version,
+            self.quirks,
+            chip,
+            policy,
+        )
+        .await
+    }
+
+    /// Set FPGA voltage (18 for 1.8V, 33 for 3.3V)
+    pub async fn set_fpga_voltage(&mut self, voltage_code: u8) -> Result<bool> {
+        commands::set_fpga_voltage(&mut self.transport, &mut self.fpga, voltage_code).await
+    }
+
+    /// Download data to SDRAM
+    pub async fn download(&mut self, data: &[u8], address: u32) -> Result<()> {
+        commands::write_sdram(&mut self.transport, data, address).await
+    }
+
+    /// Upload data from SDRAM
+    pub async fn upload(&mut self, address: u32, length: usize) -> Result<Vec<u8>> {
+        commands::read_sdram(&mut self.transport, address, length).await
+    }
+
+    /// Get serial number as string
+    pub fn serial_string(&self) -> String {
+        protocol::serial_string(self.serial_no, self.hw_version)
+    }
+
+    /// Get device information as structured data
+    pub fn get_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            mcu_version: protocol::mcu_version_string(self.mcu),
+            fpga_version: protocol::fpga_version_string(self.fpga, self.hw_version),
+            hw_version: self.hw_version,
+            serial: self.serial_string(),
+            fpga_voltage: protocol::fpga_voltage_mv(self.fpga),
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Async USB communication over nusb endpoints
+//!
+//! nusb's async transfers go through WebUSB on wasm32 and through the
+//! native USB stack elsewhere, so the async device uses this transport on
+//! both.
+
+use crate::commands::AsyncTransport;
+use crate::error::{Error, Result};
+use crate::protocol::{self, round_up_to_max_packet};
+use nusb::transfer::{Buffer, Bulk, In, Out};
+use nusb::Endpoint;
+use std::time::Duration;
+
+/// Describe a JavaScript error as "Name: message"
+///
+/// WebUSB rejects promises with DOMExceptions (e.g. NotFoundError when the
+/// device picker is cancelled), whose Debug output is just an opaque handle.
+#[cfg(target_arch = "wasm32")]
+pub fn js_error_message(value: &wasm_bindgen::JsValue) -> String {
+    use wasm_bindgen::JsValue;
+
+    let field = |key: &str| {
+        js_sys::Reflect::get(value, &JsValue::from_str(key))
+            .ok()
+            .and_then(|v| v.as_string())
+            .filter(|s| !s.is_empty())
+    };
+    match (field("name"), field("message")) {
+        (Some(name), Some(message)) => format!("{}: {}", name, message),
+        (Some(name), None) => name,
+        (None, Some(message)) => message,
+        (None, None) => value.as_string().unwrap_or_else(|| format!("{:?}", value)),
+    }
+}
+
+/// The bulk endpoints of a claimed EM100 interface
+pub struct UsbTransport {
+    /// USB bulk OUT endpoint
+    pub endpoint_out: Endpoint<Bulk, Out>,
+    /// USB bulk IN endpoint
+    pub endpoint_in: Endpoint<Bulk, In>,
+}
+
+impl UsbTransport {
+    /// Receive up to `length` bytes, rounded up to whole packets on the wire
+    async fn read(&mut self, length: usize) -> Result<Vec<u8>> {
+        let max_packet_size = self.endpoint_in.max_packet_size();
+        let requested_len = round_up_to_max_packet(length, max_packet_size);
+        let mut buf = Buffer::new(requested_len);
+        buf.set_requested_len(requested_len);
+
+        self.endpoint_in.submit(buf);
+
+        let completion = std::future::poll_fn(|cx| self.endpoint_in.poll_next_complete(cx)).await;
+        completion.status?;
+
+        let data = protocol::clamp_response(
+            &completion.buffer,
+            completion.actual_len,
+            length,
+            requested_len,
+        )?;
+        Ok(data.to_vec())
+    }
+}
+
+impl AsyncTransport for UsbTransport {
+    async fn send_cmd(&mut self, data: &[u8]) -> Result<()> {
+        let mut cmd = [0u8; 16];
+        let len = std::cmp::min(data.len(), 16);
+        cmd[..len].copy_from_slice(&data[..len]);
+
+        let buf = Buffer::from(cmd.to_vec());
+        self.endpoint_out.submit(buf);
+
+        let completion = std::future::poll_fn(|cx| self.endpoint_out.poll_next_complete(cx)).await;
+        completion.status?;
+
+        if completion.actual_len != 16 {
+            return Err(Error::Communication(format!(
+                "Expected to send 16 bytes, sent {}",
+                completion.actual_len
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn get_response(&mut self, length: usize) -> Result<Vec<u8>> {
+        self.read(length).await
+    }
+
+    async fn bulk_write(&mut self, data: &[u8]) -> Result<usize> {
+        let buf = Buffer::from(data.to_vec());
+        self.endpoint_out.submit(buf);
+
+        let completion = std::future::poll_fn(|cx| self.endpoint_out.poll_next_complete(cx)).await;
+        completion.status?;
+
+        Ok(completion.actual_len)
+    }
+
+    async fn bulk_read(&mut self, length: usize) -> Result<Vec<u8>> {
+        self.read(length).await
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        sleep(duration).await
+    }
+}
+
+/// Wait without blocking the browser's event loop
+#[cfg(target_arch = "wasm32")]
+async fn sleep(duration: Duration) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                &resolve,
+                duration.as_millis() as i32,
+            )
+            .unwrap();
+    });
+    wasm_bindgen_futures::JsFuture::from(promise).await.ok();
+}
+
+/// Wait without blocking the executor
+///
+/// A helper thread wakes the task, so this works with any async runtime.
+#[cfg(not(target_arch = "wasm32"))]
+async fn sleep(duration: Duration) {
+    use std::sync::{Arc, Mutex};
+    use std::task::{Poll, Waker};
+
+    let timer: Arc<Mutex<(bool, Option<Waker>)>> = Arc::default();
+    let expiry = Arc::clone(&timer);
+    std::thread::spawn(move || {
+        std::thread::sleep(duration);
+        let mut timer = expiry.lock().unwrap();
+        timer.0 = true;
+        if let Some(waker) = timer.1.take() {
+            waker.wake();
+        }
+    });
+
+    std::future::poll_fn(|cx| {
+        let mut timer = timer.lock().unwrap();
+        if timer.0 {
+            Poll::Ready(())
+        } else {
+            timer.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    })
+    .await
+}
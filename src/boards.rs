@@ -0,0 +1,173 @@
+//! Board profiles: chip and pin settings looked up by board name
+//!
+//! Boards are defined in `boards.toml` in the EM100 home directory
+//! (`$EM100_HOME`, or `~/.em100`), a small file meant to be shared and
+//! committed alongside board support code:
+//!
+//! ```toml
+//! [boards.myboard]
+//! chip = "W25Q256JV"
+//! holdpin = "float"
+//! compatible = true
+//! image_region = "bios"
+//! ```
+//!
+//! Only this subset of TOML is understood: `[boards.NAME]` tables holding
+//! string and boolean values, and `#` comments.
+
+use crate::chips::{get_em100_file, ChipDatabase};
+use crate::device::HoldPinState;
+use crate::error::{Error, Result};
+
+/// Name of the board file in the EM100 home directory
+pub const BOARDS_FILE: &str = "boards.toml";
+
+/// Settings applied by `--board`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Board {
+    pub name: String,
+    /// Chip to emulate
+    pub chip: Option<String>,
+    /// Hold pin state to set
+    pub holdpin: Option<HoldPinState>,
+    /// Patch images for EM100Pro compatibility
+    pub compatible: Option<bool>,
+    /// Flash region holding the image, for reference
+    pub image_region: Option<String>,
+}
+
+impl std::fmt::Display for Board {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.name)?;
+        let mut settings = Vec::new();
+        if let Some(chip) = &self.chip {
+            settings.push(format!("chip {}", chip));
+        }
+        if let Some(holdpin) = self.holdpin {
+            settings.push(format!("hold pin {}", holdpin));
+        }
+        if self.compatible == Some(true) {
+            settings.push("compatible".to_string());
+        }
+        if let Some(region) = &self.image_region {
+            settings.push(format!("image region {}", region));
+        }
+        if settings.is_empty() {
+            write!(f, " (no settings)")
+        } else {
+            write!(f, " {}", settings.join(", "))
+        }
+    }
+}
+
+/// Parse a board file
+pub fn parse_boards(text: &str) -> Result<Vec<Board>> {
+    let mut boards: Vec<Board> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let err = |msg: &str| {
+            Error::InvalidConfig(format!("{} line {}: {}", BOARDS_FILE, index + 1, msg))
+        };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .and_then(|h| h.trim().strip_prefix("boards."))
+                .map(|n| n.trim().trim_matches('"'))
+                .filter(|n| !n.is_empty())
+                .ok_or_else(|| err("expected a [boards.NAME] table"))?;
+            if boards.iter().any(|b| b.name == name) {
+                return Err(err(&format!("board '{}' defined twice", name)));
+            }
+            boards.push(Board {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| err("expected key = value"))?;
+        let (key, value) = (key.trim(), value.trim());
+        let board = boards
+            .last_mut()
+            .ok_or_else(|| err("setting outside a [boards.NAME] table"))?;
+
+        match key {
+            "chip" => {
+                board.chip = Some(parse_string(value).ok_or_else(|| err("chip must be a string"))?)
+            }
+            "holdpin" => {
+                let state = parse_string(value).ok_or_else(|| err("holdpin must be a string"))?;
+                board.holdpin = Some(
+                    state
+                        .parse()
+                        .map_err(|_| err("holdpin must be \"low\", \"float\" or \"input\""))?,
+                );
+            }
+            "compatible" => {
+                board.compatible = Some(match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(err("compatible must be true or false")),
+                })
+            }
+            "image_region" => {
+                board.image_region =
+                    Some(parse_string(value).ok_or_else(|| err("image_region must be a string"))?)
+            }
+            _ => return Err(err(&format!("unknown setting '{}'", key))),
+        }
+    }
+
+    Ok(boards)
+}
+
+/// Remove a `#` comment, ignoring `#` inside strings
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parse a basic double-quoted string without escapes
+pub(crate) fn parse_string(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    (!inner.contains('"') && !inner.contains('\\')).then(|| inner.to_string())
+}
+
+/// Load the board file, if there is one
+pub fn load_boards() -> Result<Vec<Board>> {
+    let path = get_em100_file(BOARDS_FILE)?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_boards(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that every chip referenced by a board is in the chip database
+pub fn validate_boards(boards: &[Board], db: &ChipDatabase) -> Result<()> {
+    for board in boards {
+        if let Some(chip) = &board.chip {
+            db.find_chip(chip).map_err(|_| {
+                Error::InvalidConfig(format!(
+                    "board '{}' uses unknown chip '{}'",
+                    board.name, chip
+                ))
+            })?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Digests of SDRAM contents
+//!
+//! The contents are hashed as they are read, a chunk at a time, so a digest
+//! of the whole chip never needs the image in memory.
+
+use crate::device::Em100;
+use crate::error::{Error, Result};
+use crate::sdram::{self, ProgressCallback};
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::io::{self, Write};
+
+/// Algorithm of a content digest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    /// CRC-32 as used by zlib and zip (IEEE polynomial)
+    Crc32,
+}
+
+impl std::str::FromStr for DigestAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "crc32" => Ok(DigestAlgorithm::Crc32),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid checksum algorithm: {} (expected sha256 or crc32)",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestAlgorithm::Sha256 => write!(f, "sha256"),
+            DigestAlgorithm::Crc32 => write!(f, "crc32"),
+        }
+    }
+}
+
+/// Digest of a region of SDRAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: DigestAlgorithm,
+    /// Address of the region
+    pub address: u32,
+    /// Length of the region
+    pub length: usize,
+    /// The digest, most significant byte first
+    pub bytes: Vec<u8>,
+}
+
+impl Digest {
+    /// Lower-case hex of the digest
+    pub fn hex(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex())
+    }
+}
+
+/// Incremental state of a digest, fed through `Write`
+enum Hasher {
+    Sha256(Sha256),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Crc32(hasher) => hasher.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Digest of `length` bytes of SDRAM at `address`
+///
+/// Progress is reported in bytes read, as for an upload.
+pub fn content_digest(
+    em100: &Em100,
+    algorithm: DigestAlgorithm,
+    address: u32,
+    length: usize,
+    progress: ProgressCallback,
+) -> Result<Digest> {
+    let mut hasher = Hasher::new(algorithm);
+    sdram::read_sdram_to(em100, address, length, &mut hasher, progress)?;
+    Ok(Digest {
+        algorithm,
+        address,
+        length,
+        bytes: hasher.finish(),
+    })
+}
@@ -23,6 +23,8 @@ pub struct ChipDesc {
     pub init: [[u8; BYTES_PER_INIT_ENTRY]; NUM_INIT_ENTRIES],
     /// Number of valid init entries
     pub init_len: usize,
+    /// Init entries loading the status register reset values (SRST section)
+    pub status_reset: Vec<[u8; BYTES_PER_INIT_ENTRY]>,
 }
 
 impl Default for ChipDesc {
@@ -33,6 +35,119 @@ impl Default for ChipDesc {
             size: 0,
             init: [[0u8; BYTES_PER_INIT_ENTRY]; NUM_INIT_ENTRIES],
             init_len: 0,
+            status_reset: Vec::new(),
+        }
+    }
+}
+
+impl ChipDesc {
+    /// Supply voltage in mV (1800 or 3300) set by the init sequence, if any
+    pub fn voltage_mv(&self) -> Option<u16> {
+        self.init
+            .iter()
+            .take(self.init_len)
+            .find(|entry| entry[0] == 0x11 && entry[1] == 0x04)
+            .and_then(|entry| match ((entry[2] as u16) << 8) | entry[3] as u16 {
+                1601 | 1800 => Some(1800),
+                3300 => Some(3300),
+                _ => None,
+            })
+    }
+}
+
+/// Criteria for `ChipDatabase::search` and the GUI chip pickers
+///
+/// Empty criteria match every chip. Chips without a known voltage never
+/// match a voltage criterion; size bounds are inclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChipFilter {
+    /// Case-insensitive substring of "vendor name"
+    pub text: String,
+    /// Smallest chip size in bytes
+    pub min_size: Option<u32>,
+    /// Largest chip size in bytes
+    pub max_size: Option<u32>,
+    /// Supply voltage in mV, 1800 or 3300
+    pub voltage: Option<u16>,
+    /// Vendor name, ignoring case
+    pub vendor: Option<String>,
+}
+
+impl ChipFilter {
+    /// Whether `chip` meets all criteria
+    pub fn matches(&self, chip: &ChipDesc) -> bool {
+        self.matches_attributes(chip)
+            && self.matches_text(&format!("{} {}", chip.vendor, chip.name))
+    }
+
+    /// Whether a chip's "vendor name" contains the search text
+    pub fn matches_text(&self, display_name: &str) -> bool {
+        self.text.is_empty()
+            || display_name
+                .to_lowercase()
+                .contains(&self.text.to_lowercase())
+    }
+
+    /// Whether `chip` meets all criteria except the search text
+    pub fn matches_attributes(&self, chip: &ChipDesc) -> bool {
+        if self.min_size.is_some_and(|min| chip.size < min)
+            || self.max_size.is_some_and(|max| chip.size > max)
+        {
+            return false;
+        }
+        if self
+            .vendor
+            .as_ref()
+            .is_some_and(|vendor| !chip.vendor.eq_ignore_ascii_case(vendor))
+        {
+            return false;
+        }
+        self.voltage.is_none() || chip.voltage_mv() == self.voltage
+    }
+}
+
+/// Chip size for display, e.g. "512 KB" or "32 MB"
+pub fn size_name(size: u32) -> String {
+    if size >= 1024 * 1024 && size.is_multiple_of(1024 * 1024) {
+        format!("{} MB", size / (1024 * 1024))
+    } else {
+        format!("{} KB", size / 1024)
+    }
+}
+
+/// Chips larger than this need 4-byte addressing
+pub const AUTO_4BYTE_THRESHOLD: u32 = 16 * 1024 * 1024;
+
+/// How the address mode is chosen when configuring a chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressModePolicy {
+    /// 4-byte mode for chips larger than 16MB, 3-byte mode for the rest
+    #[default]
+    Auto,
+    /// Use the given address mode (3 or 4) regardless of chip size
+    Force(u8),
+}
+
+impl AddressModePolicy {
+    /// Address mode to program for a chip
+    ///
+    /// Auto always picks a mode, so a small chip set after a large one does
+    /// not inherit 4-byte mode from the previous chip.
+    pub fn resolve(self, chip: &ChipDesc) -> u8 {
+        match self {
+            AddressModePolicy::Auto if chip.size > AUTO_4BYTE_THRESHOLD => 4,
+            AddressModePolicy::Auto => 3,
+            AddressModePolicy::Force(mode) => mode,
+        }
+    }
+
+    /// Check that a forced address mode is valid
+    pub fn validate(self) -> Result<()> {
+        match self {
+            AddressModePolicy::Force(mode) if mode != 3 && mode != 4 => Err(
+                Error::InvalidArgument(format!("Invalid address mode: {}", mode)),
+            ),
+            _ => Ok(()),
         }
     }
 }
@@ -50,7 +165,45 @@ const DEDIPROG_PROT_MAGIC: u32 = 0x544f5250; // 'PROT'
 const INIT_SEQUENCE_REGISTER_OFFSET_0: u16 = 0x2300;
 const INIT_SEQUENCE_REGISTER_OFFSET_1: u16 = 0x1100;
 
+/// Bytes `offset..offset + len` of a config file, or an error naming the field
+fn cfg_field<'a>(data: &'a [u8], offset: usize, len: usize, field: &str) -> Result<&'a [u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "{} at offset 0x{:x} runs past end of file ({} bytes)",
+                field,
+                offset,
+                data.len()
+            ))
+        })
+}
+
+/// Null-terminated string starting at `offset`, empty if it is out of range
+fn cfg_string(data: &[u8], offset: usize) -> String {
+    let bytes = data.get(offset..).unwrap_or_default();
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/// Append an init entry, failing once the init sequence is full
+fn push_init(chip: &mut ChipDesc, init_len: &mut usize, entry: [u8; 4], field: &str) -> Result<()> {
+    let slot = chip.init.get_mut(*init_len).ok_or_else(|| {
+        Error::InvalidConfig(format!(
+            "{} does not fit in the {} entry init sequence",
+            field, NUM_INIT_ENTRIES
+        ))
+    })?;
+    *slot = entry;
+    *init_len += 1;
+    Ok(())
+}
+
 /// Parse a Dediprog chip configuration file
+///
+/// Config files come from a downloaded archive, so every offset and length is
+/// checked; malformed input is reported as `Error::InvalidConfig`.
 pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
     if data.len() < DEDIPROG_CFG_PRO_SIZE {
         return Err(Error::InvalidConfig("File too small".to_string()));
@@ -60,7 +213,7 @@ pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
     let mut init_len = 0;
 
     // Parse header
-    let magic = LittleEndian::read_u32(&data[0..4]);
+    let magic = LittleEndian::read_u32(cfg_field(data, 0, 4, "magic")?);
     if magic != DEDIPROG_CFG_MAGIC {
         return Err(Error::InvalidConfig(format!(
             "Invalid magic number: 0x{:x}",
@@ -68,8 +221,8 @@ pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
         )));
     }
 
-    let ver_min = LittleEndian::read_u16(&data[4..6]);
-    let ver_maj = LittleEndian::read_u16(&data[6..8]);
+    let ver_min = LittleEndian::read_u16(cfg_field(data, 4, 2, "minor version")?);
+    let ver_maj = LittleEndian::read_u16(cfg_field(data, 6, 2, "major version")?);
     if ver_maj != 1 || ver_min != 1 {
         return Err(Error::InvalidConfig(format!(
             "Invalid version: {}.{}",
@@ -77,37 +230,24 @@ pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
         )));
     }
 
-    let init_offset = LittleEndian::read_u32(&data[8..12]) as usize;
-    chip.size = LittleEndian::read_u32(&data[12..16]);
-    let vendor_offset = LittleEndian::read_u32(&data[16..20]) as usize;
-    let chip_name_offset = LittleEndian::read_u32(&data[20..24]) as usize;
+    let init_offset = LittleEndian::read_u32(cfg_field(data, 8, 4, "init offset")?) as usize;
+    chip.size = LittleEndian::read_u32(cfg_field(data, 12, 4, "chip size")?);
+    let vendor_offset = LittleEndian::read_u32(cfg_field(data, 16, 4, "vendor offset")?) as usize;
+    let chip_name_offset =
+        LittleEndian::read_u32(cfg_field(data, 20, 4, "chip name offset")?) as usize;
 
     // Read vendor and chip name as null-terminated strings
-    if vendor_offset < data.len() {
-        let end = data[vendor_offset..]
-            .iter()
-            .position(|&b| b == 0)
-            .unwrap_or(data.len() - vendor_offset);
-        chip.vendor =
-            String::from_utf8_lossy(&data[vendor_offset..vendor_offset + end]).to_string();
-    }
-
-    if chip_name_offset < data.len() {
-        let end = data[chip_name_offset..]
-            .iter()
-            .position(|&b| b == 0)
-            .unwrap_or(data.len() - chip_name_offset);
-        chip.name =
-            String::from_utf8_lossy(&data[chip_name_offset..chip_name_offset + end]).to_string();
-    }
+    chip.vendor = cfg_string(data, vendor_offset);
+    chip.name = cfg_string(data, chip_name_offset);
 
     // Parse init sequence
     let mut reg_offset = INIT_SEQUENCE_REGISTER_OFFSET_0;
     let mut pos = init_offset;
 
-    while pos + 4 <= DEDIPROG_CFG_PRO_SIZE && init_len < NUM_INIT_ENTRIES {
-        let value = LittleEndian::read_u16(&data[pos..pos + 2]);
-        let reg = LittleEndian::read_u16(&data[pos + 2..pos + 4]);
+    while pos.saturating_add(4) <= DEDIPROG_CFG_PRO_SIZE && init_len < NUM_INIT_ENTRIES {
+        let entry = cfg_field(data, pos, 4, "init entry")?;
+        let value = LittleEndian::read_u16(&entry[0..2]);
+        let reg = LittleEndian::read_u16(&entry[2..4]);
 
         if value == 0xffff && reg == 0xffff {
             reg_offset = INIT_SEQUENCE_REGISTER_OFFSET_1;
@@ -115,41 +255,43 @@ pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
             continue;
         }
 
-        let full_reg = reg + reg_offset;
+        let full_reg = reg.checked_add(reg_offset).ok_or_else(|| {
+            Error::InvalidConfig(format!(
+                "init entry at offset 0x{:x}: register 0x{:x} out of range",
+                pos, reg
+            ))
+        })?;
 
         // Convert to big endian for output
         let be_value = value.to_be_bytes();
         let be_reg = full_reg.to_be_bytes();
 
-        chip.init[init_len][0] = be_reg[0];
-        chip.init[init_len][1] = be_reg[1];
-        chip.init[init_len][2] = be_value[0];
-        chip.init[init_len][3] = be_value[1];
-        init_len += 1;
+        push_init(
+            &mut chip,
+            &mut init_len,
+            [be_reg[0], be_reg[1], be_value[0], be_value[1]],
+            "init entry",
+        )?;
         pos += 4;
     }
 
     // Handle extra data (SFDP, SRST)
     let mut ptr = DEDIPROG_CFG_PRO_SIZE;
-    let mut length = data.len() - DEDIPROG_CFG_PRO_SIZE;
 
-    while length >= 4 {
-        let magic = LittleEndian::read_u32(&data[ptr..ptr + 4]);
+    while let Ok(magic) = cfg_field(data, ptr, 4, "section magic") {
+        let magic = LittleEndian::read_u32(magic);
         ptr += 4;
-        length -= 4;
 
         match magic {
             DEDIPROG_SFDP_MAGIC => {
-                let added = parse_sfdp(&data[ptr..], &mut chip, init_len)?;
-                init_len += added;
+                let section = cfg_field(data, ptr, DEDIPROG_CFG_PRO_SIZE_SFDP, "SFDP section")?;
+                parse_sfdp(section, &mut chip, &mut init_len)?;
                 ptr += DEDIPROG_CFG_PRO_SIZE_SFDP;
-                length = length.saturating_sub(DEDIPROG_CFG_PRO_SIZE_SFDP);
             }
             DEDIPROG_SRST_MAGIC => {
-                let added = parse_srst(&data[ptr..], &mut chip, init_len)?;
-                init_len += added;
+                let section = cfg_field(data, ptr, DEDIPROG_CFG_PRO_SIZE_SRST, "SRST section")?;
+                parse_srst(section, &mut chip, &mut init_len)?;
                 ptr += DEDIPROG_CFG_PRO_SIZE_SRST;
-                length = length.saturating_sub(DEDIPROG_CFG_PRO_SIZE_SRST);
             }
             _ => {
                 // Unknown section, skip
@@ -162,59 +304,42 @@ pub fn parse_dcfg(data: &[u8]) -> Result<ChipDesc> {
     Ok(chip)
 }
 
-fn parse_sfdp(data: &[u8], chip: &mut ChipDesc, entries: usize) -> Result<usize> {
-    if data.len() < DEDIPROG_CFG_PRO_SIZE_SFDP {
-        return Err(Error::InvalidConfig("SFDP data too small".to_string()));
+/// Append the SFDP table to the init sequence
+///
+/// `data` is exactly one SFDP section. Entries that don't fit in the init
+/// sequence are dropped, as the original em100 tool does.
+fn parse_sfdp(data: &[u8], chip: &mut ChipDesc, init_len: &mut usize) -> Result<()> {
+    // Enable SFDP
+    if *init_len < NUM_INIT_ENTRIES {
+        push_init(chip, init_len, [0x23, 0xc9, 0x00, 0x01], "SFDP enable")?;
     }
 
-    let mut len = 0;
-    let mut init_len = entries;
-
-    // Enable SFDP
-    chip.init[init_len][0] = 0x23;
-    chip.init[init_len][1] = 0xc9;
-    chip.init[init_len][2] = 0x00;
-    chip.init[init_len][3] = 0x01;
-    init_len += 1;
-    len += 1;
-
-    for i in (0..DEDIPROG_CFG_PRO_SIZE_SFDP).step_by(2) {
-        if init_len >= NUM_INIT_ENTRIES {
+    for pair in data.chunks_exact(2) {
+        if *init_len >= NUM_INIT_ENTRIES {
             break;
         }
-        chip.init[init_len][0] = 0x23;
-        chip.init[init_len][1] = 0xc1;
-        chip.init[init_len][2] = data[i + 1];
-        chip.init[init_len][3] = data[i];
-        init_len += 1;
-        len += 1;
+        push_init(chip, init_len, [0x23, 0xc1, pair[1], pair[0]], "SFDP data")?;
     }
 
-    Ok(len)
+    Ok(())
 }
 
-fn parse_srst(data: &[u8], chip: &mut ChipDesc, entries: usize) -> Result<usize> {
-    if data.len() < DEDIPROG_CFG_PRO_SIZE_SRST {
-        return Err(Error::InvalidConfig("SRST data too small".to_string()));
-    }
-
-    let mut len = 0;
-    let mut init_len = entries;
-
+/// Append the status register reset values and protection table
+///
+/// `data` is exactly one SRST section.
+fn parse_srst(data: &[u8], chip: &mut ChipDesc, init_len: &mut usize) -> Result<()> {
     // Check for PROT magic at start
-    let magic = LittleEndian::read_u32(&data[0..4]);
+    let magic = LittleEndian::read_u32(cfg_field(data, 0, 4, "PROT magic")?);
     let start_offset = if magic != DEDIPROG_PROT_MAGIC {
         // 3 SRST entries before PROT
         for j in 0..3 {
-            if init_len >= NUM_INIT_ENTRIES {
+            if *init_len >= NUM_INIT_ENTRIES {
                 break;
             }
-            chip.init[init_len][0] = 0x23;
-            chip.init[init_len][1] = data[j * 4 + 2];
-            chip.init[init_len][2] = data[j * 4 + 1];
-            chip.init[init_len][3] = data[j * 4];
-            init_len += 1;
-            len += 1;
+            let srst = cfg_field(data, j * 4, 4, "SRST entry")?;
+            let entry = [0x23, srst[2], srst[1], srst[0]];
+            push_init(chip, init_len, entry, "SRST entry")?;
+            chip.status_reset.push(entry);
         }
         16 // Skip SFDP data and PROT magic
     } else {
@@ -222,136 +347,206 @@ fn parse_srst(data: &[u8], chip: &mut ChipDesc, entries: usize) -> Result<usize>
     };
 
     // Enable PROT
-    if init_len < NUM_INIT_ENTRIES {
-        chip.init[init_len][0] = 0x23;
-        chip.init[init_len][1] = 0xc4;
-        chip.init[init_len][2] = 0x00;
-        chip.init[init_len][3] = 0x01;
-        init_len += 1;
-        len += 1;
+    if *init_len < NUM_INIT_ENTRIES {
+        push_init(chip, init_len, [0x23, 0xc4, 0x00, 0x01], "PROT enable")?;
     }
 
-    for i in (start_offset..DEDIPROG_CFG_PRO_SIZE_SRST).step_by(2) {
-        if init_len >= NUM_INIT_ENTRIES {
+    let prot = data.get(start_offset..).unwrap_or_default();
+    for pair in prot.chunks_exact(2) {
+        if *init_len >= NUM_INIT_ENTRIES {
             break;
         }
-        chip.init[init_len][0] = 0x23;
-        chip.init[init_len][1] = 0xc5;
-        chip.init[init_len][2] = data[i + 1];
-        chip.init[init_len][3] = data[i];
-        init_len += 1;
-        len += 1;
+        push_init(chip, init_len, [0x23, 0xc5, pair[1], pair[0]], "PROT data")?;
     }
 
-    Ok(len)
+    Ok(())
 }
 
-/// Chip configuration database (CLI version with file loading)
-#[cfg(feature = "cli")]
+// Include the generated chip data at module level
+include!(concat!(env!("OUT_DIR"), "/chip_data.rs"));
+
+/// Backing store of a chip database
+enum ChipSource {
+    /// configs.tar.xz, parsed on demand
+    #[cfg(feature = "cli")]
+    Archive(TarFile),
+    /// Chips parsed up front, sorted by vendor and name
+    Parsed(Vec<ChipDesc>),
+}
+
+/// Chip configuration database
+///
+/// The same type is used with and without the `cli` feature; only the
+/// available constructors differ.
 pub struct ChipDatabase {
-    pub configs: TarFile,
+    source: ChipSource,
     pub version: String,
 }
 
-#[cfg(feature = "cli")]
 impl ChipDatabase {
     /// Load chip database from configs.tar.xz
+    #[cfg(feature = "cli")]
     pub fn load() -> Result<Self> {
-        let config_path = get_em100_file("configs.tar.xz")?;
-        let configs = TarFile::load_compressed(&config_path)?;
-
-        // Read version
-        let version_data = configs.find("configs/VERSION")?;
-        let version = String::from_utf8_lossy(&version_data).trim().to_string();
-
-        Ok(Self { configs, version })
+        Self::load_archive(&get_em100_file("configs.tar.xz")?)
     }
 
-    /// Find a chip by name
-    pub fn find_chip(&self, name: &str) -> Result<ChipDesc> {
-        let cfg_name = format!("configs/{}.cfg", name);
-        let data = self
-            .configs
-            .find(&cfg_name)
-            .map_err(|_| Error::InvalidChip(format!("Could not find chip '{}'", name)))?;
-        parse_dcfg(&data)
-    }
-
-    /// List all available chips
-    pub fn list_chips(&self) -> Vec<ChipDesc> {
-        let mut chips = Vec::new();
-        for entry in self.configs.entries() {
-            if entry.ends_with(".cfg") {
-                if let Ok(data) = self.configs.find(entry) {
-                    if let Ok(chip) = parse_dcfg(&data) {
-                        chips.push(chip);
-                    }
-                }
+    /// Load chip database from a configs.tar.xz at `config_path`
+    #[cfg(feature = "cli")]
+    pub fn load_archive(config_path: &std::path::Path) -> Result<Self> {
+        let configs = TarFile::load_compressed(config_path)?;
+
+        // Locally built or mirrored archives may only contain .cfg files
+        let version = match archive_entry(&configs, "VERSION") {
+            Ok(data) => String::from_utf8_lossy(&data).trim().to_string(),
+            Err(_) => {
+                log::warn!(
+                    "{} has no VERSION file, chip database version unknown",
+                    config_path.display()
+                );
+                UNKNOWN_VERSION.to_string()
             }
-        }
-        chips
-    }
-}
+        };
 
-/// In-memory chip database (for web)
-#[cfg(not(feature = "cli"))]
-pub struct ChipDatabase {
-    pub chips: Vec<ChipDesc>,
-    pub version: String,
-}
-
-// Include the generated chip data at module level
-#[cfg(not(feature = "cli"))]
-include!(concat!(env!("OUT_DIR"), "/chip_data.rs"));
+        Ok(Self {
+            source: ChipSource::Archive(configs),
+            version,
+        })
+    }
 
-#[cfg(not(feature = "cli"))]
-impl ChipDatabase {
     /// Load chip database from embedded data
     ///
     /// Chip configurations are embedded at build time from configs.tar.xz
     pub fn load_embedded() -> Self {
-        let mut chips = Vec::new();
-        for (_name, data) in EMBEDDED_CHIP_CONFIGS {
-            if let Ok(chip) = parse_dcfg(data) {
-                chips.push(chip);
-            }
-        }
+        Self::from_parsed(
+            EMBEDDED_CHIP_CONFIGS.iter().copied(),
+            EMBEDDED_CHIP_DB_VERSION.to_string(),
+        )
+    }
+
+    /// Create chip database from in-memory data
+    pub fn from_data(chip_configs: Vec<(&str, &[u8])>, version: String) -> Result<Self> {
+        Ok(Self::from_parsed(chip_configs.into_iter(), version))
+    }
+
+    fn from_parsed<'a>(
+        chip_configs: impl Iterator<Item = (&'a str, &'a [u8])>,
+        version: String,
+    ) -> Self {
+        let mut chips: Vec<ChipDesc> = chip_configs
+            .filter_map(|(_name, data)| parse_dcfg(data).ok())
+            .collect();
 
         // Sort chips by vendor and name for better UX
         chips.sort_by(|a, b| a.vendor.cmp(&b.vendor).then(a.name.cmp(&b.name)));
 
         Self {
-            chips,
-            version: "embedded".to_string(),
+            source: ChipSource::Parsed(chips),
+            version,
         }
     }
 
-    /// Create chip database from in-memory data
-    pub fn from_data(chip_configs: Vec<(&str, &[u8])>, version: String) -> Result<Self> {
-        let mut chips = Vec::new();
-        for (_name, data) in chip_configs {
-            if let Ok(chip) = parse_dcfg(data) {
-                chips.push(chip);
+    /// Find a chip by name, ignoring case
+    ///
+    /// Like the C em100 tool, the name may be prefixed with its vendor
+    /// (e.g. "Winbond W25Q64CV" or "winbond:W25Q64CV").
+    pub fn find_chip(&self, name: &str) -> Result<ChipDesc> {
+        let err = match self.find_chip_name(name) {
+            Ok(chip) => return Ok(chip),
+            Err(e) => e,
+        };
+        let Some((vendor, chip)) = name.split_once([' ', ':']) else {
+            return Err(err);
+        };
+        match self.find_chip_name(chip.trim()) {
+            Ok(chip) if chip.vendor.eq_ignore_ascii_case(vendor.trim()) => Ok(chip),
+            _ => Err(err),
+        }
+    }
+
+    fn find_chip_name(&self, name: &str) -> Result<ChipDesc> {
+        let not_found = || Error::InvalidChip(format!("Could not find chip '{}'", name));
+        match &self.source {
+            #[cfg(feature = "cli")]
+            ChipSource::Archive(configs) => {
+                let data =
+                    archive_entry(configs, &format!("{}.cfg", name)).map_err(|_| not_found())?;
+                parse_dcfg(&data)
             }
+            ChipSource::Parsed(chips) => chips
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(name))
+                .cloned()
+                .ok_or_else(not_found),
         }
-        Ok(Self { chips, version })
     }
 
-    /// Find a chip by name
-    pub fn find_chip(&self, name: &str) -> Result<ChipDesc> {
-        self.chips
-            .iter()
-            .find(|c| c.name.eq_ignore_ascii_case(name))
-            .cloned()
-            .ok_or_else(|| Error::InvalidChip(format!("Could not find chip '{}'", name)))
+    /// Names accepted by `find_chip`, sorted, without parsing any configs
+    pub fn chip_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = match &self.source {
+            #[cfg(feature = "cli")]
+            ChipSource::Archive(configs) => configs
+                .entries()
+                .filter_map(|entry| entry.rsplit('/').next()?.strip_suffix(".cfg"))
+                .map(str::to_string)
+                .collect(),
+            ChipSource::Parsed(chips) => chips.iter().map(|c| c.name.clone()).collect(),
+        };
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Chips matching `filter`, in database order
+    pub fn search(&self, filter: &ChipFilter) -> Vec<ChipDesc> {
+        let mut chips = self.list_chips();
+        chips.retain(|chip| filter.matches(chip));
+        chips
     }
 
     /// List all available chips
     pub fn list_chips(&self) -> Vec<ChipDesc> {
-        self.chips.clone()
+        match &self.source {
+            #[cfg(feature = "cli")]
+            ChipSource::Archive(configs) => {
+                let mut chips = Vec::new();
+                for entry in configs.entries() {
+                    if entry.ends_with(".cfg") {
+                        if let Ok(data) = configs.find(entry) {
+                            if let Ok(chip) = parse_dcfg(&data) {
+                                chips.push(chip);
+                            }
+                        }
+                    }
+                }
+                // Entries come out of a hash map; sort them like parsed configs
+                chips.sort_by(|a, b| a.vendor.cmp(&b.vendor).then(a.name.cmp(&b.name)));
+                chips
+            }
+            ChipSource::Parsed(chips) => chips.clone(),
+        }
     }
 }
 
+/// Version reported for chip databases without a VERSION file
+pub const UNKNOWN_VERSION: &str = "unknown";
+
+/// Find `file` in a config archive, under `configs/` or any other directory
+#[cfg(feature = "cli")]
+fn archive_entry(configs: &TarFile, file: &str) -> Result<Vec<u8>> {
+    configs.find(&format!("configs/{}", file)).or_else(|e| {
+        let entry = configs
+            .entries()
+            .find(|entry| {
+                entry
+                    .rsplit('/')
+                    .next()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(file))
+            })
+            .ok_or(e)?;
+        configs.find(entry)
+    })
+}
+
 /// Get path to EM100 configuration file
 #[cfg(feature = "cli")]
 pub fn get_em100_file(name: &str) -> Result<std::path::PathBuf> {
@@ -372,3 +567,216 @@ pub fn get_em100_file(name: &str) -> Result<std::path::PathBuf> {
 
     Ok(base.join(name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip(size: u32) -> ChipDesc {
+        ChipDesc {
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// A chip whose init sequence sets `millivolts`, if given
+    fn part(vendor: &str, name: &str, size: u32, millivolts: Option<u16>) -> ChipDesc {
+        let mut chip = ChipDesc {
+            vendor: vendor.to_string(),
+            name: name.to_string(),
+            size,
+            ..Default::default()
+        };
+        if let Some(mv) = millivolts {
+            chip.init[0] = [0x11, 0x04, (mv >> 8) as u8, mv as u8];
+            chip.init_len = 1;
+        }
+        chip
+    }
+
+    #[test]
+    fn chip_voltage_comes_from_the_init_sequence() {
+        assert_eq!(part("A", "B", 0, Some(1800)).voltage_mv(), Some(1800));
+        // Some 1.8V configs ask for 1.601V
+        assert_eq!(part("A", "B", 0, Some(1601)).voltage_mv(), Some(1800));
+        assert_eq!(part("A", "B", 0, Some(3300)).voltage_mv(), Some(3300));
+        assert_eq!(part("A", "B", 0, Some(2500)).voltage_mv(), None);
+        assert_eq!(part("A", "B", 0, None).voltage_mv(), None);
+    }
+
+    #[test]
+    fn chip_filters_match_all_criteria() {
+        const MB: u32 = 1024 * 1024;
+        let w25q256 = part("Winbond", "W25Q256FW", 32 * MB, Some(1800));
+        let mx25l = part("Macronix", "MX25L12835F", 16 * MB, Some(3300));
+        let unknown = part("Generic", "Unknown32M", 32 * MB, None);
+        let chips = [&w25q256, &mx25l, &unknown];
+        let found = |filter: ChipFilter| -> Vec<&str> {
+            chips
+                .iter()
+                .filter(|chip| filter.matches(chip))
+                .map(|chip| chip.name.as_str())
+                .collect()
+        };
+
+        assert_eq!(found(ChipFilter::default()).len(), 3);
+        // Any 1.8V 32MB part; chips of unknown voltage don't match
+        assert_eq!(
+            found(ChipFilter {
+                min_size: Some(32 * MB),
+                max_size: Some(32 * MB),
+                voltage: Some(1800),
+                ..Default::default()
+            }),
+            ["W25Q256FW"]
+        );
+        // Size bounds are inclusive
+        let sized = |min, max| ChipFilter {
+            min_size: min,
+            max_size: max,
+            ..Default::default()
+        };
+        assert_eq!(found(sized(Some(16 * MB), Some(16 * MB))), ["MX25L12835F"]);
+        assert_eq!(found(sized(Some(16 * MB + 1), None)).len(), 2);
+        assert_eq!(found(sized(None, Some(32 * MB - 1))), ["MX25L12835F"]);
+        assert!(found(sized(Some(32 * MB), Some(16 * MB))).is_empty());
+        // Vendors match whole and ignoring case, text anywhere in "vendor name"
+        let vendor = |v: &str| ChipFilter {
+            vendor: Some(v.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(found(vendor("winbond")), ["W25Q256FW"]);
+        assert!(found(vendor("Winb")).is_empty());
+        let text = |t: &str| ChipFilter {
+            text: t.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(found(text("macronix mx25")), ["MX25L12835F"]);
+        assert_eq!(found(text("32m")), ["Unknown32M"]);
+    }
+
+    #[test]
+    fn auto_address_mode_follows_chip_size() {
+        let auto = AddressModePolicy::Auto;
+        assert_eq!(auto.resolve(&chip(8 * 1024 * 1024)), 3);
+        assert_eq!(auto.resolve(&chip(AUTO_4BYTE_THRESHOLD)), 3);
+        assert_eq!(auto.resolve(&chip(AUTO_4BYTE_THRESHOLD + 1)), 4);
+        assert_eq!(auto.resolve(&chip(64 * 1024 * 1024)), 4);
+    }
+
+    #[test]
+    fn forced_address_mode_ignores_chip_size() {
+        for size in [8 * 1024 * 1024, 64 * 1024 * 1024] {
+            assert_eq!(AddressModePolicy::Force(3).resolve(&chip(size)), 3);
+            assert_eq!(AddressModePolicy::Force(4).resolve(&chip(size)), 4);
+        }
+    }
+
+    #[test]
+    fn only_3_and_4_byte_modes_are_valid() {
+        assert!(AddressModePolicy::Auto.validate().is_ok());
+        assert!(AddressModePolicy::Force(3).validate().is_ok());
+        assert!(AddressModePolicy::Force(4).validate().is_ok());
+        assert!(matches!(
+            AddressModePolicy::Force(2).validate(),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    /// A minimal config of a 1MB chip: header, vendor and name, no init
+    #[cfg(feature = "cli")]
+    fn dcfg(vendor: &str, name: &str) -> Vec<u8> {
+        let mut data = vec![0u8; DEDIPROG_CFG_PRO_SIZE];
+        LittleEndian::write_u32(&mut data[0..4], DEDIPROG_CFG_MAGIC);
+        LittleEndian::write_u16(&mut data[4..6], 1);
+        LittleEndian::write_u16(&mut data[6..8], 1);
+        LittleEndian::write_u32(&mut data[8..12], DEDIPROG_CFG_PRO_SIZE as u32);
+        LittleEndian::write_u32(&mut data[12..16], 0x100000);
+        LittleEndian::write_u32(&mut data[16..20], 0x80);
+        LittleEndian::write_u32(&mut data[20..24], 0x90);
+        data[0x80..0x80 + vendor.len()].copy_from_slice(vendor.as_bytes());
+        data[0x90..0x90 + name.len()].copy_from_slice(name.as_bytes());
+        data
+    }
+
+    /// Write a configs.tar.xz holding `entries` and load it
+    #[cfg(feature = "cli")]
+    fn load_archive(name: &str, entries: &[(&str, &[u8])]) -> ChipDatabase {
+        use std::io::Write;
+        let mut builder = ::tar::Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("rem100-{}-{}.tar.xz", std::process::id(), name));
+        std::fs::write(&path, xz.finish().unwrap()).unwrap();
+        let db = ChipDatabase::load_archive(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        db
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn archives_with_version_report_it() {
+        let w25q80 = dcfg("Winbond", "W25Q80");
+        let db = load_archive(
+            "configs-versioned",
+            &[
+                ("configs/VERSION", b"4.3.01\n"),
+                ("configs/W25Q80.cfg", &w25q80),
+            ],
+        );
+        assert_eq!(db.version, "4.3.01");
+        assert_eq!(db.chip_names(), ["W25Q80"]);
+        let chip = db.find_chip("winbond w25q80").unwrap();
+        assert_eq!((chip.vendor.as_str(), chip.size), ("Winbond", 0x100000));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn archives_without_version_or_prefix_still_load() {
+        let w25q80 = dcfg("Winbond", "W25Q80");
+        let mx25l = dcfg("Macronix", "MX25L8005");
+        let db = load_archive(
+            "configs-flat",
+            &[("W25Q80.cfg", &w25q80), ("local/MX25L8005.cfg", &mx25l)],
+        );
+        assert_eq!(db.version, UNKNOWN_VERSION);
+        assert_eq!(db.chip_names(), ["MX25L8005", "W25Q80"]);
+        assert_eq!(db.find_chip("W25Q80").unwrap().vendor, "Winbond");
+        assert_eq!(db.find_chip("mx25l8005").unwrap().vendor, "Macronix");
+        let listed: Vec<String> = db.list_chips().into_iter().map(|c| c.name).collect();
+        assert_eq!(listed, ["MX25L8005", "W25Q80"]);
+        assert!(matches!(db.find_chip("W25Q64"), Err(Error::InvalidChip(_))));
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn chip_names_may_have_a_vendor_prefix() {
+        let w25q80 = dcfg("Winbond", "W25Q80");
+        let db = load_archive("configs-vendor", &[("configs/W25Q80.cfg", &w25q80)]);
+        for name in [
+            "W25Q80",
+            "w25q80",
+            "Winbond W25Q80",
+            "winbond:W25Q80",
+            "WINBOND w25q80",
+        ] {
+            assert_eq!(db.find_chip(name).unwrap().name, "W25Q80", "{}", name);
+        }
+        for name in ["Macronix W25Q80", "winbond:W25Q64", "Winbond"] {
+            assert!(
+                matches!(db.find_chip(name), Err(Error::InvalidChip(_))),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,5 @@
+//! Option handling of the rem100 command-line utility
+
+pub mod profile;
+pub mod transfer;
+pub mod validate;
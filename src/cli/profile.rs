@@ -0,0 +1,158 @@
+//! Board and per-device profile defaults for the command line
+
+use crate::{exit, log_event, Args};
+use rem100::boards::{self, Board};
+use rem100::chips::ChipDatabase;
+use rem100::config::Profile;
+
+/// Load and validate boards.toml, exiting on errors
+pub fn load_boards() -> Vec<Board> {
+    let boards = match boards::load_boards() {
+        Ok(boards) => boards,
+        Err(e) => {
+            eprintln!("Error loading boards: {}", e);
+            exit(1);
+        }
+    };
+    match ChipDatabase::load() {
+        Ok(db) => {
+            if let Err(e) = boards::validate_boards(&boards, &db) {
+                eprintln!("Error loading boards: {}", e);
+                exit(1);
+            }
+        }
+        Err(_) => eprintln!("Warning: no chip database, board chips were not checked"),
+    }
+    boards
+}
+
+/// Fill in settings from `board` that weren't given on the command line
+pub fn apply_board(args: &mut Args, board: &Board) {
+    outln!("Using board {}", board);
+    if args.chip.is_none() {
+        args.chip = board.chip.clone();
+    }
+    if args.holdpin.is_none() {
+        args.holdpin = board.holdpin.map(|state| state.to_string());
+    }
+    if !args.no_compatible {
+        args.compatible |= board.compatible.unwrap_or(false);
+    }
+    log_event(&format!("applied board {}", board));
+}
+
+/// The profile settings given on the command line, for --save-profile
+pub fn cli_profile(args: &Args, device: String) -> Profile {
+    let holdpin = args.holdpin.as_deref().map(|state| match state.parse() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    });
+    Profile {
+        device,
+        chip: args.chip.clone(),
+        address_mode: args.address_mode,
+        holdpin,
+        compatible: match (args.compatible, args.no_compatible) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Fill in settings from the device's `profile` that weren't given on the
+/// command line
+pub fn apply_profile(args: &mut Args, profile: &Profile) {
+    if profile.is_empty() {
+        return;
+    }
+    outln!("Using profile {}", profile);
+    let merged = cli_profile(args, profile.device.clone()).or(profile);
+    args.chip = merged.chip;
+    if !args.sync_address_mode {
+        args.address_mode = merged.address_mode;
+    }
+    args.holdpin = merged.holdpin.map(|state| state.to_string());
+    args.compatible = merged.compatible.unwrap_or(false);
+    log_event(&format!("applied profile {}", profile));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::validate::validate_combinations;
+    use crate::tests::args;
+    use rem100::device::HoldPinState;
+
+    fn stored() -> Profile {
+        Profile {
+            device: "EM123456".to_string(),
+            chip: Some("W25Q128FV".to_string()),
+            address_mode: Some(4),
+            holdpin: Some(HoldPinState::Low),
+            compatible: Some(true),
+        }
+    }
+
+    #[test]
+    fn command_line_wins_over_the_profile() {
+        let mut options = args(&["-c", "MX25L12835F", "-p", "input", "-m", "3"]);
+        apply_profile(&mut options, &stored());
+        assert_eq!(options.chip.as_deref(), Some("MX25L12835F"));
+        assert_eq!(options.holdpin.as_deref(), Some("input"));
+        assert_eq!(options.address_mode, Some(3));
+        // Only the settings given were overridden
+        assert!(options.compatible);
+
+        let mut options = args(&["--no-compatible"]);
+        apply_profile(&mut options, &stored());
+        assert!(!options.compatible);
+        assert_eq!(options.chip.as_deref(), Some("W25Q128FV"));
+        // --save-profile stores the override
+        let saved = cli_profile(&args(&["--no-compatible"]), "EM123456".to_string());
+        assert_eq!(saved.compatible, Some(false));
+    }
+
+    #[test]
+    fn profile_fills_in_what_was_not_given() {
+        let mut options = args(&[]);
+        apply_profile(&mut options, &stored());
+        assert_eq!(options.chip.as_deref(), Some("W25Q128FV"));
+        assert_eq!(options.holdpin.as_deref(), Some("low"));
+        assert_eq!(options.address_mode, Some(4));
+        assert!(options.compatible);
+        assert_eq!(validate_combinations(&options).map_err(|e| e.1), Ok(()));
+
+        // The address mode is followed rather than forced
+        let mut options = args(&["-t", "--sync-address-mode"]);
+        apply_profile(&mut options, &stored());
+        assert_eq!(options.address_mode, None);
+        assert_eq!(validate_combinations(&options).map_err(|e| e.1), Ok(()));
+    }
+
+    #[test]
+    fn defaults_stay_without_profile_settings() {
+        let mut options = args(&["-c", "MX25L12835F"]);
+        let empty = Profile {
+            device: "EM123456".to_string(),
+            ..Default::default()
+        };
+        apply_profile(&mut options, &empty);
+        assert_eq!(options.chip.as_deref(), Some("MX25L12835F"));
+        assert_eq!((options.holdpin, options.address_mode), (None, None));
+        assert!(!options.compatible);
+
+        let mut options = args(&[]);
+        let chip_only = Profile {
+            chip: Some("W25Q128FV".to_string()),
+            ..empty
+        };
+        apply_profile(&mut options, &chip_only);
+        assert_eq!(options.chip.as_deref(), Some("W25Q128FV"));
+        assert_eq!((options.holdpin, options.address_mode), (None, None));
+        assert!(!options.compatible);
+    }
+}
@@ -0,0 +1,726 @@
+//! SDRAM downloads, uploads and verification of the -d, -u and -v options
+
+use crate::{
+    check_cancelled, exit, fail, human_out, log_event, op_begin, op_bytes, op_end, parse_hex,
+    parse_size, warn_slow_transfer, Args,
+};
+use clap::error::ErrorKind;
+use clap::CommandFactory;
+use indicatif::{ProgressBar, ProgressStyle};
+use rem100::chips::ChipDesc;
+use rem100::compress::Compression;
+use rem100::device::{check_image_size, Em100, FlashOptions};
+use rem100::error::Error;
+use rem100::image::{load_hex, load_srec, segments_to_image, write_srec, ImageFormat};
+use rem100::protocol::sdram_region_len;
+use rem100::sdram::ProgressCallback;
+use rem100::verify::{hex_digest, VerifyMode, VerifyReport};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+/// One of the -u and -d steps, with its file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer<'a> {
+    /// Upload made before a download, which it is a prerequisite of
+    Backup(&'a str),
+    Download(&'a str),
+    Upload(&'a str),
+}
+
+impl std::fmt::Display for Transfer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transfer::Backup(file) | Transfer::Upload(file) => write!(f, "upload to {}", file),
+            Transfer::Download(file) => write!(f, "download {}", file),
+        }
+    }
+}
+
+/// The -u and -d steps in the order selected by --backup-first/--download-first
+pub fn transfer_order(args: &Args) -> Vec<Transfer<'_>> {
+    let download = args.download.as_deref().map(Transfer::Download);
+    match (args.upload.as_deref(), download) {
+        (Some(upload), Some(download)) if args.download_first => {
+            vec![download, Transfer::Upload(upload)]
+        }
+        (Some(upload), Some(download)) => vec![Transfer::Backup(upload), download],
+        (Some(upload), None) => vec![Transfer::Upload(upload)],
+        (None, download) => download.into_iter().collect(),
+    }
+}
+
+/// Run `transfers` in order, returning a summary line for each step run
+///
+/// The first failure ends the run and is returned with the step that failed,
+/// except for a backup with `ignore_backup_failure`: it is summarized as
+/// failed and the download runs anyway.
+pub fn run_transfers<'a>(
+    transfers: &[Transfer<'a>],
+    ignore_backup_failure: bool,
+    mut run: impl FnMut(Transfer<'a>) -> Result<String, String>,
+) -> (Vec<String>, Option<(Transfer<'a>, String)>) {
+    let mut summary = Vec::new();
+    for &transfer in transfers {
+        match (run(transfer), transfer) {
+            (Ok(done), _) => summary.push(done),
+            (Err(_), Transfer::Backup(file)) if ignore_backup_failure => {
+                summary.push(format!("backup to {} FAILED (ignored)", file));
+            }
+            (Err(e), _) => return (summary, Some((transfer, e))),
+        }
+    }
+    (summary, None)
+}
+
+/// Run -u and -d in the order selected by --backup-first/--download-first
+///
+/// With --backup-first (the default) the upload is a backup, and a failed
+/// backup aborts the download unless --ignore-backup-failure is given.
+pub fn transfer_images(args: &Args, em100: &mut Em100, chip: &Option<ChipDesc>) {
+    let transfers = transfer_order(args);
+    let upload = |em100: &mut Em100, file| {
+        upload_image(args, em100, file).inspect_err(|e| {
+            check_cancelled(em100, "Upload", false);
+            eprintln!("Upload error: {}", e);
+            op_end("error", Some(&format!("Upload error: {}", e)));
+        })
+    };
+    let (summary, failed) = run_transfers(&transfers, args.ignore_backup_failure, |transfer| {
+        match transfer {
+            Transfer::Backup(file) => {
+                upload(em100, file).map(|len| format!("backed up {} bytes to {}", len, file))
+            }
+            Transfer::Upload(file) => {
+                upload(em100, file).map(|len| format!("uploaded {} bytes to {}", len, file))
+            }
+            Transfer::Download(file) => {
+                // Exits on failure, after reporting it
+                download_image(args, em100, chip, file);
+                Ok(format!("downloaded {}", file))
+            }
+        }
+    });
+    if let Some((transfer, _)) = failed {
+        if let Transfer::Backup(_) = transfer {
+            eprintln!(
+                "Not downloading without a backup. Use --ignore-backup-failure to download anyway."
+            );
+        }
+        exit(1);
+    }
+
+    if transfers.len() > 1 {
+        outln!("Transfer summary: {}", summary.join(", then "));
+        log_event(&format!("transfer summary: {}", summary.join(", then ")));
+    }
+}
+
+/// Run an SDRAM transfer while showing its progress as a bar
+pub fn with_transfer_bar<T>(
+    length: usize,
+    transfer: impl FnOnce(ProgressCallback) -> rem100::error::Result<T>,
+) -> rem100::error::Result<T> {
+    let pb = ProgressBar::new(length as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    let result = transfer(Some(&mut |done, total| {
+        pb.set_length(total as u64);
+        pb.set_position(done as u64);
+    }));
+
+    match &result {
+        Ok(_) => pb.finish(),
+        Err(_) => pb.abandon(),
+    }
+    result
+}
+
+/// Upload the emulated flash into `upload_file`, returning its length
+///
+/// The upload starts at -a and covers --upload-length bytes, or else the rest
+/// of the chip.
+///
+/// With `compression` the readback is streamed through the compressor as it
+/// arrives. A failed upload then leaves a compressed stream without its
+/// trailer, which fails to decompress rather than passing for a short image.
+pub fn upload_image(args: &Args, em100: &Em100, upload_file: &str) -> Result<usize, String> {
+    op_begin("upload", 0);
+    let format = file_format(args, upload_file);
+    let compression = upload_compression(args, upload_file);
+    if format == ImageFormat::IntelHex {
+        return Err("Writing Intel HEX is not supported, use --format raw or srec".to_string());
+    }
+
+    let address = args
+        .start_address
+        .as_ref()
+        .and_then(|s| parse_hex(s))
+        .unwrap_or(0) as u32;
+    let maxlen = sdram_region_len(address, upload_length(args), em100.memory_size())
+        .map_err(|e| e.to_string())?;
+    op_bytes(maxlen);
+    warn_slow_transfer(em100, maxlen);
+
+    let Some(compression) = compression else {
+        if format == ImageFormat::Srec {
+            let data = with_transfer_bar(maxlen, |progress| {
+                em100.upload_with_progress(address, maxlen, progress)
+            })
+            .map_err(|e| e.to_string())?;
+            std::fs::write(upload_file, write_srec(&data, address))
+                .map_err(|e| format!("Error writing file: {}", e))?;
+        } else {
+            // Raw images go straight to the file, a chunk at a time
+            let file = File::create(upload_file)
+                .map_err(|e| format!("Could not open upload file: {}", e))?;
+            let mut out = BufWriter::new(file);
+            with_transfer_bar(maxlen, |progress| {
+                em100.upload_to(address, maxlen, &mut out, progress)
+            })
+            .map_err(|e| format!("{} ({} holds only part of the image)", e, upload_file))?;
+            out.flush()
+                .map_err(|e| format!("Error writing file: {}", e))?;
+        }
+        log_event(&format!("uploaded {} bytes to {}", maxlen, upload_file));
+        op_end("ok", None);
+        return Ok(maxlen);
+    };
+
+    let file =
+        File::create(upload_file).map_err(|e| format!("Could not open upload file: {}", e))?;
+    let mut out = compression
+        .writer(BufWriter::new(file))
+        .map_err(|e| e.to_string())?;
+    let uploaded = match format {
+        ImageFormat::Srec => with_transfer_bar(maxlen, |progress| {
+            em100.upload_with_progress(address, maxlen, progress)
+        })
+        .and_then(|data| {
+            out.write_all(write_srec(&data, address).as_bytes())?;
+            Ok(())
+        }),
+        _ => with_transfer_bar(maxlen, |progress| {
+            em100.upload_to(address, maxlen, &mut out, progress)
+        }),
+    };
+    if let Err(e) = uploaded {
+        drop(out);
+        return Err(format!(
+            "{} (the partial {} stream in {} is incomplete)",
+            e, compression, upload_file
+        ));
+    }
+    out.finish()
+        .and_then(|mut file| file.flush())
+        .map_err(|e| format!("Error writing file: {}", e))?;
+    log_event(&format!(
+        "uploaded {} bytes to {} ({})",
+        maxlen, upload_file, compression
+    ));
+    op_end("ok", None);
+    Ok(maxlen)
+}
+
+/// Length given with --upload-length; exits if it is invalid
+pub fn upload_length(args: &Args) -> Option<usize> {
+    let length = args.upload_length.as_deref()?;
+    match parse_size(length) {
+        Some(length) => Some(length as usize),
+        None => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("Invalid --upload-length '{}'", length),
+            )
+            .exit(),
+    }
+}
+
+/// Download `download_file` into SDRAM, verifying it with -v; exits on failure
+pub fn download_image(
+    args: &Args,
+    em100: &mut Em100,
+    chip: &Option<ChipDesc>,
+    download_file: &str,
+) {
+    let spi_start_address = args
+        .start_address
+        .as_ref()
+        .and_then(|s| parse_hex(s))
+        .unwrap_or(0) as u32;
+    op_begin("download", 0);
+
+    if spi_start_address != 0 {
+        outln!("SPI address: 0x{:08x}", spi_start_address);
+    }
+
+    let maxlen = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
+
+    let mut file = match File::open(download_file) {
+        Ok(f) => f,
+        Err(e) => fail(&format!("Can't open file '{}': {}", download_file, e)),
+    };
+
+    // Raw images are streamed from the file; the others, and images -C
+    // patches, are put together in memory
+    let format = file_format(args, download_file);
+    if format != ImageFormat::Raw && spi_start_address != 0 {
+        fail(&format!(
+            "Error: --start-address can't be used with {} files, their addresses are used.",
+            format
+        ));
+    }
+    let mut data = None;
+    if format != ImageFormat::Raw || args.compatible {
+        let mut contents = Vec::new();
+        if let Err(e) = file.read_to_end(&mut contents) {
+            fail(&format!("Error reading file: {}", e));
+        }
+        if format == ImageFormat::Raw {
+            data = Some(contents);
+        } else {
+            match addressed_image(
+                format,
+                &contents,
+                chip.as_ref().map(|c| c.size as usize),
+                maxlen,
+            ) {
+                Ok(image) => data = Some(image),
+                Err(e) => fail(&match chip {
+                    Some(chip) => format!(
+                        "Can't load '{}' for {} {}: {}",
+                        download_file, chip.vendor, chip.name, e
+                    ),
+                    None => format!("Can't load '{}': {}", download_file, e),
+                }),
+            }
+        }
+    }
+
+    let file_len = match (&data, file.metadata()) {
+        (Some(data), _) => data.len(),
+        (None, Ok(metadata)) => metadata.len() as usize,
+        (None, Err(e)) => fail(&format!("Error reading file: {}", e)),
+    };
+    let room = maxlen.saturating_sub(spi_start_address as usize);
+    let mut len = file_len;
+    if args.truncate && len > room {
+        eprintln!(
+            "Warning: Only the first {} of {} bytes of '{}' fit at 0x{:08x}, the rest is not downloaded.",
+            room,
+            file_len,
+            download_file,
+            spi_start_address
+        );
+        len = room;
+        if let Some(data) = data.as_mut() {
+            data.truncate(room);
+        }
+    }
+
+    // With a chip, the file has to fill it from the start address on
+    if let Err(e) = check_image_size(chip.as_ref(), len, spi_start_address, maxlen) {
+        eprintln!("FATAL: {}", e);
+        if len > room {
+            eprintln!("Use --truncate to download only the part that fits.");
+        }
+        exit(1);
+    }
+
+    op_bytes(len);
+    warn_slow_transfer(em100, len);
+
+    let (verification, elapsed) = match data {
+        Some(data) => {
+            // The chip was configured earlier, so flash_image() leaves it alone
+            let opts = FlashOptions {
+                start_address: spi_start_address,
+                memory_size: Some(maxlen),
+                verify: args.verify,
+                verify_mode: verify_mode(args),
+                compatible: args.compatible,
+                ..Default::default()
+            };
+            match with_transfer_bar(len, |progress| {
+                em100.flash_image_with_progress(None, &data, opts, progress)
+            }) {
+                Ok(report) => (report.verification, report.elapsed),
+                Err(e) => {
+                    check_cancelled(em100, "Download", true);
+                    fail(&format!("Download error: {}", e));
+                }
+            }
+        }
+        None => {
+            let started = std::time::Instant::now();
+            let written = with_transfer_bar(len, |progress| {
+                em100.download_from(&mut file, len, spi_start_address, progress)
+            });
+            if let Err(e) = written {
+                check_cancelled(em100, "Download", true);
+                fail(&format!("Download error: {}", e));
+            }
+            let elapsed = started.elapsed();
+            let mut verification = Vec::new();
+            if args.verify {
+                // Read the file again rather than keeping it in memory
+                let verified = file.rewind().map_err(Error::from).and_then(|_| {
+                    with_transfer_bar(len, |progress| {
+                        rem100::verify::verify_reader(
+                            em100,
+                            spi_start_address,
+                            &mut file,
+                            len,
+                            verify_mode(args),
+                            progress,
+                        )
+                    })
+                });
+                match verified {
+                    Ok(report) => verification.push(report),
+                    Err(e) => {
+                        check_cancelled(em100, "Verify", false);
+                        fail(&format!("Verify error: {}", e));
+                    }
+                }
+            }
+            (verification, elapsed)
+        }
+    };
+    log_event(&format!(
+        "downloaded {} bytes from {} to 0x{:08x} in {:.1}s",
+        len,
+        download_file,
+        spi_start_address,
+        elapsed.as_secs_f32()
+    ));
+
+    for report in &verification {
+        print_verify_report(report);
+    }
+    if let Some(path) = &args.verify_report {
+        write_verify_report(&verification, path);
+    }
+    if !verification.iter().all(VerifyReport::passed) {
+        op_end("error", Some("Verification failed"));
+        exit(1);
+    }
+    op_end("ok", None);
+}
+
+/// Print the outcome of a verification and record it in the log
+pub fn print_verify_report(report: &VerifyReport) {
+    let detail = match (report.mode, &report.hashes) {
+        (VerifyMode::Hash, Some((_, actual))) => format!(" (SHA-256 {})", hex_digest(actual)),
+        (VerifyMode::Sample { seed, .. }, _) => format!(
+            " ({} blocks of {} bytes sampled, --verify-seed 0x{:x})",
+            report.sampled.len(),
+            rem100::verify::SAMPLE_BLOCK_SIZE,
+            seed
+        ),
+        _ => String::new(),
+    };
+    let result = if report.passed() { "PASS" } else { "FAIL" };
+    outln!("Verify: {}{}", result, detail);
+    if let (false, Some((expected, _))) = (report.passed(), &report.hashes) {
+        outln!("Expected SHA-256 {}", hex_digest(expected));
+    }
+    if let Some(mismatch) = &report.mismatch {
+        mismatch.write(&mut human_out()).ok();
+    }
+    if let Some(diff) = &report.diff {
+        diff.write_table(&mut human_out(), report.address as u64)
+            .ok();
+    }
+    if !report.sampled.is_empty() {
+        log::debug!(
+            "Sampled blocks: {}",
+            report
+                .sampled
+                .iter()
+                .map(|a| format!("0x{:08x}", a))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    log_event(&format!(
+        "{} verify at 0x{:08x} {}{}",
+        report.mode,
+        report.address,
+        if report.passed() { "passed" } else { "failed" },
+        detail
+    ));
+}
+
+/// Write the --verify-report, a JSON array with one object per range verified
+pub fn write_verify_report(reports: &[VerifyReport], path: &Path) {
+    let result = File::create(path).and_then(|mut f| {
+        writeln!(f, "[")?;
+        for (i, report) in reports.iter().enumerate() {
+            if i > 0 {
+                writeln!(f, ",")?;
+            }
+            report.write_json(&mut f)?;
+        }
+        writeln!(f, "]")
+    });
+    if let Err(e) = result {
+        eprintln!("Error: Can't write verify report {}: {}", path.display(), e);
+    }
+}
+
+/// Verification mode of -v and --verify-only, from --verify-mode and friends
+pub fn verify_mode(args: &Args) -> VerifyMode {
+    let mode = match args.verify_mode.as_deref().map(str::parse::<VerifyMode>) {
+        Some(Ok(mode)) => mode,
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => VerifyMode::default(),
+    };
+    let VerifyMode::Sample { samples, seed } = mode else {
+        return mode;
+    };
+    let seed = match args.verify_seed.as_deref().map(parse_hex) {
+        Some(Some(seed)) => seed,
+        Some(None) => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "Invalid --verify-seed, expected a hex number",
+            )
+            .exit(),
+        None => seed,
+    };
+    VerifyMode::Sample {
+        samples: args.verify_samples.unwrap_or(samples),
+        seed,
+    }
+}
+
+/// Compare SDRAM at the -a address with `file`; exits on a mismatch
+pub fn verify_only(args: &Args, em100: &Em100, file: &str) {
+    let address = args
+        .start_address
+        .as_ref()
+        .and_then(|s| parse_hex(s))
+        .unwrap_or(0) as u32;
+    op_begin("verify", 0);
+    let (mut expected, len) = match File::open(file).and_then(|f| Ok((f.metadata()?.len(), f))) {
+        Ok((len, f)) => (f, len as usize),
+        Err(e) => fail(&format!("Can't open file '{}': {}", file, e)),
+    };
+
+    op_bytes(len);
+    warn_slow_transfer(em100, len);
+    let report = match with_transfer_bar(len, |progress| {
+        rem100::verify::verify_reader(
+            em100,
+            address,
+            &mut expected,
+            len,
+            verify_mode(args),
+            progress,
+        )
+    }) {
+        Ok(report) => report,
+        Err(e) => {
+            check_cancelled(em100, "Verify", false);
+            fail(&format!("Verify error: {}", e));
+        }
+    };
+
+    print_verify_report(&report);
+    if let Some(path) = &args.verify_report {
+        write_verify_report(std::slice::from_ref(&report), path);
+    }
+    if !report.passed() {
+        op_end("error", Some("Verification failed"));
+        exit(1);
+    }
+    op_end("ok", None);
+}
+
+/// Format of a -d/-u file: --format, or else guessed from the extension
+pub fn file_format(args: &Args, file: &str) -> ImageFormat {
+    match args.format.as_deref().map(str::parse::<ImageFormat>) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        // Look through a compression extension, as in dump.srec.xz
+        None => match Compression::from_path(Path::new(file)) {
+            Some(_) => {
+                ImageFormat::from_path(Path::new(Path::new(file).file_stem().unwrap_or_default()))
+            }
+            None => ImageFormat::from_path(Path::new(file)),
+        },
+    }
+}
+
+/// Compression of the -u file, from --upload-compress or its extension
+pub fn upload_compression(args: &Args, file: &str) -> Option<Compression> {
+    match args
+        .upload_compress
+        .as_deref()
+        .map(str::parse::<Compression>)
+    {
+        Some(Ok(compression)) => Some(compression),
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => Compression::from_path(Path::new(file)),
+    }
+}
+
+/// Lay out an Intel HEX or S-record file as a binary image
+///
+/// The image covers the chip, or without one ends with the last data, and
+/// gaps are filled with 0xff.
+pub fn addressed_image(
+    format: ImageFormat,
+    file: &[u8],
+    chip_size: Option<usize>,
+    maxlen: usize,
+) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(file)
+        .map_err(|_| Error::Parse(format!("{} file is not text", format)))?;
+    let segments = match format {
+        ImageFormat::Srec => load_srec(text)?,
+        _ => load_hex(text)?,
+    };
+    let end = segments
+        .last()
+        .map(|s| s.address as usize + s.data.len())
+        .ok_or_else(|| Error::Parse(format!("{} file has no data", format)))?;
+    segments_to_image(&segments, chip_size.unwrap_or(end.min(maxlen)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::args;
+    use clap::Parser;
+    use rem100::mock::{MockTransport, Step};
+    use rem100::protocol;
+
+    const OLD: [u8; 0x100] = [0x11; 0x100];
+    const NEW: [u8; 0x100] = [0x22; 0x100];
+
+    /// Run the transfers of `argv` the way `transfer_images` does, with the
+    /// backup or upload reading OLD and the download writing NEW
+    fn run(mock: &MockTransport, argv: &[&str]) -> (Vec<String>, Option<String>) {
+        let args = args(argv);
+        let em100 = mock.open().unwrap();
+        let (summary, failed) = run_transfers(
+            &transfer_order(&args),
+            args.ignore_backup_failure,
+            |transfer| match transfer {
+                Transfer::Backup(file) | Transfer::Upload(file) => em100
+                    .upload(0, OLD.len())
+                    .map(|data| format!("uploaded {} bytes to {}", data.len(), file))
+                    .map_err(|e| e.to_string()),
+                Transfer::Download(file) => em100
+                    .download(&NEW, 0)
+                    .map(|_| format!("downloaded {}", file))
+                    .map_err(|e| e.to_string()),
+            },
+        );
+        mock.assert_done();
+        (summary, failed.map(|(transfer, _)| transfer.to_string()))
+    }
+
+    fn upload(mock: MockTransport) -> MockTransport {
+        mock.command(protocol::read_sdram_cmd(0, OLD.len()))
+            .step(Step::BulkRead(OLD.to_vec()))
+    }
+
+    fn failed_upload(mock: MockTransport) -> MockTransport {
+        mock.command(protocol::read_sdram_cmd(0, OLD.len()))
+            .step(Step::Timeout)
+    }
+
+    fn download(mock: MockTransport) -> MockTransport {
+        mock.command(protocol::write_sdram_cmd(0, NEW.len()))
+            .step(Step::BulkWrite(NEW.to_vec()))
+    }
+
+    #[test]
+    fn transfers_are_ordered_by_the_backup_options() {
+        use Transfer::*;
+        let cases: [(&[&str], &[Transfer]); 5] = [
+            (&["-u", "a", "-d", "b"], &[Backup("a"), Download("b")]),
+            (
+                &["-d", "b", "-u", "a", "--backup-first"],
+                &[Backup("a"), Download("b")],
+            ),
+            (
+                &["-u", "a", "-d", "b", "--download-first"],
+                &[Download("b"), Upload("a")],
+            ),
+            (&["-u", "a", "--download-first"], &[Upload("a")]),
+            (&["-d", "b"], &[Download("b")]),
+        ];
+        for (argv, order) in cases {
+            assert_eq!(transfer_order(&args(argv)), order, "{:?}", argv);
+        }
+        for argv in [
+            ["--backup-first", "--download-first"],
+            ["--ignore-backup-failure", "--download-first"],
+        ] {
+            let argv = std::iter::once("rem100").chain(argv);
+            assert!(Args::try_parse_from(argv).is_err());
+        }
+    }
+
+    #[test]
+    fn backup_is_uploaded_before_the_download() {
+        let mock = download(upload(MockTransport::stock()));
+        let (summary, failed) = run(&mock, &["-u", "old.bin", "-d", "new.bin"]);
+        assert_eq!(
+            summary,
+            ["uploaded 256 bytes to old.bin", "downloaded new.bin"]
+        );
+        assert_eq!(failed, None);
+    }
+
+    #[test]
+    fn failed_backup_aborts_the_download() {
+        let mock = failed_upload(MockTransport::stock());
+        let (summary, failed) = run(&mock, &["-u", "old.bin", "-d", "new.bin"]);
+        assert!(summary.is_empty());
+        assert_eq!(failed.as_deref(), Some("upload to old.bin"));
+    }
+
+    #[test]
+    fn failed_backup_can_be_ignored() {
+        let mock = download(failed_upload(MockTransport::stock()));
+        let (summary, failed) = run(
+            &mock,
+            &["-u", "old.bin", "-d", "new.bin", "--ignore-backup-failure"],
+        );
+        assert_eq!(
+            summary,
+            ["backup to old.bin FAILED (ignored)", "downloaded new.bin"]
+        );
+        assert_eq!(failed, None);
+    }
+
+    #[test]
+    fn download_first_uploads_the_new_contents() {
+        let mock = upload(download(MockTransport::stock()));
+        let (summary, failed) = run(
+            &mock,
+            &["-u", "new.bin", "-d", "new.bin", "--download-first"],
+        );
+        assert_eq!(
+            summary,
+            ["downloaded new.bin", "uploaded 256 bytes to new.bin"]
+        );
+        assert_eq!(failed, None);
+
+        // A failed upload after the download is an error like any other
+        let mock = failed_upload(download(MockTransport::stock()));
+        let (summary, failed) = run(&mock, &["-u", "a", "-d", "b", "--download-first"]);
+        assert_eq!(summary, ["downloaded b"]);
+        assert_eq!(failed.as_deref(), Some("upload to a"));
+    }
+}
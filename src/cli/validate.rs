@@ -0,0 +1,441 @@
+//! Checks of the command line made before the device is opened
+
+use crate::cli::transfer::{transfer_order, Transfer};
+use crate::{parse_commands, parse_hex_val, trace_trigger, Args};
+use clap::error::ErrorKind;
+use rem100::device::HoldPinState;
+use rem100::trace::TraceFormat;
+
+/// Check -O/-L describe a console buffer within the 32-bit address space
+pub fn validate_console_buffer(offset: Option<&str>, length: Option<&str>) -> Result<(), String> {
+    let offset = match offset.map(parse_hex_val) {
+        Some(Some(offset)) if offset != 0 => offset,
+        Some(_) => return Err("--traceconsole needs a non-zero hex -O/--offset".to_string()),
+        None => return Err("--traceconsole needs the console buffer address, use -O".to_string()),
+    };
+    let length = match length.map(parse_hex_val) {
+        Some(Some(length)) if length != 0 => length,
+        Some(_) => return Err("--traceconsole needs a non-zero hex -L/--length".to_string()),
+        None => return Err("--traceconsole needs the console buffer length, use -L".to_string()),
+    };
+    if offset.checked_add(length).is_none_or(|end| end > 1 << 32) {
+        return Err(format!(
+            "Console buffer 0x{:x}+0x{:x} extends past the 32-bit address space",
+            offset, length
+        ));
+    }
+    Ok(())
+}
+
+/// Reject options that contradict each other or do nothing on their own
+///
+/// Runs before the device is opened, so nothing is done for a command line
+/// that would be rejected, and again once the device's profile filled in
+/// the settings not given.
+pub fn validate_combinations(args: &Args) -> Result<(), (ErrorKind, String)> {
+    // Stopping, changing the emulation and starting again is the usual
+    // order; with nothing in between the two contradict each other
+    let steps_between = args.chip.is_some()
+        || args.download.is_some()
+        || args.upload.is_some()
+        || args.fill.is_some()
+        || args.poke.is_some()
+        || args.verify_only.is_some()
+        || args.firmware_update.is_some()
+        || args.firmware_dump.is_some()
+        || args.firmware_write.is_some()
+        || args.set_serialno.is_some();
+    if args.start && args.stop && !steps_between {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "-r/--start and -s/--stop contradict each other without a step between them"
+                .to_string(),
+        ));
+    }
+    if args.verify
+        && args.download.is_none()
+        && args.verify_only.is_none()
+        && args.firmware_update.is_none()
+    {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "-v/--verify needs something to verify: -d, --verify-only or -F".to_string(),
+        ));
+    }
+    if args.start_address.is_some()
+        && args.download.is_none()
+        && args.upload.is_none()
+        && args.verify_only.is_none()
+    {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "-a/--start-address needs -d, -u or --verify-only".to_string(),
+        ));
+    }
+    if args.address_mode.is_some_and(|mode| mode != 3 && mode != 4) {
+        return Err((
+            ErrorKind::InvalidValue,
+            "-m/--address-mode must be 3 or 4".to_string(),
+        ));
+    }
+    if let Some(Err(e)) = args.holdpin.as_deref().map(str::parse::<HoldPinState>) {
+        return Err((ErrorKind::InvalidValue, e.to_string()));
+    }
+    if args.traceconsole {
+        validate_console_buffer(args.offset.as_deref(), args.length.as_deref())
+            .map_err(|msg| (ErrorKind::InvalidValue, msg))?;
+    }
+    if args.firmware_dump_part.is_some()
+        && args.firmware_dump.is_none()
+        && args.firmware_write.is_none()
+    {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "--firmware-dump-part needs -f or -g".to_string(),
+        ));
+    }
+
+    let tracing = args.trace || args.traceconsole;
+    let terminal = args.terminal || args.terminal_interactive || args.terminal_send.is_some();
+    let format = match args.trace_format.as_deref().map(str::parse::<TraceFormat>) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return Err((ErrorKind::InvalidValue, e.to_string())),
+        None => TraceFormat::default(),
+    };
+    if format != TraceFormat::Text && (terminal || args.traceconsole) {
+        return Err((
+            ErrorKind::ArgumentConflict,
+            "--trace-format csv/jsonl/vcd can't be combined with -T or -R".to_string(),
+        ));
+    }
+    if format == TraceFormat::Vcd && (args.trace_file.is_none() || args.trace_append) {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "--trace-format vcd needs a new --trace-file (without --trace-append)".to_string(),
+        ));
+    }
+    if args.sync_address_mode && !tracing {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "--sync-address-mode needs --trace or --traceconsole".to_string(),
+        ));
+    }
+    if (args.trace_seconds.is_some() || args.trace_count.is_some()) && !tracing {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "--trace-seconds and --trace-count need --trace or --traceconsole".to_string(),
+        ));
+    }
+    match trace_trigger(args) {
+        None => return Err((
+            ErrorKind::InvalidValue,
+            "Invalid trace trigger, expected --trace-trigger-addr ADDR and --trace-trigger-cmd CMD"
+                .to_string(),
+        )),
+        Some(trigger) if !trigger.is_set() && args.trace_pretrigger.is_some() => {
+            return Err((
+                ErrorKind::MissingRequiredArgument,
+                "--trace-pretrigger needs --trace-trigger-addr or --trace-trigger-cmd".to_string(),
+            ))
+        }
+        Some(trigger) if trigger.is_set() && !tracing => {
+            return Err((
+                ErrorKind::MissingRequiredArgument,
+                "--trace-trigger-addr and --trace-trigger-cmd need --trace or --traceconsole"
+                    .to_string(),
+            ))
+        }
+        Some(_) => {}
+    }
+    if args.terminal_lut.is_some() && !terminal {
+        return Err((
+            ErrorKind::MissingRequiredArgument,
+            "--terminal-lut needs --terminal, --terminal-interactive or --terminal-send"
+                .to_string(),
+        ));
+    }
+    if args
+        .console_write_cmds
+        .as_deref()
+        .is_some_and(|s| parse_commands(s).is_none())
+    {
+        return Err((
+            ErrorKind::InvalidValue,
+            "Invalid --console-write-cmds, expected CMD[,CMD...] (e.g. 0x02,0x12)".to_string(),
+        ));
+    }
+    if args.usb_timeout_ms == Some(0) {
+        return Err((
+            ErrorKind::InvalidValue,
+            "--usb-timeout-ms must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The steps `run_operations` takes for `args`, in the order it takes them
+pub fn operation_plan(args: &Args) -> Vec<String> {
+    if let Some(firmware) = &args.firmware_update {
+        return vec![format!("update firmware from {}", firmware)];
+    }
+    let mut plan = Vec::new();
+    if let Some(file) = &args.firmware_dump {
+        plan.push(format!("dump firmware to {}", file));
+    }
+    if let Some(file) = &args.firmware_write {
+        plan.push(format!("write firmware to {}", file));
+    }
+    if let Some(serial) = &args.set_serialno {
+        plan.push(format!("set serial number {}", serial));
+    }
+    let tracing = args.trace || args.terminal || args.traceconsole;
+    if args.stop || (tracing && args.download.is_some()) || args.fill.is_some() {
+        plan.push("stop emulation".to_string());
+    }
+    if let Some(chip) = &args.chip {
+        plan.push(format!("set chip {}", chip));
+    }
+    if let Some(voltage) = &args.set_voltage {
+        plan.push(format!("set voltage {}", voltage));
+    }
+    if let Some(holdpin) = &args.holdpin {
+        plan.push(format!("set hold pin {}", holdpin));
+    }
+    if let Some(pattern) = &args.fill {
+        plan.push(format!("fill with {}", pattern));
+    }
+    for transfer in transfer_order(args) {
+        match transfer {
+            Transfer::Download(file) if args.verify => {
+                plan.push(format!("download {} and verify", file))
+            }
+            transfer => plan.push(transfer.to_string()),
+        }
+    }
+    if args.simulate_erase {
+        plan.push("simulate chip erase".to_string());
+    }
+    if let Some(poke) = &args.poke {
+        plan.push(format!("poke {}", poke));
+    }
+    if let Some(file) = &args.verify_only {
+        plan.push(format!("verify against {}", file));
+    }
+    if args.checksum.is_some() {
+        plan.push("checksum".to_string());
+    }
+    if let Some(peek) = &args.peek {
+        plan.push(format!("peek {}", peek));
+    }
+    if let Some(file) = &args.snapshot {
+        plan.push(format!("snapshot to {}", file.display()));
+    }
+    if args.start && !tracing {
+        plan.push("start emulation".to_string());
+    }
+    #[cfg(feature = "fuse")]
+    if let Some(dir) = &args.mount {
+        plan.push(format!("mount on {}", dir.display()));
+    }
+    if tracing {
+        plan.push("arm trace".to_string());
+        if !args.stop {
+            plan.push("start emulation".to_string());
+        }
+        plan.push("trace until CTRL-C".to_string());
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::args;
+
+    #[test]
+    fn contradicting_options_are_rejected() {
+        use ErrorKind::*;
+        let cases: &[(&[&str], ErrorKind, &str)] = &[
+            (&["-r", "-s"], ArgumentConflict, "-r/--start and -s/--stop"),
+            (
+                &["-r", "-s", "-p", "low"],
+                ArgumentConflict,
+                "-r/--start and -s/--stop",
+            ),
+            (&["-v"], MissingRequiredArgument, "-v/--verify"),
+            (&["-v", "-u", "a"], MissingRequiredArgument, "-v/--verify"),
+            (
+                &["-a", "0x1000"],
+                MissingRequiredArgument,
+                "-a/--start-address",
+            ),
+            (&["-R"], InvalidValue, "use -O"),
+            (&["-R", "-O", "0x1000"], InvalidValue, "use -L"),
+            (&["-R", "-O", "0", "-L", "10"], InvalidValue, "-O/--offset"),
+            (&["-R", "-O", "ffffffff", "-L", "2"], InvalidValue, "32-bit"),
+            (
+                &["--firmware-dump-part", "mcu"],
+                MissingRequiredArgument,
+                "--firmware-dump-part",
+            ),
+            (&["-t", "--trace-format", "xml"], InvalidValue, "xml"),
+            (
+                &["-R", "-O", "1", "-L", "1", "--trace-format", "csv"],
+                ArgumentConflict,
+                "-T or -R",
+            ),
+            (
+                &["--terminal-send", "x", "--trace-format", "jsonl"],
+                ArgumentConflict,
+                "-T or -R",
+            ),
+            (
+                &["-t", "--trace-format", "vcd"],
+                MissingRequiredArgument,
+                "vcd",
+            ),
+            (
+                &["--sync-address-mode"],
+                MissingRequiredArgument,
+                "--sync-address-mode",
+            ),
+            (
+                &["-T", "--sync-address-mode"],
+                MissingRequiredArgument,
+                "--sync-address-mode",
+            ),
+            (
+                &["--trace-seconds", "5"],
+                MissingRequiredArgument,
+                "--trace-seconds",
+            ),
+            (
+                &["-T", "--trace-count", "5"],
+                MissingRequiredArgument,
+                "--trace-count",
+            ),
+            (
+                &["-t", "--trace-trigger-cmd", "0x1ff"],
+                InvalidValue,
+                "trace trigger",
+            ),
+            (
+                &["-t", "--trace-trigger-addr", "x"],
+                InvalidValue,
+                "trace trigger",
+            ),
+            (
+                &["-t", "--trace-pretrigger", "4"],
+                MissingRequiredArgument,
+                "--trace-pretrigger",
+            ),
+            (
+                &["--trace-trigger-cmd", "0x20"],
+                MissingRequiredArgument,
+                "--trace-trigger-addr",
+            ),
+            (
+                &["--terminal-lut", "lut.txt"],
+                MissingRequiredArgument,
+                "--terminal-lut",
+            ),
+            (
+                &["-t", "--terminal-lut", "lut.txt"],
+                MissingRequiredArgument,
+                "--terminal-lut",
+            ),
+            (
+                &[
+                    "-R",
+                    "-O",
+                    "1",
+                    "-L",
+                    "1",
+                    "--console-write-cmds",
+                    "0x02,zz",
+                ],
+                InvalidValue,
+                "--console-write-cmds",
+            ),
+            (&["--usb-timeout-ms", "0"], InvalidValue, "--usb-timeout-ms"),
+            (&["-m", "5"], InvalidValue, "-m/--address-mode"),
+            (&["-p", "high"], InvalidValue, "hold pin state"),
+        ];
+        for (argv, kind, msg) in cases {
+            match validate_combinations(&args(argv)) {
+                Err((k, m)) => {
+                    assert_eq!(k, *kind, "{:?}: {}", argv, m);
+                    assert!(m.contains(msg), "{:?}: {}", argv, m);
+                }
+                Ok(()) => panic!("{:?} was accepted", argv),
+            }
+        }
+    }
+
+    #[test]
+    fn options_with_what_they_need_are_accepted() {
+        let valid: &[&[&str]] = &[
+            &["-v", "-d", "a"],
+            &["--stop", "--set", "M25P80", "-d", "f", "--start"],
+            &["-s", "-u", "a", "-r"],
+            &["-s", "--poke", "0x100=ff", "-r"],
+            &["-v", "--verify-only", "a"],
+            &["-v", "-F", "a"],
+            &["-a", "0x1000", "-u", "a"],
+            &["-a", "0x1000", "--verify-only", "a"],
+            &["-g", "a.dpfw", "--firmware-dump-part", "fpga"],
+            &["-t", "--trace-format", "csv"],
+            &["-t", "--trace-format", "vcd", "--trace-file", "t.vcd"],
+            &["-t", "--sync-address-mode"],
+            &[
+                "-R",
+                "-O",
+                "1",
+                "-L",
+                "1",
+                "--sync-address-mode",
+                "--trace-count",
+                "5",
+            ],
+            &["-t", "--trace-seconds", "5"],
+            &[
+                "-t",
+                "--trace-trigger-cmd",
+                "0x20",
+                "--trace-pretrigger",
+                "4",
+            ],
+            &[
+                "-R",
+                "-O",
+                "1",
+                "-L",
+                "1",
+                "--trace-trigger-addr",
+                "0xfff00000",
+            ],
+            &["-T", "--terminal-lut", "lut.txt"],
+            &["--terminal-interactive", "--terminal-lut", "lut.txt"],
+            &["--terminal-send", "x", "--terminal-lut", "lut.txt"],
+            &[
+                "-R",
+                "-O",
+                "1",
+                "-L",
+                "1",
+                "--console-write-cmds",
+                "0x02,0x12",
+            ],
+            &["--usb-timeout-ms", "1"],
+            &["-m", "4", "-p", "LOW"],
+        ];
+        for argv in valid {
+            assert_eq!(
+                validate_combinations(&args(argv)).map_err(|e| e.1),
+                Ok(()),
+                "{:?}",
+                argv
+            );
+        }
+    }
+}
@@ -0,0 +1,845 @@
+//! EM100 command sequences shared by the blocking and async devices
+//!
+//! Every multi-step exchange with the device (initialization, chip setup,
+//! hold pin and emulation state, SDRAM chunks) is written once here as an
+//! async function over an [`AsyncTransport`]. The async device drives them
+//! with nusb's async transfers (WebUSB on wasm32); the blocking `Em100`
+//! runs them on a transport whose operations complete immediately.
+
+use crate::chips::{AddressModePolicy, ChipDesc};
+use crate::error::{Error, Result};
+use crate::protocol::{self, HoldPinState, HwVersion, PostInitReadback, Quirks};
+use std::time::Duration;
+
+/// Moves EM100 commands and data over USB
+///
+/// The futures are only awaited by this crate's own sequences, so they
+/// don't need to be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport {
+    /// Send a 16-byte command
+    async fn send_cmd(&mut self, cmd: &[u8]) -> Result<()>;
+
+    /// Read a response of up to `length` bytes
+    async fn get_response(&mut self, length: usize) -> Result<Vec<u8>>;
+
+    /// Send a command that merely queries the device and read its response
+    ///
+    /// Transports that can recover from a lost response may retry.
+    async fn exchange(&mut self, cmd: &[u8], length: usize) -> Result<Vec<u8>> {
+        self.send_cmd(cmd).await?;
+        self.get_response(length).await
+    }
+
+    /// Send bulk data, returning how much was sent
+    async fn bulk_write(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Receive up to `length` bytes of bulk data
+    async fn bulk_read(&mut self, length: usize) -> Result<Vec<u8>>;
+
+    /// Wait before sending the next command
+    async fn sleep(&mut self, duration: Duration);
+
+    /// Note that a sequence repeated writes that didn't stick
+    fn count_retry(&mut self) {}
+}
+
+/// Firmware and identity of a device, read when it is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub mcu: u16,
+    pub fpga: u16,
+    pub serial_no: u32,
+    pub hw_version: HwVersion,
+    pub quirks: Quirks,
+}
+
+/// Check that an EM100Pro answers and read its firmware and identity
+///
+/// A warning is logged if the firmware is older than recommended.
+pub async fn identify<T: AsyncTransport>(t: &mut T) -> Result<Identity> {
+    // The status check reads the ID of the EM100's own SPI flash
+    let id = get_spi_flash_id(t).await?;
+    if protocol::find_spi_flash_part(id).is_none() {
+        return Err(Error::StatusUnknown);
+    }
+
+    let (mcu, fpga) = get_version(t).await?;
+    let (serial_no, hw_version) =
+        protocol::parse_info_page(&read_spi_flash_page(t, protocol::INFO_PAGE).await?);
+
+    if let Some(warning) = protocol::firmware_warning(hw_version, mcu, fpga) {
+        log::warn!("{}", warning);
+    }
+    Ok(Identity {
+        mcu,
+        fpga,
+        serial_no,
+        hw_version,
+        quirks: protocol::firmware_quirks(hw_version, fpga),
+    })
+}
+
+/// Read the MCU and FPGA firmware versions
+pub async fn get_version<T: AsyncTransport>(t: &mut T) -> Result<(u16, u16)> {
+    let data = t.exchange(&protocol::version_cmd(), 512).await?;
+    protocol::parse_version(&data)
+}
+
+/// Read the JEDEC ID of the EM100's own SPI flash
+pub async fn get_spi_flash_id<T: AsyncTransport>(t: &mut T) -> Result<u32> {
+    let data = t.exchange(&protocol::spi_flash_id_cmd(), 512).await?;
+    protocol::parse_spi_flash_id(&data)
+}
+
+/// Read a 256-byte page of the EM100's own SPI flash
+pub async fn read_spi_flash_page<T: AsyncTransport>(t: &mut T, address: u32) -> Result<Vec<u8>> {
+    let data = t
+        .exchange(&protocol::read_spi_flash_page_cmd(address), 256)
+        .await?;
+    if data.len() == 256 {
+        Ok(data)
+    } else {
+        Err(Error::InvalidResponse)
+    }
+}
+
+/// Read an FPGA register
+pub async fn read_fpga_register<T: AsyncTransport>(t: &mut T, reg: u8) -> Result<u16> {
+    let data = t
+        .exchange(&protocol::read_fpga_register_cmd(reg), 3)
+        .await?;
+    protocol::parse_fpga_register(&data)
+}
+
+/// Write an FPGA register
+pub async fn write_fpga_register<T: AsyncTransport>(t: &mut T, reg: u8, val: u16) -> Result<()> {
+    t.send_cmd(&protocol::write_fpga_register_cmd(reg, val))
+        .await
+}
+
+/// Writes of the emulation state before giving up on the device following
+///
+/// The state register occasionally lags the write, so a mismatch is
+/// written and checked once more before it counts as a refusal.
+const SET_STATE_ATTEMPTS: u32 = 2;
+
+/// Start or stop emulation, checking that the device followed
+///
+/// Without a chip configured the device refuses to start, which fails with
+/// `Error::OperationFailed` naming the state it reports.
+pub async fn set_state<T: AsyncTransport>(t: &mut T, run: bool) -> Result<()> {
+    let verb = if run { "start" } else { "stop" };
+    let mut actual = !run;
+    for attempt in 0..SET_STATE_ATTEMPTS {
+        if attempt > 0 {
+            log::debug!("Emulation didn't {} yet, writing the state again", verb);
+        }
+        write_fpga_register(t, protocol::FPGA_REG_STATE, run as u16).await?;
+        actual = get_state(t).await?;
+        if actual == run {
+            return Ok(());
+        }
+    }
+    Err(Error::OperationFailed(format!(
+        "Failed to {} emulation. Device reports: {}",
+        verb,
+        if actual { "running" } else { "stopped" }
+    )))
+}
+
+/// Whether emulation is running
+pub async fn get_state<T: AsyncTransport>(t: &mut T) -> Result<bool> {
+    Ok(read_fpga_register(t, protocol::FPGA_REG_STATE).await? != 0)
+}
+
+/// Set the address mode (3 or 4 byte)
+pub async fn set_address_mode<T: AsyncTransport>(t: &mut T, mode: u8) -> Result<()> {
+    let val = protocol::address_mode_value(mode)?;
+    write_fpga_register(t, protocol::FPGA_REG_ADDRESS_MODE, val).await
+}
+
+/// Get the address mode (3 or 4 byte) the emulation is configured for
+pub async fn get_address_mode<T: AsyncTransport>(t: &mut T) -> Result<u8> {
+    let val = read_fpga_register(t, protocol::FPGA_REG_ADDRESS_MODE).await?;
+    Ok(protocol::parse_address_mode(val))
+}
+
+/// Get the hold pin state
+pub async fn get_hold_pin_state<T: AsyncTransport>(t: &mut T) -> Result<HoldPinState> {
+    let val = read_fpga_register(t, protocol::FPGA_REG_HOLD_PIN).await?;
+    protocol::parse_hold_pin_state(val)
+}
+
+/// Set the hold pin state and check that it was taken
+pub async fn set_hold_pin_state<T: AsyncTransport>(t: &mut T, state: HoldPinState) -> Result<()> {
+    let reg = protocol::FPGA_REG_HOLD_PIN;
+
+    // Read and acknowledge current state
+    let val = read_fpga_register(t, reg).await?;
+    write_fpga_register(t, reg, (1 << 2) | val).await?;
+
+    // Read again
+    read_fpga_register(t, reg).await?;
+
+    // Set desired state
+    write_fpga_register(t, reg, state as u16).await?;
+
+    // Verify
+    let new_val = read_fpga_register(t, reg).await?;
+    if new_val != state as u16 {
+        return Err(Error::OperationFailed(format!(
+            "Failed to set hold pin state. Expected {:?}, got {}",
+            state, new_val
+        )));
+    }
+
+    Ok(())
+}
+
+/// Switch the FPGA I/O voltage (18 for 1.8V, 33 for 3.3V)
+///
+/// The FPGA is reconfigured, and `fpga` is updated from the version read
+/// back afterwards. Returns whether the FPGA now runs at the voltage.
+pub async fn set_fpga_voltage<T: AsyncTransport>(
+    t: &mut T,
+    fpga: &mut u16,
+    voltage_code: u8,
+) -> Result<bool> {
+    t.send_cmd(&protocol::fpga_reconfigure_cmd()).await?;
+    t.send_cmd(&protocol::set_fpga_voltage_cmd(voltage_code))
+        .await?;
+
+    // Must wait 2s before issuing any other USB command
+    t.sleep(Duration::from_secs(2)).await;
+
+    if let Ok((_, new_fpga)) = get_version(t).await {
+        *fpga = new_fpga;
+    }
+    Ok(protocol::fpga_voltage_code(*fpga) == voltage_code)
+}
+
+/// Stop emulation and set up `chip`
+///
+/// The FPGA voltage is switched if the chip needs it, updating `fpga`. The
+/// address mode is programmed afterwards according to `policy`. The FPGA
+/// registers written after the init sequence are read back and written once
+/// more if they didn't stick; their final values are returned.
+pub async fn set_chip_type<T: AsyncTransport>(
+    t: &mut T,
+    fpga: &mut u16,
+    hw_version: HwVersion,
+    quirks: Quirks,
+    chip: &ChipDesc,
+    policy: AddressModePolicy,
+) -> Result<PostInitReadback> {
+    policy.validate()?;
+
+    // The init sequence must not reach a running emulation; the state isn't
+    // verified, as it may not read back until a chip is set up
+    write_fpga_register(t, protocol::FPGA_REG_STATE, 0).await?;
+
+    if let Some(switch) = protocol::required_voltage_switch(chip, *fpga) {
+        // Without the voltage flag the switch can't be verified, and
+        // the reported 3.3V may not even be the current voltage
+        if quirks.no_voltage_flag {
+            return Err(Error::OperationFailed(format!(
+                "The current FPGA firmware ({}) can't report its voltage, update it to emulate {} {} ({:.1}V)",
+                protocol::fpga_version_string(*fpga, hw_version),
+                chip.vendor,
+                chip.name,
+                switch.chip_mv as f32 / 1000.0
+            )));
+        }
+        let fpga_mv = protocol::fpga_voltage_mv(*fpga);
+        if !set_fpga_voltage(t, fpga, switch.voltage_code).await? {
+            return Err(Error::OperationFailed(format!(
+                "The current FPGA firmware ({:.1}V) does not support {} {} ({:.1}V)",
+                fpga_mv as f32 / 1000.0,
+                chip.vendor,
+                chip.name,
+                switch.chip_mv as f32 / 1000.0
+            )));
+        }
+    }
+
+    // Send init sequence; one that broke off leaves the chip half set up,
+    // so emulation is kept stopped
+    for (i, entry) in chip.init.iter().take(chip.init_len).enumerate() {
+        if let Err(e) = t.send_cmd(entry).await {
+            write_fpga_register(t, protocol::FPGA_REG_STATE, 0)
+                .await
+                .ok();
+            return Err(Error::OperationFailed(format!(
+                "Init entry {} of {} for {} ({:02x?}) failed, emulation left stopped: {}",
+                i + 1,
+                chip.init_len,
+                chip.name,
+                entry,
+                e
+            )));
+        }
+    }
+
+    // Set FPGA registers
+    for reg in &protocol::POST_INIT_REGISTERS {
+        write_fpga_register(t, reg.reg, reg.value).await?;
+    }
+    let mut readback = read_post_init_registers(t, false).await?;
+    if readback.mismatches().next().is_some() {
+        log::warn!(
+            "FPGA registers lost after chip setup ({}), retrying",
+            readback
+        );
+        t.count_retry();
+        for (reg, _) in readback.mismatches() {
+            write_fpga_register(t, reg.reg, reg.value).await?;
+        }
+        readback = read_post_init_registers(t, true).await?.check()?;
+    }
+
+    set_address_mode(t, policy.resolve(chip)).await?;
+
+    Ok(readback)
+}
+
+async fn read_post_init_registers<T: AsyncTransport>(
+    t: &mut T,
+    retried: bool,
+) -> Result<PostInitReadback> {
+    let mut values = [0u16; 3];
+    for (value, reg) in values.iter_mut().zip(&protocol::POST_INIT_REGISTERS) {
+        *value = read_fpga_register(t, reg.reg).await?;
+    }
+    Ok(PostInitReadback { values, retried })
+}
+
+/// Write one chunk of at most `protocol::TRANSFER_LENGTH` bytes to SDRAM
+///
+/// Returns how many bytes the device took.
+pub async fn write_sdram_chunk<T: AsyncTransport>(
+    t: &mut T,
+    address: u32,
+    chunk: &[u8],
+) -> Result<usize> {
+    t.send_cmd(&protocol::write_sdram_cmd(address, chunk.len()))
+        .await?;
+    t.bulk_write(chunk).await
+}
+
+/// Read one chunk of at most `protocol::TRANSFER_LENGTH` bytes from SDRAM
+///
+/// A short result means the device sent less than asked for.
+pub async fn read_sdram_chunk<T: AsyncTransport>(
+    t: &mut T,
+    address: u32,
+    length: usize,
+) -> Result<Vec<u8>> {
+    t.send_cmd(&protocol::read_sdram_cmd(address, length))
+        .await?;
+    t.bulk_read(length).await
+}
+
+/// Write `data` to SDRAM at `address`, one command per chunk
+pub async fn write_sdram<T: AsyncTransport>(t: &mut T, data: &[u8], address: u32) -> Result<()> {
+    let length = data.len();
+    let mut bytes_sent = 0;
+    while bytes_sent < length {
+        let chunk_len = protocol::next_chunk_len(length, bytes_sent);
+        let chunk = &data[bytes_sent..bytes_sent + chunk_len];
+        let actual = write_sdram_chunk(t, address + bytes_sent as u32, chunk).await?;
+        bytes_sent += actual;
+        if actual < chunk_len {
+            break;
+        }
+    }
+
+    if bytes_sent != length {
+        return Err(Error::Communication(format!(
+            "SDRAM write failed: sent {} of {} bytes",
+            bytes_sent, length
+        )));
+    }
+    Ok(())
+}
+
+/// Read `length` bytes of SDRAM at `address`, one command per chunk
+pub async fn read_sdram<T: AsyncTransport>(
+    t: &mut T,
+    address: u32,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(length);
+    while data.len() < length {
+        let chunk_len = protocol::next_chunk_len(length, data.len());
+        let chunk = read_sdram_chunk(t, address + data.len() as u32, chunk_len).await?;
+        data.extend_from_slice(&chunk);
+        if chunk.len() < chunk_len {
+            break;
+        }
+    }
+
+    if data.len() != length {
+        return Err(Error::Communication(format!(
+            "SDRAM read failed: read {} of {} bytes",
+            data.len(),
+            length
+        )));
+    }
+    Ok(data)
+}
+
+/// The SDRAM chunk loops over a fake endpoint, natively and in the browser
+#[cfg(test)]
+mod chunk_tests {
+    use super::*;
+    use protocol::{Command, TRANSFER_LENGTH};
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test as test;
+
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+    /// Stands in for the bulk endpoints of a device with `sdram` as memory
+    ///
+    /// Every transfer completes at once. `limit` caps the bytes a single bulk
+    /// transfer moves, like a device that gives up in the middle of a chunk.
+    struct FakeEndpoint {
+        sdram: Vec<u8>,
+        commands: Vec<Command>,
+        limit: usize,
+    }
+
+    impl FakeEndpoint {
+        fn new(sdram: Vec<u8>) -> Self {
+            FakeEndpoint {
+                sdram,
+                commands: Vec::new(),
+                limit: usize::MAX,
+            }
+        }
+
+        /// The (address, length) of every SDRAM command with `opcode`
+        fn chunks(&self, opcode: u8) -> Vec<(u32, usize)> {
+            self.commands
+                .iter()
+                .filter(|cmd| cmd[0] == opcode)
+                .map(|cmd| {
+                    let address = u32::from_be_bytes(cmd[1..5].try_into().unwrap());
+                    let length = u32::from_be_bytes(cmd[5..9].try_into().unwrap());
+                    (address, length as usize)
+                })
+                .collect()
+        }
+
+        /// SDRAM range a bulk transfer of `length` bytes after `opcode` covers
+        fn transfer(&self, opcode: u8, length: usize) -> std::ops::Range<usize> {
+            let cmd = self
+                .commands
+                .last()
+                .expect("bulk transfer before a command");
+            assert_eq!(cmd[0], opcode, "bulk transfer after the wrong command");
+            let address = u32::from_be_bytes(cmd[1..5].try_into().unwrap()) as usize;
+            address..address + length.min(self.limit)
+        }
+    }
+
+    impl AsyncTransport for FakeEndpoint {
+        async fn send_cmd(&mut self, cmd: &[u8]) -> Result<()> {
+            self.commands.push(cmd.try_into().unwrap());
+            Ok(())
+        }
+
+        async fn get_response(&mut self, _length: usize) -> Result<Vec<u8>> {
+            unreachable!("SDRAM transfers have no response")
+        }
+
+        async fn bulk_write(&mut self, data: &[u8]) -> Result<usize> {
+            let range = self.transfer(0x40, data.len());
+            let len = range.len();
+            self.sdram[range].copy_from_slice(&data[..len]);
+            Ok(len)
+        }
+
+        async fn bulk_read(&mut self, length: usize) -> Result<Vec<u8>> {
+            let range = self.transfer(0x41, length);
+            Ok(self.sdram[range].to_vec())
+        }
+
+        async fn sleep(&mut self, _duration: Duration) {}
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn writes_are_split_into_transfer_sized_chunks() {
+        let data = pattern(2 * TRANSFER_LENGTH + 5);
+        let mut ep = FakeEndpoint::new(vec![0; 0x100 + data.len()]);
+        futures_lite::future::block_on(write_sdram(&mut ep, &data, 0x100)).unwrap();
+        let chunk = TRANSFER_LENGTH as u32;
+        assert_eq!(
+            ep.chunks(0x40),
+            [
+                (0x100, TRANSFER_LENGTH),
+                (0x100 + chunk, TRANSFER_LENGTH),
+                (0x100 + 2 * chunk, 5)
+            ]
+        );
+        assert_eq!(ep.sdram[0x100..], data[..]);
+    }
+
+    #[test]
+    fn reads_are_split_into_transfer_sized_chunks() {
+        let mut ep = FakeEndpoint::new(pattern(TRANSFER_LENGTH + 0x300));
+        let data =
+            futures_lite::future::block_on(read_sdram(&mut ep, 0x200, TRANSFER_LENGTH + 0x100))
+                .unwrap();
+        assert_eq!(
+            ep.chunks(0x41),
+            [
+                (0x200, TRANSFER_LENGTH),
+                (0x200 + TRANSFER_LENGTH as u32, 0x100)
+            ]
+        );
+        assert_eq!(data, ep.sdram[0x200..]);
+    }
+
+    #[test]
+    fn short_transfers_stop_the_loop() {
+        let data = pattern(TRANSFER_LENGTH + 16);
+        let mut ep = FakeEndpoint::new(vec![0; data.len()]);
+        ep.limit = 1000;
+        match futures_lite::future::block_on(write_sdram(&mut ep, &data, 0)) {
+            Err(Error::Communication(msg)) => assert!(msg.contains("sent 1000 of"), "{}", msg),
+            other => panic!("short write was accepted: {:?}", other),
+        }
+        assert!(matches!(
+            futures_lite::future::block_on(read_sdram(&mut ep, 0, data.len())),
+            Err(Error::Communication(_))
+        ));
+        // Neither loop went on to the second chunk
+        assert_eq!(ep.chunks(0x40).len(), 1);
+        assert_eq!(ep.chunks(0x41).len(), 1);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTransport, Step};
+    use crate::usb::{self, Blocking};
+
+    /// FPGA 0.52 at 3.3V, new enough for the voltage flag
+    const FPGA_3V3: u16 = 0x0034;
+    const FPGA_1V8: u16 = 0x8034;
+
+    fn em100pro(fpga: u16) -> MockTransport {
+        MockTransport::em100pro(0x0102, fpga, 123456, HwVersion::Em100Pro)
+    }
+
+    /// A 1.8V chip: the voltage entry is 0x11 0x04 followed by the millivolts
+    fn chip_1v8(size: u32) -> ChipDesc {
+        let mut chip = ChipDesc {
+            vendor: "Test".to_string(),
+            name: "T18".to_string(),
+            size,
+            ..Default::default()
+        };
+        chip.init[0] = [0x11, 0x04, 0x07, 0x08];
+        chip.init[1] = [0x23, 0xc2, 0x25, 0x39];
+        chip.init_len = 2;
+        chip
+    }
+
+    #[test]
+    fn version_is_parsed() {
+        let mock = em100pro(FPGA_1V8).query(protocol::version_cmd(), &[4, 0x00, 0x35, 0x02, 0x27]);
+        let em100 = mock.open().unwrap();
+        assert_eq!((em100.mcu, em100.fpga), (0x0102, FPGA_1V8));
+        assert_eq!(
+            usb::block_on(get_version(&mut Blocking(&em100))).unwrap(),
+            (0x0227, 0x0035)
+        );
+        mock.assert_done();
+    }
+
+    #[test]
+    fn malformed_version_is_rejected() {
+        let mock = em100pro(FPGA_3V3).query(protocol::version_cmd(), &[3, 0x00, 0x35, 0x02]);
+        let em100 = mock.open().unwrap();
+        assert!(matches!(
+            usb::block_on(get_version(&mut Blocking(&em100))),
+            Err(Error::InvalidResponse)
+        ));
+        mock.assert_done();
+    }
+
+    #[test]
+    fn serial_and_hw_version_come_from_the_info_page() {
+        let em100 = MockTransport::em100pro(0x0102, FPGA_3V3, 0x0001_e240, HwVersion::Em100ProG2)
+            .open()
+            .unwrap();
+        assert_eq!(em100.serial_no, 123456);
+        assert_eq!(em100.hw_version, HwVersion::Em100ProG2);
+        assert!(!em100.has_blank_serial());
+
+        let blank = MockTransport::em100pro(0x0102, FPGA_3V3, u32::MAX, HwVersion::Em100Pro)
+            .open()
+            .unwrap();
+        assert!(blank.has_blank_serial());
+    }
+
+    #[test]
+    fn unknown_spi_flash_is_not_an_em100() {
+        let mock = MockTransport::new().query(protocol::spi_flash_id_cmd(), &[0x12, 0x34, 0x56]);
+        assert!(matches!(mock.open(), Err(Error::StatusUnknown)));
+    }
+
+    #[test]
+    fn hold_pin_is_acknowledged_set_and_verified() {
+        let reg = protocol::FPGA_REG_HOLD_PIN;
+        let mock = em100pro(FPGA_3V3)
+            .read_register(reg, 3)
+            .write_register(reg, 4 | 3)
+            .read_register(reg, 3)
+            .write_register(reg, HoldPinState::Float as u16)
+            .read_register(reg, HoldPinState::Float as u16);
+        let em100 = mock.open().unwrap();
+        em100.set_hold_pin_state(HoldPinState::Float).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn hold_pin_not_taken_is_an_error() {
+        let reg = protocol::FPGA_REG_HOLD_PIN;
+        let mock = em100pro(FPGA_3V3)
+            .read_register(reg, 0)
+            .write_register(reg, 4)
+            .read_register(reg, 0)
+            .write_register(reg, HoldPinState::Input as u16)
+            .read_register(reg, HoldPinState::Low as u16);
+        let em100 = mock.open().unwrap();
+        assert!(matches!(
+            em100.set_hold_pin_state(HoldPinState::Input),
+            Err(Error::OperationFailed(_))
+        ));
+        mock.assert_done();
+    }
+
+    #[test]
+    fn chip_setup_switches_the_fpga_voltage() {
+        let chip = chip_1v8(0x800000);
+        let mock = em100pro(FPGA_3V3)
+            .write_register(protocol::FPGA_REG_STATE, 0)
+            .command(protocol::fpga_reconfigure_cmd())
+            .command(protocol::set_fpga_voltage_cmd(18))
+            .query(protocol::version_cmd(), &[4, 0x80, 0x34, 0x01, 0x02])
+            .chip_init(&chip, 3);
+        let mut em100 = mock.open().unwrap();
+        em100
+            .set_chip_type(&chip, AddressModePolicy::Force(3))
+            .unwrap();
+        assert_eq!(em100.fpga, FPGA_1V8);
+        mock.assert_done();
+    }
+
+    #[test]
+    fn address_mode_register_follows_the_policy() {
+        const MB: u32 = 1024 * 1024;
+        // (chip size, policy, FPGA_REG_ADDRESS_MODE value)
+        let cases = [
+            (8 * MB, AddressModePolicy::Auto, 0),
+            (16 * MB, AddressModePolicy::Auto, 0),
+            (32 * MB, AddressModePolicy::Auto, 1),
+            (64 * MB, AddressModePolicy::Auto, 1),
+            (8 * MB, AddressModePolicy::Force(3), 0),
+            (32 * MB, AddressModePolicy::Force(3), 0),
+            (8 * MB, AddressModePolicy::Force(4), 1),
+            (32 * MB, AddressModePolicy::Force(4), 1),
+        ];
+        for (size, policy, value) in cases {
+            let chip = chip_1v8(size);
+            let mock = em100pro(FPGA_1V8).chip_setup(&chip, protocol::parse_address_mode(value));
+            let mut em100 = mock.open().unwrap();
+            em100.set_chip_type(&chip, policy).unwrap();
+            mock.assert_done();
+        }
+    }
+
+    #[test]
+    fn chip_setup_skips_the_switch_at_the_right_voltage() {
+        let chip = chip_1v8(0x800000);
+        let mock = em100pro(FPGA_1V8).chip_setup(&chip, 3);
+        let mut em100 = mock.open().unwrap();
+        em100
+            .set_chip_type(&chip, AddressModePolicy::Force(3))
+            .unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn chip_setup_fails_if_the_fpga_stays_at_3v3() {
+        let chip = chip_1v8(0x800000);
+        let mock = em100pro(FPGA_3V3)
+            .write_register(protocol::FPGA_REG_STATE, 0)
+            .command(protocol::fpga_reconfigure_cmd())
+            .command(protocol::set_fpga_voltage_cmd(18))
+            .query(protocol::version_cmd(), &[4, 0x00, 0x34, 0x01, 0x02]);
+        let mut em100 = mock.open().unwrap();
+        match em100.set_chip_type(&chip, AddressModePolicy::Auto) {
+            Err(Error::OperationFailed(msg)) => {
+                assert!(msg.contains("does not support"), "{}", msg)
+            }
+            other => panic!("{:?}", other),
+        }
+        mock.assert_done();
+    }
+
+    #[test]
+    fn chip_setup_refuses_a_switch_without_voltage_flag() {
+        let chip = chip_1v8(0x800000);
+        let mock = em100pro(0x0033).write_register(protocol::FPGA_REG_STATE, 0);
+        let mut em100 = mock.open().unwrap();
+        assert!(em100.quirks.no_voltage_flag);
+        match em100.set_chip_type(&chip, AddressModePolicy::Auto) {
+            Err(Error::OperationFailed(msg)) => assert!(msg.contains("can't report"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+        mock.assert_done();
+    }
+
+    #[test]
+    fn chip_setup_is_recorded() {
+        let chip = chip_1v8(0x800000);
+        let mock = em100pro(FPGA_1V8).chip_setup(&chip, 3);
+        let mut em100 = mock.open().unwrap();
+        assert!(em100.chip.is_none());
+        assert_eq!(em100.memory_size(), crate::device::MAX_MEMORY_SIZE);
+        em100
+            .set_chip_type(&chip, AddressModePolicy::Force(3))
+            .unwrap();
+        assert_eq!(em100.chip.as_ref().map(|c| c.name.as_str()), Some("T18"));
+        assert_eq!(em100.memory_size(), 0x800000);
+        mock.assert_done();
+    }
+
+    #[test]
+    fn failed_init_entry_is_named_and_leaves_emulation_stopped() {
+        let chip = chip_1v8(0x800000);
+        let mut first = [0u8; protocol::CMD_LEN];
+        first[..4].copy_from_slice(&chip.init[0]);
+        let mock = em100pro(FPGA_1V8)
+            .chip_setup(&chip, 3)
+            .write_register(protocol::FPGA_REG_STATE, 0)
+            .command(first)
+            .step(Step::Timeout)
+            .write_register(protocol::FPGA_REG_STATE, 0);
+        let mut em100 = mock.open().unwrap();
+        em100
+            .set_chip_type(&chip, AddressModePolicy::Force(3))
+            .unwrap();
+        match em100.set_chip_type(&chip, AddressModePolicy::Force(3)) {
+            Err(Error::OperationFailed(msg)) => {
+                assert!(msg.starts_with("Init entry 2 of 2 for T18"), "{}", msg)
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(em100.chip.is_none());
+        mock.assert_done();
+    }
+
+    #[test]
+    fn state_change_confirmed_at_once() {
+        let reg = protocol::FPGA_REG_STATE;
+        let mock = em100pro(FPGA_3V3)
+            .write_register(reg, 1)
+            .read_register(reg, 1);
+        let em100 = mock.open().unwrap();
+        em100.set_state(true).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn lagging_state_change_is_written_again() {
+        let reg = protocol::FPGA_REG_STATE;
+        let mock = em100pro(FPGA_3V3)
+            .write_register(reg, 0)
+            .read_register(reg, 1)
+            .write_register(reg, 0)
+            .read_register(reg, 0);
+        let em100 = mock.open().unwrap();
+        em100.set_state(false).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn refused_start_reports_the_device_state() {
+        let reg = protocol::FPGA_REG_STATE;
+        let mock = em100pro(FPGA_3V3)
+            .write_register(reg, 1)
+            .read_register(reg, 0)
+            .write_register(reg, 1)
+            .read_register(reg, 0);
+        let em100 = mock.open().unwrap();
+        match em100.set_state(true) {
+            Err(Error::OperationFailed(msg)) => {
+                assert_eq!(msg, "Failed to start emulation. Device reports: stopped")
+            }
+            other => panic!("{:?}", other),
+        }
+        mock.assert_done();
+    }
+
+    #[test]
+    fn sdram_writes_are_split_into_transfer_length_chunks() {
+        let len = protocol::TRANSFER_LENGTH + 0x100;
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let (first, rest) = data.split_at(protocol::TRANSFER_LENGTH);
+        let mock = em100pro(FPGA_3V3)
+            .command(protocol::write_sdram_cmd(0x1000, first.len()))
+            .step(Step::BulkWrite(first.to_vec()))
+            .command(protocol::write_sdram_cmd(
+                0x1000 + first.len() as u32,
+                rest.len(),
+            ))
+            .step(Step::BulkWrite(rest.to_vec()));
+        let em100 = mock.open().unwrap();
+        usb::block_on(write_sdram(&mut Blocking(&em100), &data, 0x1000)).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn sdram_reads_are_split_and_reassembled() {
+        let len = protocol::TRANSFER_LENGTH + 3;
+        let mock = em100pro(FPGA_3V3)
+            .command(protocol::read_sdram_cmd(0, protocol::TRANSFER_LENGTH))
+            .step(Step::BulkRead(vec![0xaa; protocol::TRANSFER_LENGTH]))
+            .command(protocol::read_sdram_cmd(
+                protocol::TRANSFER_LENGTH as u32,
+                3,
+            ))
+            .step(Step::BulkRead(vec![1, 2, 3]));
+        let em100 = mock.open().unwrap();
+        let data = usb::block_on(read_sdram(&mut Blocking(&em100), 0, len)).unwrap();
+        assert_eq!(data.len(), len);
+        assert_eq!(&data[protocol::TRANSFER_LENGTH..], &[1, 2, 3]);
+        mock.assert_done();
+    }
+
+    #[test]
+    fn short_sdram_read_is_an_error() {
+        let mock = em100pro(FPGA_3V3)
+            .command(protocol::read_sdram_cmd(0, 16))
+            .step(Step::BulkRead(vec![0; 8]));
+        let em100 = mock.open().unwrap();
+        let short_transfers = em100.usb_counters().short_transfers;
+        match usb::block_on(read_sdram(&mut Blocking(&em100), 0, 16)) {
+            Err(Error::Communication(msg)) => assert!(msg.contains("read 8 of 16"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(em100.usb_counters().short_transfers, short_transfers + 1);
+        mock.assert_done();
+    }
+}
@@ -0,0 +1,174 @@
+//! Compressed image files
+
+use crate::error::{Error, Result};
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+use std::path::Path;
+use xz2::write::XzEncoder;
+
+/// Compression of an image file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    /// Needs the "zstd" feature
+    Zstd,
+}
+
+impl Compression {
+    /// Guess the compression from a file name extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "gz" => Some(Compression::Gzip),
+            "xz" => Some(Compression::Xz),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress everything written to the returned writer into `w`
+    pub fn writer<W: Write>(self, w: W) -> Result<CompressedWriter<W>> {
+        let gate = Gate {
+            inner: w,
+            abandoned: false,
+        };
+        let encoder = match self {
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(gate, flate2::Compression::default()))
+            }
+            Compression::Xz => Encoder::Xz(XzEncoder::new(gate, 6)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(gate, 0)?),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => {
+                return Err(Error::InvalidArgument(
+                    "zstd compression needs rem100 built with the \"zstd\" feature".to_string(),
+                ))
+            }
+        };
+        Ok(CompressedWriter {
+            encoder: Some(encoder),
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "gz" | "gzip" => Ok(Compression::Gzip),
+            "xz" => Ok(Compression::Xz),
+            "zst" | "zstd" => Ok(Compression::Zstd),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid compression: {} (expected gz, xz or zstd)",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gz"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Streaming compressor returned by `Compression::writer`
+///
+/// The stream is only completed by `finish`. Dropping the writer without
+/// finishing it, e.g. when an upload fails halfway, leaves the stream
+/// without its trailer, so the partial file fails to decompress instead of
+/// looking like a shorter image.
+pub struct CompressedWriter<W: Write> {
+    encoder: Option<Encoder<W>>,
+}
+
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<Gate<W>>),
+    Xz(XzEncoder<Gate<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, Gate<W>>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    /// Complete the compressed stream and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let gate = match self.encoder.take() {
+            Some(Encoder::Gzip(e)) => e.finish()?,
+            Some(Encoder::Xz(e)) => e.finish()?,
+            #[cfg(feature = "zstd")]
+            Some(Encoder::Zstd(e)) => e.finish()?,
+            None => unreachable!("finish consumes the writer"),
+        };
+        Ok(gate.inner)
+    }
+
+    fn encoder(&mut self) -> &mut Encoder<W> {
+        self.encoder
+            .as_mut()
+            .expect("encoder is only taken by finish")
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.encoder() {
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.encoder() {
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+impl<W: Write> Drop for CompressedWriter<W> {
+    fn drop(&mut self) {
+        // The gzip and xz encoders complete the stream when dropped; keep
+        // them from writing the trailer of an unfinished stream
+        let gate = match self.encoder.as_mut() {
+            Some(Encoder::Gzip(e)) => e.get_mut(),
+            Some(Encoder::Xz(e)) => e.get_mut(),
+            #[cfg(feature = "zstd")]
+            Some(Encoder::Zstd(e)) => e.get_mut(),
+            None => return,
+        };
+        gate.abandoned = true;
+    }
+}
+
+/// Writer that silently drops everything once abandoned
+struct Gate<W> {
+    inner: W,
+    abandoned: bool,
+}
+
+impl<W: Write> Write for Gate<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.abandoned {
+            return Ok(buf.len());
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.abandoned {
+            return Ok(());
+        }
+        self.inner.flush()
+    }
+}
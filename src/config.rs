@@ -0,0 +1,325 @@
+//! Per-device profiles: default settings looked up by device identity
+//!
+//! Profiles live in `profiles.toml` in the EM100 home directory
+//! (`$EM100_HOME`, or `~/.em100`) and are applied whenever the device they
+//! name is opened:
+//!
+//! ```toml
+//! [profiles.EM123456]
+//! chip = "W25Q128FV"
+//! address_mode = 4
+//! holdpin = "float"
+//! compatible = true
+//! ```
+//!
+//! A device is named by its serial number, or for a blank serial by
+//! "N.A.@" and its USB port path, as `Em100::identity` gives it. Options
+//! given on the command line take precedence over the profile, which takes
+//! precedence over the built-in defaults. The file understands the same
+//! subset of TOML as the board file.
+
+use crate::boards::{parse_string, strip_comment};
+use crate::chips::get_em100_file;
+use crate::device::HoldPinState;
+use crate::error::{Error, Result};
+use std::path::PathBuf;
+
+/// Name of the profile file in the EM100 home directory
+pub const PROFILES_FILE: &str = "profiles.toml";
+
+/// Default settings of one device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Identity of the device (e.g. "EM123456")
+    pub device: String,
+    /// Chip to emulate
+    pub chip: Option<String>,
+    /// Address mode (3 or 4 byte)
+    pub address_mode: Option<u8>,
+    /// Hold pin state to set
+    pub holdpin: Option<HoldPinState>,
+    /// Patch images for EM100Pro compatibility
+    pub compatible: Option<bool>,
+}
+
+impl Profile {
+    /// Whether the profile holds no settings
+    pub fn is_empty(&self) -> bool {
+        self.chip.is_none()
+            && self.address_mode.is_none()
+            && self.holdpin.is_none()
+            && self.compatible.is_none()
+    }
+
+    /// These settings, with those missing taken from `fallback`
+    ///
+    /// Apply it to the command line options with the profile as fallback,
+    /// so options given explicitly win.
+    pub fn or(self, fallback: &Profile) -> Profile {
+        Profile {
+            device: self.device,
+            chip: self.chip.or_else(|| fallback.chip.clone()),
+            address_mode: self.address_mode.or(fallback.address_mode),
+            holdpin: self.holdpin.or(fallback.holdpin),
+            compatible: self.compatible.or(fallback.compatible),
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.device)?;
+        let mut settings = Vec::new();
+        if let Some(chip) = &self.chip {
+            settings.push(format!("chip {}", chip));
+        }
+        if let Some(mode) = self.address_mode {
+            settings.push(format!("{} byte address mode", mode));
+        }
+        if let Some(holdpin) = self.holdpin {
+            settings.push(format!("hold pin {}", holdpin));
+        }
+        if self.compatible == Some(true) {
+            settings.push("compatible".to_string());
+        }
+        if settings.is_empty() {
+            write!(f, " (no settings)")
+        } else {
+            write!(f, " {}", settings.join(", "))
+        }
+    }
+}
+
+/// Parse a profile file
+pub fn parse_profiles(text: &str) -> Result<Vec<Profile>> {
+    let mut profiles: Vec<Profile> = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let err = |msg: &str| {
+            Error::InvalidConfig(format!("{} line {}: {}", PROFILES_FILE, index + 1, msg))
+        };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let device = header
+                .strip_suffix(']')
+                .and_then(|h| h.trim().strip_prefix("profiles."))
+                .map(|n| n.trim().trim_matches('"'))
+                .filter(|n| !n.is_empty())
+                .ok_or_else(|| err("expected a [profiles.DEVICE] table"))?;
+            if profiles.iter().any(|p| p.device == device) {
+                return Err(err(&format!("device '{}' has two profiles", device)));
+            }
+            profiles.push(Profile {
+                device: device.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| err("expected key = value"))?;
+        let (key, value) = (key.trim(), value.trim());
+        let profile = profiles
+            .last_mut()
+            .ok_or_else(|| err("setting outside a [profiles.DEVICE] table"))?;
+
+        match key {
+            "chip" => {
+                profile.chip =
+                    Some(parse_string(value).ok_or_else(|| err("chip must be a string"))?)
+            }
+            "address_mode" => {
+                profile.address_mode = Some(match value {
+                    "3" => 3,
+                    "4" => 4,
+                    _ => return Err(err("address_mode must be 3 or 4")),
+                })
+            }
+            "holdpin" => {
+                let state = parse_string(value).ok_or_else(|| err("holdpin must be a string"))?;
+                profile.holdpin = Some(
+                    state
+                        .parse()
+                        .map_err(|_| err("holdpin must be \"low\", \"float\" or \"input\""))?,
+                );
+            }
+            "compatible" => {
+                profile.compatible = Some(match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(err("compatible must be true or false")),
+                })
+            }
+            _ => return Err(err(&format!("unknown setting '{}'", key))),
+        }
+    }
+
+    Ok(profiles)
+}
+
+/// Write profiles in the format `parse_profiles` reads
+pub fn format_profiles(profiles: &[Profile]) -> String {
+    let mut text = String::new();
+    for (i, profile) in profiles.iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        text.push_str(&format!("[profiles.\"{}\"]\n", profile.device));
+        if let Some(chip) = &profile.chip {
+            text.push_str(&format!("chip = \"{}\"\n", chip));
+        }
+        if let Some(mode) = profile.address_mode {
+            text.push_str(&format!("address_mode = {}\n", mode));
+        }
+        if let Some(holdpin) = profile.holdpin {
+            text.push_str(&format!("holdpin = \"{}\"\n", holdpin));
+        }
+        if let Some(compatible) = profile.compatible {
+            text.push_str(&format!("compatible = {}\n", compatible));
+        }
+    }
+    text
+}
+
+/// Load the profile file, if there is one
+pub fn load_profiles() -> Result<Vec<Profile>> {
+    let path = get_em100_file(PROFILES_FILE)?;
+    match std::fs::read_to_string(&path) {
+        Ok(text) => parse_profiles(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Profile of the device named `device`, if it has one
+pub fn find_profile(device: &str) -> Result<Option<Profile>> {
+    Ok(load_profiles()?.into_iter().find(|p| p.device == device))
+}
+
+/// Store `profile`, replacing the device's previous one, and return the path
+///
+/// The file is rewritten, so comments in it are lost.
+pub fn save_profile(profile: Profile) -> Result<PathBuf> {
+    let mut profiles = load_profiles()?;
+    match profiles.iter_mut().find(|p| p.device == profile.device) {
+        Some(existing) => *existing = profile,
+        None => profiles.push(profile),
+    }
+    let path = get_em100_file(PROFILES_FILE)?;
+    std::fs::write(&path, format_profiles(&profiles))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(chip: Option<&str>, address_mode: Option<u8>) -> Profile {
+        Profile {
+            device: "EM123456".to_string(),
+            chip: chip.map(str::to_string),
+            address_mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn given_settings_win_over_the_fallback() {
+        let stored = Profile {
+            holdpin: Some(HoldPinState::Low),
+            compatible: Some(true),
+            ..profile(Some("W25Q128FV"), Some(4))
+        };
+        let merged = profile(Some("MX25L12835F"), None).or(&stored);
+        assert_eq!(merged.chip.as_deref(), Some("MX25L12835F"));
+        assert_eq!(merged.address_mode, Some(4));
+        assert_eq!(merged.holdpin, Some(HoldPinState::Low));
+        assert_eq!(merged.compatible, Some(true));
+
+        // Settings neither gives are left to the built-in defaults
+        let merged = profile(None, None).or(&profile(None, Some(3)));
+        assert_eq!((merged.chip, merged.address_mode), (None, Some(3)));
+        assert_eq!((merged.holdpin, merged.compatible), (None, None));
+        assert_eq!(merged.device, "EM123456");
+    }
+
+    #[test]
+    fn profiles_are_parsed() {
+        let profiles = parse_profiles(
+            "# Rack
+             [profiles.EM123456]
+             chip = \"W25Q128FV\"   # boot flash
+             address_mode = 4
+             holdpin = \"float\"
+             compatible = true
+
+             [profiles.\"N.A.@1-3.2\"]
+             holdpin = \"LOW\"
+             ",
+        )
+        .unwrap();
+        assert_eq!(
+            profiles,
+            [
+                Profile {
+                    holdpin: Some(HoldPinState::Float),
+                    compatible: Some(true),
+                    ..profile(Some("W25Q128FV"), Some(4))
+                },
+                Profile {
+                    device: "N.A.@1-3.2".to_string(),
+                    holdpin: Some(HoldPinState::Low),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(
+            parse_profiles(&format_profiles(&profiles)).unwrap(),
+            profiles
+        );
+        assert!(profiles[1].chip.is_none() && !profiles[1].is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_reported_with_their_number() {
+        for (text, msg) in [
+            ("chip = \"W25Q128FV\"", "line 1: setting outside"),
+            (
+                "[profiles.EM1]\naddress_mode = 5",
+                "line 2: address_mode must be 3 or 4",
+            ),
+            (
+                "[profiles.EM1]\nholdpin = \"high\"",
+                "line 2: holdpin must be",
+            ),
+            (
+                "[profiles.EM1]\nchip = W25Q128FV",
+                "line 2: chip must be a string",
+            ),
+            (
+                "[profiles.EM1]\ncompatible = yes",
+                "line 2: compatible must be",
+            ),
+            (
+                "[profiles.EM1]\nvoltage = 1.8",
+                "line 2: unknown setting 'voltage'",
+            ),
+            ("[profiles.EM1]\nchip", "line 2: expected key = value"),
+            ("[boards.x]", "line 1: expected a [profiles.DEVICE] table"),
+            (
+                "[profiles.EM1]\n\n[profiles.EM1]",
+                "line 3: device 'EM1' has two profiles",
+            ),
+        ] {
+            match parse_profiles(text) {
+                Err(Error::InvalidConfig(e)) => assert!(e.contains(msg), "{}: {}", text, e),
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+        }
+    }
+}
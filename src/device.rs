@@ -1,102 +1,298 @@
 //! Core EM100 device structure and operations
 
-use crate::chips::ChipDesc;
+use crate::cancel::CancelToken;
+use crate::checksum::{self, Digest, DigestAlgorithm};
+use crate::chips::{AddressModePolicy, ChipDesc};
+use crate::commands;
 use crate::error::{Error, Result};
 use crate::fpga;
-use crate::sdram;
+use crate::protocol;
+use crate::record::{self, RecordingTransport};
+use crate::sdram::{self, ProgressCallback};
 use crate::spi;
 use crate::system;
-use crate::usb;
-use nusb::transfer::{Bulk, In, Out};
-use nusb::{Endpoint, MaybeFuture};
-use std::cell::RefCell;
+use crate::usb::{self, NusbTransport};
+use crate::verify::{self, VerifyMode, VerifyReport};
+use nusb::{MaybeFuture, Speed};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::time::Duration;
 
-/// EM100 USB Vendor ID
-pub const VENDOR_ID: u16 = 0x04b4;
-/// EM100 USB Product ID  
-pub const PRODUCT_ID: u16 = 0x1235;
+/// Where the udev rule granting access to the EM100Pro is installed
+pub const UDEV_RULE_PATH: &str = "/etc/udev/rules.d/99-em100.rules";
 
-/// USB bulk transfer timeout in milliseconds
-pub const BULK_SEND_TIMEOUT: Duration = Duration::from_millis(5000);
+/// udev rule giving logged-in users access to the EM100Pro
+pub fn udev_rule() -> String {
+    format!(
+        r#"SUBSYSTEM=="usb", ATTR{{idVendor}}=="{:04x}", ATTR{{idProduct}}=="{:04x}", MODE="0666", TAG+="uaccess""#,
+        VENDOR_ID, PRODUCT_ID
+    )
+}
 
-/// Hardware versions
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum HwVersion {
-    /// Early EM100Pro (hardware version 0xff)
-    Em100ProEarly = 0xff,
-    /// EM100Pro (hardware version 0x04)
-    Em100Pro = 0x04,
-    /// EM100Pro-G2 (hardware version 0x06)
-    Em100ProG2 = 0x06,
-    /// Unknown hardware version
-    Unknown = 0x00,
+/// udev rule giving the members of `group` access to the EM100Pro
+pub fn udev_rule_for_group(group: &str) -> String {
+    format!(
+        r#"SUBSYSTEM=="usb", ATTR{{idVendor}}=="{:04x}", ATTR{{idProduct}}=="{:04x}", MODE="0660", GROUP="{}""#,
+        VENDOR_ID, PRODUCT_ID, group
+    )
 }
 
-impl From<u8> for HwVersion {
-    fn from(v: u8) -> Self {
-        match v {
-            0xff => HwVersion::Em100ProEarly,
-            0x04 => HwVersion::Em100Pro,
-            0x06 => HwVersion::Em100ProG2,
-            _ => HwVersion::Unknown,
-        }
+/// Shell commands installing the udev rule
+pub fn udev_rule_commands() -> String {
+    udev_install_commands(&udev_rule(), Path::new(UDEV_RULE_PATH))
+}
+
+/// Shell commands installing `rule` as the udev rule file `path`
+pub fn udev_install_commands(rule: &str, path: &Path) -> String {
+    format!(
+        "echo '{}' | sudo tee {}\nsudo udevadm control --reload-rules\nsudo udevadm trigger",
+        rule,
+        path.display()
+    )
+}
+
+/// Outcome of installing or removing the udev rule file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UdevChange {
+    /// The file was written or removed
+    Done,
+    /// There was no file to remove
+    NotInstalled,
+    /// Not allowed to change the file; these shell commands do it by hand
+    NeedsRoot(String),
+}
+
+/// Write `rule` to the udev rule file `path`
+pub fn install_udev_rule(path: &Path, rule: &str) -> std::io::Result<UdevChange> {
+    udev_change(std::fs::write(path, format!("{}\n", rule)), || {
+        udev_install_commands(rule, path)
+    })
+}
+
+/// Remove the udev rule file `path`
+pub fn uninstall_udev_rule(path: &Path) -> std::io::Result<UdevChange> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(UdevChange::NotInstalled),
+        result => udev_change(result, || {
+            format!(
+                "sudo rm {}\nsudo udevadm control --reload-rules",
+                path.display()
+            )
+        }),
     }
 }
 
-impl std::fmt::Display for HwVersion {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            HwVersion::Em100ProEarly => write!(f, "EM100Pro (early)"),
-            HwVersion::Em100Pro => write!(f, "EM100Pro"),
-            HwVersion::Em100ProG2 => write!(f, "EM100Pro-G2"),
-            HwVersion::Unknown => write!(f, "Unknown"),
+/// Turn a lack of privileges into the commands to run by hand
+fn udev_change(
+    result: std::io::Result<()>,
+    by_hand: impl FnOnce() -> String,
+) -> std::io::Result<UdevChange> {
+    match result {
+        Ok(()) => Ok(UdevChange::Done),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Ok(UdevChange::NeedsRoot(by_hand()))
         }
+        Err(e) => Err(e),
     }
 }
 
-/// Hold pin states
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub enum HoldPinState {
-    #[default]
-    Float = 0x2,
-    Low = 0x0,
-    Input = 0x3,
+/// Guidance for `Error::PermissionDenied`
+pub fn permission_help() -> String {
+    format!(
+        "The EM100Pro was found, but this user is not allowed to access it.\n\
+         On Linux, install a udev rule:\n\n{}\n\n\
+         Then unplug and replug the EM100Pro.",
+        udev_rule_commands()
+    )
 }
 
-impl std::str::FromStr for HoldPinState {
+/// Timeout of every USB bulk transfer unless set with `Em100::set_usb_timeout`
+pub const DEFAULT_USB_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// How long `Em100::reconnect` waits for the device to enumerate again
+pub const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub use crate::protocol::{
+    HoldPinState, HwVersion, PostInitReadback, Quirks, PRODUCT_ID, VENDOR_ID,
+};
+
+/// Device selection as given on the command line with `-x`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// USB bus number and device address
+    BusDevice(u8, u8),
+    /// Serial number (without the DP/EM prefix)
+    Serial(u32),
+    /// Device with a blank serial number at USB bus and device address
+    BlankSerial(u8, u8),
+    /// Device plugged into a physical port: USB bus and hub ports from the
+    /// root hub down, written BUS-PORT[.PORT...] (e.g. 1-3.2)
+    PortPath(u8, Vec<u8>),
+}
+
+impl std::str::FromStr for DeviceSelector {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        match s.to_uppercase().as_str() {
-            "FLOAT" => Ok(HoldPinState::Float),
-            "LOW" => Ok(HoldPinState::Low),
-            "INPUT" => Ok(HoldPinState::Input),
-            _ => Err(Error::InvalidArgument(format!(
-                "Invalid hold pin state: {}",
+        let invalid = || {
+            Error::InvalidArgument(format!(
+                "Invalid device selector '{}'. Use BUS:DEV (e.g. 001:003), \
+                 a serial number (e.g. EM123456 or DP123456), \
+                 na:BUS:DEV for a device without serial number \
+                 or a USB port path (e.g. 1-3.2)",
                 s
-            ))),
+            ))
+        };
+
+        let mut upper = s.trim().to_uppercase();
+        let blank = upper.starts_with("NA:");
+        if blank {
+            upper.drain(..3);
         }
+        if let Some(digits) = upper
+            .strip_prefix("DP")
+            .or_else(|| upper.strip_prefix("EM"))
+        {
+            if blank || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            let serial = digits.parse::<u32>().map_err(|_| invalid())?;
+            return Ok(DeviceSelector::Serial(serial));
+        }
+
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if let Some((bus, ports)) = upper.split_once('-') {
+            if blank || !is_number(bus) || !ports.split('.').all(is_number) {
+                return Err(invalid());
+            }
+            let bus = bus.parse::<u8>().map_err(|_| invalid())?;
+            let ports = ports
+                .split('.')
+                .map(|p| p.parse::<u8>().map_err(|_| invalid()))
+                .collect::<Result<_>>()?;
+            return Ok(DeviceSelector::PortPath(bus, ports));
+        }
+
+        let (bus, dev) = upper.split_once(':').ok_or_else(invalid)?;
+        if !is_number(bus) || !is_number(dev) {
+            return Err(invalid());
+        }
+        let bus = bus.parse::<u8>().map_err(|_| invalid())?;
+        let dev = dev.parse::<u8>().map_err(|_| invalid())?;
+        if blank {
+            return Ok(DeviceSelector::BlankSerial(bus, dev));
+        }
+        Ok(DeviceSelector::BusDevice(bus, dev))
     }
 }
 
-impl std::fmt::Display for HoldPinState {
+impl std::fmt::Display for DeviceSelector {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            HoldPinState::Float => write!(f, "float"),
-            HoldPinState::Low => write!(f, "low"),
-            HoldPinState::Input => write!(f, "input"),
+            DeviceSelector::BusDevice(bus, dev) => write!(f, "{:03}:{:03}", bus, dev),
+            DeviceSelector::Serial(serial) => write!(f, "EM{:06}", serial),
+            DeviceSelector::BlankSerial(bus, dev) => write!(f, "na:{:03}:{:03}", bus, dev),
+            DeviceSelector::PortPath(bus, ports) => {
+                let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+                write!(f, "{}-{}", bus, ports.join("."))
+            }
+        }
+    }
+}
+
+/// How often `Em100::open_with_timeout` looks for the device again
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Emulation settings `Em100::reconnect` puts back
+///
+/// Settings left `None` aren't restored. SDRAM contents are not part of it.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceState {
+    /// Chip to set up again
+    pub chip: Option<ChipDesc>,
+    /// Address mode (3 or 4 byte)
+    pub address_mode: Option<u8>,
+    pub hold_pin: Option<HoldPinState>,
+    /// Whether emulation was running
+    pub running: Option<bool>,
+}
+
+/// USB bus/port topology and negotiated link speed of a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsbLocation {
+    /// USB bus number
+    pub bus: u8,
+    /// USB device address
+    pub address: u8,
+    /// Hub port numbers from the root hub down to the device
+    pub port_chain: Vec<u8>,
+    /// Negotiated link speed, if known
+    pub speed: Option<Speed>,
+}
+
+impl UsbLocation {
+    fn from_device_info(device: &nusb::DeviceInfo) -> Self {
+        Self {
+            bus: device.busnum(),
+            address: device.device_address(),
+            port_chain: device.port_chain().to_vec(),
+            speed: device.speed(),
         }
     }
+
+    /// Port chain in sysfs notation (e.g. "1-2.3")
+    pub fn port_path(&self) -> String {
+        let ports: Vec<String> = self.port_chain.iter().map(|p| p.to_string()).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+
+    /// Whether the device is plugged into `port_chain` on `bus`
+    ///
+    /// The whole chain has to match, so devices on a hub plugged into that
+    /// port don't.
+    pub fn is_at(&self, bus: u8, port_chain: &[u8]) -> bool {
+        self.bus == bus && self.port_chain == port_chain
+    }
+
+    /// Human readable link speed
+    pub fn speed_name(&self) -> &'static str {
+        match self.speed {
+            Some(Speed::Low) => "Low speed (1.5 Mbit/s)",
+            Some(Speed::Full) => "Full speed (12 Mbit/s)",
+            Some(Speed::High) => "High speed (480 Mbit/s)",
+            Some(Speed::Super) => "SuperSpeed (5 Gbit/s)",
+            Some(Speed::SuperPlus) => "SuperSpeed+ (10 Gbit/s)",
+            _ => "unknown speed",
+        }
+    }
+
+    /// Whether large SDRAM transfers over this link will take minutes
+    pub fn is_slow_link(&self) -> bool {
+        matches!(self.speed, Some(Speed::Low | Speed::Full | Speed::High))
+    }
+}
+
+impl std::fmt::Display for UsbLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bus {:03} device {:03}, port {}, {}",
+            self.bus,
+            self.address,
+            self.port_path(),
+            self.speed_name()
+        )
+    }
 }
 
 /// EM100 device structure
 pub struct Em100 {
-    /// USB bulk OUT endpoint
-    pub endpoint_out: RefCell<Endpoint<Bulk, Out>>,
-    /// USB bulk IN endpoint
-    pub endpoint_in: RefCell<Endpoint<Bulk, In>>,
+    /// Carries commands and data to the device
+    pub(crate) transport: Box<dyn usb::Transport>,
     /// MCU firmware version
     pub mcu: u16,
     /// FPGA firmware version
@@ -105,65 +301,314 @@ pub struct Em100 {
     pub serial_no: u32,
     /// Hardware version
     pub hw_version: HwVersion,
+    /// Protocol quirks of the installed firmware
+    pub quirks: Quirks,
+    /// USB topology and link speed
+    pub usb: UsbLocation,
+    /// USB trouble seen since the device was opened
+    pub(crate) usb_counters: Cell<usb::UsbCounters>,
+    /// Timeout of every USB bulk transfer
+    pub(crate) usb_timeout: Duration,
+    /// Sleep the specified time after SPI flash erases instead of polling
+    pub conservative_timing: bool,
+    /// Checked by long transfers and firmware operations between chunks
+    pub cancel: CancelToken,
+    /// Chip last set up with [`Em100::set_chip_type`]
+    ///
+    /// None until a chip is set up, and again after a setup failed part-way.
+    /// The device can't report its chip, so this only knows about chips set
+    /// up through this handle.
+    pub chip: Option<ChipDesc>,
 }
 
-/// USB endpoint addresses
-const ENDPOINT_OUT: u8 = 0x01;
-const ENDPOINT_IN: u8 = 0x82;
+/// Advisory lock marking a device as busy with a long-running rem100 session
+///
+/// Other rem100 processes check this before probing a device, since opening
+/// and claiming it would disturb a running trace. The lock is released when
+/// the holder exits, however it exits.
+pub struct DeviceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+/// Lock files held by this process, which may reopen its own devices
+static HELD_LOCKS: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+impl DeviceLock {
+    fn path(usb: &UsbLocation) -> PathBuf {
+        std::env::temp_dir().join(format!("rem100-{}.lock", usb.port_path()))
+    }
+
+    /// Lock the device at `usb`, recording our PID and what we are doing
+    pub fn acquire(usb: &UsbLocation, activity: &str) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(Self::path(usb))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::OperationFailed(format!(
+                    "Device at port {} is in use",
+                    usb.port_path()
+                )));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "PID {} ({})", std::process::id(), activity)?;
+        file.flush()?;
+        let path = Self::path(usb);
+        HELD_LOCKS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashSet::new)
+            .insert(path.clone());
+        Ok(Self { _file: file, path })
+    }
+
+    /// Describe the session holding the lock on `usb`, if another one does
+    pub fn holder(usb: &UsbLocation) -> Option<String> {
+        let path = Self::path(usb);
+        if HELD_LOCKS
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|held| held.contains(&path))
+        {
+            return None;
+        }
+        let mut file = File::open(path).ok()?;
+        match file.try_lock_shared() {
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                file.rewind().ok()?;
+                file.read_to_string(&mut holder).ok()?;
+                Some(holder)
+            }
+            _ => None,
+        }
+    }
+
+    /// Fail if another session holds the lock on `usb`
+    fn check(usb: &UsbLocation) -> Result<()> {
+        match Self::holder(usb) {
+            Some(holder) => Err(Error::OperationFailed(format!(
+                "Device at port {} is in use by {}",
+                usb.port_path(),
+                holder
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        if let Some(held) = HELD_LOCKS.lock().unwrap().as_mut() {
+            held.remove(&self.path);
+        }
+    }
+}
+
+/// Location and endpoints of a freshly opened device
+type OpenedDevice = (UsbLocation, Box<dyn usb::Transport>);
+
+/// Open `device` unless another session holds its lock
+fn open_unlocked(device: &nusb::DeviceInfo) -> Result<OpenedDevice> {
+    let usb = UsbLocation::from_device_info(device);
+    DeviceLock::check(&usb)?;
+    let transport = NusbTransport::open(device, DEFAULT_USB_TIMEOUT)?;
+    Ok((usb, Box::new(transport)))
+}
+
+/// `transport`, recording its traffic if recording is on
+///
+/// With `append` the traffic is added to the existing recording, as for a
+/// device that is reopened after dropping off the bus.
+fn recorded(transport: Box<dyn usb::Transport>, append: bool) -> Result<Box<dyn usb::Transport>> {
+    let Some(path) = record::record_path() else {
+        return Ok(transport);
+    };
+    Ok(Box::new(if append {
+        RecordingTransport::append(transport, &path)?
+    } else {
+        RecordingTransport::create(transport, &path)?
+    }))
+}
 
 impl Em100 {
     /// Open an EM100 device
     ///
     /// If bus and device are specified, opens the device at that location.
     /// If serial_number is specified, opens the device with that serial number.
-    /// Otherwise, opens the first EM100 device found.
+    /// Otherwise, opens the only EM100 device connected; with several
+    /// connected this fails with `Error::MultipleDevices` rather than guess.
     pub fn open(bus: Option<u8>, device: Option<u8>, serial_number: Option<u32>) -> Result<Self> {
-        let (endpoint_out, endpoint_in) = if let (Some(bus), Some(dev)) = (bus, device) {
+        let opened = if let (Some(bus), Some(dev)) = (bus, device) {
             // Find device by bus:device
             Self::open_by_bus_device(bus, dev)?
         } else if let Some(serial) = serial_number {
             // Find device by serial number - need to open each and check
             Self::open_by_serial(serial)?
         } else {
-            // Open first available device
-            Self::open_first()?
+            Self::open_only()?
         };
+        Self::from_opened(opened)
+    }
+
+    /// Open the first EM100 device enumerated, however many are connected
+    ///
+    /// The enumeration order can change across boots, so with several
+    /// devices this picks one at random.
+    pub fn open_any() -> Result<Self> {
+        Self::from_opened(Self::open_first()?)
+    }
 
-        let mut em100 = Em100 {
-            endpoint_out: RefCell::new(endpoint_out),
-            endpoint_in: RefCell::new(endpoint_in),
+    /// Open an EM100 device like [`Em100::open`], waiting up to `timeout`
+    /// for it to appear
+    ///
+    /// Enumeration is retried every 500ms, also when the device vanishes
+    /// while being opened. Several devices matching, or bus:device naming
+    /// something else, fail at once. "Waiting for EM100pro..." is logged
+    /// once if the first attempt fails; after the timeout the last error is
+    /// returned.
+    pub fn open_with_timeout(
+        bus: Option<u8>,
+        device: Option<u8>,
+        serial_number: Option<u32>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::wait_for(timeout, || Self::open(bus, device, serial_number))
+    }
+
+    /// Like [`Em100::open_any`], waiting up to `timeout` for a device to appear
+    pub fn open_any_with_timeout(timeout: Duration) -> Result<Self> {
+        Self::wait_for(timeout, Self::open_any)
+    }
+
+    /// Call `open` every 500ms until it succeeds or `timeout` runs out
+    ///
+    /// This is what [`Em100::open_with_timeout`] does, for any way of
+    /// opening a device; errors are handled the same way.
+    pub fn wait_for(timeout: Duration, mut open: impl FnMut() -> Result<Self>) -> Result<Self> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut waiting = false;
+        loop {
+            let now = std::time::Instant::now();
+            match open() {
+                Err(e @ (Error::MultipleDevices(_) | Error::InvalidArgument(_))) => return Err(e),
+                Err(e) if now >= deadline => return Err(e),
+                Err(_) => {
+                    if !waiting {
+                        log::info!("Waiting for EM100pro...");
+                        waiting = true;
+                    }
+                    std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+                }
+                opened => return opened,
+            }
+        }
+    }
+
+    /// Open the EM100 plugged into the USB port at `path`, e.g. "1-3.2"
+    ///
+    /// The path is the bus number, a dash and the hub port numbers from the
+    /// root hub down, separated by dots, as `--list-devices` shows it. The
+    /// bus and every port have to match; a device further down a hub
+    /// plugged into that port doesn't.
+    pub fn open_by_port_path(path: &str) -> Result<Self> {
+        match path.parse()? {
+            DeviceSelector::PortPath(bus, ports) => {
+                Self::from_opened(Self::open_by_port(bus, &ports)?)
+            }
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid USB port path: {} (expected e.g. 1-3.2)",
+                path
+            ))),
+        }
+    }
+
+    fn from_opened((usb, transport): OpenedDevice) -> Result<Self> {
+        let mut em100 = Self::new(recorded(transport, false)?, usb);
+        em100.init()?;
+        Ok(em100)
+    }
+
+    /// Use an EM100 reached through `transport`, e.g. a mock device
+    ///
+    /// The device is checked and its versions and serial number are read as
+    /// when opening it over USB.
+    pub fn with_transport(transport: Box<dyn usb::Transport>) -> Result<Self> {
+        let mut em100 = Self::new(transport, UsbLocation::default());
+        em100.init()?;
+        Ok(em100)
+    }
+
+    fn new(transport: Box<dyn usb::Transport>, usb: UsbLocation) -> Self {
+        Em100 {
+            transport,
             mcu: 0,
             fpga: 0,
             serial_no: 0,
             hw_version: HwVersion::Unknown,
-        };
+            quirks: Quirks::default(),
+            usb,
+            usb_counters: Cell::default(),
+            usb_timeout: DEFAULT_USB_TIMEOUT,
+            conservative_timing: false,
+            cancel: CancelToken::new(),
+            chip: None,
+        }
+    }
 
-        em100.init()?;
-        Ok(em100)
+    fn open_only() -> Result<OpenedDevice> {
+        let count = nusb::list_devices()
+            .wait()?
+            .filter(|d| d.vendor_id() == VENDOR_ID && d.product_id() == PRODUCT_ID)
+            .count();
+        if count > 1 {
+            let candidates = list_devices()?
+                .into_iter()
+                .map(|d| {
+                    format!(
+                        "{} (bus {:03} device {:03})",
+                        d.serial_or_status(),
+                        d.usb.bus,
+                        d.usb.address
+                    )
+                })
+                .collect();
+            return Err(Error::MultipleDevices(candidates));
+        }
+        Self::open_first()
     }
 
-    fn open_first() -> Result<(Endpoint<Bulk, Out>, Endpoint<Bulk, In>)> {
+    /// Open the first EM100 not locked by another session
+    ///
+    /// If every one is locked, the error names the first one's holder.
+    fn open_first() -> Result<OpenedDevice> {
+        let mut in_use = None;
         for device in nusb::list_devices().wait()? {
             if device.vendor_id() == VENDOR_ID && device.product_id() == PRODUCT_ID {
-                let dev = device.open().wait()?;
-                let interface = dev.claim_interface(0).wait()?;
-                let endpoint_out = interface.endpoint::<Bulk, Out>(ENDPOINT_OUT)?;
-                let endpoint_in = interface.endpoint::<Bulk, In>(ENDPOINT_IN)?;
-                return Ok((endpoint_out, endpoint_in));
+                match DeviceLock::check(&UsbLocation::from_device_info(&device)) {
+                    Ok(()) => return open_unlocked(&device),
+                    Err(e) => {
+                        in_use.get_or_insert(e);
+                    }
+                }
             }
         }
-        Err(Error::DeviceNotFound)
+        Err(in_use.unwrap_or(Error::DeviceNotFound))
     }
 
-    fn open_by_bus_device(bus: u8, dev: u8) -> Result<(Endpoint<Bulk, Out>, Endpoint<Bulk, In>)> {
+    fn open_by_bus_device(bus: u8, dev: u8) -> Result<OpenedDevice> {
         for device in nusb::list_devices().wait()? {
             if device.busnum() == bus && device.device_address() == dev {
                 if device.vendor_id() == VENDOR_ID && device.product_id() == PRODUCT_ID {
-                    let usb_dev = device.open().wait()?;
-                    let interface = usb_dev.claim_interface(0).wait()?;
-                    let endpoint_out = interface.endpoint::<Bulk, Out>(ENDPOINT_OUT)?;
-                    let endpoint_in = interface.endpoint::<Bulk, In>(ENDPOINT_IN)?;
-                    return Ok((endpoint_out, endpoint_in));
+                    return open_unlocked(&device);
                 } else {
                     return Err(Error::InvalidArgument(format!(
                         "USB device on bus {:03}:{:02} is not an EM100pro",
@@ -175,28 +620,39 @@ impl Em100 {
         Err(Error::DeviceNotFound)
     }
 
-    fn open_by_serial(serial: u32) -> Result<(Endpoint<Bulk, Out>, Endpoint<Bulk, In>)> {
+    /// Open the EM100 plugged into the port at `port_chain` on `bus`
+    ///
+    /// Unlike the device address, the port stays the same when the device
+    /// re-enumerates.
+    fn open_by_port(bus: u8, port_chain: &[u8]) -> Result<OpenedDevice> {
+        for device in nusb::list_devices().wait()? {
+            if device.vendor_id() == VENDOR_ID
+                && device.product_id() == PRODUCT_ID
+                && UsbLocation::from_device_info(&device).is_at(bus, port_chain)
+            {
+                return open_unlocked(&device);
+            }
+        }
+        Err(Error::DeviceNotFound)
+    }
+
+    /// Open the EM100 with serial number `serial`
+    ///
+    /// Devices locked by another session are not opened to read their
+    /// serial number, so a locked device is reported as not found.
+    fn open_by_serial(serial: u32) -> Result<OpenedDevice> {
         for device in nusb::list_devices().wait()? {
             if device.vendor_id() == VENDOR_ID && device.product_id() == PRODUCT_ID {
-                let usb_dev = device.open().wait()?;
-                let interface = usb_dev.claim_interface(0).wait()?;
-                let endpoint_out = interface.endpoint::<Bulk, Out>(ENDPOINT_OUT)?;
-                let endpoint_in = interface.endpoint::<Bulk, In>(ENDPOINT_IN)?;
-                let mut em100 = Em100 {
-                    endpoint_out: RefCell::new(endpoint_out),
-                    endpoint_in: RefCell::new(endpoint_in),
-                    mcu: 0,
-                    fpga: 0,
-                    serial_no: 0,
-                    hw_version: HwVersion::Unknown,
-                };
+                let usb = UsbLocation::from_device_info(&device);
+                if DeviceLock::holder(&usb).is_some() {
+                    continue;
+                }
+                let transport = NusbTransport::open(&device, DEFAULT_USB_TIMEOUT)?;
+                let mut em100 = Self::new(Box::new(transport), usb);
 
                 // Try to init and check serial
-                if em100.init().is_ok() && em100.serial_no == serial {
-                    // Re-extract the endpoints (can't return from a moved em100)
-                    let endpoint_out = em100.endpoint_out.into_inner();
-                    let endpoint_in = em100.endpoint_in.into_inner();
-                    return Ok((endpoint_out, endpoint_in));
+                if em100.init().is_ok() && serial_matches(em100.serial_no, serial) {
+                    return Ok((em100.usb, em100.transport));
                 }
             }
         }
@@ -206,188 +662,125 @@ impl Em100 {
     /// Initialize the device
     fn init(&mut self) -> Result<()> {
         // nusb handles kernel driver detachment and interface claiming automatically
-
-        // Check device status
-        if !self.check_status()? {
-            return Err(Error::StatusUnknown);
-        }
-
-        // Get version information
-        self.get_version()?;
-
-        // Get device info (serial number, hardware version)
-        self.get_device_info()?;
-
+        let identity = usb::block_on(commands::identify(&mut usb::Blocking(self)))?;
+        self.mcu = identity.mcu;
+        self.fpga = identity.fpga;
+        self.serial_no = identity.serial_no;
+        self.hw_version = identity.hw_version;
+        self.quirks = identity.quirks;
         Ok(())
     }
 
-    /// Check device status by reading SPI flash ID
-    fn check_status(&self) -> Result<bool> {
-        let id = spi::get_spi_flash_id(self)?;
-        // Check for Micron M25P16 or MX77L12850F
-        Ok(id == 0x202015 || id == 0xc27518)
-    }
-
-    /// Get firmware version information
-    fn get_version(&mut self) -> Result<()> {
-        let (mcu, fpga) = system::get_version(self)?;
-        self.mcu = mcu;
-        self.fpga = fpga;
-        Ok(())
-    }
-
-    /// Get device serial number and hardware version
-    fn get_device_info(&mut self) -> Result<()> {
-        let mut data = [0u8; 256];
-        spi::read_spi_flash_page(self, 0x1fff00, &mut data)?;
-
-        self.serial_no = (data[5] as u32) << 24
-            | (data[4] as u32) << 16
-            | (data[3] as u32) << 8
-            | data[2] as u32;
-        self.hw_version = HwVersion::from(data[1]);
-        Ok(())
-    }
-
-    /// Start or stop emulation
+    /// Start or stop emulation, checking that the device followed
     pub fn set_state(&self, run: bool) -> Result<()> {
-        fpga::write_fpga_register(self, 0x28, if run { 1 } else { 0 })?;
-        Ok(())
+        usb::block_on(commands::set_state(&mut usb::Blocking(self), run))
     }
 
     /// Get current emulation state
     pub fn get_state(&self) -> Result<bool> {
-        let state = fpga::read_fpga_register(self, 0x28)?;
-        Ok(state != 0)
+        usb::block_on(commands::get_state(&mut usb::Blocking(self)))
     }
 
     /// Set address mode (3 or 4 byte)
     pub fn set_address_mode(&self, mode: u8) -> Result<()> {
-        if mode != 3 && mode != 4 {
-            return Err(Error::InvalidArgument(format!(
-                "Invalid address mode: {}",
-                mode
-            )));
-        }
-        fpga::write_fpga_register(self, 0x4f, if mode == 4 { 1 } else { 0 })?;
-        Ok(())
+        usb::block_on(commands::set_address_mode(&mut usb::Blocking(self), mode))
+    }
+
+    /// Get the address mode (3 or 4 byte) the emulation is configured for
+    pub fn get_address_mode(&self) -> Result<u8> {
+        usb::block_on(commands::get_address_mode(&mut usb::Blocking(self)))
     }
 
     /// Get current hold pin state
     pub fn get_hold_pin_state(&self) -> Result<HoldPinState> {
-        let val = fpga::read_fpga_register(self, 0x2a)?;
-        match val {
-            0 => Ok(HoldPinState::Low),
-            2 => Ok(HoldPinState::Float),
-            3 => Ok(HoldPinState::Input),
-            _ => Err(Error::InvalidResponse),
-        }
+        usb::block_on(commands::get_hold_pin_state(&mut usb::Blocking(self)))
     }
 
     /// Set hold pin state
     pub fn set_hold_pin_state(&self, state: HoldPinState) -> Result<()> {
-        // Read and acknowledge current state
-        let val = fpga::read_fpga_register(self, 0x2a)?;
-        fpga::write_fpga_register(self, 0x2a, (1 << 2) | val)?;
-
-        // Read again
-        let _ = fpga::read_fpga_register(self, 0x2a)?;
-
-        // Set desired state
-        fpga::write_fpga_register(self, 0x2a, state as u16)?;
-
-        // Verify
-        let new_val = fpga::read_fpga_register(self, 0x2a)?;
-        if new_val != state as u16 {
-            return Err(Error::OperationFailed(format!(
-                "Failed to set hold pin state. Expected {:?}, got {}",
-                state, new_val
-            )));
-        }
-
-        Ok(())
+        usb::block_on(commands::set_hold_pin_state(
+            &mut usb::Blocking(self),
+            state,
+        ))
     }
 
-    /// Set chip type for emulation
-    pub fn set_chip_type(&mut self, chip: &ChipDesc) -> Result<()> {
-        let fpga_voltage = if self.fpga & 0x8000 != 0 { 1800 } else { 3300 };
-
-        // Check if we need to switch FPGA voltage
-        for entry in chip.init.iter().take(chip.init_len) {
-            if entry[0] != 0x11 || entry[1] != 0x04 {
-                continue;
-            }
-
-            let chip_voltage = ((entry[2] as u16) << 8) | (entry[3] as u16);
-
-            let req_voltage = match chip_voltage {
-                1601 | 1800 if fpga_voltage == 3300 => Some(18),
-                3300 if fpga_voltage == 1800 => Some(33),
-                _ => None,
-            };
-
-            if let Some(voltage) = req_voltage {
-                if !self.set_fpga_voltage(voltage)? {
-                    return Err(Error::OperationFailed(format!(
-                        "The current FPGA firmware ({:.1}V) does not support {} {} ({:.1}V)",
-                        fpga_voltage as f32 / 1000.0,
-                        chip.vendor,
-                        chip.name,
-                        chip_voltage as f32 / 1000.0
-                    )));
-                }
-            }
-            break;
+    /// Stop emulation and set the chip type for emulation
+    ///
+    /// The address mode is programmed afterwards according to `policy`.
+    /// The FPGA registers written after the init sequence are read back and
+    /// written once more if they didn't stick; their final values are returned.
+    /// If an init entry fails, the error names it and emulation stays
+    /// stopped. The chip is recorded in [`Em100::chip`] once set up.
+    pub fn set_chip_type(
+        &mut self,
+        chip: &ChipDesc,
+        policy: AddressModePolicy,
+    ) -> Result<PostInitReadback> {
+        self.chip = None;
+        let mut fpga = self.fpga;
+        let result = usb::block_on(commands::set_chip_type(
+            &mut usb::Blocking(self),
+            &mut fpga,
+            self.hw_version,
+            self.quirks,
+            chip,
+            policy,
+        ));
+        self.fpga = fpga;
+        if result.is_ok() {
+            self.chip = Some(chip.clone());
         }
+        result
+    }
+
+    /// Size of the emulated memory: the chip's, or all of the SDRAM if no
+    /// chip was set up
+    pub fn memory_size(&self) -> usize {
+        self.chip
+            .as_ref()
+            .map_or(MAX_MEMORY_SIZE, |chip| chip.size as usize)
+    }
 
-        // Send init sequence
-        for entry in chip.init.iter().take(chip.init_len) {
+    /// Make the emulated chip look as if it had just been chip-erased
+    ///
+    /// Fills the chip's memory with 0xff, generated chunk by chunk as it is
+    /// sent rather than in a buffer the size of the chip. For chips whose configuration has an
+    /// SRST section the emulated status registers are also reloaded with their
+    /// reset values, so WIP and WEL read back clear as after a real erase.
+    /// Returns whether the status registers were modeled.
+    pub fn simulate_erase(&self, chip: &ChipDesc) -> Result<bool> {
+        sdram::fill_sdram_pattern(self, 0, chip.size as usize, &[0xff], None)?;
+
+        for entry in &chip.status_reset {
             usb::send_cmd(self, entry)?;
         }
 
-        // Set FPGA registers
-        fpga::write_fpga_register(self, 0xc4, 0x01)?;
-        fpga::write_fpga_register(self, 0x10, 0x00)?;
-        fpga::write_fpga_register(self, 0x81, 0x00)?;
-
-        Ok(())
+        Ok(!chip.status_reset.is_empty())
     }
 
     /// Set FPGA voltage (18 for 1.8V, 33 for 3.3V)
     pub fn set_fpga_voltage(&mut self, voltage_code: u8) -> Result<bool> {
-        fpga::fpga_reconfigure(self)?;
-
-        let mut cmd = [0u8; 16];
-        cmd[0] = 0x24;
-        if voltage_code == 18 {
-            cmd[2] = 7;
-            cmd[3] = 0x80;
-        }
-        usb::send_cmd(self, &cmd)?;
-
-        // Must wait 2s before issuing any other USB command
-        std::thread::sleep(Duration::from_secs(2));
-
-        // Verify
-        self.get_version().ok();
-        let actual = if self.fpga & 0x8000 != 0 { 18 } else { 33 };
-
-        if actual != voltage_code {
-            return Ok(false);
-        }
-
-        Ok(true)
+        let mut fpga = self.fpga;
+        let result = usb::block_on(commands::set_fpga_voltage(
+            &mut usb::Blocking(self),
+            &mut fpga,
+            voltage_code,
+        ));
+        self.fpga = fpga;
+        result
     }
 
     /// Set serial number
-    pub fn set_serial_no(&mut self, serial: u32) -> Result<()> {
+    ///
+    /// A blank info page is written directly. Replacing a serial number
+    /// erases the bootloader sector and restores its magic page afterwards;
+    /// only when that page cannot be read does `allow_protected` have to
+    /// permit erasing the sector without it.
+    pub fn set_serial_no(&mut self, serial: u32, allow_protected: bool) -> Result<()> {
         let mut data = [0u8; 512];
-        spi::read_spi_flash_page(self, 0x1fff00, &mut data[..256])?;
+        spi::read_spi_flash_page(self, protocol::INFO_PAGE, &mut data[..256])?;
 
-        let old_serial = (data[5] as u32) << 24
-            | (data[4] as u32) << 16
-            | (data[3] as u32) << 8
-            | data[2] as u32;
+        let (old_serial, _) = protocol::parse_info_page(&data);
 
         if old_serial == serial {
             return Ok(());
@@ -398,115 +791,372 @@ impl Em100 {
         data[4] = (serial >> 16) as u8;
         data[5] = (serial >> 24) as u8;
 
-        if old_serial != 0xffffffff {
+        let id = spi::get_spi_flash_id(self)?;
+        let part = spi::find_spi_flash_part(id).ok_or_else(|| {
+            Error::InvalidFirmware(format!("Unknown SPI flash id = {:06x}. Please report", id))
+        })?;
+
+        // The info page is protected; this is one of the sanctioned writers
+        if old_serial != protocol::BLANK_SERIAL {
             // Preserve magic
-            spi::read_spi_flash_page(self, 0x1f0000, &mut data[256..512])?;
+            let magic = spi::read_spi_flash_page(self, 0x1f0000, &mut data[256..512]);
+            if let Err(e) = &magic {
+                if !allow_protected {
+                    return Err(Error::InvalidArgument(format!(
+                        "Can't preserve the bootloader magic page: {}",
+                        e
+                    )));
+                }
+                log::warn!(
+                    "Bootloader magic page unreadable ({}); erasing without it",
+                    e
+                );
+            }
             spi::unlock_spi_flash(self)?;
             spi::get_spi_flash_id(self)?;
-            spi::erase_spi_flash_sector(self, 0x1f)?;
-            spi::write_spi_flash_page(self, 0x1f0000, &data[256..512])?;
+            spi::erase_spi_flash_sector_guarded(self, part, 0x1f, true)?;
+            if magic.is_ok() {
+                spi::write_spi_flash_page_guarded(self, part, 0x1f0000, &data[256..512], true)?;
+            }
         }
 
-        spi::write_spi_flash_page(self, 0x1fff00, &data[..256])?;
+        spi::write_spi_flash_page_guarded(self, part, protocol::INFO_PAGE, &data[..256], true)?;
 
         // Re-read serial number
-        self.get_device_info()?;
+        let page = usb::block_on(commands::read_spi_flash_page(
+            &mut usb::Blocking(self),
+            protocol::INFO_PAGE,
+        ))?;
+        (self.serial_no, self.hw_version) = protocol::parse_info_page(&page);
 
         Ok(())
     }
 
     /// Download data to SDRAM
     pub fn download(&self, data: &[u8], address: u32) -> Result<()> {
-        sdram::write_sdram(self, data, address)
+        self.download_with_progress(data, address, None)
+    }
+
+    /// Download data to SDRAM, reporting `(bytes_done, total)` after every 2MB chunk
+    pub fn download_with_progress(
+        &self,
+        data: &[u8],
+        address: u32,
+        progress: ProgressCallback,
+    ) -> Result<()> {
+        sdram::write_sdram_with_progress(self, data, address, progress)
+    }
+
+    /// Download `length` bytes from `reader` to SDRAM, reporting progress
+    ///
+    /// Only one chunk is held in memory at a time.
+    pub fn download_from<R: Read + ?Sized>(
+        &self,
+        reader: &mut R,
+        length: usize,
+        address: u32,
+        progress: ProgressCallback,
+    ) -> Result<()> {
+        sdram::write_sdram_from_reader(self, reader, length, address, progress)
+    }
+
+    /// Download an image to `start_address`, keeping the rest of `memory_size`
+    ///
+    /// The write command carries a 32-bit byte address and length and has no
+    /// alignment constraint, so only the image itself is transferred; the
+    /// rest of the memory is never touched. Returns the ranges that were
+    /// written, as `(address, contents)`, so they can be verified exactly.
+    pub fn download_image(
+        &self,
+        data: &[u8],
+        start_address: u32,
+        memory_size: usize,
+        progress: ProgressCallback,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        let start = start_address as usize;
+        if start
+            .checked_add(data.len())
+            .is_none_or(|end| end > memory_size)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "{} bytes at 0x{:08x} exceed the {} byte emulated memory",
+                data.len(),
+                start_address,
+                memory_size
+            )));
+        }
+
+        self.download_with_progress(data, start_address, progress)?;
+        Ok(vec![(start_address, data.to_vec())])
+    }
+
+    /// Load an image and optionally verify it and start emulation
+    ///
+    /// With a chip, emulation is stopped and the chip (and address mode) is
+    /// configured first, and the image must fill the chip from the start
+    /// address on. Without one, the chip already set up is used and
+    /// emulation is left as it is until `opts.start`.
+    pub fn flash_image(
+        &mut self,
+        chip: Option<&ChipDesc>,
+        data: &[u8],
+        opts: FlashOptions,
+    ) -> Result<FlashReport> {
+        self.flash_image_with_progress(chip, data, opts, None)
+    }
+
+    /// [`Em100::flash_image`], reporting `(bytes_done, total)` over the
+    /// write and the verify readback
+    pub fn flash_image_with_progress(
+        &mut self,
+        chip: Option<&ChipDesc>,
+        data: &[u8],
+        opts: FlashOptions,
+        mut progress: ProgressCallback,
+    ) -> Result<FlashReport> {
+        let started = std::time::Instant::now();
+        let memory_size = opts
+            .memory_size
+            .or(chip.map(|c| c.size as usize))
+            .unwrap_or(MAX_MEMORY_SIZE);
+        check_image_size(chip, data.len(), opts.start_address, memory_size)?;
+
+        if let Some(chip) = chip {
+            self.set_state(false)?;
+            self.set_chip_type(chip, opts.address_mode)?;
+        }
+
+        let mut image = data.to_vec();
+        if opts.compatible {
+            crate::image::autocorrect_image(self, &mut image)?;
+        }
+
+        let verify_len = if opts.verify { image.len() } else { 0 };
+        let mut write_total = 0;
+        let written = self.download_image(
+            &image,
+            opts.start_address,
+            memory_size,
+            Some(&mut |done, total| {
+                write_total = total;
+                if let Some(cb) = progress.as_mut() {
+                    cb(done, total + verify_len)
+                }
+            }),
+        )?;
+
+        let mut verification = Vec::new();
+        if opts.verify {
+            let mut checked = 0;
+            for (address, contents) in &written {
+                // Sample mode reads less than it covers; progress covers the region
+                let report = verify::verify_region(
+                    self,
+                    *address,
+                    contents,
+                    opts.verify_mode,
+                    Some(&mut |done, total| {
+                        if let Some(cb) = progress.as_mut() {
+                            let done = done * contents.len() / total.max(1);
+                            cb(write_total + checked + done, write_total + verify_len)
+                        }
+                    }),
+                )?;
+                checked += contents.len();
+                verification.push(report);
+            }
+        }
+        let verified = opts
+            .verify
+            .then(|| verification.iter().all(VerifyReport::passed));
+
+        if opts.start && verified != Some(false) {
+            self.set_state(true)?;
+        }
+
+        Ok(FlashReport {
+            bytes_written: written.iter().map(|(_, contents)| contents.len()).sum(),
+            verified,
+            verification,
+            elapsed: started.elapsed(),
+        })
     }
 
     /// Upload data from SDRAM
     pub fn upload(&self, address: u32, length: usize) -> Result<Vec<u8>> {
-        sdram::read_sdram(self, address, length)
+        self.upload_with_progress(address, length, None)
+    }
+
+    /// Upload data from SDRAM, reporting `(bytes_done, total)` after every 2MB chunk
+    pub fn upload_with_progress(
+        &self,
+        address: u32,
+        length: usize,
+        progress: ProgressCallback,
+    ) -> Result<Vec<u8>> {
+        sdram::read_sdram_with_progress(self, address, length, progress)
+    }
+
+    /// Digest of `length` bytes of SDRAM at `address`, hashed as they arrive
+    pub fn content_digest(
+        &self,
+        algorithm: DigestAlgorithm,
+        address: u32,
+        length: usize,
+        progress: ProgressCallback,
+    ) -> Result<Digest> {
+        checksum::content_digest(self, algorithm, address, length, progress)
+    }
+
+    /// Upload data from SDRAM into `out` as it arrives, reporting progress
+    ///
+    /// On error `out` holds the part uploaded until then.
+    pub fn upload_to<W: Write + ?Sized>(
+        &self,
+        address: u32,
+        length: usize,
+        out: &mut W,
+        progress: ProgressCallback,
+    ) -> Result<()> {
+        sdram::read_sdram_to(self, address, length, out, progress)
     }
 
     /// Get serial number as string
     pub fn serial_string(&self) -> String {
-        if self.serial_no == 0xffffffff {
-            "N.A.".to_string()
-        } else {
-            let prefix = if self.hw_version == HwVersion::Em100ProEarly {
-                "DP"
+        protocol::serial_string(self.serial_no, self.hw_version)
+    }
+
+    /// USB trouble seen since the device was opened
+    pub fn usb_counters(&self) -> usb::UsbCounters {
+        self.usb_counters.get()
+    }
+
+    /// Timeout of every USB bulk transfer
+    pub fn usb_timeout(&self) -> Duration {
+        self.usb_timeout
+    }
+
+    /// Set the timeout of every later USB bulk transfer
+    ///
+    /// A transfer that times out fails with [`Error::Timeout`]. Queries are
+    /// retried once after that; SDRAM and firmware transfers are not.
+    pub fn set_usb_timeout(&mut self, timeout: Duration) {
+        self.usb_timeout = timeout;
+        self.transport.set_timeout(timeout);
+    }
+
+    /// Read the settings [`Em100::reconnect`] can restore
+    ///
+    /// The chip can't be read back from the device; it is the one last set
+    /// up through this handle, if any.
+    pub fn device_state(&self) -> Result<DeviceState> {
+        Ok(DeviceState {
+            chip: self.chip.clone(),
+            address_mode: Some(self.get_address_mode()?),
+            hold_pin: Some(self.get_hold_pin_state()?),
+            running: Some(self.get_state()?),
+        })
+    }
+
+    /// Open the device again after it dropped off the bus, and restore `state`
+    ///
+    /// Waits up to [`RECONNECT_TIMEOUT`] for the device to enumerate again.
+    /// It is found by serial number, or by its USB port if the serial is
+    /// blank. Versions are read again; USB counters, timeout and the cancel
+    /// token carry over. A device that never left the bus can't be reopened
+    /// while this handle still claims it, so this fails with
+    /// `Error::DeviceNotFound` for it.
+    ///
+    /// Emulation is stopped while the chip is set up and only started again
+    /// if `state.running` says so. The SDRAM contents are not restored; a
+    /// device that lost power has to be given its image again.
+    pub fn reconnect(&mut self, state: &DeviceState) -> Result<()> {
+        let deadline = std::time::Instant::now() + RECONNECT_TIMEOUT;
+        let (usb, mut transport) = loop {
+            let opened = if self.has_blank_serial() {
+                Self::open_by_port(self.usb.bus, &self.usb.port_chain)
             } else {
-                "EM"
+                Self::open_by_serial(self.serial_no)
             };
-            format!("{}{:06}", prefix, self.serial_no)
+            match opened {
+                Ok(opened) => break opened,
+                Err(e) if std::time::Instant::now() >= deadline => return Err(e),
+                Err(_) => std::thread::sleep(Duration::from_millis(250)),
+            }
+        };
+        transport.set_timeout(self.usb_timeout);
+        self.transport = recorded(transport, true)?;
+        self.usb = usb;
+        usb::count(self, |c| c.reconnects += 1);
+        self.init()?;
+
+        if let Some(chip) = &state.chip {
+            self.set_state(false)?;
+            let policy = state
+                .address_mode
+                .map_or(AddressModePolicy::Auto, AddressModePolicy::Force);
+            self.set_chip_type(chip, policy)?;
+        } else if let Some(mode) = state.address_mode {
+            self.set_address_mode(mode)?;
         }
+        if let Some(hold_pin) = state.hold_pin {
+            self.set_hold_pin_state(hold_pin)?;
+        }
+        if let Some(running) = state.running {
+            self.set_state(running)?;
+        }
+        Ok(())
     }
 
-    /// Get device information as structured data
-    pub fn get_info(&self) -> DeviceInfo {
-        let mcu_version = format!("{}.{:02}", self.mcu >> 8, self.mcu & 0xff);
+    /// Whether the device has no serial number programmed
+    pub fn has_blank_serial(&self) -> bool {
+        self.serial_no == protocol::BLANK_SERIAL
+    }
 
-        let fpga_version = match self.hw_version {
-            HwVersion::Em100Pro | HwVersion::Em100ProEarly => {
-                if self.fpga > 0x0033 {
-                    format!(
-                        "{}.{:02} ({})",
-                        (self.fpga >> 8) & 0x7f,
-                        self.fpga & 0xff,
-                        if self.fpga & 0x8000 != 0 {
-                            "1.8V"
-                        } else {
-                            "3.3V"
-                        }
-                    )
-                } else {
-                    format!("{}.{:02}", self.fpga >> 8, self.fpga & 0xff)
-                }
-            }
-            HwVersion::Em100ProG2 => {
-                format!("{}.{:03}", (self.fpga >> 8) & 0x7f, self.fpga & 0xff)
-            }
-            _ => format!("{}.{}", self.fpga >> 8, self.fpga & 0xff),
-        };
+    /// Name identifying this device across sessions
+    ///
+    /// This is the serial number, or for devices with a blank serial their
+    /// USB port path (e.g. "N.A.@1-2.3"), which stays the same across
+    /// reconnects to the same port.
+    pub fn identity(&self) -> String {
+        if self.has_blank_serial() {
+            format!("{}@{}", protocol::BLANK_SERIAL_NAME, self.usb.port_path())
+        } else {
+            self.serial_string()
+        }
+    }
 
+    /// Get device information as structured data
+    pub fn get_info(&self) -> DeviceInfo {
         DeviceInfo {
-            mcu_version,
-            fpga_version,
+            mcu_version: protocol::mcu_version_string(self.mcu),
+            fpga_version: protocol::fpga_version_string(self.fpga, self.hw_version),
             hw_version: self.hw_version,
             serial: self.serial_string(),
-            fpga_voltage: if self.fpga & 0x8000 != 0 { 1800 } else { 3300 },
+            fpga_voltage: protocol::fpga_voltage_mv(self.fpga),
+            quirks: self.quirks,
+            usb: self.usb.clone(),
         }
     }
 
-    /// Print device information (CLI convenience)
-    #[cfg(feature = "cli")]
-    pub fn print_info(&self) {
-        let info = self.get_info();
-        println!("MCU version: {}", info.mcu_version);
-        println!("FPGA version: {}", info.fpga_version);
-        println!("Hardware version: {:?}", info.hw_version);
-        println!("Serial number: {}", info.serial);
-    }
-
     /// Get debug information (voltages and FPGA registers)
+    ///
+    /// This only reads from the device, so it is safe while emulating.
     pub fn get_debug_info(&self) -> Result<DebugInfo> {
-        system::set_led(self, system::LedState::BothOff)?;
         let v1_2 = system::get_voltage(self, system::GetVoltageChannel::V1_2)?;
         let e_vcc = system::get_voltage(self, system::GetVoltageChannel::EVcc)?;
-        system::set_led(self, system::LedState::BothOn)?;
         let ref_plus = system::get_voltage(self, system::GetVoltageChannel::RefPlus)?;
         let ref_minus = system::get_voltage(self, system::GetVoltageChannel::RefMinus)?;
-        system::set_led(self, system::LedState::RedOn)?;
         let buffer_vcc = system::get_voltage(self, system::GetVoltageChannel::BufferVcc)?;
         let trig_vcc = system::get_voltage(self, system::GetVoltageChannel::TriggerVcc)?;
-        system::set_led(self, system::LedState::BothOn)?;
         let rst_vcc = system::get_voltage(self, system::GetVoltageChannel::ResetVcc)?;
         let v3_3 = system::get_voltage(self, system::GetVoltageChannel::V3_3)?;
-        system::set_led(self, system::LedState::RedOn)?;
         let buffer_v3_3 = system::get_voltage(self, system::GetVoltageChannel::BufferV3_3)?;
         let v5 = system::get_voltage(self, system::GetVoltageChannel::V5)?;
-        system::set_led(self, system::LedState::GreenOn)?;
 
         let mut fpga_registers = [0u16; 128];
-        for i in 0..128 {
-            fpga_registers[i] = fpga::read_fpga_register(self, (i * 2) as u8).unwrap_or(0xFFFF);
+        for (i, reg) in fpga_registers.iter_mut().enumerate() {
+            *reg = fpga::read_fpga_register(self, (i * 2) as u8).unwrap_or(0xFFFF);
         }
 
         Ok(DebugInfo {
@@ -526,34 +1176,97 @@ impl Em100 {
         })
     }
 
-    /// Debug mode - print voltages and FPGA registers (CLI convenience)
-    #[cfg(feature = "cli")]
-    pub fn debug(&self) -> Result<()> {
-        let info = self.get_debug_info()?;
-
-        println!("Voltages:");
-        println!("  1.2V:        {}mV", info.voltages.v1_2);
-        println!("  E_VCC:       {}mV", info.voltages.e_vcc);
-        println!("  REF+:        {}mV", info.voltages.ref_plus);
-        println!("  REF-:        {}mV", info.voltages.ref_minus);
-        println!("  Buffer VCC:  {}mV", info.voltages.buffer_vcc);
-        println!("  Trig VCC:    {}mV", info.voltages.trig_vcc);
-        println!("  RST VCC:     {}mV", info.voltages.rst_vcc);
-        println!("  3.3V:        {}mV", info.voltages.v3_3);
-        println!("  Buffer 3.3V: {}mV", info.voltages.buffer_v3_3);
-        println!("  5V:          {}mV", info.voltages.v5);
-
-        println!("\nFPGA registers:");
-        for i in 0..128 {
-            if i % 8 == 0 {
-                print!("\n  {:04x}: ", i * 2);
-            }
-            print!("{:04x} ", info.fpga_registers[i]);
+    /// Blink the LEDs so the device can be found on a shared bench
+    pub fn identify(&self) -> Result<()> {
+        for state in [
+            system::LedState::BothOff,
+            system::LedState::BothOn,
+            system::LedState::RedOn,
+            system::LedState::BothOn,
+            system::LedState::RedOn,
+        ] {
+            system::set_led(self, state)?;
+            std::thread::sleep(Duration::from_millis(250));
         }
-        println!();
+        system::set_led(self, system::LedState::GreenOn)
+    }
+}
 
-        Ok(())
+/// Largest emulated memory, used when no chip is known (64MB)
+pub const MAX_MEMORY_SIZE: usize = 0x4000000;
+
+/// Options for [`Em100::flash_image`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlashOptions {
+    /// SPI address the image is loaded at; the rest of the memory is kept
+    pub start_address: u32,
+    /// Size of the emulated memory; defaults to the chip size, or 64MB
+    pub memory_size: Option<usize>,
+    /// Address mode to set along with the chip
+    pub address_mode: AddressModePolicy,
+    /// Read back and compare what was written
+    pub verify: bool,
+    /// How `verify` compares the contents
+    pub verify_mode: VerifyMode,
+    /// Patch the image for EM100Pro compatibility
+    pub compatible: bool,
+    /// Start emulation afterwards (not done if verification failed)
+    pub start: bool,
+}
+
+/// Result of [`Em100::flash_image`]
+#[derive(Debug, Clone)]
+pub struct FlashReport {
+    /// Bytes written to SDRAM
+    pub bytes_written: usize,
+    /// Whether the readback matched, if verification was requested
+    pub verified: Option<bool>,
+    /// How each range written was verified, empty without verification
+    pub verification: Vec<VerifyReport>,
+    pub elapsed: Duration,
+}
+
+/// Check that an image fits the memory and, with a chip, fills it from `start_address` on
+pub fn check_image_size(
+    chip: Option<&ChipDesc>,
+    len: usize,
+    start_address: u32,
+    memory_size: usize,
+) -> Result<()> {
+    if len == 0 {
+        return Err(Error::InvalidArgument("The image is empty".to_string()));
+    }
+    if len > memory_size {
+        return Err(Error::InvalidArgument(format!(
+            "The image ({} bytes) exceeds the {} byte emulated memory",
+            len, memory_size
+        )));
     }
+    let end = (start_address as usize).saturating_add(len);
+    if end > memory_size {
+        let memory = match chip {
+            Some(chip) => format!("{} {}", chip.vendor, chip.name),
+            None => "emulated memory".to_string(),
+        };
+        return Err(Error::InvalidArgument(format!(
+            "The image ({} bytes) at start address 0x{:08x} ends {} bytes past the end of the {} byte {}",
+            len,
+            start_address,
+            end - memory_size,
+            memory_size,
+            memory
+        )));
+    }
+    if let Some(chip) = chip {
+        let expected = (chip.size as usize).saturating_sub(start_address as usize);
+        if len != expected {
+            return Err(Error::InvalidArgument(format!(
+                "The image size ({}) does not match chip size minus start address ({})",
+                len, expected
+            )));
+        }
+    }
+    Ok(())
 }
 
 /// Device information structure
@@ -564,6 +1277,34 @@ pub struct DeviceInfo {
     pub hw_version: HwVersion,
     pub serial: String,
     pub fpga_voltage: u16,
+    pub quirks: Quirks,
+    pub usb: UsbLocation,
+}
+
+impl DeviceInfo {
+    /// Write the information as a JSON document
+    pub fn write_json<W: Write + ?Sized>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"mcu_version\": \"{}\",", self.mcu_version)?;
+        writeln!(w, "  \"fpga_version\": \"{}\",", self.fpga_version)?;
+        writeln!(w, "  \"hw_version\": \"{}\",", self.hw_version)?;
+        writeln!(w, "  \"serial\": \"{}\",", self.serial)?;
+        writeln!(w, "  \"fpga_voltage\": {},", self.fpga_voltage)?;
+        writeln!(
+            w,
+            "  \"quirks\": {{\"no_voltage_flag\": {}, \"legacy_trace_framing\": {}}},",
+            self.quirks.no_voltage_flag, self.quirks.legacy_trace_framing
+        )?;
+        writeln!(
+            w,
+            "  \"usb\": {{\"bus\": {}, \"address\": {}, \"port_path\": \"{}\", \"speed\": \"{}\"}}",
+            self.usb.bus,
+            self.usb.address,
+            self.usb.port_path(),
+            self.usb.speed_name()
+        )?;
+        writeln!(w, "}}")
+    }
 }
 
 /// Voltage readings
@@ -588,8 +1329,106 @@ pub struct DebugInfo {
     pub fpga_registers: [u16; 128],
 }
 
+impl DebugInfo {
+    /// Write the information as a JSON document, voltages in mV
+    ///
+    /// Registers are listed by index; register `i` is at FPGA address `2 * i`.
+    /// Those that couldn't be read are 65535.
+    pub fn write_json<W: Write + ?Sized>(&self, w: &mut W) -> std::io::Result<()> {
+        let v = &self.voltages;
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"voltages\": {{")?;
+        let channels = [
+            ("v1_2", v.v1_2),
+            ("e_vcc", v.e_vcc),
+            ("ref_plus", v.ref_plus),
+            ("ref_minus", v.ref_minus),
+            ("buffer_vcc", v.buffer_vcc),
+            ("trig_vcc", v.trig_vcc),
+            ("rst_vcc", v.rst_vcc),
+            ("v3_3", v.v3_3),
+            ("buffer_v3_3", v.buffer_v3_3),
+            ("v5", v.v5),
+        ];
+        for (i, (name, mv)) in channels.iter().enumerate() {
+            let comma = if i + 1 < channels.len() { "," } else { "" };
+            writeln!(w, "    \"{}\": {}{}", name, mv, comma)?;
+        }
+        writeln!(w, "  }},")?;
+        let registers: Vec<_> = self.fpga_registers.iter().map(|r| r.to_string()).collect();
+        writeln!(w, "  \"fpga_registers\": [{}]", registers.join(", "))?;
+        writeln!(w, "}}")
+    }
+}
+
+/// How `list_devices` got on with a device's serial number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerialSource {
+    /// Read from the USB serial string descriptor, without opening the device
+    Descriptor,
+    /// Read from the device's SPI flash after opening it
+    Opened,
+    /// Not read, another session holds the device (the holder's description)
+    InUse(String),
+    /// Not read, opening the device was refused
+    PermissionDenied,
+    /// Not read, opening the device failed
+    OpenFailed(String),
+}
+
+/// An EM100 found by `list_devices`
+#[derive(Debug, Clone)]
+pub struct ListedDevice {
+    pub usb: UsbLocation,
+    /// Serial number (e.g. "EM123456", or "N.A." if blank), if it could be read
+    pub serial: Option<String>,
+    /// USB product string, if the device has one
+    pub product: Option<String>,
+    /// Hardware version, if the device was opened to read its serial
+    pub hw_version: Option<HwVersion>,
+    pub source: SerialSource,
+}
+
+impl ListedDevice {
+    /// Whether the device can be opened, as far as listing it found out
+    pub fn is_available(&self) -> bool {
+        matches!(self.source, SerialSource::Descriptor | SerialSource::Opened)
+    }
+
+    /// Whether the device has no serial number programmed
+    pub fn has_blank_serial(&self) -> bool {
+        self.serial.as_deref() == Some(protocol::BLANK_SERIAL_NAME)
+    }
+
+    /// The serial number, or why it is missing (e.g. "no permission")
+    pub fn serial_or_status(&self) -> String {
+        match (&self.serial, &self.source) {
+            (Some(serial), _) => serial.clone(),
+            (None, SerialSource::InUse(holder)) => format!("in use by {}", holder),
+            (None, SerialSource::PermissionDenied) => "no permission".to_string(),
+            (None, _) => "unknown".to_string(),
+        }
+    }
+}
+
+/// Whether a device's serial number `found` is the `wanted` one; blank
+/// serials never match
+fn serial_matches(found: u32, wanted: u32) -> bool {
+    found == wanted && found != protocol::BLANK_SERIAL
+}
+
+/// Serial number in a USB serial string descriptor, if it holds one
+fn descriptor_serial(device: &nusb::DeviceInfo) -> Option<String> {
+    let serial = device.serial_number()?.trim();
+    matches!(serial.parse(), Ok(DeviceSelector::Serial(_))).then(|| serial.to_uppercase())
+}
+
 /// List all connected EM100 devices
-pub fn list_devices() -> Result<Vec<(u8, u8, String)>> {
+///
+/// The serial number is taken from the USB descriptors where they carry
+/// one; only devices without it are opened to read it from their SPI flash.
+/// Devices another session holds are never opened.
+pub fn list_devices() -> Result<Vec<ListedDevice>> {
     let mut devices = Vec::new();
 
     for device in nusb::list_devices().wait()? {
@@ -597,19 +1436,398 @@ pub fn list_devices() -> Result<Vec<(u8, u8, String)>> {
             continue;
         }
 
-        let bus = device.busnum();
-        let addr = device.device_address();
+        let usb = UsbLocation::from_device_info(&device);
+        let mut listed = ListedDevice {
+            serial: descriptor_serial(&device),
+            product: device.product_string().map(str::to_string),
+            hw_version: None,
+            source: SerialSource::Descriptor,
+            usb,
+        };
 
-        // Try to get serial number
-        match Em100::open(Some(bus), Some(addr), None) {
-            Ok(em100) => {
-                devices.push((bus, addr, em100.serial_string()));
-            }
-            Err(_) => {
-                devices.push((bus, addr, "unknown".to_string()));
+        // Opening a device another session is tracing would disturb it
+        if let Some(holder) = DeviceLock::holder(&listed.usb) {
+            listed.source = SerialSource::InUse(holder);
+        } else if listed.serial.is_none() {
+            match Em100::open(Some(listed.usb.bus), Some(listed.usb.address), None) {
+                Ok(em100) => {
+                    listed.serial = Some(em100.serial_string());
+                    listed.hw_version = Some(em100.hw_version);
+                    listed.source = SerialSource::Opened;
+                }
+                Err(Error::PermissionDenied) => listed.source = SerialSource::PermissionDenied,
+                Err(e) => listed.source = SerialSource::OpenFailed(e.to_string()),
             }
         }
+        devices.push(listed);
     }
 
     Ok(devices)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<DeviceSelector> {
+        s.parse()
+    }
+
+    #[test]
+    fn selector_forms() {
+        assert_eq!(parse("001:003").unwrap(), DeviceSelector::BusDevice(1, 3));
+        assert_eq!(parse("2:17").unwrap(), DeviceSelector::BusDevice(2, 17));
+        assert_eq!(parse("EM123456").unwrap(), DeviceSelector::Serial(123456));
+        assert_eq!(parse("dp000042").unwrap(), DeviceSelector::Serial(42));
+        assert_eq!(
+            parse("na:001:003").unwrap(),
+            DeviceSelector::BlankSerial(1, 3)
+        );
+        assert_eq!(parse("NA:4:5").unwrap(), DeviceSelector::BlankSerial(4, 5));
+        assert_eq!(
+            parse("1-3.2").unwrap(),
+            DeviceSelector::PortPath(1, vec![3, 2])
+        );
+        assert_eq!(
+            parse(" 3-1 ").unwrap(),
+            DeviceSelector::PortPath(3, vec![1])
+        );
+    }
+
+    #[test]
+    fn selector_rejects_malformed() {
+        for s in [
+            "",
+            "garbage",
+            "001:",
+            ":003",
+            "1:2:3",
+            "256:1",
+            "1:x",
+            "EM",
+            "EMabc",
+            "DP12a",
+            "EM99999999999",
+            "na:EM123456",
+            "na:1-3",
+            "na:",
+            "1-",
+            "-3",
+            "1-3.",
+            "1-3..2",
+            "1-300",
+        ] {
+            match parse(s) {
+                Err(Error::InvalidArgument(msg)) => assert!(msg.contains("e.g. 1-3.2"), "{}", msg),
+                other => panic!("{:?} parsed as {:?}", s, other),
+            }
+        }
+    }
+
+    #[test]
+    fn selector_display_round_trips() {
+        for selector in [
+            DeviceSelector::BusDevice(1, 3),
+            DeviceSelector::Serial(42),
+            DeviceSelector::BlankSerial(2, 9),
+            DeviceSelector::PortPath(1, vec![3, 2, 4]),
+        ] {
+            assert_eq!(parse(&selector.to_string()).unwrap(), selector);
+        }
+    }
+
+    #[test]
+    fn port_paths_match_the_whole_chain() {
+        let usb = UsbLocation {
+            bus: 1,
+            address: 9,
+            port_chain: vec![3, 2],
+            ..Default::default()
+        };
+        assert_eq!(usb.port_path(), "1-3.2");
+        let DeviceSelector::PortPath(bus, ports) = parse(&usb.port_path()).unwrap() else {
+            panic!("not a port path");
+        };
+        assert!(usb.is_at(bus, &ports));
+
+        assert!(!usb.is_at(2, &[3, 2]));
+        // Neither the hub above the device nor a device behind it
+        assert!(!usb.is_at(1, &[3]));
+        assert!(!usb.is_at(1, &[3, 2, 1]));
+        assert!(!usb.is_at(1, &[2, 3]));
+    }
+
+    #[test]
+    fn open_by_port_path_takes_only_port_paths() {
+        for path in ["001:003", "EM123456", "na:1:3", "1-x"] {
+            assert!(matches!(
+                Em100::open_by_port_path(path),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    /// A port no real device sits on, unique per test
+    fn test_port(port: u8) -> UsbLocation {
+        UsbLocation {
+            bus: 255,
+            port_chain: vec![std::process::id() as u8, port],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn blank_serial_devices_are_told_apart_by_port() {
+        let open = |port| {
+            let mock = crate::mock::MockTransport::em100pro(
+                0x0227,
+                0x0034,
+                protocol::BLANK_SERIAL,
+                HwVersion::Em100Pro,
+            );
+            let mut em100 = mock.open().unwrap();
+            em100.usb = UsbLocation {
+                address: port,
+                ..test_port(port)
+            };
+            em100
+        };
+        let (first, second) = (open(3), open(4));
+        assert!(first.has_blank_serial() && second.has_blank_serial());
+        assert_eq!(first.serial_string(), second.serial_string());
+
+        // Each gets its own identity and selector
+        assert_eq!(
+            first.identity(),
+            format!("N.A.@255-{}.3", std::process::id() as u8)
+        );
+        assert_ne!(first.identity(), second.identity());
+        let selector =
+            |em100: &Em100| DeviceSelector::BlankSerial(em100.usb.bus, em100.usb.address);
+        assert_eq!(selector(&first).to_string(), "na:255:003");
+        assert_eq!(
+            parse(&selector(&second).to_string()).unwrap(),
+            selector(&second)
+        );
+
+        // Locking one leaves the other free
+        let lock = DeviceLock::acquire(&first.usb, "tracing").unwrap();
+        assert_ne!(DeviceLock::path(&first.usb), DeviceLock::path(&second.usb));
+        assert!(DeviceLock::check(&second.usb).is_ok());
+        drop(lock);
+        std::fs::remove_file(DeviceLock::path(&first.usb)).unwrap();
+
+        // Neither is opened when a serial number is asked for
+        assert!(!serial_matches(first.serial_no, protocol::BLANK_SERIAL));
+        assert!(!serial_matches(first.serial_no, 123456));
+        assert!(serial_matches(123456, 123456));
+    }
+
+    #[test]
+    fn own_locks_do_not_block_reopening() {
+        let usb = test_port(1);
+        let lock = DeviceLock::acquire(&usb, "tracing").unwrap();
+        assert_eq!(DeviceLock::holder(&usb), None);
+        assert!(DeviceLock::check(&usb).is_ok());
+        drop(lock);
+        assert_eq!(DeviceLock::holder(&usb), None);
+        std::fs::remove_file(DeviceLock::path(&usb)).unwrap();
+    }
+
+    #[test]
+    fn locks_of_other_sessions_are_reported() {
+        let usb = test_port(2);
+        // Another process's lock, as far as the lock file is concerned
+        let mut other = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(DeviceLock::path(&usb))
+            .unwrap();
+        other.try_lock().unwrap();
+        write!(other, "PID 1 (tracing)").unwrap();
+
+        assert_eq!(DeviceLock::holder(&usb).as_deref(), Some("PID 1 (tracing)"));
+        match DeviceLock::check(&usb) {
+            Err(Error::OperationFailed(msg)) => {
+                assert!(msg.contains("in use by PID 1 (tracing)"), "{}", msg)
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(DeviceLock::acquire(&usb, "tracing").is_err());
+
+        drop(other);
+        assert_eq!(DeviceLock::holder(&usb), None);
+        std::fs::remove_file(DeviceLock::path(&usb)).unwrap();
+    }
+
+    #[test]
+    fn simulated_erase_streams_0xff_and_resets_the_status() {
+        let chunk = protocol::TRANSFER_LENGTH;
+        let mut chip = ChipDesc {
+            size: (2 * chunk) as u32,
+            ..Default::default()
+        };
+        chip.status_reset.push([0x23, 0xc8, 0x00, 0x00]);
+        let mut mock = crate::mock::MockTransport::stock();
+        for i in 0..2 {
+            mock = mock
+                .command(protocol::write_sdram_cmd((i * chunk) as u32, chunk))
+                .step(crate::mock::Step::BulkWrite(vec![0xff; chunk]));
+        }
+        let mut reset = [0u8; protocol::CMD_LEN];
+        reset[..4].copy_from_slice(&chip.status_reset[0]);
+        let mock = mock.command(reset);
+
+        let em100 = mock.open().unwrap();
+        assert!(em100.simulate_erase(&chip).unwrap());
+        mock.assert_done();
+    }
+
+    #[test]
+    fn serial_is_not_replaced_without_the_bootloader_magic() {
+        let mut info = [0xffu8; 256];
+        info[..6].copy_from_slice(&[0, 0x04, 0x40, 0xe2, 0x01, 0x00]);
+        let mock = crate::mock::MockTransport::stock()
+            .query(
+                protocol::read_spi_flash_page_cmd(protocol::INFO_PAGE),
+                &info,
+            )
+            .query(protocol::spi_flash_id_cmd(), &[0x20, 0x20, 0x15])
+            .query(protocol::read_spi_flash_page_cmd(0x1f0000), &[]);
+
+        let mut em100 = mock.open().unwrap();
+        assert!(matches!(
+            em100.set_serial_no(42, false),
+            Err(Error::InvalidArgument(_))
+        ));
+        mock.assert_done();
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Options of `-a <start_address> --verify` in a 64KB memory
+    fn at(start_address: u32) -> FlashOptions {
+        FlashOptions {
+            start_address,
+            memory_size: Some(0x10000),
+            verify: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn start_address_downloads_verify_the_written_range() {
+        let data = image(0x1000);
+        let mock = crate::mock::MockTransport::stock()
+            .command(protocol::write_sdram_cmd(0x8000, data.len()))
+            .step(crate::mock::Step::BulkWrite(data.clone()))
+            .command(protocol::read_sdram_cmd(0x8000, data.len()))
+            .step(crate::mock::Step::BulkRead(data.clone()));
+        let mut em100 = mock.open().unwrap();
+        let report = em100.flash_image(None, &data, at(0x8000)).unwrap();
+        mock.assert_done();
+
+        assert_eq!(report.verified, Some(true));
+        assert_eq!(report.bytes_written, data.len());
+        assert_eq!(report.verification.len(), 1);
+        assert_eq!(report.verification[0].address, 0x8000);
+        assert_eq!(report.verification[0].bytes_checked, data.len());
+    }
+
+    #[test]
+    fn stale_memory_at_the_start_address_fails_verify() {
+        let data = image(0x100);
+        let mut stale = data.clone();
+        stale[0x80] ^= 0xff;
+        let mock = crate::mock::MockTransport::stock()
+            .command(protocol::write_sdram_cmd(0xff00, data.len()))
+            .step(crate::mock::Step::BulkWrite(data.clone()))
+            .command(protocol::read_sdram_cmd(0xff00, data.len()))
+            .step(crate::mock::Step::BulkRead(stale));
+        let mut em100 = mock.open().unwrap();
+        let report = em100.flash_image(None, &data, at(0xff00)).unwrap();
+        mock.assert_done();
+
+        assert_eq!(report.verified, Some(false));
+        let mismatch = report.verification[0].mismatch.as_ref().unwrap();
+        assert_eq!((mismatch.first, mismatch.count), (0xff80, 1));
+    }
+
+    #[test]
+    fn images_past_the_memory_end_are_not_written() {
+        let mock = crate::mock::MockTransport::stock();
+        let mut em100 = mock.open().unwrap();
+        for (len, start) in [(0x101, 0xff00), (0x10001, 0), (0, 0x8000)] {
+            assert!(matches!(
+                em100.flash_image(None, &image(len), at(start)),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            em100.download_image(&image(0x101), 0xff00, 0x10000, None),
+            Err(Error::InvalidArgument(_))
+        ));
+        mock.assert_done();
+    }
+
+    #[test]
+    fn download_image_reports_what_it_wrote() {
+        let data = image(0x40);
+        let mock = crate::mock::MockTransport::stock()
+            .command(protocol::write_sdram_cmd(0x20, data.len()))
+            .step(crate::mock::Step::BulkWrite(data.clone()));
+        let em100 = mock.open().unwrap();
+        let written = em100.download_image(&data, 0x20, 0x10000, None).unwrap();
+        mock.assert_done();
+        assert_eq!(written, [(0x20, data)]);
+    }
+
+    #[test]
+    fn udev_rules_match_the_em100pro() {
+        let rule = udev_rule();
+        assert!(rule.contains("04b4") && rule.contains("1235"));
+        assert!(rule.contains("TAG+=\"uaccess\""));
+        let rule = udev_rule_for_group("plugdev");
+        assert!(rule.contains("GROUP=\"plugdev\"") && rule.contains("MODE=\"0660\""));
+        assert!(!rule.contains("uaccess"));
+    }
+
+    #[test]
+    fn udev_rules_are_installed_and_removed() {
+        let path =
+            std::env::temp_dir().join(format!("rem100-{}-60-em100.rules", std::process::id()));
+        let rule = udev_rule();
+        assert_eq!(install_udev_rule(&path, &rule).unwrap(), UdevChange::Done);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", rule)
+        );
+        assert_eq!(uninstall_udev_rule(&path).unwrap(), UdevChange::Done);
+        assert!(!path.exists());
+        assert_eq!(
+            uninstall_udev_rule(&path).unwrap(),
+            UdevChange::NotInstalled
+        );
+    }
+
+    #[test]
+    fn non_root_users_get_the_commands_to_run() {
+        let denied = || Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        let path = Path::new(UDEV_RULE_PATH);
+        let rule = udev_rule();
+        let Ok(UdevChange::NeedsRoot(commands)) =
+            udev_change(denied(), || udev_install_commands(&rule, path))
+        else {
+            panic!("permission errors should fall back to commands");
+        };
+        assert_eq!(commands, udev_rule_commands());
+        assert!(commands.starts_with(&format!("echo '{}' | sudo tee {}", rule, UDEV_RULE_PATH)));
+        assert!(commands.contains("sudo udevadm control --reload-rules"));
+
+        let other = Err(std::io::Error::from(std::io::ErrorKind::Other));
+        assert!(udev_change(other, String::new).is_err());
+    }
+}
@@ -46,19 +46,9 @@ fn download_from_drive(id: &str, filename: &std::path::Path) -> Result<()> {
 /// Download a named file
 fn download(name: &str, id: &str) -> Result<()> {
     let filename = get_em100_file(name)?;
-    print!("Downloading {}: ", name);
-    std::io::stdout().flush().ok();
+    log::info!("Downloading {}", name);
 
-    match download_from_drive(id, &filename) {
-        Ok(_) => {
-            println!("OK");
-            Ok(())
-        }
-        Err(e) => {
-            println!("FAILED.");
-            Err(e)
-        }
-    }
+    download_from_drive(id, &filename).inspect_err(|_| log::error!("Downloading {} failed", name))
 }
 
 /// Version information
@@ -119,18 +109,19 @@ pub fn update_all_files() -> Result<()> {
     // Compare timestamps
     if let Some(old) = &old_version {
         if old.time >= new_version.time {
-            println!(
+            log::info!(
                 "Current version: {}. No newer version available.",
                 old.version
             );
             return Ok(());
         }
-        println!(
+        log::info!(
             "Update available: {} (installed: {})",
-            new_version.version, old.version
+            new_version.version,
+            old.version
         );
     } else {
-        println!("Downloading latest version: {}", new_version.version);
+        log::info!("Downloading latest version: {}", new_version.version);
     }
 
     // Download everything
@@ -9,17 +9,29 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("USB error: {0}")]
-    Usb(#[from] nusb::Error),
+    Usb(nusb::Error),
+
+    #[error("Permission denied accessing the USB device")]
+    PermissionDenied,
+
+    #[error("WebUSB is not available in this browser, use Chrome, Edge or Opera")]
+    WebUsbUnavailable,
 
     #[error("USB transfer error: {0}")]
     UsbTransfer(#[from] nusb::transfer::TransferError),
 
+    #[error("USB transfer timed out")]
+    Timeout,
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
     #[error("Device not found")]
     DeviceNotFound,
 
+    #[error("{} EM100 devices connected, select one of: {}", .0.len(), .0.join(", "))]
+    MultipleDevices(Vec<String>),
+
     #[error("Device communication failed: {0}")]
     Communication(String),
 
@@ -67,4 +79,30 @@ pub enum Error {
 
     #[error("Unsupported hardware version: {0}")]
     UnsupportedHardware(u8),
+
+    #[error("Operation cancelled")]
+    Cancelled,
+
+    #[error(
+        "Partial dump: {} page(s) could not be read, first at 0x{:08x}",
+        bad_pages.len(),
+        bad_pages.first().copied().unwrap_or_default()
+    )]
+    PartialDump { bad_pages: Vec<u32> },
+}
+
+impl Error {
+    /// Whether repeating the failed exchange may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+}
+
+impl From<nusb::Error> for Error {
+    fn from(e: nusb::Error) -> Self {
+        match e.kind() {
+            nusb::ErrorKind::PermissionDenied => Error::PermissionDenied,
+            _ => Error::Usb(e),
+        }
+    }
 }
@@ -7,6 +7,7 @@ use crate::error::{Error, Result};
 use crate::spi;
 #[cfg(feature = "cli")]
 use crate::tar::TarFile;
+use crate::usb;
 use byteorder::{ByteOrder, LittleEndian};
 #[cfg(feature = "cli")]
 use indicatif::{ProgressBar, ProgressStyle};
@@ -15,9 +16,6 @@ use std::fs::File;
 #[cfg(feature = "cli")]
 use std::io::{Read, Write};
 
-/// Size constants
-const MB: usize = 1024 * 1024;
-
 fn get_le32(data: &[u8]) -> u32 {
     LittleEndian::read_u32(data)
 }
@@ -26,125 +24,198 @@ fn put_le32(data: &mut [u8], val: u32) {
     LittleEndian::write_u32(data, val);
 }
 
+/// Run a flash exchange, retrying once after resynchronizing the USB stream
+fn with_resync<T>(em100: &Em100, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    match f() {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            if usb::resync(em100).is_err() {
+                return Err(e);
+            }
+            usb::count(em100, |c| c.retries += 1);
+            f()
+        }
+    }
+}
+
 /// Progress callback type for reporting firmware operations
 pub type FirmwareProgressCallback<'a> = Option<&'a mut dyn FnMut(usize, usize, &str)>;
 
-/// Read firmware from device into memory
-pub fn firmware_read(em100: &Em100, mut progress: FirmwareProgressCallback) -> Result<Vec<u8>> {
+/// The EM100Pro's SPI flash part
+fn spi_flash_part(em100: &Em100) -> Result<&'static spi::SpiFlashPart> {
     let id = spi::get_spi_flash_id(em100)?;
-    let rom_size = match id {
-        0x202015 => 2 * MB,  // M25P16
-        0xc27518 => 16 * MB, // MX77L12850F
-        _ => {
-            return Err(Error::InvalidFirmware(format!(
-                "Unknown SPI flash id = {:06x}. Please report",
-                id
-            )));
-        }
-    };
+    spi::find_spi_flash_part(id).ok_or_else(|| {
+        Error::InvalidFirmware(format!("Unknown SPI flash id = {:06x}. Please report", id))
+    })
+}
+
+/// Size of the EM100Pro's SPI flash
+fn spi_flash_size(em100: &Em100) -> Result<usize> {
+    Ok(spi_flash_part(em100)?.size)
+}
 
+/// Read firmware from device into memory
+///
+/// Pages that can't be read are reported together as `Error::PartialDump`.
+pub fn firmware_read(em100: &Em100, mut progress: FirmwareProgressCallback) -> Result<Vec<u8>> {
+    let rom_size = spi_flash_size(em100)?;
     let mut data = vec![0u8; rom_size];
 
-    for i in (0..rom_size).step_by(256) {
-        // Retry up to 3 times
-        for retry in 0..3 {
-            if spi::read_spi_flash_page(em100, i as u32, &mut data[i..i + 256]).is_ok() {
-                break;
-            }
-            if retry == 2 {
-                return Err(Error::Communication(format!("Couldn't read @{:08x}", i)));
+    let bad_pages = spi::read_spi_flash_region(
+        em100,
+        0,
+        &mut data,
+        false,
+        Some(&mut |pos, total| {
+            if let Some(ref mut cb) = progress {
+                cb(pos, total, "Reading");
             }
-        }
-        if let Some(ref mut cb) = progress {
-            cb(i + 256, rom_size, "Reading");
-        }
+        }),
+    )?;
+    if !bad_pages.is_empty() {
+        return Err(Error::PartialDump { bad_pages });
     }
 
     Ok(data)
 }
 
-/// Convert raw firmware data to DPFW format
-pub fn firmware_to_dpfw(em100: &Em100, data: &[u8]) -> Result<Vec<u8>> {
-    let hdr_version = match em100.hw_version {
-        HwVersion::Em100ProEarly | HwVersion::Em100Pro => 1,
-        HwVersion::Em100ProG2 => 2,
-        _ => {
-            return Err(Error::UnsupportedHardware(em100.hw_version as u8));
-        }
-    };
+/// Flash offset of the FPGA bitstream
+const FPGA_OFFSET: usize = 0;
 
-    // Find FPGA firmware end
-    let all_ff = [0xffu8; 256];
-    let mut fpga_size = 0;
-    for i in (0..0x100000).step_by(0x100) {
-        if data[i..i + 256] == all_ff {
-            fpga_size = i;
-            break;
+/// Flash offset of the MCU firmware
+const MCU_OFFSET: usize = 0x100100;
+
+/// Part of the firmware to dump
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FirmwarePart {
+    /// FPGA bitstream and MCU firmware
+    #[default]
+    All,
+    /// FPGA bitstream only
+    Fpga,
+    /// MCU firmware only
+    Mcu,
+}
+
+impl std::str::FromStr for FirmwarePart {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(FirmwarePart::All),
+            "fpga" => Ok(FirmwarePart::Fpga),
+            "mcu" => Ok(FirmwarePart::Mcu),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid firmware part: {} (expected all, fpga or mcu)",
+                s
+            ))),
         }
     }
-    if fpga_size == 0 {
-        return Err(Error::InvalidFirmware(
-            "Can't parse device firmware. Please extract raw firmware instead.".to_string(),
-        ));
-    }
+}
 
-    // Find MCU firmware end
-    let mut mcu_size = 0;
-    for i in (0..0xfff00).step_by(0x100) {
-        if data[0x100100 + i..0x100100 + i + 256] == all_ff {
-            mcu_size = i;
-            break;
+impl std::fmt::Display for FirmwarePart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirmwarePart::All => write!(f, "all"),
+            FirmwarePart::Fpga => write!(f, "fpga"),
+            FirmwarePart::Mcu => write!(f, "mcu"),
         }
     }
-    if mcu_size == 0 {
-        return Err(Error::InvalidFirmware(
+}
+
+/// Length of the image at the start of `data`, up to the first all-0xFF page
+fn image_size(data: &[u8], max: usize) -> Result<usize> {
+    let all_ff = [0xffu8; 256];
+    match (0..max)
+        .step_by(0x100)
+        .find(|&i| data[i..i + 256] == all_ff)
+    {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(Error::InvalidFirmware(
             "Can't parse device firmware. Please extract raw firmware instead.".to_string(),
-        ));
+        )),
     }
+}
+
+/// Length of the FPGA bitstream in raw firmware
+fn fpga_size(data: &[u8]) -> Result<usize> {
+    image_size(&data[FPGA_OFFSET..], 0x100000)
+}
 
-    let mcu_version = format!("{}.{}", em100.mcu >> 8, em100.mcu & 0xff);
-    let fpga_version = format!("{}.{}", (em100.fpga >> 8) & 0x7f, em100.fpga & 0xff);
+/// Length of the MCU firmware in raw firmware
+fn mcu_size(data: &[u8]) -> Result<usize> {
+    image_size(&data[MCU_OFFSET..], 0xfff00)
+}
 
-    let mut header = [0u8; 0x100];
-    match hdr_version {
-        1 => header[..8].copy_from_slice(b"em100pro"),
-        2 => header[..11].copy_from_slice(b"EM100Pro-G2"),
-        _ => {}
-    }
-    header[0x28..0x2c].copy_from_slice(b"WFPD");
-    header[0x14..0x14 + mcu_version.len().min(4)]
-        .copy_from_slice(&mcu_version.as_bytes()[..mcu_version.len().min(4)]);
-    header[0x1e..0x1e + fpga_version.len().min(4)]
-        .copy_from_slice(&fpga_version.as_bytes()[..fpga_version.len().min(4)]);
-    put_le32(&mut header[0x38..], 0x100);
-    put_le32(&mut header[0x3c..], fpga_size as u32);
-    put_le32(&mut header[0x40..], 0x100 + fpga_size as u32);
-    put_le32(&mut header[0x44..], mcu_size as u32);
+/// Convert raw firmware data to DPFW format
+pub fn firmware_to_dpfw(em100: &Em100, data: &[u8]) -> Result<Vec<u8>> {
+    firmware_part_to_dpfw(em100, data, FirmwarePart::All)
+}
 
-    let mut output = Vec::with_capacity(0x100 + fpga_size + mcu_size);
-    output.extend_from_slice(&header);
-    output.extend_from_slice(&data[..fpga_size]);
-    output.extend_from_slice(&data[0x100100..0x100100 + mcu_size]);
+/// Convert a part of raw firmware data to DPFW format
+///
+/// The section of the part left out has length 0.
+pub fn firmware_part_to_dpfw(em100: &Em100, data: &[u8], part: FirmwarePart) -> Result<Vec<u8>> {
+    let target = match em100.hw_version {
+        HwVersion::Em100ProEarly | HwVersion::Em100Pro => DpfwTarget::Em100Pro,
+        HwVersion::Em100ProG2 => DpfwTarget::Em100ProG2,
+        _ => {
+            return Err(Error::UnsupportedHardware(em100.hw_version as u8));
+        }
+    };
+
+    let fpga_size = match part {
+        FirmwarePart::All | FirmwarePart::Fpga => fpga_size(data)?,
+        FirmwarePart::Mcu => 0,
+    };
+    let mcu_size = match part {
+        FirmwarePart::All | FirmwarePart::Mcu => mcu_size(data)?,
+        FirmwarePart::Fpga => 0,
+    };
+
+    // Versions are written like in the firmware files, which the updater
+    // compares against the installed ones
+    let fpga_major = (em100.fpga >> 8) & 0x7f;
+    let fpga_minor = em100.fpga & 0xff;
+    let header = DpfwHeader {
+        target,
+        mcu_version: format!("{}.{}", em100.mcu >> 8, em100.mcu & 0xff),
+        fpga_version: match target {
+            DpfwTarget::Em100Pro => format!("{}.{}", fpga_major, fpga_minor),
+            DpfwTarget::Em100ProG2 => format!("{}.{:03}", fpga_major, fpga_minor),
+        },
+        fpga_offset: DpfwHeader::SIZE,
+        fpga_len: fpga_size,
+        mcu_offset: DpfwHeader::SIZE + fpga_size,
+        mcu_len: mcu_size,
+    };
+
+    let mut output = Vec::with_capacity(DpfwHeader::SIZE + fpga_size + mcu_size);
+    output.extend_from_slice(&header.to_bytes());
+    output.extend_from_slice(&data[FPGA_OFFSET..FPGA_OFFSET + fpga_size]);
+    output.extend_from_slice(&data[MCU_OFFSET..MCU_OFFSET + mcu_size]);
 
     Ok(output)
 }
 
 /// Dump firmware from device to file (CLI version)
+///
+/// Raw dumps of a single part hold just that image, cut at the first
+/// all-0xFF page like in DPFW files.
 #[cfg(feature = "cli")]
-pub fn firmware_dump(em100: &Em100, filename: &str, firmware_is_dpfw: bool) -> Result<()> {
-    let id = spi::get_spi_flash_id(em100)?;
-    let rom_size = match id {
-        0x202015 => 2 * MB,
-        0xc27518 => 16 * MB,
-        _ => {
-            return Err(Error::InvalidFirmware(format!(
-                "Unknown SPI flash id = {:06x}. Please report",
-                id
-            )));
-        }
-    };
+pub fn firmware_dump(
+    em100: &Em100,
+    filename: &str,
+    firmware_is_dpfw: bool,
+    part: FirmwarePart,
+) -> Result<()> {
+    if !firmware_is_dpfw && part == FirmwarePart::All {
+        return firmware_dump_range(em100, filename, None, false, false).map(|_| ());
+    }
+
+    let rom_size = spi_flash_size(em100)?;
 
-    println!("\nWriting EM100Pro firmware to file {}", filename);
+    log::info!("Writing EM100Pro firmware to file {}", filename);
 
     let pb = ProgressBar::new(rom_size as u64);
     pb.set_style(
@@ -164,16 +235,160 @@ pub fn firmware_dump(em100: &Em100, filename: &str, firmware_is_dpfw: bool) -> R
     )?;
     pb.finish();
 
-    let mut file = File::create(filename)?;
+    let output = match part {
+        _ if firmware_is_dpfw => firmware_part_to_dpfw(em100, &data, part)?,
+        FirmwarePart::Fpga => data[FPGA_OFFSET..FPGA_OFFSET + fpga_size(&data)?].to_vec(),
+        FirmwarePart::Mcu => data[MCU_OFFSET..MCU_OFFSET + mcu_size(&data)?].to_vec(),
+        FirmwarePart::All => data,
+    };
+    File::create(filename)?.write_all(&output)?;
+
+    Ok(())
+}
+
+/// Pattern unreadable pages are filled with in dumps that ignore read errors
+pub const BAD_PAGE_PATTERN: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+/// Outcome of a firmware dump
+#[derive(Debug, Clone, Default)]
+pub struct DumpReport {
+    /// Pages read from the device, not counting pages kept when resuming
+    pub pages_read: usize,
+    /// Addresses of pages that couldn't be read
+    pub bad_pages: Vec<u32>,
+}
+
+/// Sidecar file listing the unreadable pages of a dump
+pub fn bad_pages_path(filename: &str) -> String {
+    format!("{}.badpages", filename)
+}
+
+/// Dump raw firmware, or a `(start, length)` range of it, to file (CLI version)
+///
+/// With `resume`, the existing file is read back first and only pages that
+/// are still all zero, or listed in its `.badpages` file, are fetched from
+/// the device. Unreadable pages are written as zeros and reported as
+/// `Error::PartialDump`, so a later resume can retry just those. With
+/// `ignore_read_errors` they are filled with `BAD_PAGE_PATTERN` instead,
+/// listed in the `.badpages` file and returned in the report.
+#[cfg(feature = "cli")]
+pub fn firmware_dump_range(
+    em100: &Em100,
+    filename: &str,
+    range: Option<(usize, usize)>,
+    resume: bool,
+    ignore_read_errors: bool,
+) -> Result<DumpReport> {
+    let rom_size = spi_flash_size(em100)?;
+    let (start, len) = range.unwrap_or((0, rom_size));
+    if len == 0 || start.checked_add(len).is_none_or(|end| end > rom_size) {
+        return Err(Error::InvalidArgument(format!(
+            "Range 0x{:x}:0x{:x} exceeds the {}KB SPI flash",
+            start,
+            len,
+            rom_size / 1024
+        )));
+    }
+
+    let badpages_file = bad_pages_path(filename);
+    let mut data = Vec::new();
+    if resume {
+        match std::fs::read(filename) {
+            Ok(existing) => data = existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if data.len() > len {
+            return Err(Error::InvalidArgument(format!(
+                "Can't resume: '{}' is larger than the requested range",
+                filename
+            )));
+        }
+    }
+    data.resize(len, 0);
+    if resume {
+        // Pages filled with the pattern by an earlier dump are retried too
+        for page in read_bad_pages(&badpages_file)? {
+            let offset = (page as usize).wrapping_sub(start);
+            if offset < len {
+                data[offset..(offset + 256).min(len)].fill(0);
+            }
+        }
+    }
+    let pending = data
+        .chunks(256)
+        .filter(|page| !resume || page.iter().all(|&b| b == 0))
+        .count();
+
+    log::info!("Writing EM100Pro firmware to file {}", filename);
+
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:50}] {percent}%")
+            .unwrap()
+            .progress_chars("=> "),
+    );
 
-    if firmware_is_dpfw {
-        let dpfw_data = firmware_to_dpfw(em100, &data)?;
-        file.write_all(&dpfw_data)?;
+    let bad_pages = spi::read_spi_flash_region(
+        em100,
+        start as u32,
+        &mut data,
+        resume,
+        Some(&mut |pos, _total| {
+            if pos & 0x7fff == 0 {
+                pb.set_position(pos as u64);
+            }
+        }),
+    )?;
+    pb.finish();
+
+    if ignore_read_errors {
+        for &page in &bad_pages {
+            let offset = page as usize - start;
+            for (i, byte) in data[offset..offset + 256].iter_mut().enumerate() {
+                *byte = BAD_PAGE_PATTERN[i % BAD_PAGE_PATTERN.len()];
+            }
+        }
+    }
+    File::create(filename)?.write_all(&data)?;
+
+    if bad_pages.is_empty() {
+        match std::fs::remove_file(&badpages_file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    } else if ignore_read_errors {
+        let mut list = File::create(&badpages_file)?;
+        for page in &bad_pages {
+            writeln!(list, "0x{:08x}", page)?;
+        }
     } else {
-        file.write_all(&data)?;
+        return Err(Error::PartialDump { bad_pages });
     }
 
-    Ok(())
+    Ok(DumpReport {
+        pages_read: pending - bad_pages.len(),
+        bad_pages,
+    })
+}
+
+/// Page addresses in a `.badpages` file, none if there is no such file
+#[cfg(feature = "cli")]
+fn read_bad_pages(path: &str) -> Result<Vec<u32>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            u32::from_str_radix(line.trim_start_matches("0x"), 16)
+                .map_err(|_| Error::Parse(format!("{}: invalid page address '{}'", path, line)))
+        })
+        .collect()
 }
 
 /// Firmware update info
@@ -186,58 +401,160 @@ pub struct FirmwareInfo {
     pub mcu_len: usize,
 }
 
-/// Validate and parse firmware file
-pub fn validate_firmware(em100: &Em100, fw: &[u8]) -> Result<FirmwareInfo> {
-    // Validate firmware file
-    match em100.hw_version {
-        HwVersion::Em100ProEarly | HwVersion::Em100Pro => {
-            if fw.len() < 0x48 || &fw[..8] != b"em100pro" || &fw[0x28..0x2c] != b"WFPD" {
-                return Err(Error::InvalidFirmware(
-                    "Not an EM100Pro (original) firmware file.".to_string(),
-                ));
-            }
-        }
-        HwVersion::Em100ProG2 => {
-            if fw.len() < 0x48 || &fw[..11] != b"EM100Pro-G2" || &fw[0x28..0x2c] != b"WFPD" {
-                return Err(Error::InvalidFirmware(
-                    "Not an EM100Pro-G2 firmware file.".to_string(),
-                ));
-            }
+/// Hardware a DPFW file is built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DpfwTarget {
+    Em100Pro,
+    Em100ProG2,
+}
+
+impl std::fmt::Display for DpfwTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DpfwTarget::Em100Pro => write!(f, "EM100Pro"),
+            DpfwTarget::Em100ProG2 => write!(f, "EM100Pro-G2"),
         }
-        _ => {
-            return Err(Error::UnsupportedHardware(em100.hw_version as u8));
+    }
+}
+
+/// Header of a DPFW firmware file
+#[derive(Debug, Clone)]
+pub struct DpfwHeader {
+    pub target: DpfwTarget,
+    pub mcu_version: String,
+    pub fpga_version: String,
+    pub fpga_offset: usize,
+    pub fpga_len: usize,
+    pub mcu_offset: usize,
+    pub mcu_len: usize,
+}
+
+impl DpfwHeader {
+    /// Parse the header at the start of a DPFW file
+    ///
+    /// Only the magic values are checked; see `check_sections` for the rest.
+    pub fn parse(fw: &[u8]) -> Result<Self> {
+        if fw.len() < 0x48 || &fw[0x28..0x2c] != b"WFPD" {
+            return Err(Error::InvalidFirmware(
+                "Not a DPFW firmware file.".to_string(),
+            ));
         }
+        let target = if &fw[..11] == b"EM100Pro-G2" {
+            DpfwTarget::Em100ProG2
+        } else if &fw[..8] == b"em100pro" {
+            DpfwTarget::Em100Pro
+        } else {
+            return Err(Error::InvalidFirmware(
+                "DPFW file for unknown hardware.".to_string(),
+            ));
+        };
+
+        let version = |range: std::ops::Range<usize>| {
+            String::from_utf8_lossy(&fw[range])
+                .trim_end_matches('\0')
+                .to_string()
+        };
+
+        Ok(Self {
+            target,
+            mcu_version: version(0x14..0x1e),
+            fpga_version: version(0x1e..0x28),
+            fpga_offset: get_le32(&fw[0x38..]) as usize,
+            fpga_len: get_le32(&fw[0x3c..]) as usize,
+            mcu_offset: get_le32(&fw[0x40..]) as usize,
+            mcu_len: get_le32(&fw[0x44..]) as usize,
+        })
     }
 
-    let fpga_offset = get_le32(&fw[0x38..]) as usize;
-    let fpga_len = get_le32(&fw[0x3c..]) as usize;
-    let mcu_offset = get_le32(&fw[0x40..]) as usize;
-    let mcu_len = get_le32(&fw[0x44..]) as usize;
+    /// Size of the header `to_bytes` writes, where the first section starts
+    pub const SIZE: usize = 0x100;
 
-    let mcu_version = String::from_utf8_lossy(&fw[0x14..0x1e])
-        .trim_end_matches('\0')
-        .to_string();
-    let fpga_version = String::from_utf8_lossy(&fw[0x1e..0x28])
-        .trim_end_matches('\0')
-        .to_string();
+    /// The header as written in front of the sections of a DPFW file
+    ///
+    /// Version strings are cut to their 10 byte fields, which `parse` reads
+    /// back with the NUL padding removed.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut header = [0u8; Self::SIZE];
+        match self.target {
+            DpfwTarget::Em100Pro => header[..8].copy_from_slice(b"em100pro"),
+            DpfwTarget::Em100ProG2 => header[..11].copy_from_slice(b"EM100Pro-G2"),
+        }
+        for (field, version) in [
+            (0x14..0x1e, &self.mcu_version),
+            (0x1e..0x28, &self.fpga_version),
+        ] {
+            let len = version.len().min(field.len());
+            header[field.start..field.start + len].copy_from_slice(&version.as_bytes()[..len]);
+        }
+        header[0x28..0x2c].copy_from_slice(b"WFPD");
+        put_le32(&mut header[0x38..], self.fpga_offset as u32);
+        put_le32(&mut header[0x3c..], self.fpga_len as u32);
+        put_le32(&mut header[0x40..], self.mcu_offset as u32);
+        put_le32(&mut header[0x44..], self.mcu_len as u32);
+        header
+    }
 
-    if fpga_len < 256 || mcu_len < 256 || fpga_len > 0x100000 || mcu_len > 0xf0000 {
-        return Err(Error::InvalidFirmware(
-            "Firmware file not valid.".to_string(),
-        ));
+    /// Check the section lengths and that both sections lie in a file of
+    /// `file_len` bytes
+    pub fn check_sections(&self, file_len: usize) -> Result<()> {
+        if self.fpga_len < 256
+            || self.mcu_len < 256
+            || self.fpga_len > 0x100000
+            || self.mcu_len > 0xf0000
+        {
+            return Err(Error::InvalidFirmware(
+                "Firmware file not valid.".to_string(),
+            ));
+        }
+        for (name, offset, len) in [
+            ("FPGA", self.fpga_offset, self.fpga_len),
+            ("MCU", self.mcu_offset, self.mcu_len),
+        ] {
+            if offset.checked_add(len).is_none_or(|end| end > file_len) {
+                return Err(Error::InvalidFirmware(format!(
+                    "{} section 0x{:x}+0x{:x} extends past the end of the file (0x{:x} bytes).",
+                    name, offset, len, file_len
+                )));
+            }
+        }
+        Ok(())
     }
+}
+
+/// Validate and parse firmware file
+pub fn validate_firmware(em100: &Em100, fw: &[u8]) -> Result<FirmwareInfo> {
+    let expected = match em100.hw_version {
+        HwVersion::Em100ProEarly | HwVersion::Em100Pro => DpfwTarget::Em100Pro,
+        HwVersion::Em100ProG2 => DpfwTarget::Em100ProG2,
+        _ => {
+            return Err(Error::UnsupportedHardware(em100.hw_version as u8));
+        }
+    };
+    let header = match DpfwHeader::parse(fw) {
+        Ok(header) if header.target == expected => header,
+        _ => {
+            return Err(Error::InvalidFirmware(match expected {
+                DpfwTarget::Em100Pro => "Not an EM100Pro (original) firmware file.".to_string(),
+                DpfwTarget::Em100ProG2 => "Not an EM100Pro-G2 firmware file.".to_string(),
+            }));
+        }
+    };
+    header.check_sections(fw.len())?;
 
     Ok(FirmwareInfo {
-        mcu_version,
-        fpga_version,
-        fpga_offset,
-        fpga_len,
-        mcu_offset,
-        mcu_len,
+        mcu_version: header.mcu_version,
+        fpga_version: header.fpga_version,
+        fpga_offset: header.fpga_offset,
+        fpga_len: header.fpga_len,
+        mcu_offset: header.mcu_offset,
+        mcu_len: header.mcu_len,
     })
 }
 
 /// Write firmware to device (core function)
+///
+/// Checks `em100.cancel` before every sector and page; a cancelled write
+/// leaves the firmware incomplete until it is written again.
 pub fn firmware_write(
     em100: &Em100,
     fw: &[u8],
@@ -247,12 +564,13 @@ pub fn firmware_write(
 ) -> Result<()> {
     // Unlock and erase
     spi::unlock_spi_flash(em100)?;
-    spi::get_spi_flash_id(em100)?;
+    let sectors = spi_flash_part(em100)?.firmware_sectors;
 
-    for i in 0..=0x1e {
-        spi::erase_spi_flash_sector(em100, i as u8)?;
+    for i in 0..sectors {
+        em100.cancel.check()?;
+        spi::erase_spi_flash_sector(em100, i)?;
         if let Some(ref mut cb) = progress {
-            cb(i as usize + 1, 0x1f, "Erasing");
+            cb(i as usize + 1, sectors as usize, "Erasing");
         }
     }
 
@@ -264,11 +582,12 @@ pub fn firmware_write(
     // Write FPGA firmware
     let mut page = [0xffu8; 256];
     for i in (0..info.fpga_len).step_by(256) {
+        em100.cancel.check()?;
         page.fill(0xff);
         let chunk_len = (info.fpga_len - i).min(256);
         page[..chunk_len]
             .copy_from_slice(&fw[info.fpga_offset + i..info.fpga_offset + i + chunk_len]);
-        spi::write_spi_flash_page(em100, i as u32, &page)?;
+        with_resync(em100, || spi::write_spi_flash_page(em100, i as u32, &page))?;
         written += chunk_len;
         if let Some(ref mut cb) = progress {
             cb(written, total_len, "Writing");
@@ -277,11 +596,14 @@ pub fn firmware_write(
 
     // Write MCU firmware
     for i in (0..info.mcu_len).step_by(256) {
+        em100.cancel.check()?;
         page.fill(0xff);
         let chunk_len = (info.mcu_len - i).min(256);
         page[..chunk_len]
             .copy_from_slice(&fw[info.mcu_offset + i..info.mcu_offset + i + chunk_len]);
-        spi::write_spi_flash_page(em100, (i + 0x100100) as u32, &page)?;
+        with_resync(em100, || {
+            spi::write_spi_flash_page(em100, (i + 0x100100) as u32, &page)
+        })?;
         written += chunk_len;
         if let Some(ref mut cb) = progress {
             cb(written, total_len, "Writing");
@@ -294,11 +616,14 @@ pub fn firmware_write(
 
         // Verify FPGA
         for i in (0..info.fpga_len).step_by(256) {
+            em100.cancel.check()?;
             page.fill(0xff);
             let chunk_len = (info.fpga_len - i).min(256);
             page[..chunk_len]
                 .copy_from_slice(&fw[info.fpga_offset + i..info.fpga_offset + i + chunk_len]);
-            spi::read_spi_flash_page(em100, i as u32, &mut vpage)?;
+            with_resync(em100, || {
+                spi::read_spi_flash_page(em100, i as u32, &mut vpage)
+            })?;
             if page != vpage {
                 return Err(Error::VerificationFailed);
             }
@@ -310,11 +635,14 @@ pub fn firmware_write(
 
         // Verify MCU
         for i in (0..info.mcu_len).step_by(256) {
+            em100.cancel.check()?;
             page.fill(0xff);
             let chunk_len = (info.mcu_len - i).min(256);
             page[..chunk_len]
                 .copy_from_slice(&fw[info.mcu_offset + i..info.mcu_offset + i + chunk_len]);
-            spi::read_spi_flash_page(em100, (i + 0x100100) as u32, &mut vpage)?;
+            with_resync(em100, || {
+                spi::read_spi_flash_page(em100, (i + 0x100100) as u32, &mut vpage)
+            })?;
             if page != vpage {
                 return Err(Error::VerificationFailed);
             }
@@ -335,11 +663,13 @@ pub fn firmware_write(
     page[5] = 0x54; // 'T'
     page[6] = 0x55;
     page[7] = 0xaa;
-    spi::write_spi_flash_page(em100, 0x100000, &page)?;
+    with_resync(em100, || spi::write_spi_flash_page(em100, 0x100000, &page))?;
 
     if verify {
         let mut vpage = [0u8; 256];
-        spi::read_spi_flash_page(em100, 0x100000, &mut vpage)?;
+        with_resync(em100, || {
+            spi::read_spi_flash_page(em100, 0x100000, &mut vpage)
+        })?;
         if page != vpage {
             return Err(Error::VerificationFailed);
         }
@@ -349,14 +679,18 @@ pub fn firmware_write(
 }
 
 /// Update firmware from file (CLI version)
+///
+/// Firmware older than the installed one is refused, and the installed
+/// version isn't flashed again, unless `force` is set. Returns whether the
+/// firmware was flashed.
 #[cfg(feature = "cli")]
-pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()> {
+pub fn firmware_update(em100: &Em100, filename: &str, verify: bool, force: bool) -> Result<bool> {
     match em100.hw_version {
         HwVersion::Em100ProEarly | HwVersion::Em100Pro => {
-            println!("Detected EM100Pro (original).");
+            log::info!("Detected EM100Pro (original).");
         }
         HwVersion::Em100ProG2 => {
-            println!("Detected EM100Pro-G2.");
+            log::info!("Detected EM100Pro-G2.");
         }
         _ => {
             return Err(Error::UnsupportedHardware(em100.hw_version as u8));
@@ -364,10 +698,10 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
     }
 
     let fw = if filename.eq_ignore_ascii_case("auto") {
-        println!("\nAutomatic firmware update.");
+        log::info!("Automatic firmware update.");
         load_auto_firmware(em100)?
     } else {
-        println!("\nFirmware update with file {}", filename);
+        log::info!("Firmware update with file {}", filename);
         let mut file = File::open(filename)?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
@@ -376,7 +710,7 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
 
     let info = validate_firmware(em100, &fw)?;
 
-    println!(
+    log::info!(
         "EM100Pro{} Update File: {}",
         if em100.hw_version == HwVersion::Em100ProG2 {
             "-G2"
@@ -387,7 +721,7 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
     );
 
     if em100.hw_version == HwVersion::Em100Pro {
-        println!(
+        log::info!(
             "  Installed version:  MCU {}.{}, FPGA {}.{} ({})",
             em100.mcu >> 8,
             em100.mcu & 0xff,
@@ -400,7 +734,7 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
             }
         );
     } else {
-        println!(
+        log::info!(
             "  Installed version:  MCU {}.{}, FPGA {}.{:03}",
             em100.mcu >> 8,
             em100.mcu & 0xff,
@@ -409,11 +743,27 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
         );
     }
 
-    println!(
+    log::info!(
         "  New version:        MCU {}, FPGA {}",
-        info.mcu_version, info.fpga_version
+        info.mcu_version,
+        info.fpga_version
     );
 
+    if !force {
+        match check_version_change(em100, &info)? {
+            VersionChange::Same => {
+                log::info!("Identical version already installed, skipping.");
+                return Ok(false);
+            }
+            VersionChange::Unknown => {
+                log::warn!(
+                    "Can't parse the firmware file's versions, not checking for a downgrade."
+                )
+            }
+            VersionChange::Upgrade => {}
+        }
+    }
+
     let total_len = info.fpga_len + info.mcu_len;
     let pb = ProgressBar::new(total_len as u64);
     pb.set_style(
@@ -423,22 +773,80 @@ pub fn firmware_update(em100: &Em100, filename: &str, verify: bool) -> Result<()
             .progress_chars("=> "),
     );
 
-    firmware_write(
+    let written = firmware_write(
         em100,
         &fw,
         &info,
         verify,
-        Some(&mut |pos, _total, msg| {
+        Some(&mut |pos, total, msg| {
+            // Erasing counts sectors, writing counts bytes
+            pb.set_length(total as u64);
             pb.set_message(msg.to_string());
             pb.set_position(pos as u64);
         }),
-    )?;
+    );
+    if let Err(e) = written {
+        pb.abandon();
+        return Err(e);
+    }
 
     pb.finish_with_message("Complete");
 
-    println!("\nDisconnect and reconnect your EM100pro");
+    log::info!("Disconnect and reconnect your EM100pro");
 
-    Ok(())
+    Ok(true)
+}
+
+/// How a firmware file's versions compare to the installed ones
+#[cfg(feature = "cli")]
+enum VersionChange {
+    Same,
+    Upgrade,
+    /// The file's versions can't be parsed
+    Unknown,
+}
+
+/// Compare the MCU and FPGA versions of `info` to the installed ones
+///
+/// Installing an older MCU or FPGA version is an error. The installed FPGA
+/// version carries the 1.8V flag in bit 15, which isn't part of the version.
+#[cfg(feature = "cli")]
+fn check_version_change(em100: &Em100, info: &FirmwareInfo) -> Result<VersionChange> {
+    let (Some(mcu), Some(fpga)) = (
+        parse_version(&info.mcu_version),
+        parse_version(&info.fpga_version),
+    ) else {
+        return Ok(VersionChange::Unknown);
+    };
+    let installed_mcu = (em100.mcu >> 8, em100.mcu & 0xff);
+    let installed_fpga = ((em100.fpga >> 8) & 0x7f, em100.fpga & 0xff);
+
+    if mcu < installed_mcu || fpga < installed_fpga {
+        return Err(Error::InvalidFirmware(format!(
+            "Refusing to downgrade from MCU {}.{}, FPGA {}.{} to MCU {}, FPGA {}. \
+             Use --force to install it anyway.",
+            installed_mcu.0,
+            installed_mcu.1,
+            installed_fpga.0,
+            installed_fpga.1,
+            info.mcu_version,
+            info.fpga_version
+        )));
+    }
+    if (mcu, fpga) == (installed_mcu, installed_fpga) {
+        return Ok(VersionChange::Same);
+    }
+    Ok(VersionChange::Upgrade)
+}
+
+/// Major and minor number of a DPFW header version like "2.27" or "1.012"
+///
+/// The minor number is decimal; G2 FPGA versions are written with three
+/// digits, which parse to the same number as the installed version.
+#[cfg(feature = "cli")]
+fn parse_version(version: &str) -> Option<(u16, u16)> {
+    let (major, minor) = version.trim().split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
 }
 
 #[cfg(feature = "cli")]
@@ -446,37 +854,390 @@ fn load_auto_firmware(em100: &Em100) -> Result<Vec<u8>> {
     let firmware_path = get_em100_file("firmware.tar.xz")?;
     let tar = TarFile::load_compressed(&firmware_path)?;
 
-    // Find appropriate firmware
-    let firmware_prefix = match em100.hw_version {
-        HwVersion::Em100ProEarly | HwVersion::Em100Pro => "firmware/em100pro_fw_",
-        HwVersion::Em100ProG2 => {
-            return Err(Error::InvalidFirmware(
-                "EM100Pro-G2 currently does not support auto-updating firmware.".to_string(),
-            ));
-        }
-        _ => {
-            return Err(Error::UnsupportedHardware(em100.hw_version as u8));
-        }
-    };
+    let (entry, data) = select_auto_firmware(em100, tar.entries(), |entry| tar.find(entry))?;
+    log::debug!("select {}", entry);
+    Ok(data)
+}
 
+/// Pick the firmware file to update `em100` with among the archive `entries`
+///
+/// Files for other hardware or another voltage are skipped. Of the rest the
+/// newest by file name version is taken, then by MCU and FPGA version.
+#[cfg(feature = "cli")]
+fn select_auto_firmware<'a>(
+    em100: &Em100,
+    entries: impl Iterator<Item = &'a str>,
+    read: impl Fn(&str) -> Result<Vec<u8>>,
+) -> Result<(&'a str, Vec<u8>)> {
     let voltage_suffix = if em100.fpga & 0x8000 != 0 {
         "1.8V"
     } else {
         "3.3V"
     };
 
-    // Find the latest firmware file that matches
-    let mut selected: Option<(String, Vec<u8>)> = None;
-    for entry in tar.entries() {
-        if entry.starts_with(firmware_prefix) && entry.contains(voltage_suffix) {
-            if let Ok(data) = tar.find(entry) {
-                println!("select {}", entry);
-                selected = Some((entry.to_string(), data));
+    // Find appropriate firmware. G2 firmware works at both voltages and is
+    // recognized by its DPFW header rather than its name.
+    let candidate = |entry: &str| match em100.hw_version {
+        HwVersion::Em100ProEarly | HwVersion::Em100Pro => {
+            entry.starts_with("firmware/em100pro_fw_") && entry.contains(voltage_suffix)
+        }
+        _ => entry.starts_with("firmware/"),
+    };
+    if !matches!(
+        em100.hw_version,
+        HwVersion::Em100ProEarly | HwVersion::Em100Pro | HwVersion::Em100ProG2
+    ) {
+        return Err(Error::UnsupportedHardware(em100.hw_version as u8));
+    }
+
+    // Select the newest by file name version, then by the MCU and FPGA
+    // versions in the header; the archive's entries come in no set order
+    let mut selected: Option<(FirmwareVersion, &str, Vec<u8>)> = None;
+    for entry in entries.filter(|entry| candidate(entry)) {
+        let Ok(data) = read(entry) else {
+            continue;
+        };
+        let info = match validate_firmware(em100, &data) {
+            Ok(info) => info,
+            Err(e) => {
+                log::debug!("skip {}: {}", entry, e);
+                continue;
             }
+        };
+        log::debug!(
+            "candidate {}: MCU {}, FPGA {}",
+            entry,
+            info.mcu_version,
+            info.fpga_version
+        );
+        let version = (
+            file_version(entry),
+            version_key(&info.mcu_version),
+            version_key(&info.fpga_version),
+        );
+        let newer = selected
+            .as_ref()
+            .is_none_or(|(v, name, _)| (&version, entry) > (v, *name));
+        if newer {
+            selected = Some((version, entry, data));
         }
     }
 
-    selected.map(|(_, data)| data).ok_or_else(|| {
+    let (_, entry, data) = selected.ok_or_else(|| {
         Error::InvalidFirmware("Could not find suitable firmware for autoupdate".to_string())
-    })
+    })?;
+    Ok((entry, data))
+}
+
+/// Sort key of a firmware file: file name, MCU and FPGA version
+#[cfg(feature = "cli")]
+type FirmwareVersion = (Vec<u32>, Vec<u32>, Vec<u32>);
+
+/// Version in a firmware file name, e.g. 2.27 in `em100pro_fw_2.27_1.8V.dpfw`
+///
+/// Empty for names without one.
+#[cfg(feature = "cli")]
+fn file_version(entry: &str) -> Vec<u32> {
+    let name = entry.rsplit('/').next().unwrap_or(entry);
+    let name = name.strip_suffix(".dpfw").unwrap_or(name);
+    name.split('_')
+        .filter(|part| part.starts_with(|c: char| c.is_ascii_digit()) && !part.ends_with('V'))
+        .flat_map(version_key)
+        .collect()
+}
+
+/// Numeric parts of a version string, for ordering versions like 2.10 after 2.9
+#[cfg(feature = "cli")]
+fn version_key(version: &str) -> Vec<u32> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+
+    /// A DPFW header laid out by hand: MCU 2.27 at 0x200, FPGA 0.52 at 0x100
+    fn synthetic_header(magic: &[u8]) -> Vec<u8> {
+        let mut fw = vec![0u8; 0x100];
+        fw[..magic.len()].copy_from_slice(magic);
+        fw[0x14..0x18].copy_from_slice(b"2.27");
+        fw[0x1e..0x22].copy_from_slice(b"0.52");
+        fw[0x28..0x2c].copy_from_slice(b"WFPD");
+        put_le32(&mut fw[0x38..], 0x100);
+        put_le32(&mut fw[0x3c..], 0x100);
+        put_le32(&mut fw[0x40..], 0x200);
+        put_le32(&mut fw[0x44..], 0x180);
+        fw
+    }
+
+    #[test]
+    fn dpfw_header_fields_are_parsed() {
+        let header = DpfwHeader::parse(&synthetic_header(b"em100pro")).unwrap();
+        assert_eq!(header.target, DpfwTarget::Em100Pro);
+        assert_eq!(header.mcu_version, "2.27");
+        assert_eq!(header.fpga_version, "0.52");
+        assert_eq!((header.fpga_offset, header.fpga_len), (0x100, 0x100));
+        assert_eq!((header.mcu_offset, header.mcu_len), (0x200, 0x180));
+
+        let header = DpfwHeader::parse(&synthetic_header(b"EM100Pro-G2")).unwrap();
+        assert_eq!(header.target, DpfwTarget::Em100ProG2);
+    }
+
+    #[test]
+    fn non_dpfw_files_are_rejected() {
+        let mut fw = synthetic_header(b"em100pro");
+        fw[0x28..0x2c].copy_from_slice(b"DPFW");
+        assert!(matches!(
+            DpfwHeader::parse(&fw),
+            Err(Error::InvalidFirmware(_))
+        ));
+        assert!(DpfwHeader::parse(&synthetic_header(b"em100pro")[..0x47]).is_err());
+        assert!(DpfwHeader::parse(&synthetic_header(b"EM200")).is_err());
+    }
+
+    #[test]
+    fn sections_must_fit_the_file() {
+        let fw = synthetic_header(b"em100pro");
+        let header = DpfwHeader::parse(&fw).unwrap();
+        assert!(header.check_sections(0x380).is_ok());
+        assert!(header.check_sections(0x37f).is_err());
+
+        // Sections under 256 bytes are never valid firmware
+        let mut fw = fw;
+        put_le32(&mut fw[0x44..], 0xff);
+        let header = DpfwHeader::parse(&fw).unwrap();
+        assert!(header.check_sections(0x1000).is_err());
+    }
+
+    /// Raw firmware with a 0x300 byte FPGA bitstream and 0x200 byte MCU
+    /// firmware, the rest erased
+    fn raw_firmware() -> Vec<u8> {
+        let mut data = vec![0xff; MCU_OFFSET + 0x1000];
+        data[FPGA_OFFSET..FPGA_OFFSET + 0x300].fill(0xf0);
+        data[MCU_OFFSET..MCU_OFFSET + 0x200].fill(0x0c);
+        data
+    }
+
+    #[test]
+    fn dumped_dpfw_is_read_back_by_the_updater() {
+        for (hw, fpga, fpga_version) in [
+            (HwVersion::Em100Pro, 0x0034, "0.52"),
+            (HwVersion::Em100ProG2, 0x010c, "1.012"),
+        ] {
+            let mock = MockTransport::em100pro(0x027f, fpga, 123456, hw);
+            let em100 = mock.open().unwrap();
+            let data = raw_firmware();
+            let fw = firmware_to_dpfw(&em100, &data).unwrap();
+
+            let info = validate_firmware(&em100, &fw).unwrap();
+            assert_eq!(info.mcu_version, "2.127");
+            assert_eq!(info.fpga_version, fpga_version);
+            assert_eq!((info.fpga_offset, info.fpga_len), (DpfwHeader::SIZE, 0x300));
+            assert_eq!(
+                (info.mcu_offset, info.mcu_len),
+                (DpfwHeader::SIZE + 0x300, 0x200)
+            );
+            assert_eq!(fw.len(), DpfwHeader::SIZE + 0x500);
+            assert_eq!(
+                fw[info.fpga_offset..info.fpga_offset + info.fpga_len],
+                data[FPGA_OFFSET..FPGA_OFFSET + 0x300]
+            );
+            assert_eq!(
+                fw[info.mcu_offset..info.mcu_offset + info.mcu_len],
+                data[MCU_OFFSET..MCU_OFFSET + 0x200]
+            );
+            // The versions read back are the installed ones
+            #[cfg(feature = "cli")]
+            assert!(matches!(
+                check_version_change(&em100, &info),
+                Ok(VersionChange::Same)
+            ));
+        }
+    }
+
+    #[test]
+    fn long_versions_fill_their_fields() {
+        let header = DpfwHeader {
+            target: DpfwTarget::Em100Pro,
+            mcu_version: "2.127".to_string(),
+            fpga_version: "0123456789abc".to_string(),
+            fpga_offset: DpfwHeader::SIZE,
+            fpga_len: 0x100,
+            mcu_offset: DpfwHeader::SIZE + 0x100,
+            mcu_len: 0x100,
+        };
+        let parsed = DpfwHeader::parse(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.mcu_version, "2.127");
+        assert_eq!(parsed.fpga_version, "0123456789");
+        assert_eq!(parsed.mcu_offset, header.mcu_offset);
+        assert_eq!(parsed.mcu_len, header.mcu_len);
+    }
+
+    /// A DPFW file for `target` with the given versions
+    #[cfg(feature = "cli")]
+    fn dpfw(target: DpfwTarget, mcu: &str, fpga: &str) -> Vec<u8> {
+        let header = DpfwHeader {
+            target,
+            mcu_version: mcu.to_string(),
+            fpga_version: fpga.to_string(),
+            fpga_offset: DpfwHeader::SIZE,
+            fpga_len: 256,
+            mcu_offset: DpfwHeader::SIZE + 256,
+            mcu_len: 256,
+        };
+        let mut fw = header.to_bytes().to_vec();
+        fw.resize(DpfwHeader::SIZE + 512, 0xa5);
+        fw
+    }
+
+    /// The entry `select_auto_firmware` picks for `hw` among `files`
+    #[cfg(feature = "cli")]
+    fn select(hw: HwVersion, fpga: u16, files: &[(&str, Vec<u8>)]) -> Result<String> {
+        let mock = MockTransport::em100pro(0x0227, fpga, 123456, hw);
+        let em100 = mock.open().unwrap();
+        let read = |entry: &str| {
+            files
+                .iter()
+                .find(|(name, _)| *name == entry)
+                .map(|(_, data)| data.clone())
+                .ok_or_else(|| Error::InvalidFirmware(entry.to_string()))
+        };
+        let (entry, data) =
+            select_auto_firmware(&em100, files.iter().map(|(name, _)| *name), read)?;
+        assert_eq!(data, read(entry).unwrap());
+        Ok(entry.to_string())
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn file_name_version_ranks_first() {
+        let pro = DpfwTarget::Em100Pro;
+        let files = [
+            (
+                "firmware/em100pro_fw_2.9_3.3V.dpfw",
+                dpfw(pro, "3.0", "0.90"),
+            ),
+            (
+                "firmware/em100pro_fw_2.10_3.3V.dpfw",
+                dpfw(pro, "2.10", "0.52"),
+            ),
+            (
+                "firmware/em100pro_fw_2.8_3.3V.dpfw",
+                dpfw(pro, "2.8", "0.99"),
+            ),
+        ];
+        assert_eq!(
+            select(HwVersion::Em100Pro, 0x0034, &files).unwrap(),
+            "firmware/em100pro_fw_2.10_3.3V.dpfw"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn mcu_then_fpga_version_break_ties() {
+        let pro = DpfwTarget::Em100Pro;
+        let files = [
+            (
+                "firmware/em100pro_fw_2.27_3.3V_a.dpfw",
+                dpfw(pro, "2.27", "0.90"),
+            ),
+            (
+                "firmware/em100pro_fw_2.27_3.3V_b.dpfw",
+                dpfw(pro, "2.28", "0.52"),
+            ),
+            (
+                "firmware/em100pro_fw_2.27_3.3V_c.dpfw",
+                dpfw(pro, "2.9", "0.99"),
+            ),
+        ];
+        assert_eq!(
+            select(HwVersion::Em100Pro, 0x0034, &files).unwrap(),
+            "firmware/em100pro_fw_2.27_3.3V_b.dpfw"
+        );
+
+        let files = [
+            (
+                "firmware/em100pro_fw_2.27_3.3V_a.dpfw",
+                dpfw(pro, "2.27", "0.52"),
+            ),
+            (
+                "firmware/em100pro_fw_2.27_3.3V_b.dpfw",
+                dpfw(pro, "2.27", "0.100"),
+            ),
+            (
+                "firmware/em100pro_fw_2.27_3.3V_c.dpfw",
+                dpfw(pro, "2.27", "0.99"),
+            ),
+        ];
+        assert_eq!(
+            select(HwVersion::Em100Pro, 0x0034, &files).unwrap(),
+            "firmware/em100pro_fw_2.27_3.3V_b.dpfw"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn other_voltages_and_hardware_are_skipped() {
+        let pro = DpfwTarget::Em100Pro;
+        let mut broken = dpfw(pro, "9.9", "9.9");
+        broken.truncate(DpfwHeader::SIZE + 256);
+        let files = [
+            (
+                "firmware/em100pro_fw_3.0_1.8V.dpfw",
+                dpfw(pro, "3.0", "0.90"),
+            ),
+            (
+                "firmware/em100pro_fw_2.1_3.3V.dpfw",
+                dpfw(pro, "2.1", "0.52"),
+            ),
+            ("firmware/em100pro_fw_9.9_3.3V.dpfw", broken),
+            (
+                "firmware/em100pro_fw_4.0_3.3V.dpfw",
+                dpfw(DpfwTarget::Em100ProG2, "4.0", "0.90"),
+            ),
+            ("firmware/readme_5.0_3.3V.dpfw", dpfw(pro, "5.0", "0.90")),
+        ];
+        assert_eq!(
+            select(HwVersion::Em100Pro, 0x0034, &files).unwrap(),
+            "firmware/em100pro_fw_2.1_3.3V.dpfw"
+        );
+        assert_eq!(
+            select(HwVersion::Em100Pro, 0x8034, &files).unwrap(),
+            "firmware/em100pro_fw_3.0_1.8V.dpfw"
+        );
+        // G2 firmware is recognized by its header, whatever its name
+        assert_eq!(
+            select(HwVersion::Em100ProG2, 0x0034, &files).unwrap(),
+            "firmware/em100pro_fw_4.0_3.3V.dpfw"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn no_candidate_is_an_error() {
+        let files = [(
+            "firmware/em100pro_fw_3.0_1.8V.dpfw",
+            dpfw(DpfwTarget::Em100Pro, "3.0", "0.90"),
+        )];
+        assert!(matches!(
+            select(HwVersion::Em100Pro, 0x0034, &files),
+            Err(Error::InvalidFirmware(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "cli")]
+    fn versions_order_numerically() {
+        assert_eq!(file_version("firmware/em100pro_fw_2.27_1.8V.dpfw"), [2, 27]);
+        assert_eq!(
+            file_version("firmware/em100pro_fw_1.8V.dpfw"),
+            Vec::<u32>::new()
+        );
+        assert!(version_key("2.10") > version_key("2.9"));
+        assert!(version_key("0.100") > version_key("0.99"));
+    }
 }
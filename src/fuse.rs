@@ -0,0 +1,205 @@
+//! Read-only FUSE view of the emulated flash
+//!
+//! The mount contains a single file, `flash.bin`, whose reads are served on
+//! demand from SDRAM. Data is fetched and cached in 2MB blocks; the cache is
+//! dropped whenever this process writes to SDRAM. Writes by the SoC while
+//! emulation is running are not tracked, so remount to see them.
+
+use crate::device::Em100;
+use crate::error::Result;
+use crate::protocol::TRANSFER_LENGTH;
+use crate::sdram;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyOpen, Request,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Name of the file exposing the emulated flash
+pub const FILE_NAME: &str = "flash.bin";
+
+const ROOT_INO: u64 = 1;
+const FILE_INO: u64 = 2;
+
+/// How long the kernel may cache attributes and lookups
+const TTL: Duration = Duration::from_secs(1);
+
+/// Filesystem serving `flash.bin` from SDRAM
+pub struct FlashFs<'a> {
+    em100: &'a Em100,
+    size: u64,
+    cache: HashMap<u64, Vec<u8>>,
+    generation: u64,
+    mounted: SystemTime,
+}
+
+impl<'a> FlashFs<'a> {
+    /// Expose the first `size` bytes of SDRAM
+    pub fn new(em100: &'a Em100, size: u64) -> Self {
+        Self {
+            em100,
+            size,
+            cache: HashMap::new(),
+            generation: sdram::write_generation(),
+            mounted: SystemTime::now(),
+        }
+    }
+
+    /// Cached contents of 2MB block `index`, read from SDRAM on first use
+    fn block(&mut self, index: u64) -> Result<&[u8]> {
+        let generation = sdram::write_generation();
+        if generation != self.generation {
+            self.cache.clear();
+            self.generation = generation;
+        }
+
+        match self.cache.entry(index) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => {
+                let start = index * TRANSFER_LENGTH as u64;
+                let len = (self.size - start).min(TRANSFER_LENGTH as u64) as usize;
+                let data = sdram::read_sdram_with_progress(self.em100, start as u32, len, None)?;
+                Ok(e.insert(data))
+            }
+        }
+    }
+
+    /// Read `size` bytes at `offset`, clamped to the end of the file
+    fn read_range(&mut self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let end = offset.saturating_add(size).min(self.size);
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let block_len = TRANSFER_LENGTH as u64;
+            let in_block = (pos % block_len) as usize;
+            let take = (end - pos).min(block_len - in_block as u64) as usize;
+            let block = self.block(pos / block_len)?;
+            out.extend_from_slice(&block[in_block..in_block + take]);
+            pos += take as u64;
+        }
+        Ok(out)
+    }
+
+    fn attr(&self, ino: u64) -> FileAttr {
+        let (kind, perm, size, nlink) = if ino == ROOT_INO {
+            (FileType::Directory, 0o555, 0, 2)
+        } else {
+            (FileType::RegularFile, 0o444, self.size, 1)
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink,
+            // SAFETY: getuid/getgid have no preconditions and can't fail
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for FlashFs<'_> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == ROOT_INO && name == FILE_NAME {
+            reply.entry(&TTL, &self.attr(FILE_INO), 0);
+        } else {
+            reply.error(libc::ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match ino {
+            ROOT_INO | FILE_INO => reply.attr(&TTL, &self.attr(ino)),
+            _ => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if ino != FILE_INO {
+            reply.error(libc::ENOENT);
+        } else if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+        } else {
+            reply.opened(0, 0);
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if ino != FILE_INO {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let Ok(offset) = u64::try_from(offset) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        match self.read_range(offset, size as u64) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                log::error!("Read of 0x{:x}+0x{:x} failed: {}", offset, size, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != ROOT_INO {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let entries = [
+            (ROOT_INO, FileType::Directory, "."),
+            (ROOT_INO, FileType::Directory, ".."),
+            (FILE_INO, FileType::RegularFile, FILE_NAME),
+        ];
+        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount the first `size` bytes of SDRAM read-only at `dir`
+///
+/// Blocks until the filesystem is unmounted (e.g. `fusermount -u dir`).
+pub fn mount(em100: &Em100, size: u64, dir: &Path) -> Result<()> {
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("rem100".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(FlashFs::new(em100, size), dir, &options)?;
+    Ok(())
+}
@@ -1,7 +1,9 @@
 //! Debug hex dump utility
 
-/// Print a hex dump of memory
-pub fn hexdump(memory: &[u8]) {
+use std::io::{self, Write};
+
+/// Write a hex dump of memory, collapsing runs of all-0x00 or all-0xff lines
+pub fn hexdump<W: Write>(w: &mut W, memory: &[u8]) -> io::Result<()> {
     let mut all_zero = 0;
     let mut all_one = 0;
 
@@ -25,34 +27,155 @@ pub fn hexdump(memory: &[u8]) {
         }
 
         if all_zero < 2 && all_one < 2 {
-            print!("{:08x}:", i);
+            write!(w, "{:08x}:", i)?;
 
             // Print hex bytes
             for j in 0..16 {
                 if i + j < memory.len() {
-                    print!(" {:02x}", memory[i + j]);
+                    write!(w, " {:02x}", memory[i + j])?;
                 } else {
-                    print!("   ");
+                    write!(w, "   ")?;
                 }
             }
 
-            print!("  ");
+            write!(w, "  ")?;
 
             // Print ASCII
             for j in 0..16 {
                 if i + j < memory.len() {
                     let c = memory[i + j];
                     if c.is_ascii_graphic() || c == b' ' {
-                        print!("{}", c as char);
+                        write!(w, "{}", c as char)?;
                     } else {
-                        print!(".");
+                        write!(w, ".")?;
                     }
                 }
             }
 
-            println!();
+            writeln!(w)?;
         } else if all_zero == 2 || all_one == 2 {
-            println!("...");
+            writeln!(w, "...")?;
         }
     }
+
+    Ok(())
+}
+
+/// Where and how much two buffers differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffReport {
+    /// Address of the first differing byte
+    pub first: usize,
+    /// Number of differing bytes; bytes missing from the shorter side count
+    pub count: usize,
+    /// Address of the 16-byte line holding the first difference
+    pub line_address: usize,
+    /// That line in the expected contents
+    pub expected_line: Vec<u8>,
+    /// That line in the actual contents
+    pub actual_line: Vec<u8>,
+}
+
+impl DiffReport {
+    /// Compare `actual` to `expected`, both starting at address `base`
+    ///
+    /// Returns `None` if they are equal.
+    pub fn compare(expected: &[u8], actual: &[u8], base: usize) -> Option<Self> {
+        let common = expected.len().min(actual.len());
+        let mismatches = || (0..common).filter(|&i| expected[i] != actual[i]);
+        let first = mismatches()
+            .next()
+            .or((expected.len() != actual.len()).then_some(common))?;
+        let count = mismatches().count() + expected.len().max(actual.len()) - common;
+
+        let line = first & !0xf;
+        let line_of =
+            |data: &[u8]| data[line.min(data.len())..(line + 16).min(data.len())].to_vec();
+        Some(Self {
+            first: base + first,
+            count,
+            line_address: base + line,
+            expected_line: line_of(expected),
+            actual_line: line_of(actual),
+        })
+    }
+
+    /// Write a summary and the first differing line of both sides
+    pub fn write<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(
+            w,
+            "First difference at 0x{:08x}, {} byte(s) differ",
+            self.first, self.count
+        )?;
+        for (name, line) in [
+            ("expected", &self.expected_line),
+            ("actual", &self.actual_line),
+        ] {
+            write!(w, "  {:<8} {:08x}:", name, self.line_address)?;
+            for byte in line {
+                write!(w, " {:02x}", byte)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        (0..0x40).collect()
+    }
+
+    #[test]
+    fn equal_buffers_have_no_report() {
+        assert_eq!(DiffReport::compare(&image(), &image(), 0x1000), None);
+        assert_eq!(DiffReport::compare(&[], &[], 0), None);
+    }
+
+    #[test]
+    fn differences_are_counted_from_the_first() {
+        let mut actual = image();
+        actual[0x13] = 0;
+        actual[0x14] = 0;
+        actual[0x3f] = 0;
+        let report = DiffReport::compare(&image(), &actual, 0x1000).unwrap();
+        assert_eq!(report.first, 0x1013);
+        assert_eq!(report.count, 3);
+        assert_eq!(report.line_address, 0x1010);
+        assert_eq!(report.expected_line, image()[0x10..0x20]);
+        assert_eq!(report.actual_line, actual[0x10..0x20]);
+    }
+
+    #[test]
+    fn missing_bytes_count_as_differences() {
+        let expected = image();
+        let report = DiffReport::compare(&expected, &expected[..0x38], 0).unwrap();
+        assert_eq!((report.first, report.count), (0x38, 8));
+        assert_eq!(report.line_address, 0x30);
+        assert_eq!(report.expected_line, expected[0x30..0x40]);
+        assert_eq!(report.actual_line, expected[0x30..0x38]);
+
+        // A longer device read is just as much a mismatch
+        let report = DiffReport::compare(&expected[..0x20], &expected, 0).unwrap();
+        assert_eq!((report.first, report.count), (0x20, 0x20));
+        assert!(report.expected_line.is_empty());
+    }
+
+    #[test]
+    fn report_shows_the_first_differing_line() {
+        let mut actual = image();
+        actual[0x21] = 0xff;
+        let report = DiffReport::compare(&image(), &actual, 0x100).unwrap();
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "First difference at 0x00000121, 1 byte(s) differ\n\
+             \x20 expected 00000120: 20 21 22 23 24 25 26 27 28 29 2a 2b 2c 2d 2e 2f\n\
+             \x20 actual   00000120: 20 ff 22 23 24 25 26 27 28 29 2a 2b 2c 2d 2e 2f\n"
+        );
+    }
 }
@@ -1,8 +1,122 @@
-//! Image auto-correction for Intel Flash Descriptor images
+//! Image file formats and auto-correction for Intel Flash Descriptor images
 
 use crate::device::{Em100, HwVersion};
-use crate::error::Result;
+use crate::error::{Error, Result};
 use byteorder::{ByteOrder, LittleEndian};
+use std::path::Path;
+
+pub use ihex::load_hex;
+pub use srec::{load_srec, write_srec};
+
+pub mod diff;
+mod ihex;
+mod srec;
+
+/// Format of an image file given to `--download` or `--upload`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Raw binary, loaded as is
+    #[default]
+    Raw,
+    /// Intel HEX, laid out by the addresses in the file
+    IntelHex,
+    /// Motorola S-record, laid out by the addresses in the file
+    Srec,
+}
+
+impl ImageFormat {
+    /// Guess the format from the file name's extension
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match ext.as_str() {
+            "hex" | "ihex" => ImageFormat::IntelHex,
+            "srec" | "s19" | "s28" | "s37" => ImageFormat::Srec,
+            _ => ImageFormat::Raw,
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageFormat::Raw => write!(f, "raw"),
+            ImageFormat::IntelHex => write!(f, "Intel HEX"),
+            ImageFormat::Srec => write!(f, "S-record"),
+        }
+    }
+}
+
+/// Contiguous data from an image file with addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+/// Sort records into segments, merging adjacent ones and rejecting overlaps
+fn merge_segments(mut records: Vec<Segment>, format: &str) -> Result<Vec<Segment>> {
+    records.sort_by_key(|r| r.address);
+    let mut segments: Vec<Segment> = Vec::new();
+    for record in records.into_iter().filter(|r| !r.data.is_empty()) {
+        match segments.last_mut() {
+            Some(last) if last.end() > record.address as u64 => {
+                return Err(Error::Parse(format!(
+                    "{} records overlap at 0x{:08x}",
+                    format, record.address
+                )));
+            }
+            Some(last) if last.end() == record.address as u64 => {
+                last.data.extend_from_slice(&record.data)
+            }
+            _ => segments.push(record),
+        }
+    }
+    Ok(segments)
+}
+
+/// Lay segments out in a `size` byte image, filling the gaps with 0xff
+pub fn segments_to_image(segments: &[Segment], size: usize) -> Result<Vec<u8>> {
+    let mut image = vec![0xff; size];
+    for segment in segments {
+        if segment.end() > size as u64 {
+            return Err(Error::InvalidArgument(format!(
+                "Data at 0x{:08x}-0x{:08x} is beyond the end of the {} byte flash",
+                segment.address,
+                segment.end() - 1,
+                size
+            )));
+        }
+        let start = segment.address as usize;
+        image[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    Ok(image)
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "raw" | "bin" => Ok(ImageFormat::Raw),
+            "ihex" | "hex" => Ok(ImageFormat::IntelHex),
+            "srec" => Ok(ImageFormat::Srec),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid image format: {} (expected raw, ihex or srec)",
+                s
+            ))),
+        }
+    }
+}
 
 /// Flash descriptor signature
 const FD_SIGNATURE: u32 = 0x0FF0A55A;
@@ -17,7 +131,7 @@ enum IfdVersion {
 /// SPI frequency settings
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 enum SpiFrequency {
     Freq20MHz = 0,
     Freq33MHz = 1,
@@ -44,7 +158,7 @@ fn get_ifd_version(flcomp: u32) -> IfdVersion {
         0 => IfdVersion::V1,     // 20MHz
         4 | 6 => IfdVersion::V2, // 50MHz/30MHz or 17MHz
         _ => {
-            eprintln!("Unknown descriptor version: {}", read_freq);
+            log::warn!("Unknown descriptor version: {}", read_freq);
             IfdVersion::V2
         }
     }
@@ -65,7 +179,7 @@ fn set_spi_frequency(flcomp: &mut u32, freq: SpiFrequency) {
 /// Set EM100 mode in flash descriptor
 fn set_em100_mode(image: &mut [u8], fcba_offset: usize, em100: &Em100) {
     if em100.hw_version == HwVersion::Em100ProG2 {
-        println!("Warning: EM100Pro-G2 can run at full speed.");
+        log::warn!("EM100Pro-G2 can run at full speed.");
     }
 
     let flcomp = LittleEndian::read_u32(&image[fcba_offset..]);
@@ -76,7 +190,7 @@ fn set_em100_mode(image: &mut [u8], fcba_offset: usize, em100: &Em100) {
         IfdVersion::V2 => (SpiFrequency::Freq17MHz, "17MHz"),
     };
 
-    println!("Limit SPI frequency to {}.", freq_name);
+    log::info!("Limit SPI frequency to {}.", freq_name);
 
     let mut new_flcomp = flcomp;
     set_spi_frequency(&mut new_flcomp, freq);
@@ -90,24 +204,22 @@ fn set_em100_mode(image: &mut [u8], fcba_offset: usize, em100: &Em100) {
 /// Returns Ok(true) if the image was patched, Ok(false) if the image
 /// type was not recognized.
 pub fn autocorrect_image(em100: &Em100, image: &mut [u8]) -> Result<bool> {
-    print!("Auto-detecting image type ... ");
-
     if let Some(fd_offset) = find_fd(image) {
-        println!("IFD");
+        log::info!("Detected image type: IFD");
 
         // Read flmap0 to find FCBA offset
         let flmap0 = LittleEndian::read_u32(&image[fd_offset + 4..]);
         let fcba_offset = ((flmap0 & 0xff) as usize) << 4;
 
         if fcba_offset >= image.len() {
-            println!("Inconsistent image.");
+            log::warn!("Inconsistent IFD image, not patched.");
             return Ok(false);
         }
 
         set_em100_mode(image, fcba_offset, em100);
         Ok(true)
     } else {
-        println!("<unknown>");
+        log::info!("Unknown image type, not patched.");
         Ok(false)
     }
 }
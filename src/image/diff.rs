@@ -0,0 +1,295 @@
+//! Map of the differences between an image and what the device holds
+
+use std::io::{self, Write};
+
+/// Most mismatching ranges a `DiffMap` keeps
+pub const MAX_DIFF_RANGES: usize = 64;
+
+/// Length of the window used to look for shifted contents
+const ANCHOR_LEN: usize = 32;
+
+/// A run of differing bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffRange {
+    /// Offset of the first differing byte
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Differences between expected and actual contents
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffMap {
+    /// The first `MAX_DIFF_RANGES` mismatching ranges
+    pub ranges: Vec<DiffRange>,
+    /// Number of mismatching ranges, including those not kept
+    pub total_ranges: usize,
+    /// Number of differing bytes; bytes missing from the shorter side count
+    pub differing_bytes: usize,
+    /// Distance the actual contents are the expected ones moved by, if so
+    ///
+    /// `Some(d)` means `actual[i + d] == expected[i]` wherever both exist.
+    pub shift: Option<isize>,
+}
+
+impl DiffMap {
+    pub fn is_empty(&self) -> bool {
+        self.total_ranges == 0
+    }
+
+    /// Print the ranges as a table, with offsets counted from `base`
+    pub fn write_table<W: Write + ?Sized>(&self, w: &mut W, base: u64) -> io::Result<()> {
+        writeln!(
+            w,
+            "{} byte(s) differ in {} range(s){}:",
+            self.differing_bytes,
+            self.total_ranges,
+            if self.total_ranges > self.ranges.len() {
+                format!(", first {} shown", self.ranges.len())
+            } else {
+                String::new()
+            }
+        )?;
+        writeln!(w, "  {:<12}length", "address")?;
+        for range in &self.ranges {
+            writeln!(
+                w,
+                "  0x{:08x}  0x{:x}",
+                base + range.offset as u64,
+                range.len
+            )?;
+        }
+        if let Some(shift) = self.shift {
+            writeln!(
+                w,
+                "The contents match the file moved by {}0x{:x} bytes; check the start address (-a).",
+                if shift < 0 { "-" } else { "+" },
+                shift.unsigned_abs()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the map as the members of a JSON object, offsets counted from `base`
+    ///
+    /// The caller writes the braces, so the map can be part of a larger object.
+    pub fn write_json_fields<W: Write + ?Sized>(&self, w: &mut W, base: u64) -> io::Result<()> {
+        writeln!(w, "  \"differing_bytes\": {},", self.differing_bytes)?;
+        writeln!(w, "  \"total_ranges\": {},", self.total_ranges)?;
+        match self.shift {
+            Some(shift) => writeln!(w, "  \"shift\": {},", shift)?,
+            None => writeln!(w, "  \"shift\": null,")?,
+        }
+        writeln!(w, "  \"ranges\": [")?;
+        for (i, range) in self.ranges.iter().enumerate() {
+            writeln!(
+                w,
+                "    {{\"address\": {}, \"length\": {}}}{}",
+                base + range.offset as u64,
+                range.len,
+                if i + 1 < self.ranges.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "  ]")
+    }
+}
+
+/// Map where `actual` differs from `expected`
+pub fn diff(expected: &[u8], actual: &[u8]) -> DiffMap {
+    let mut map = DiffMap::default();
+    let common = expected.len().min(actual.len());
+    let mut run: Option<usize> = None;
+
+    let push = |map: &mut DiffMap, offset: usize, len: usize| {
+        map.total_ranges += 1;
+        map.differing_bytes += len;
+        if map.ranges.len() < MAX_DIFF_RANGES {
+            map.ranges.push(DiffRange { offset, len });
+        }
+    };
+    for i in 0..common {
+        match (expected[i] != actual[i], run) {
+            (true, None) => run = Some(i),
+            (false, Some(start)) => {
+                push(&mut map, start, i - start);
+                run = None;
+            }
+            _ => {}
+        }
+    }
+    let end = expected.len().max(actual.len());
+    match run {
+        Some(start) => push(&mut map, start, end - start),
+        None if end > common => push(&mut map, common, end - common),
+        None => {}
+    }
+
+    if !map.is_empty() {
+        map.shift = find_shift(expected, actual);
+    }
+    map
+}
+
+/// Distance `actual` holds `expected` moved by, if it is moved at all
+///
+/// A window of `expected` that isn't all one value is looked up in `actual`
+/// and the other way round. A candidate only counts if the whole overlap
+/// matches and covers at least half of the data.
+fn find_shift(expected: &[u8], actual: &[u8]) -> Option<isize> {
+    let forward = find_anchor(expected, actual).map(|d| d as isize);
+    let backward = find_anchor(actual, expected).map(|d| -(d as isize));
+    [forward, backward]
+        .into_iter()
+        .flatten()
+        .find(|&shift| shift != 0 && matches_shifted(expected, actual, shift))
+}
+
+/// How far after its own position the first non-uniform window of `needle`
+/// is found in `haystack`
+fn find_anchor(needle: &[u8], haystack: &[u8]) -> Option<usize> {
+    let start = (0..needle.len().checked_sub(ANCHOR_LEN)? + 1)
+        .step_by(ANCHOR_LEN)
+        .find(|&i| needle[i..i + ANCHOR_LEN].iter().any(|&b| b != needle[i]))?;
+    let anchor = &needle[start..start + ANCHOR_LEN];
+    let found = haystack
+        .get(start + 1..)?
+        .windows(ANCHOR_LEN)
+        .position(|w| w == anchor)?;
+    Some(found + 1)
+}
+
+/// Whether `actual[i + shift] == expected[i]` over an overlap of at least
+/// half the data
+fn matches_shifted(expected: &[u8], actual: &[u8], shift: isize) -> bool {
+    let full = expected.len().max(actual.len());
+    let (expected, actual) = if shift >= 0 {
+        (expected, actual.get(shift as usize..).unwrap_or_default())
+    } else {
+        (
+            expected.get(shift.unsigned_abs()..).unwrap_or_default(),
+            actual,
+        )
+    };
+    let overlap = expected.len().min(actual.len());
+    overlap > 0 && overlap * 2 >= full && expected[..overlap] == actual[..overlap]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 4KB without repeating windows
+    fn image() -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..0x1000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn equal_contents_have_no_ranges() {
+        let map = diff(&image(), &image());
+        assert!(map.is_empty());
+        assert_eq!(map, DiffMap::default());
+    }
+
+    #[test]
+    fn mismatches_are_grouped_into_ranges() {
+        let expected = image();
+        let mut actual = expected.clone();
+        actual[0x10] ^= 0xff;
+        for b in &mut actual[0x100..0x180] {
+            *b ^= 0xff;
+        }
+        let map = diff(&expected, &actual[..0xff0]);
+        assert_eq!(
+            map.ranges,
+            [
+                DiffRange {
+                    offset: 0x10,
+                    len: 1
+                },
+                DiffRange {
+                    offset: 0x100,
+                    len: 0x80
+                },
+                DiffRange {
+                    offset: 0xff0,
+                    len: 0x10
+                },
+            ]
+        );
+        assert_eq!((map.total_ranges, map.differing_bytes), (3, 0x91));
+        assert_eq!(map.shift, None);
+    }
+
+    #[test]
+    fn ranges_are_capped() {
+        let expected = vec![0u8; 0x1000];
+        let mut actual = expected.clone();
+        for i in (0..0x1000).step_by(0x10) {
+            actual[i] = 1;
+        }
+        let map = diff(&expected, &actual);
+        assert_eq!(map.ranges.len(), MAX_DIFF_RANGES);
+        assert_eq!((map.total_ranges, map.differing_bytes), (0x100, 0x100));
+        assert_eq!(map.ranges[MAX_DIFF_RANGES - 1].offset, 0x3f0);
+    }
+
+    #[test]
+    fn shifted_contents_are_detected() {
+        let expected = image();
+        // Downloaded 0x100 bytes too high
+        let mut actual = vec![0xff; 0x100];
+        actual.extend_from_slice(&expected[..0xf00]);
+        assert_eq!(diff(&expected, &actual).shift, Some(0x100));
+        // ... or too low
+        let mut actual = expected[0x40..].to_vec();
+        actual.resize(expected.len(), 0xff);
+        assert_eq!(diff(&expected, &actual).shift, Some(-0x40));
+    }
+
+    #[test]
+    fn other_mismatches_are_not_shifts() {
+        let expected = image();
+        let mut actual = expected.clone();
+        actual[0x800..].fill(0xff);
+        assert_eq!(diff(&expected, &actual).shift, None);
+        // Erased contents match anything moved
+        assert_eq!(diff(&[0xff; 0x100], &[0; 0x100]).shift, None);
+        // A copy overlapping less than half of the data
+        let mut actual = vec![0; 0xc00];
+        actual.extend_from_slice(&expected[..0x400]);
+        assert_eq!(diff(&expected, &actual).shift, None);
+    }
+
+    #[test]
+    fn table_and_json_count_from_the_base() {
+        let map = DiffMap {
+            ranges: vec![DiffRange {
+                offset: 0x10,
+                len: 2,
+            }],
+            total_ranges: 1,
+            differing_bytes: 2,
+            shift: Some(-0x100),
+        };
+        let mut table = Vec::new();
+        map.write_table(&mut table, 0x1000).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "2 byte(s) differ in 1 range(s):\n  address     length\n  0x00001010  0x2\n\
+             The contents match the file moved by -0x100 bytes; check the start address (-a).\n"
+        );
+
+        let mut json = Vec::new();
+        map.write_json_fields(&mut json, 0x1000).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "  \"differing_bytes\": 2,\n  \"total_ranges\": 1,\n  \"shift\": -256,\n\
+             \x20 \"ranges\": [\n    {\"address\": 4112, \"length\": 2}\n  ]\n"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Intel HEX images
+
+use super::Segment;
+use crate::error::{Error, Result};
+
+/// Parse an Intel HEX file into segments, sorted by address
+///
+/// Data (00), end of file (01), extended segment address (02) and extended
+/// linear address (04) records are used. Start address records (03, 05)
+/// are accepted and ignored, since the emulator has nothing to start.
+/// Adjacent records are merged into one segment; overlapping ones are an
+/// error.
+pub fn load_hex(text: &str) -> Result<Vec<Segment>> {
+    let mut records: Vec<Segment> = Vec::new();
+    let mut base = 0u32;
+    let mut seen_eof = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let err = |msg: &str| Error::Parse(format!("Intel HEX line {}: {}", index + 1, msg));
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if seen_eof {
+            return Err(err("data after end of file record"));
+        }
+
+        let hex = line
+            .strip_prefix(':')
+            .ok_or_else(|| err("record doesn't start with ':'"))?;
+        if hex.len() % 2 != 0 || hex.len() < 10 {
+            return Err(err("truncated record"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| err("invalid hex digit"))?;
+
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 {
+            return Err(err(&format!(
+                "record length {} doesn't match {} data bytes",
+                len,
+                bytes.len() - 5
+            )));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0 {
+            let checksum = bytes[bytes.len() - 1];
+            return Err(err(&format!(
+                "bad checksum 0x{:02x}, expected 0x{:02x}",
+                checksum,
+                checksum.wrapping_sub(sum)
+            )));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..4 + len];
+        match bytes[3] {
+            0x00 => {
+                let address = base
+                    .checked_add(offset)
+                    .filter(|a| (*a as u64) + (len as u64) <= 1 << 32)
+                    .ok_or_else(|| err("data beyond the 4GB address space"))?;
+                records.push(Segment {
+                    address,
+                    data: data.to_vec(),
+                });
+            }
+            0x01 => seen_eof = true,
+            0x02 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            0x03 if len == 4 => {}
+            0x05 if len == 4 => {}
+            0x02..=0x05 => {
+                return Err(err(&format!("bad length for record type {:02x}", bytes[3])))
+            }
+            t => return Err(err(&format!("unknown record type {:02x}", t))),
+        }
+    }
+
+    if !seen_eof {
+        return Err(Error::Parse(
+            "Intel HEX file has no end of file record".to_string(),
+        ));
+    }
+
+    super::merge_segments(records, "Intel HEX")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One record with its checksum
+    fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", hex)
+    }
+
+    fn eof() -> String {
+        record(0x01, 0, &[])
+    }
+
+    fn parse_error(text: &str) -> String {
+        match load_hex(text) {
+            Err(Error::Parse(msg)) => msg,
+            other => panic!("{:?} parsed as {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn data_records_become_segments() {
+        let text = record(0x00, 0x0010, &[1, 2, 3, 4])
+            + &record(0x00, 0x0014, &[5, 6])
+            + &record(0x00, 0x0000, &[0xaa])
+            + &eof();
+        assert_eq!(
+            load_hex(&text).unwrap(),
+            [
+                Segment {
+                    address: 0,
+                    data: vec![0xaa]
+                },
+                Segment {
+                    address: 0x10,
+                    data: vec![1, 2, 3, 4, 5, 6]
+                },
+            ]
+        );
+
+        let image = super::super::segments_to_image(&load_hex(&text).unwrap(), 0x20).unwrap();
+        assert_eq!(image[0], 0xaa);
+        assert_eq!(image[1..0x10], [0xff; 0xf]);
+        assert_eq!(image[0x10..0x16], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(image[0x16..], [0xff; 0xa]);
+    }
+
+    #[test]
+    fn extended_addresses_set_the_base() {
+        let text = record(0x04, 0, &[0x00, 0x7f])
+            + &record(0x00, 0xfff0, &[1, 2])
+            + &record(0x02, 0, &[0x10, 0x00])
+            + &record(0x00, 0x0004, &[3])
+            + &record(0x05, 0, &[0x00, 0x7f, 0xff, 0xf0])
+            + &record(0x03, 0, &[0x00, 0x00, 0x01, 0x00])
+            + &eof();
+        let segments = load_hex(&text).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            (segments[0].address, &segments[0].data[..]),
+            (0x10004, &[3][..])
+        );
+        assert_eq!(
+            (segments[1].address, &segments[1].data[..]),
+            (0x7ffff0, &[1, 2][..])
+        );
+    }
+
+    #[test]
+    fn blank_lines_and_crlf_are_accepted() {
+        let text = format!("\r\n{}\r\n{}", record(0x00, 0, &[7]).trim(), eof());
+        assert_eq!(load_hex(&text).unwrap()[0].data, [7]);
+    }
+
+    #[test]
+    fn malformed_records_are_parse_errors() {
+        let mut bad_checksum = record(0x00, 0, &[1, 2]);
+        bad_checksum.replace_range(13..15, "00");
+        let msg = parse_error(&(bad_checksum + &eof()));
+        assert!(
+            msg.contains("line 1: bad checksum 0x00, expected 0xfb"),
+            "{}",
+            msg
+        );
+
+        let overlapping = record(0x00, 0x10, &[1, 2, 3, 4]) + &record(0x00, 0x12, &[5]) + &eof();
+        assert!(parse_error(&overlapping).contains("overlap at 0x00000012"));
+
+        for (text, expected) in [
+            (record(0x00, 0, &[1]), "no end of file record"),
+            (
+                eof() + &record(0x00, 0, &[1]),
+                "line 2: data after end of file",
+            ),
+            (
+                "0000000000\n".to_string() + &eof(),
+                "doesn't start with ':'",
+            ),
+            (":00000001F\n".to_string(), "truncated record"),
+            (":0G00000100\n".to_string(), "invalid hex digit"),
+            (
+                ":0200000001FD\n".to_string(),
+                "record length 2 doesn't match 1 data bytes",
+            ),
+            (
+                record(0x04, 0, &[1]) + &eof(),
+                "bad length for record type 04",
+            ),
+            (record(0x06, 0, &[]) + &eof(), "unknown record type 06"),
+        ] {
+            let msg = parse_error(&text);
+            assert!(msg.contains(expected), "{:?}: {}", text, msg);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Motorola S-record images
+
+use super::Segment;
+use crate::error::{Error, Result};
+use std::fmt::Write;
+
+/// Data bytes per record written by `write_srec`
+const SREC_LINE_BYTES: usize = 32;
+
+/// Parse an S-record file into segments, sorted by address
+///
+/// S1, S2 and S3 data records are used, and the file has to end with an S7,
+/// S8 or S9 termination record. Header (S0) and count (S5, S6) records are
+/// checked and otherwise ignored. Adjacent records are merged into one
+/// segment; overlapping ones are an error.
+pub fn load_srec(text: &str) -> Result<Vec<Segment>> {
+    let mut records: Vec<Segment> = Vec::new();
+    let mut terminated = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let err = |msg: &str| Error::Parse(format!("S-record line {}: {}", index + 1, msg));
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if terminated {
+            return Err(err("data after the termination record"));
+        }
+
+        let rest = line
+            .strip_prefix(['S', 's'])
+            .ok_or_else(|| err("record doesn't start with 'S'"))?;
+        let (kind, hex) = rest
+            .split_at_checked(1)
+            .ok_or_else(|| err("truncated record"))?;
+        let address_len = match kind {
+            "0" | "1" | "5" | "9" => 2,
+            "2" | "6" | "8" => 3,
+            "3" | "7" => 4,
+            _ => return Err(err(&format!("unknown record type S{}", kind))),
+        };
+        if hex.len() % 2 != 0 || hex.len() < 4 {
+            return Err(err("truncated record"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| err("invalid hex digit"))?;
+
+        let count = bytes[0] as usize;
+        if bytes.len() != count + 1 || count < address_len + 1 {
+            return Err(err(&format!(
+                "byte count {} doesn't match the {} bytes in the record",
+                count,
+                bytes.len() - 1
+            )));
+        }
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0xff {
+            let checksum = bytes[count];
+            return Err(err(&format!(
+                "bad checksum 0x{:02x}, expected 0x{:02x}",
+                checksum,
+                !sum.wrapping_sub(checksum)
+            )));
+        }
+
+        let address = bytes[1..1 + address_len]
+            .iter()
+            .fold(0u32, |a, b| (a << 8) | *b as u32);
+        let data = &bytes[1 + address_len..count];
+        match kind {
+            "1" | "2" | "3" => {
+                if address as u64 + data.len() as u64 > 1 << 32 {
+                    return Err(err("data beyond the 4GB address space"));
+                }
+                records.push(Segment {
+                    address,
+                    data: data.to_vec(),
+                });
+            }
+            "7" | "8" | "9" => terminated = true,
+            _ => {}
+        }
+    }
+
+    if !terminated {
+        return Err(Error::Parse(
+            "S-record file has no S7, S8 or S9 termination record".to_string(),
+        ));
+    }
+
+    super::merge_segments(records, "S-record")
+}
+
+/// Format `data` at `address` as S-records
+///
+/// The shortest address size that fits is used: S1/S9 up to 64KB, S2/S8 up
+/// to 16MB and S3/S7 beyond.
+pub fn write_srec(data: &[u8], address: u32) -> String {
+    let end = address as u64 + data.len() as u64;
+    let (data_type, end_type, address_len) = match end {
+        0..=0x10000 => ('1', '9', 2),
+        0x10001..=0x1000000 => ('2', '8', 3),
+        _ => ('3', '7', 4),
+    };
+
+    let mut out = String::new();
+    push_record(&mut out, '0', 2, 0, b"rem100");
+    for (i, chunk) in data.chunks(SREC_LINE_BYTES).enumerate() {
+        let offset = address.wrapping_add((i * SREC_LINE_BYTES) as u32);
+        push_record(&mut out, data_type, address_len, offset, chunk);
+    }
+    push_record(&mut out, end_type, address_len, 0, &[]);
+    out
+}
+
+fn push_record(out: &mut String, kind: char, address_len: usize, address: u32, data: &[u8]) {
+    let address_bytes = &address.to_be_bytes()[4 - address_len..];
+    let count = (address_len + data.len() + 1) as u8;
+    let sum = address_bytes
+        .iter()
+        .chain(data)
+        .fold(count, |sum, b| sum.wrapping_add(*b));
+
+    let _ = write!(out, "S{}{:02X}", kind, count);
+    for b in address_bytes.iter().chain(data) {
+        let _ = write!(out, "{:02X}", b);
+    }
+    let _ = writeln!(out, "{:02X}", !sum);
+}
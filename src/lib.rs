@@ -9,10 +9,18 @@
 //! This program is free software; you can redistribute it and/or modify
 //! it under the terms of the GNU General Public License as published by
 //! the Free Software Foundation; version 2 of the License.
+//!
+//! Diagnostics are reported through the `log` crate; the library never
+//! prints them itself.
+
+#![deny(clippy::print_stdout, clippy::print_stderr)]
 
+pub mod cancel;
 pub mod chips;
+pub mod commands;
 pub mod error;
 pub mod hexdump;
+pub mod protocol;
 
 // Image module requires device types
 #[cfg(not(target_arch = "wasm32"))]
@@ -20,14 +28,26 @@ pub mod image;
 
 // Modules that require blocking USB operations (not available on wasm32)
 #[cfg(not(target_arch = "wasm32"))]
+pub mod checksum;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod device;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod firmware;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod fpga;
 #[cfg(not(target_arch = "wasm32"))]
+pub mod mock;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod record;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod sdram;
 #[cfg(not(target_arch = "wasm32"))]
+pub mod session_log;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod shared;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod snapshot;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod spi;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod system;
@@ -35,32 +55,61 @@ pub mod system;
 pub mod trace;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod usb;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod verify;
 
 // CLI-only modules
 #[cfg(feature = "cli")]
+pub mod boards;
+#[cfg(feature = "cli")]
+pub mod compress;
+#[cfg(feature = "cli")]
+pub mod config;
+#[cfg(feature = "cli")]
 pub mod download;
 #[cfg(feature = "cli")]
 pub mod tar;
 
+// Optional FUSE mount of the emulated flash
+#[cfg(feature = "fuse")]
+pub mod fuse;
+
 // Web module (native GUI only, not wasm32)
 #[cfg(all(feature = "web", not(target_arch = "wasm32")))]
 pub mod web;
 
-// Async WebUSB modules (for wasm32)
-#[cfg(target_arch = "wasm32")]
-pub mod web_device;
-#[cfg(target_arch = "wasm32")]
-pub mod web_usb;
+// Async device, over WebUSB on wasm32 and natively with the "async" feature
+#[cfg(any(target_arch = "wasm32", feature = "async"))]
+pub mod async_device;
+#[cfg(any(target_arch = "wasm32", feature = "async"))]
+pub mod async_usb;
 
-pub use chips::{parse_dcfg, ChipDatabase, ChipDesc};
+pub use cancel::CancelToken;
+pub use chips::{parse_dcfg, AddressModePolicy, ChipDatabase, ChipDesc, ChipFilter};
 pub use error::{Error, Result};
 
 // Re-exports for native platforms only
 #[cfg(not(target_arch = "wasm32"))]
-pub use device::{list_devices, DebugInfo, DeviceInfo, Em100, HoldPinState, HwVersion, Voltages};
+pub use checksum::{Digest, DigestAlgorithm};
+#[cfg(not(target_arch = "wasm32"))]
+pub use device::{
+    check_image_size, list_devices, DebugInfo, DeviceInfo, DeviceLock, DeviceSelector, DeviceState,
+    Em100, FlashOptions, FlashReport, HoldPinState, HwVersion, PostInitReadback, UsbLocation,
+    Voltages,
+};
 #[cfg(not(target_arch = "wasm32"))]
 pub use firmware::{
-    firmware_read, firmware_to_dpfw, firmware_write, validate_firmware, FirmwareInfo,
+    firmware_part_to_dpfw, firmware_read, firmware_to_dpfw, firmware_write, validate_firmware,
+    DpfwHeader, DpfwTarget, DumpReport, FirmwareInfo, FirmwarePart,
 };
 #[cfg(not(target_arch = "wasm32"))]
-pub use sdram::{read_sdram_with_progress, write_sdram_with_progress, ProgressCallback};
+pub use sdram::{
+    fill_sdram, fill_sdram_pattern, patch_bytes, read_sdram_to, read_sdram_with_progress,
+    write_sdram_from_reader, write_sdram_with_progress, ProgressCallback,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use session_log::SessionLog;
+#[cfg(not(target_arch = "wasm32"))]
+pub use shared::SharedEm100;
+#[cfg(not(target_arch = "wasm32"))]
+pub use verify::{VerifyMode, VerifyReport};
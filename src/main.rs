@@ -3,17 +3,71 @@
 //! A Rust port of the em100 utility for controlling the Dediprog EM100Pro
 //! SPI flash emulator hardware.
 
-use clap::Parser;
-use rem100::chips::ChipDatabase;
-use rem100::device::{list_devices, Em100, HoldPinState};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+use rem100::boards;
+use rem100::checksum::DigestAlgorithm;
+use rem100::chips::{get_em100_file, AddressModePolicy, ChipDatabase, ChipDesc, ChipFilter};
+use rem100::config::{find_profile, save_profile, PROFILES_FILE};
+use rem100::device::{
+    self, check_image_size, list_devices, permission_help, udev_rule, udev_rule_for_group,
+    DeviceLock, DeviceSelector, DeviceState, Em100, HoldPinState, UdevChange, MAX_MEMORY_SIZE,
+    UDEV_RULE_PATH,
+};
 use rem100::download::update_all_files;
-use rem100::firmware::{firmware_dump, firmware_update};
-use rem100::image::autocorrect_image;
-use rem100::trace::{self, TraceState};
+use rem100::error::Error;
+use rem100::firmware::{
+    bad_pages_path, firmware_dump, firmware_dump_range, firmware_update, DpfwHeader, FirmwarePart,
+    BAD_PAGE_PATTERN,
+};
+use rem100::protocol::sdram_region_len;
+use rem100::session_log::SessionLog;
+use rem100::snapshot::SnapshotMode;
+use rem100::trace::{
+    self, BootProfile, ColorMode, Milestone, TraceFilter, TraceFormat, TraceSink, TraceState,
+    TraceTrigger,
+};
+use rem100::usb;
+use rem100::verify::VerifyMode;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// `println!`, to stderr with --json so stdout only carries JSON
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        if $crate::JSON_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// `print!`, to stderr with --json so stdout only carries JSON
+macro_rules! out {
+    ($($arg:tt)*) => {
+        if $crate::JSON_OUTPUT.load(std::sync::atomic::Ordering::Relaxed) {
+            eprint!($($arg)*)
+        } else {
+            print!($($arg)*)
+        }
+    };
+}
+
+mod cli;
+
+use cli::profile::{apply_board, apply_profile, cli_profile, load_boards};
+use cli::transfer::{
+    print_verify_report, transfer_images, upload_compression, upload_length, verify_mode,
+    verify_only, with_transfer_bar,
+};
+use cli::validate::{operation_plan, validate_combinations};
 
 /// EM100Pro command-line utility
 #[derive(Parser, Debug)]
@@ -29,25 +83,110 @@ Example:
 )]
 struct Args {
     /// Select chip emulation
-    #[arg(short = 'c', long = "set")]
+    #[arg(short = 'c', long = "set", value_name = "CHIP")]
     chip: Option<String>,
 
+    /// List the chips in the chip database
+    #[arg(long = "list-chips")]
+    list_chips: bool,
+
+    /// With --list-chips, print one chip name per line (stable, for scripts)
+    #[arg(long = "names-only", requires = "list_chips")]
+    names_only: bool,
+
+    /// With --list-chips, only list chips whose "vendor name" contains TEXT
+    #[arg(long = "chip-search", value_name = "TEXT", requires = "list_chips")]
+    chip_search: Option<String>,
+
+    /// With --list-chips, only list chips of this vendor
+    #[arg(long = "chip-vendor", value_name = "VENDOR", requires = "list_chips")]
+    chip_vendor: Option<String>,
+
+    /// With --list-chips, only list chips for this voltage: 1.8 or 3.3
+    #[arg(long = "chip-voltage", value_name = "VOLTS", requires = "list_chips")]
+    chip_voltage: Option<String>,
+
+    /// With --list-chips, only list chips of at least this size (e.g. 16M)
+    #[arg(long = "chip-min-size", value_name = "SIZE", requires = "list_chips")]
+    chip_min_size: Option<String>,
+
+    /// With --list-chips, only list chips of at most this size (e.g. 32M)
+    #[arg(long = "chip-max-size", value_name = "SIZE", requires = "list_chips")]
+    chip_max_size: Option<String>,
+
+    /// Apply the chip, hold pin and compatibility settings of a board from boards.toml
+    #[arg(long = "board", value_name = "NAME")]
+    board: Option<String>,
+
+    /// List the boards defined in boards.toml
+    #[arg(long = "list-boards")]
+    list_boards: bool,
+
+    /// Store the chip, address mode, hold pin and compatibility options given
+    /// as this device's profile in profiles.toml
+    #[arg(long = "save-profile")]
+    save_profile: bool,
+
+    /// Don't apply this device's profile from profiles.toml
+    #[arg(long = "no-profile", conflicts_with = "save_profile")]
+    no_profile: bool,
+
     /// Download FILE into EM100pro
     #[arg(short = 'd', long = "download")]
     download: Option<String>,
 
-    /// Start address for download (e.g., -a 0x300000)
+    /// Format of the -d/-u files: raw, ihex or srec (default: from the file extension)
+    #[arg(long = "format", value_name = "FORMAT")]
+    format: Option<String>,
+
+    /// Compress the -u file: gz, xz or zstd (default: from the file extension)
+    #[arg(long = "upload-compress", value_name = "FORMAT", requires = "upload")]
+    upload_compress: Option<String>,
+
+    /// Download only the part of the file that fits behind the start address
+    #[arg(long = "truncate", requires = "download")]
+    truncate: bool,
+
+    /// Start address for download and upload (e.g., -a 0x300000)
     #[arg(short = 'a', long = "start-address")]
     start_address: Option<String>,
 
+    /// Only upload LEN bytes from the start address (e.g., 4K)
+    #[arg(long = "upload-length", value_name = "LEN", requires = "upload")]
+    upload_length: Option<String>,
+
     /// Force 3 or 4 byte address mode
     #[arg(short = 'm', long = "address-mode")]
     address_mode: Option<u8>,
 
+    /// While tracing, follow the host's 4-byte mode switches on the emulator
+    #[arg(long = "sync-address-mode", conflicts_with = "address_mode")]
+    sync_address_mode: bool,
+
+    /// Stop tracing after this many seconds, counted from the first traced packet
+    #[arg(long = "trace-seconds", value_name = "SECONDS")]
+    trace_seconds: Option<u64>,
+
+    /// Stop tracing after this many decoded SPI commands
+    #[arg(long = "trace-count", value_name = "N")]
+    trace_count: Option<u64>,
+
     /// Upload from EM100pro into FILE
     #[arg(short = 'u', long = "upload")]
     upload: Option<String>,
 
+    /// With -u and -d, upload a backup before downloading (default)
+    #[arg(long = "backup-first", conflicts_with = "download_first")]
+    backup_first: bool,
+
+    /// With -u and -d, download first and upload the new contents afterwards
+    #[arg(long = "download-first")]
+    download_first: bool,
+
+    /// With -u and -d, download even if the backup upload failed
+    #[arg(long = "ignore-backup-failure", conflicts_with = "download_first")]
+    ignore_backup_failure: bool,
+
     /// Start emulation
     #[arg(short = 'r', long = "start")]
     start: bool,
@@ -60,11 +199,27 @@ struct Args {
     #[arg(short = 'v', long = "verify")]
     verify: bool,
 
+    /// How -v and --verify-only compare: full, hash or sample
+    #[arg(long = "verify-mode", value_name = "MODE")]
+    verify_mode: Option<String>,
+
+    /// Blocks of 4KB compared by --verify-mode sample
+    #[arg(long = "verify-samples", value_name = "N", requires = "verify_mode")]
+    verify_samples: Option<usize>,
+
+    /// Seed picking the --verify-mode sample blocks, to repeat a verification
+    #[arg(long = "verify-seed", value_name = "SEED", requires = "verify_mode")]
+    verify_seed: Option<String>,
+
+    /// Write the outcome of -v or --verify-only to FILE as JSON
+    #[arg(long = "verify-report", value_name = "FILE")]
+    verify_report: Option<PathBuf>,
+
     /// Enable trace mode
     #[arg(short = 't', long = "trace")]
     trace: bool,
 
-    /// Address offset for trace mode (hex)
+    /// Address offset for trace mode (hex); start of the console buffer with -R
     #[arg(short = 'O', long = "offset")]
     offset: Option<String>,
 
@@ -72,18 +227,119 @@ struct Args {
     #[arg(short = 'T', long = "terminal")]
     terminal: bool,
 
+    /// Strings for the host's lookup table messages in terminal mode, one
+    /// "ID STRING" per line
+    #[arg(long = "terminal-lut", value_name = "FILE")]
+    terminal_lut: Option<PathBuf>,
+
+    /// Terminal mode that also sends what is typed to the host through the dFIFO
+    #[arg(long = "terminal-interactive", conflicts_with_all = ["trace", "traceconsole"])]
+    terminal_interactive: bool,
+
+    /// Terminal mode that sends the contents of FILE to the host through the dFIFO
+    #[arg(
+        long = "terminal-send",
+        value_name = "FILE",
+        conflicts_with_all = ["trace", "traceconsole", "terminal_interactive"]
+    )]
+    terminal_send: Option<PathBuf>,
+
     /// Enable trace console mode
     #[arg(short = 'R', long = "traceconsole")]
     traceconsole: bool,
 
-    /// Length of buffer for traceconsole mode (hex)
+    /// Length of buffer for traceconsole mode (hex), starting at -O
     #[arg(short = 'L', long = "length")]
     length: Option<String>,
 
+    /// SPI opcodes writing the trace console (default: 0x02,0x12,0x38,0x3e)
+    #[arg(
+        long = "console-write-cmds",
+        value_name = "CMD[,CMD...]",
+        requires = "traceconsole"
+    )]
+    console_write_cmds: Option<String>,
+
     /// Brief mode for traces
     #[arg(short = 'b', long = "brief")]
     brief: bool,
 
+    /// In brief mode, print every command instead of merging sequential accesses
+    #[arg(long = "no-coalesce", requires = "brief")]
+    no_coalesce: bool,
+
+    /// Print at most N data bytes per traced transaction
+    #[arg(long = "trace-data-max", value_name = "N")]
+    trace_data_max: Option<usize>,
+
+    /// Record the first trace access to a named region (repeatable)
+    #[arg(long = "trace-milestone", value_name = "NAME=ADDR[:LEN]")]
+    trace_milestone: Vec<String>,
+
+    /// Write per-64KB read statistics of the trace to FILE as JSON
+    #[arg(long = "boot-report", value_name = "FILE", requires = "trace")]
+    boot_report: Option<PathBuf>,
+
+    /// Only trace these SPI commands (e.g., 0x03,0x0b,0xeb)
+    #[arg(long = "trace-filter-cmd", value_name = "CMD[,CMD...]")]
+    trace_filter_cmd: Option<String>,
+
+    /// Only trace transactions in this address range, after the -O offset
+    #[arg(long = "trace-filter-range", value_name = "START-END")]
+    trace_filter_range: Option<String>,
+
+    /// Only start tracing at the first access to this address (after the -O offset)
+    #[arg(long = "trace-trigger-addr", value_name = "ADDR")]
+    trace_trigger_addr: Option<String>,
+
+    /// Only start tracing at the first occurrence of this SPI command
+    #[arg(long = "trace-trigger-cmd", value_name = "CMD")]
+    trace_trigger_cmd: Option<String>,
+
+    /// Also print the last N transactions before the trace trigger
+    #[arg(long = "trace-pretrigger", value_name = "N")]
+    trace_pretrigger: Option<usize>,
+
+    /// Don't annotate status register writes in traces
+    #[arg(long = "no-decode-status")]
+    no_decode_status: bool,
+
+    /// Don't save raw trace buffers to $EM100_HOME/traces on decode anomalies
+    #[arg(long = "no-trace-dump")]
+    no_trace_dump: bool,
+
+    /// Give up on USB errors while tracing instead of reopening the device
+    #[arg(long = "no-reconnect")]
+    no_reconnect: bool,
+
+    /// Also write the decoded trace to this file (truncated unless --trace-append)
+    #[arg(long = "trace-file", value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Append to the --trace-file instead of truncating it
+    #[arg(long = "trace-append", requires = "trace_file")]
+    trace_append: bool,
+
+    /// Trace output format: text, csv, jsonl or vcd (needs --trace-file)
+    #[arg(long = "trace-format", value_name = "FORMAT")]
+    trace_format: Option<String>,
+
+    /// Include the data bytes in csv and jsonl traces
+    #[arg(long = "trace-data", requires = "trace_format")]
+    trace_data: bool,
+
+    /// Append a timestamped record of device operations to this file
+    #[arg(long = "log-file", value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Run COMMAND whenever rem100 starts/stops emulation or sets the hold pin
+    #[arg(long = "notify-state-changes", value_name = "COMMAND")]
+    notify_state_changes: Option<String>,
+
+    /// Colorize trace output: auto, always or never
+    #[arg(long = "color", value_name = "WHEN")]
+    color: Option<String>,
+
     /// Update EM100pro firmware (dangerous). Use "auto" for automatic update.
     #[arg(short = 'F', long = "firmware-update")]
     firmware_update: Option<String>,
@@ -92,14 +348,47 @@ struct Args {
     #[arg(short = 'f', long = "firmware-dump")]
     firmware_dump: Option<String>,
 
+    /// Only dump this range of the firmware flash (e.g., 0:0x100000)
+    #[arg(
+        long = "firmware-dump-range",
+        value_name = "START:LEN",
+        requires = "firmware_dump"
+    )]
+    firmware_dump_range: Option<String>,
+
+    /// Continue a firmware dump, reading only pages still missing from the file
+    #[arg(long = "resume", requires = "firmware_dump")]
+    resume: bool,
+
+    /// Only dump the FPGA or the MCU part of the firmware (with -f or -g)
+    #[arg(
+        long = "firmware-dump-part",
+        value_name = "PART",
+        conflicts_with_all = ["firmware_dump_range", "resume", "ignore_read_errors"]
+    )]
+    firmware_dump_part: Option<String>,
+
+    /// Write the firmware dump even if pages can't be read, filling them with a pattern
+    #[arg(long = "ignore-read-errors", requires = "firmware_dump")]
+    ignore_read_errors: bool,
+
     /// Export EM100pro firmware to DPFW file
     #[arg(short = 'g', long = "firmware-write")]
     firmware_write: Option<String>,
 
+    /// Print the header of a DPFW firmware file and exit
+    #[arg(long = "firmware-info", value_name = "FILE")]
+    firmware_info: Option<PathBuf>,
+
     /// Set serial number
     #[arg(short = 'S', long = "set-serialno")]
     set_serialno: Option<String>,
 
+    /// Allow -S to erase the bootloader sector of the EM100Pro's own SPI
+    /// flash even when its magic page can't be preserved
+    #[arg(long = "allow-protected-region")]
+    allow_protected_region: bool,
+
     /// Switch FPGA voltage (1.8 or 3.3) - obsolete
     #[arg(short = 'P', long = "set-voltage")]
     set_voltage: Option<String>,
@@ -108,10 +397,27 @@ struct Args {
     #[arg(short = 'p', long = "holdpin")]
     holdpin: Option<String>,
 
-    /// Use EM100pro on USB bus:device or serial number (e.g., 001:003 or EM123456)
+    /// Use EM100pro on USB bus:device or serial number (e.g., 001:003 or EM123456);
+    /// na:bus:device selects a device without serial number. A port path
+    /// BUS-PORT[.PORT...] (e.g., 1-3.2, shown by --list-devices) selects the
+    /// device plugged into that physical port: the bus and every hub port must
+    /// match, and it stays the same across re-enumeration
     #[arg(short = 'x', long = "device")]
     device: Option<String>,
 
+    /// Wait up to SECONDS (default 30) for the EM100pro to be plugged in
+    #[arg(
+        long = "wait-device",
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "30"
+    )]
+    wait_device: Option<u64>,
+
+    /// Without -x, use the first EM100pro found even if several are connected
+    #[arg(long = "any-device", conflicts_with = "device")]
+    any_device: bool,
+
     /// List all connected EM100pro devices
     #[arg(short = 'l', long = "list-devices")]
     list_devices: bool,
@@ -120,13 +426,138 @@ struct Args {
     #[arg(short = 'U', long = "update-files")]
     update_files: bool,
 
+    /// Install the udev rule giving users access to the EM100Pro (needs root)
+    #[arg(long = "install-udev-rule", conflicts_with = "uninstall_udev_rule")]
+    install_udev_rule: bool,
+
+    /// With --install-udev-rule, grant access to GROUP (mode 0660) instead of logged-in users
+    #[arg(
+        long = "udev-group",
+        value_name = "GROUP",
+        requires = "install_udev_rule"
+    )]
+    udev_group: Option<String>,
+
+    /// Remove the udev rule installed by --install-udev-rule (needs root)
+    #[arg(long = "uninstall-udev-rule")]
+    uninstall_udev_rule: bool,
+
+    /// Print a completion script for SHELL (bash, zsh, fish, elvish or powershell)
+    #[arg(long = "completions", value_name = "SHELL")]
+    completions: Option<String>,
+
     /// Enable compatibility mode (patch image for EM100Pro)
     #[arg(short = 'C', long = "compatible")]
     compatible: bool,
 
-    /// Print debug information
+    /// Disable compatibility mode turned on by the board or profile
+    #[arg(long = "no-compatible", conflicts_with = "compatible")]
+    no_compatible: bool,
+
+    /// Print debug information after all other operations (implies verbose output)
     #[arg(short = 'D', long = "debug")]
     debug: bool,
+
+    /// Print device status, including firmware quirks, and exit
+    #[arg(long = "status")]
+    status: bool,
+
+    /// Blink the LEDs to identify the device
+    #[arg(long = "identify")]
+    identify: bool,
+
+    /// Make the selected chip look freshly erased (all 0xff, status registers reset)
+    #[arg(long = "simulate-erase")]
+    simulate_erase: bool,
+
+    /// Fill the emulated chip with a byte or repeating pattern (e.g., 0xff, 0xdeadbeef)
+    #[arg(long = "fill", value_name = "PATTERN")]
+    fill: Option<String>,
+
+    /// Only fill this range of SDRAM (e.g., 0x10000:0x1000)
+    #[arg(long = "fill-range", value_name = "START:LEN", requires = "fill")]
+    fill_range: Option<String>,
+
+    /// Overwrite bytes in SDRAM (e.g., --poke 0x1000=0x01,0x02)
+    #[arg(long = "poke", value_name = "ADDR=BYTE[,BYTE...]")]
+    poke: Option<String>,
+
+    /// Print a digest of the emulated chip: sha256 (default) or crc32
+    #[arg(
+        long = "checksum",
+        value_name = "ALGO",
+        num_args = 0..=1,
+        default_missing_value = "sha256"
+    )]
+    checksum: Option<String>,
+
+    /// Only checksum this range of SDRAM (e.g., 0x10000:0x1000)
+    #[arg(
+        long = "checksum-range",
+        value_name = "START:LEN",
+        requires = "checksum"
+    )]
+    checksum_range: Option<String>,
+
+    /// Compare SDRAM at the -a address with FILE without downloading it
+    #[arg(long = "verify-only", value_name = "FILE")]
+    verify_only: Option<String>,
+
+    /// Print bytes from SDRAM (e.g., --peek 0x1000:16)
+    #[arg(long = "peek", value_name = "ADDR[:LEN]")]
+    peek: Option<String>,
+
+    /// Read SDRAM into FILE without stopping emulation; chunks may be from
+    /// different points in time (described in FILE.json)
+    #[arg(long = "snapshot", value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+
+    /// With --snapshot, stop emulation around every chunk instead
+    #[arg(long = "snapshot-consistent", requires = "snapshot")]
+    snapshot_consistent: bool,
+
+    /// Expose the emulated flash read-only as DIR/flash.bin until unmounted
+    #[cfg(feature = "fuse")]
+    #[arg(long = "mount", value_name = "DIR")]
+    mount: Option<PathBuf>,
+
+    /// Force operations that are refused by default
+    #[arg(long = "force")]
+    force: bool,
+
+    /// Wait a fixed 5s after SPI flash erases instead of polling the flash status
+    #[arg(long = "conservative-timing")]
+    conservative_timing: bool,
+
+    /// Timeout of each USB transfer in milliseconds (default 5000)
+    #[arg(long = "usb-timeout-ms", value_name = "MS")]
+    usb_timeout_ms: Option<u64>,
+
+    /// Record the USB traffic with the device to FILE, for replaying a
+    /// session later (also set by EM100_USB_RECORD)
+    #[arg(long = "usb-record", value_name = "FILE")]
+    usb_record: Option<PathBuf>,
+
+    /// Keep the device open and read commands (set, download, start, trace...)
+    /// from stdin; other operations given run first
+    #[arg(long = "interactive")]
+    interactive: bool,
+
+    /// Run the commands of FILE, one per line in the --interactive syntax;
+    /// stops at the first failing one. # starts a comment, ${NAME} is
+    /// replaced by the environment variable NAME
+    #[arg(long = "script", value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Print device info, --list-devices and the results of -d, -u and
+    /// --verify-only as JSON on stdout, one document per line; everything
+    /// else goes to stderr
+    #[arg(long = "json", conflicts_with = "compat_c")]
+    json: bool,
+
+    /// Print the device banner like the C em100 tool, for scripts parsing it
+    #[arg(long = "compat-c")]
+    compat_c: bool,
 }
 
 fn parse_hex(s: &str) -> Option<u64> {
@@ -138,221 +569,2086 @@ fn parse_hex(s: &str) -> Option<u64> {
     }
 }
 
-fn parse_device(s: &str) -> (Option<u8>, Option<u8>, Option<u32>) {
-    let s = s.to_uppercase();
-    if s.starts_with("DP") || s.starts_with("EM") {
-        // Serial number
-        if let Ok(serial) = s[2..].parse::<u32>() {
-            return (None, None, Some(serial));
+/// Parse a -O/-L value, which is hex even without 0x like in the C em100 tool
+fn parse_hex_val(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Parse a --set-serialno value, which may start with DP or EM like in the C em100 tool
+fn parse_serialno(s: &str) -> Option<u32> {
+    let upper = s.to_uppercase();
+    let digits = upper
+        .strip_prefix("DP")
+        .or_else(|| upper.strip_prefix("EM"))
+        .unwrap_or(&upper);
+    digits.parse().ok()
+}
+
+/// Parse a size in bytes, optionally with a K or M suffix
+fn parse_size(s: &str) -> Option<u32> {
+    let upper = s.trim().to_uppercase();
+    let upper = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, unit) = if let Some(n) = upper.strip_suffix('K') {
+        (n, 1024)
+    } else if let Some(n) = upper.strip_suffix('M') {
+        (n, 1024 * 1024)
+    } else {
+        (upper, 1)
+    };
+    u32::try_from(parse_hex(number)?.checked_mul(unit)?).ok()
+}
+
+/// Chip filter given with --list-chips
+fn chip_filter(args: &Args) -> Result<ChipFilter, String> {
+    let size = |s: &Option<String>| match s.as_deref().map(parse_size) {
+        Some(None) => Err(format!("Invalid chip size '{}'", s.as_deref().unwrap())),
+        Some(size) => Ok(size),
+        None => Ok(None),
+    };
+    let voltage = match args.chip_voltage.as_deref() {
+        Some(v) => match v.trim_end_matches(['v', 'V']) {
+            "1.8" => Some(1800),
+            "3.3" => Some(3300),
+            _ => return Err(format!("Invalid chip voltage '{}', expected 1.8 or 3.3", v)),
+        },
+        None => None,
+    };
+    Ok(ChipFilter {
+        text: args.chip_search.clone().unwrap_or_default(),
+        min_size: size(&args.chip_min_size)?,
+        max_size: size(&args.chip_max_size)?,
+        voltage,
+        vendor: args.chip_vendor.clone(),
+    })
+}
+
+/// Whether `len` bytes at `addr` lie within the 64MB SDRAM
+fn fits_sdram(addr: u32, len: usize) -> bool {
+    (addr as usize)
+        .checked_add(len)
+        .is_some_and(|end| end <= MAX_MEMORY_SIZE)
+}
+
+fn parse_poke(s: &str) -> Option<(u32, Vec<u8>)> {
+    let (addr, bytes) = s.split_once('=')?;
+    let addr = u32::try_from(parse_hex(addr)?).ok()?;
+    let bytes = bytes
+        .split(',')
+        .map(|b| parse_hex(b).and_then(|v| u8::try_from(v).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+    fits_sdram(addr, bytes.len()).then_some((addr, bytes))
+}
+
+fn parse_peek(s: &str) -> Option<(u32, usize)> {
+    let (addr, len) = match s.split_once(':') {
+        Some((addr, len)) => (addr, usize::try_from(parse_hex(len)?).ok()?),
+        None => (s, 1),
+    };
+    let addr = u32::try_from(parse_hex(addr)?).ok()?;
+    if len == 0 || !fits_sdram(addr, len) {
+        return None;
+    }
+    Some((addr, len))
+}
+
+/// Parse a --fill pattern: hex digits, most significant byte first
+fn parse_pattern(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = if hex.len() % 2 == 1 {
+        format!("0{}", hex)
+    } else {
+        hex.to_string()
+    };
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (start, len) = s.split_once(':')?;
+    Some((parse_hex(start)? as usize, parse_hex(len)? as usize))
+}
+
+fn parse_milestone(s: &str) -> Option<Milestone> {
+    let (name, region) = s.split_once('=')?;
+    let (addr, len) = match region.split_once(':') {
+        Some((addr, len)) => (addr, parse_hex(len)?),
+        None => (region, 1),
+    };
+    if name.is_empty() || len == 0 {
+        return None;
+    }
+    Some(Milestone::new(name, parse_hex(addr)?, len))
+}
+
+/// Parse a comma separated list of SPI opcodes
+fn parse_commands(s: &str) -> Option<Vec<u8>> {
+    s.split(',')
+        .map(|c| parse_hex(c).and_then(|v| u8::try_from(v).ok()))
+        .collect()
+}
+
+/// Trace trigger given with --trace-trigger-addr/--trace-trigger-cmd
+fn trace_trigger(args: &Args) -> Option<TraceTrigger> {
+    let command = match args.trace_trigger_cmd.as_deref() {
+        Some(s) => Some(parse_hex(s).and_then(|v| u8::try_from(v).ok())?),
+        None => None,
+    };
+    let address = match args.trace_trigger_addr.as_deref() {
+        Some(s) => Some(parse_hex(s)?),
+        None => None,
+    };
+    Some(TraceTrigger {
+        command,
+        address,
+        pretrigger: args.trace_pretrigger.unwrap_or(0),
+    })
+}
+
+fn parse_trace_filter(commands: Option<&str>, range: Option<&str>) -> Option<TraceFilter> {
+    let commands = match commands {
+        Some(s) => parse_commands(s)?,
+        None => Vec::new(),
+    };
+    let range = match range {
+        Some(s) => {
+            let (start, end) = s.split_once('-')?;
+            let (start, end) = (parse_hex(start)?, parse_hex(end)?);
+            if start > end {
+                return None;
+            }
+            Some((start, end))
         }
-    } else if s.contains(':') {
-        // Bus:device
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() == 2 {
-            if let (Ok(bus), Ok(dev)) = (parts[0].parse::<u8>(), parts[1].parse::<u8>()) {
-                return (Some(bus), Some(dev), None);
+        None => None,
+    };
+    Some(TraceFilter { commands, range })
+}
+
+fn format_bytes(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Transfers of this size take minutes on anything slower than SuperSpeed
+const SLOW_TRANSFER_THRESHOLD: usize = 64 * 1024 * 1024;
+
+fn warn_slow_transfer(em100: &Em100, length: usize) {
+    if length >= SLOW_TRANSFER_THRESHOLD && em100.usb.is_slow_link() {
+        eprintln!(
+            "Warning: transferring {}MB over a {} link will take several minutes.",
+            length / (1024 * 1024),
+            em100.usb.speed_name()
+        );
+    }
+}
+
+/// Session log opened by --log-file
+static SESSION_LOG: Mutex<Option<SessionLog>> = Mutex::new(None);
+
+/// Echo logged events to stderr (set by --debug)
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Set by --json: stdout only carries JSON documents, one per line
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Operation whose result --json prints when it ends
+struct JsonOp {
+    op: &'static str,
+    started: Instant,
+    bytes: usize,
+}
+
+/// The operation running, for --json
+static JSON_OP: Mutex<Option<JsonOp>> = Mutex::new(None);
+
+/// Print a JSON document on stdout
+fn print_json(value: &serde_json::Value) {
+    println!("{}", value);
+}
+
+/// Where human-readable output goes: stdout, or stderr with --json
+fn human_out() -> Box<dyn Write> {
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    }
+}
+
+/// Start `op` on `bytes` bytes, for its --json result
+fn op_begin(op: &'static str, bytes: usize) {
+    *JSON_OP.lock().unwrap() = Some(JsonOp {
+        op,
+        started: Instant::now(),
+        bytes,
+    });
+}
+
+/// Set the size of the running operation once it is known
+fn op_bytes(bytes: usize) {
+    if let Some(op) = JSON_OP.lock().unwrap().as_mut() {
+        op.bytes = bytes;
+    }
+}
+
+/// Print the --trace-milestone results as a JSON document
+fn print_milestones_json(trace_state: &TraceState) {
+    if trace_state.milestones().is_empty() {
+        return;
+    }
+    let reached = trace_state.milestone_hits().into_iter().map(|hit| {
+        serde_json::json!({
+            "name": hit.name,
+            "timestamp": hit.timestamp,
+            "from_first_us": hit.from_first_us,
+            "from_previous_us": hit.from_previous_us,
+        })
+    });
+    let missed = trace_state
+        .milestones()
+        .iter()
+        .filter(|m| m.hit.is_none())
+        .map(|m| serde_json::json!({ "name": m.name, "timestamp": null }));
+    print_json(&serde_json::json!({
+        "milestones": reached.chain(missed).collect::<Vec<_>>(),
+    }));
+}
+
+/// End the running operation, failed with `error` if given
+///
+/// With --json this prints its result object. An operation still running
+/// at exit is ended by `exit`.
+fn op_end(status: &str, error: Option<&str>) {
+    let Some(op) = JSON_OP.lock().unwrap().take() else {
+        return;
+    };
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        print_json(&serde_json::json!({
+            "op": op.op,
+            "status": status,
+            "bytes": op.bytes,
+            "duration_ms": op.started.elapsed().as_millis() as u64,
+            "error": error,
+        }));
+    }
+}
+
+/// Report the running operation as failed with `message` and exit
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    op_end("error", Some(message));
+    exit(1)
+}
+
+fn log_event(message: &str) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("[rem100] {}", message);
+    }
+    if let Some(log) = SESSION_LOG.lock().unwrap().as_mut() {
+        if let Err(e) = log.record(message) {
+            eprintln!("Warning: failed writing log file: {}", e);
+        }
+    }
+}
+
+/// Reopen a device that dropped off the bus while tracing and re-arm the trace
+///
+/// Returns false if the device couldn't be reopened.
+fn reconnect_trace(
+    args: &Args,
+    em100: &mut Em100,
+    restore: &DeviceState,
+    trace_state: &mut TraceState,
+) -> bool {
+    eprintln!(
+        "Warning: lost the EM100 ({}), reconnecting...",
+        em100.usb_counters()
+    );
+    log_event(&format!(
+        "reconnecting after usb errors: {}",
+        em100.usb_counters()
+    ));
+    if let Err(e) = em100.reconnect(restore) {
+        eprintln!("Error: Can't reconnect: {}", e);
+        log_event(&format!("reconnect failed: {}", e));
+        return false;
+    }
+    if args.trace || args.traceconsole {
+        trace::reset_spi_trace(em100).ok();
+    }
+    if args.terminal {
+        trace::init_spi_terminal(em100).ok();
+    }
+    trace_state.resync();
+    eprintln!(
+        "Reconnected to {} at {}; SDRAM contents may have been lost.",
+        em100.identity(),
+        em100.usb
+    );
+    log_event(&format!(
+        "reconnected to {} at {}",
+        em100.identity(),
+        em100.usb
+    ));
+    true
+}
+
+/// A command typed at the --interactive prompt or listed in a --script
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ReplCommand {
+    /// Configure the chip to emulate
+    Set(String),
+    /// Write a raw image to SDRAM
+    Download {
+        file: PathBuf,
+        address: u32,
+    },
+    /// Read SDRAM into a raw image, by default up to the end of the chip
+    Upload {
+        file: PathBuf,
+        address: u32,
+        length: Option<usize>,
+    },
+    /// Compare SDRAM with a raw image, by default the last one downloaded
+    Verify(Option<(PathBuf, u32)>),
+    Start,
+    Stop,
+    /// Trace SPI commands until CTRL-C
+    Trace,
+    Info,
+    Help,
+    Quit,
+}
+
+const REPL_HELP: &str = "Commands:
+  set CHIP                      configure the chip to emulate
+  download FILE [@ADDR]         write a raw image to SDRAM
+  upload FILE [@ADDR] [LEN]     read SDRAM into a raw image
+  verify [FILE [@ADDR]]         compare SDRAM with FILE, or the last download
+  start, stop                   start or stop emulation
+  trace                         trace SPI commands until CTRL-C
+  info                          show the device and its state
+  help                          show this list
+  quit                          leave (also CTRL-D)
+CTRL-C stops a running trace or transfer and returns to the prompt.";
+
+impl std::str::FromStr for ReplCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let address = |word: &str| {
+            word.strip_prefix('@')
+                .and_then(parse_hex)
+                .and_then(|a| u32::try_from(a).ok())
+                .ok_or_else(|| format!("Invalid address '{}', expected @HEX", word))
+        };
+        match words.as_slice() {
+            ["set", chip] => Ok(ReplCommand::Set(chip.to_string())),
+            ["download", file] => Ok(ReplCommand::Download {
+                file: PathBuf::from(file),
+                address: 0,
+            }),
+            ["download", file, at] => Ok(ReplCommand::Download {
+                file: PathBuf::from(file),
+                address: address(at)?,
+            }),
+            ["upload", file, rest @ ..] if rest.len() <= 2 => {
+                let (address, rest) = match rest {
+                    [at, rest @ ..] if at.starts_with('@') => (address(at)?, rest),
+                    _ => (0, rest),
+                };
+                let length = match rest {
+                    [] => None,
+                    [len] => Some(
+                        parse_size(len)
+                            .ok_or_else(|| format!("Invalid length '{}', e.g. 4M", len))?
+                            as usize,
+                    ),
+                    _ => return Err("Usage: upload FILE [@ADDR] [LEN]".to_string()),
+                };
+                Ok(ReplCommand::Upload {
+                    file: PathBuf::from(file),
+                    address,
+                    length,
+                })
+            }
+            ["verify"] => Ok(ReplCommand::Verify(None)),
+            ["verify", file] => Ok(ReplCommand::Verify(Some((PathBuf::from(file), 0)))),
+            ["verify", file, at] => Ok(ReplCommand::Verify(Some((
+                PathBuf::from(file),
+                address(at)?,
+            )))),
+            ["start"] => Ok(ReplCommand::Start),
+            ["stop"] => Ok(ReplCommand::Stop),
+            ["trace"] => Ok(ReplCommand::Trace),
+            ["info"] => Ok(ReplCommand::Info),
+            ["help" | "?"] => Ok(ReplCommand::Help),
+            ["quit" | "exit"] => Ok(ReplCommand::Quit),
+            [command, ..] => Err(format!(
+                "Unknown or malformed command '{}', type help for the list",
+                command
+            )),
+            [] => Err("Empty command".to_string()),
+        }
+    }
+}
+
+/// What --interactive and --script commands remember between each other
+#[derive(Default)]
+struct ReplSession {
+    /// File and address of the last download, for a bare verify
+    last_download: Option<(PathBuf, u32)>,
+}
+
+/// Read a --script: one command per line, `#` starts a comment
+///
+/// `${NAME}` is replaced by the environment variable NAME. Every line is
+/// checked here, before the device is opened; the commands come with their
+/// line numbers.
+fn load_script(path: &Path) -> Result<Vec<(usize, ReplCommand)>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read script {}: {}", path.display(), e))?;
+    let mut commands = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = expand_env(line).map_err(|e| format!("{}:{}: {}", path.display(), number, e))?;
+        let command = line
+            .parse::<ReplCommand>()
+            .map_err(|e| format!("{}:{}: {}", path.display(), number, e))?;
+        commands.push((number, command));
+    }
+    Ok(commands)
+}
+
+/// Replace each `${NAME}` in `line` with the environment variable NAME
+fn expand_env(line: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "Unterminated ${".to_string())?;
+        let name = &rest[start + 2..start + end];
+        let value =
+            std::env::var(name).map_err(|_| format!("Environment variable {} is not set", name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Run the commands of a --script in order, returning whether all succeeded
+///
+/// The first failing command ends the script. `quit` ends it early.
+fn run_script(
+    em100: &mut Em100,
+    chip_db: Option<&ChipDatabase>,
+    session: &mut ReplSession,
+    exit_requested: &AtomicBool,
+    path: &Path,
+    commands: &[(usize, ReplCommand)],
+) -> bool {
+    for (number, command) in commands {
+        if *command == ReplCommand::Quit {
+            break;
+        }
+        exit_requested.store(false, Ordering::SeqCst);
+        em100.cancel.reset();
+        log_event(&format!("script line {}: {:?}", number, command));
+        if let Err(e) = run_repl_command(em100, chip_db, session, exit_requested, command.clone()) {
+            eprintln!("{}:{}: Error: {}", path.display(), number, e);
+            log_event(&format!("script failed at line {}: {}", number, e));
+            return false;
+        }
+    }
+    log_event(&format!("script {} done", path.display()));
+    true
+}
+
+/// Read commands from stdin and run them on the open device until quit
+///
+/// A failed command is reported and the prompt comes back. CTRL-C only
+/// stops the command running, through `exit_requested` and the cancel token.
+fn interactive(
+    em100: &mut Em100,
+    chip_db: Option<&ChipDatabase>,
+    session: &mut ReplSession,
+    exit_requested: &AtomicBool,
+) {
+    outln!("Type help for the list of commands.");
+    let stdin = std::io::stdin();
+    loop {
+        out!("rem100> ");
+        std::io::stdout().flush().ok();
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) => {
+                outln!();
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Error reading command: {}", e);
+                break;
+            }
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match line.parse::<ReplCommand>() {
+            Ok(ReplCommand::Quit) => break,
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        exit_requested.store(false, Ordering::SeqCst);
+        em100.cancel.reset();
+        log_event(&format!("interactive: {}", line.trim()));
+        if let Err(e) = run_repl_command(em100, chip_db, session, exit_requested, command) {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+/// Run one --interactive or --script command
+fn run_repl_command(
+    em100: &mut Em100,
+    chip_db: Option<&ChipDatabase>,
+    session: &mut ReplSession,
+    exit_requested: &AtomicBool,
+    command: ReplCommand,
+) -> Result<(), Error> {
+    let memory_size = em100.memory_size();
+    match command {
+        ReplCommand::Set(name) => {
+            let db = chip_db.ok_or_else(|| {
+                Error::FileNotFound("chip configs, run rem100 --update-files".to_string())
+            })?;
+            let found = db.find_chip(&name)?;
+            em100.set_chip_type(&found, AddressModePolicy::default())?;
+            outln!("Chip set to {} {}.", found.vendor, found.name);
+            log_event(&format!("chip set to {} {}", found.vendor, found.name));
+        }
+        ReplCommand::Download { file, address } => {
+            let mut f = File::open(&file)?;
+            let len = f.metadata()?.len() as usize;
+            check_image_size(em100.chip.as_ref(), len, address, memory_size)?;
+            warn_slow_transfer(em100, len);
+            with_transfer_bar(len, |progress| {
+                em100.download_from(&mut f, len, address, progress)
+            })?;
+            outln!("Downloaded {} bytes to 0x{:08x}", len, address);
+            log_event(&format!(
+                "downloaded {} bytes from {} to 0x{:08x}",
+                len,
+                file.display(),
+                address
+            ));
+            session.last_download = Some((file, address));
+        }
+        ReplCommand::Verify(target) => {
+            let (file, address) = target
+                .or_else(|| session.last_download.clone())
+                .ok_or_else(|| {
+                    Error::InvalidArgument("Nothing downloaded yet, use verify FILE".to_string())
+                })?;
+            let mut f = File::open(&file)?;
+            let len = f.metadata()?.len() as usize;
+            let report = with_transfer_bar(len, |progress| {
+                rem100::verify::verify_reader(
+                    em100,
+                    address,
+                    &mut f,
+                    len,
+                    VerifyMode::Full,
+                    progress,
+                )
+            })?;
+            print_verify_report(&report);
+            if !report.passed() {
+                return Err(Error::VerificationFailed);
             }
         }
+        ReplCommand::Upload {
+            file,
+            address,
+            length,
+        } => {
+            let len = sdram_region_len(address, length, memory_size)?;
+            let mut out = BufWriter::new(File::create(&file)?);
+            warn_slow_transfer(em100, len);
+            with_transfer_bar(len, |progress| {
+                em100.upload_to(address, len, &mut out, progress)
+            })?;
+            out.flush()?;
+            outln!("Uploaded {} bytes from 0x{:08x}", len, address);
+            log_event(&format!("uploaded {} bytes to {}", len, file.display()));
+        }
+        ReplCommand::Start => {
+            em100.set_state(true)?;
+            outln!("Started EM100Pro");
+            state_changed(StateChange::Started);
+        }
+        ReplCommand::Stop => {
+            em100.set_state(false)?;
+            outln!("Stopped EM100Pro");
+            state_changed(StateChange::Stopped);
+        }
+        ReplCommand::Trace => {
+            let address_mode = em100.get_address_mode().unwrap_or(3);
+            let mut trace_state = TraceState::new(false, address_mode)
+                .with_hw_version(em100.hw_version)
+                .with_quirks(em100.quirks);
+            trace::reset_spi_trace(em100)?;
+            outln!(
+                "Tracing in {} byte address mode. Press CTRL-C to stop.",
+                address_mode
+            );
+            while !exit_requested.load(Ordering::SeqCst) {
+                match trace::read_spi_trace(em100, &mut trace_state, false, 0) {
+                    Err(Error::Io(e)) => return Err(Error::Io(e)),
+                    Err(_) => {
+                        usb::resync(em100)?;
+                        trace_state.resync();
+                    }
+                    Ok(_) => {}
+                }
+            }
+            trace_state.finish()?;
+        }
+        ReplCommand::Info => {
+            print_device_info(em100, false, false);
+            if let Some(chip) = &em100.chip {
+                outln!("Chip: {} {}", chip.vendor, chip.name);
+            }
+            let running = em100.get_state()?;
+            outln!(
+                "EM100Pro currently {}",
+                if running { "running" } else { "stopped" }
+            );
+            outln!(
+                "EM100Pro hold pin currently {}",
+                em100.get_hold_pin_state()?
+            );
+        }
+        ReplCommand::Help => outln!("{}", REPL_HELP),
+        ReplCommand::Quit => {}
+    }
+    Ok(())
+}
+
+/// Run-state and hold pin changes made by rem100
+#[derive(Debug, Clone, Copy)]
+enum StateChange {
+    Started,
+    Stopped,
+    HoldPin(HoldPinState),
+}
+
+impl StateChange {
+    /// Value of REM100_EVENT for --notify-state-changes
+    fn name(&self) -> &'static str {
+        match self {
+            StateChange::Started => "started",
+            StateChange::Stopped => "stopped",
+            StateChange::HoldPin(_) => "holdpin",
+        }
     }
-    (None, None, None)
+}
+
+impl std::fmt::Display for StateChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateChange::Started => write!(f, "emulation started"),
+            StateChange::Stopped => write!(f, "emulation stopped"),
+            StateChange::HoldPin(state) => write!(f, "hold pin set to {}", state),
+        }
+    }
+}
+
+/// Command run by --notify-state-changes
+struct StateHook {
+    command: String,
+    serial: String,
+}
+
+static STATE_HOOK: Mutex<Option<StateHook>> = Mutex::new(None);
+
+/// Record a state change and notify subscribers
+fn state_changed(change: StateChange) {
+    log_event(&change.to_string());
+    if let Some(hook) = STATE_HOOK.lock().unwrap().as_ref() {
+        run_state_hook(hook, change);
+    }
+}
+
+/// Run the hook command; failures are reported but never abort rem100
+fn run_state_hook(hook: &StateHook, change: StateChange) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    #[cfg(windows)]
+    let mut cmd = Command::new("cmd");
+    #[cfg(windows)]
+    cmd.arg("/C");
+    #[cfg(not(windows))]
+    let mut cmd = Command::new("sh");
+    #[cfg(not(windows))]
+    cmd.arg("-c");
+
+    let status = cmd
+        .arg(&hook.command)
+        .env("REM100_EVENT", change.name())
+        .env("REM100_SERIAL", &hook.serial)
+        .env(
+            "REM100_TIMESTAMP",
+            format!("{}.{:03}", now.as_secs(), now.subsec_millis()),
+        )
+        .status();
+
+    let failure = match status {
+        Ok(status) if status.success() => return,
+        Ok(status) => format!("state change hook for '{}' {}", change.name(), status),
+        Err(e) => format!("can't run state change hook: {}", e),
+    };
+    eprintln!("Warning: {}", failure);
+    log_event(&failure);
+}
+
+/// Minimum time between address mode writes made by --sync-address-mode
+const ADDRESS_MODE_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Mirrors address mode switches seen in the trace into FPGA register 0x4f
+///
+/// The register value found at the start of the session, which reflects the
+/// chip's address mode policy, is restored when the session ends.
+struct AddressModeSync {
+    original: u8,
+    current: u8,
+    last_write: Option<Instant>,
+    changes: Vec<(u8, u8)>,
+}
+
+impl AddressModeSync {
+    fn start(em100: &Em100) -> Option<Self> {
+        match em100.get_address_mode() {
+            Ok(mode) => Some(Self {
+                original: mode,
+                current: mode,
+                last_write: None,
+                changes: Vec::new(),
+            }),
+            Err(e) => {
+                eprintln!("Warning: can't read address mode, not syncing it: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Follow the mode the decoder last saw, at most once per interval
+    fn update(&mut self, em100: &Em100, observed: u8) {
+        if observed == self.current
+            || self
+                .last_write
+                .is_some_and(|t| t.elapsed() < ADDRESS_MODE_SYNC_INTERVAL)
+        {
+            return;
+        }
+        self.last_write = Some(Instant::now());
+        match em100.set_address_mode(observed) {
+            Ok(()) => {
+                log_event(&format!(
+                    "address mode synced from {}-byte to {}-byte",
+                    self.current, observed
+                ));
+                self.changes.push((self.current, observed));
+                self.current = observed;
+            }
+            Err(e) => eprintln!("\nWarning: failed to sync address mode: {}", e),
+        }
+    }
+
+    /// Restore the original mode and summarize the changes made
+    fn finish(self, em100: &Em100) {
+        if self.changes.is_empty() {
+            outln!("Address mode sync: no mode switches applied");
+            return;
+        }
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|(from, to)| format!("{}->{}", from, to))
+            .collect();
+        out!(
+            "Address mode sync: applied {} switch(es) ({})",
+            changes.len(),
+            changes.join(", ")
+        );
+        if self.current != self.original {
+            match em100.set_address_mode(self.original) {
+                Ok(()) => {
+                    out!(", restored {}-byte mode", self.original);
+                    log_event(&format!("address mode restored to {}-byte", self.original));
+                }
+                Err(e) => out!(", failed to restore {}-byte mode: {}", self.original, e),
+            }
+        }
+        outln!();
+    }
+}
+
+/// Exit status after CTRL-C cancelled a transfer, like shells use for SIGINT
+const EXIT_CANCELLED: i32 = 130;
+
+/// Exit if the failed operation `what` was cancelled with CTRL-C
+///
+/// With `stop_emulation` emulation is stopped first, since the SDRAM then
+/// holds a partial image.
+fn check_cancelled(em100: &Em100, what: &str, stop_emulation: bool) {
+    if !em100.cancel.is_cancelled() {
+        return;
+    }
+    eprintln!("{} cancelled.", what);
+    log_event(&format!("{} cancelled", what.to_lowercase()));
+    if stop_emulation {
+        match em100.set_state(false) {
+            Ok(()) => {
+                outln!("Stopped EM100Pro");
+                state_changed(StateChange::Stopped);
+            }
+            Err(e) => eprintln!("Error stopping emulation: {}", e),
+        }
+    }
+    exit(EXIT_CANCELLED);
+}
+
+/// Exit the process, recording the exit status in the session log
+fn exit(code: i32) -> ! {
+    if code == EXIT_CANCELLED {
+        op_end("cancelled", Some("Operation cancelled"));
+    } else if code != 0 {
+        op_end("error", Some(&format!("exit status {}", code)));
+    }
+    log_event(&format!("exit status {}", code));
+    std::process::exit(code)
 }
 
 fn main() {
     let args = Args::parse();
+    VERBOSE.store(args.debug, Ordering::Relaxed);
+    JSON_OUTPUT.store(args.json, Ordering::Relaxed);
+
+    // Library diagnostics; RUST_LOG overrides the default level
+    let level = if args.debug {
+        "rem100=debug"
+    } else {
+        "rem100=info"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level))
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "Error: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+
+    if let Some(path) = &args.log_file {
+        match SessionLog::open(path) {
+            Ok(log) => *SESSION_LOG.lock().unwrap() = Some(log),
+            Err(e) => {
+                eprintln!("Can't open log file '{}': {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+        log_event(&format!(
+            "rem100 {} started: {}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::args().collect::<Vec<_>>().join(" ")
+        ));
+    }
+
+    run(args);
+    exit(0);
+}
+
+fn run(mut args: Args) {
+    // Handle --completions
+    if let Some(shell) = &args.completions {
+        match shell.parse::<Shell>() {
+            Ok(shell) => print_completions(shell),
+            Err(e) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        }
+        return;
+    }
+
+    // Handle --install-udev-rule and --uninstall-udev-rule
+    if args.install_udev_rule {
+        install_udev_rule(args.udev_group.as_deref());
+        return;
+    }
+    if args.uninstall_udev_rule {
+        uninstall_udev_rule();
+        return;
+    }
+
+    // Handle --list-chips
+    if args.list_chips {
+        let filter = match chip_filter(&args) {
+            Ok(filter) => filter,
+            Err(msg) => Args::command().error(ErrorKind::InvalidValue, msg).exit(),
+        };
+        let Ok(db) = ChipDatabase::load() else {
+            eprintln!("Can't find chip configs. Please run: rem100 --update-files");
+            exit(1);
+        };
+        if args.names_only && filter == ChipFilter::default() {
+            for name in db.chip_names() {
+                outln!("{}", name);
+            }
+        } else if args.names_only {
+            for chip in db.search(&filter) {
+                outln!("{}", chip.name);
+            }
+        } else {
+            print_chips(&db, &filter);
+        }
+        return;
+    }
+
+    // Handle --firmware-info
+    if let Some(path) = &args.firmware_info {
+        if !print_firmware_info(path) {
+            exit(1);
+        }
+        return;
+    }
 
     // Handle --list-devices
     if args.list_devices {
         match list_devices() {
+            Ok(devices) if args.json => {
+                let devices: Vec<_> = devices
+                    .iter()
+                    .map(|d| {
+                        serde_json::json!({
+                            "bus": d.usb.bus,
+                            "device": d.usb.address,
+                            "port": d.usb.port_path(),
+                            "serial": d.serial,
+                            "hw_version": d.hw_version.map(|v| v.to_string()),
+                        })
+                    })
+                    .collect();
+                print_json(&serde_json::Value::Array(devices));
+            }
             Ok(devices) => {
                 if devices.is_empty() {
-                    println!("No EM100pro devices found.");
+                    outln!("No EM100pro devices found.");
                 } else {
-                    for (bus, dev, serial) in devices {
-                        println!(" Bus {:03} Device {:03}: EM100pro {}", bus, dev, serial);
+                    let mut blank = false;
+                    for device in devices {
+                        let usb = &device.usb;
+                        out!(
+                            " Bus {:03} Device {:03}: EM100pro {} (port {}, {})",
+                            usb.bus,
+                            usb.address,
+                            device.serial_or_status(),
+                            usb.port_path(),
+                            usb.speed_name()
+                        );
+                        if device.has_blank_serial() {
+                            blank = true;
+                            out!(
+                                " - no serial number, select with -x {} or -x {}",
+                                DeviceSelector::BlankSerial(usb.bus, usb.address),
+                                usb.port_path()
+                            );
+                        }
+                        outln!();
+                    }
+                    if blank {
+                        outln!(
+                            "Give devices without a serial number one with -x na:BUS:DEV --set-serialno NUM."
+                        );
                     }
                 }
             }
             Err(e) => {
                 eprintln!("Error listing devices: {}", e);
-                std::process::exit(1);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    // Handle --update-files
+    if args.update_files {
+        if let Err(e) = update_all_files() {
+            eprintln!("Error updating files: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    // Handle --list-boards
+    if args.list_boards {
+        let boards = load_boards();
+        if boards.is_empty() {
+            outln!(
+                "No boards defined, add them to {}",
+                get_em100_file(boards::BOARDS_FILE)
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_else(|_| boards::BOARDS_FILE.to_string())
+            );
+        }
+        for board in boards {
+            outln!("  {}", board);
+        }
+        return;
+    }
+
+    // Expand --board; explicit options take precedence
+    if let Some(name) = args.board.clone() {
+        let Some(board) = load_boards().into_iter().find(|b| b.name == name) else {
+            Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("Unknown board '{}', see --list-boards", name),
+                )
+                .exit();
+        };
+        apply_board(&mut args, &board);
+    }
+
+    if let Err((kind, msg)) = validate_combinations(&args) {
+        Args::command().error(kind, msg).exit();
+    }
+    if args.debug {
+        eprintln!("Operation plan: {}", operation_plan(&args).join(" -> "));
+    }
+    let script = args.script.as_deref().map(|path| match load_script(path) {
+        Ok(commands) => commands,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    });
+
+    // Parse device selection
+    let selector = match args.device.as_deref().map(str::parse::<DeviceSelector>) {
+        Some(Ok(selector)) => Some(selector),
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => None,
+    };
+    let color = match args.color.as_deref().map(str::parse::<ColorMode>) {
+        Some(Ok(mode)) => mode,
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => ColorMode::default(),
+    };
+    // Reject a bad --verify-mode before downloading anything
+    verify_mode(&args);
+    upload_length(&args);
+    fill_pattern(&args);
+    checksum_algorithm(&args);
+    if args.firmware_dump_part.is_some() {
+        firmware_part(&args);
+    }
+    // Reject a bad --upload-compress before uploading anything
+    if let Some(file) = &args.upload {
+        upload_compression(&args, file);
+    }
+    let mut milestones = Vec::new();
+    for s in &args.trace_milestone {
+        match parse_milestone(s) {
+            Some(m) => milestones.push(m),
+            None => Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!("Invalid milestone '{}', expected NAME=ADDR[:LEN]", s),
+                )
+                .exit(),
+        }
+    }
+    let trace_filter = match parse_trace_filter(
+        args.trace_filter_cmd.as_deref(),
+        args.trace_filter_range.as_deref(),
+    ) {
+        Some(filter) => filter,
+        None => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "Invalid trace filter, expected --trace-filter-cmd CMD[,CMD...] and --trace-filter-range START-END",
+            )
+            .exit(),
+    };
+    if let Some(path) = &args.usb_record {
+        rem100::record::set_record_path(Some(path.clone()));
+    }
+    args.terminal |= args.terminal_interactive || args.terminal_send.is_some();
+    let (bus, device, serial) = match selector {
+        Some(DeviceSelector::BusDevice(bus, dev) | DeviceSelector::BlankSerial(bus, dev)) => {
+            (Some(bus), Some(dev), None)
+        }
+        Some(DeviceSelector::Serial(serial)) => (None, None, Some(serial)),
+        Some(DeviceSelector::PortPath(..)) | None => (None, None, None),
+    };
+
+    // Open device
+    let open = || match &selector {
+        Some(port @ DeviceSelector::PortPath(..)) => Em100::open_by_port_path(&port.to_string()),
+        _ if args.any_device => Em100::open_any(),
+        _ => Em100::open(bus, device, serial),
+    };
+    let opened = match args.wait_device {
+        Some(secs) => Em100::wait_for(Duration::from_secs(secs), open),
+        None => open(),
+    };
+    let mut em100 = match opened {
+        Ok(mut em100) => {
+            if selector.is_none() && !args.compat_c {
+                outln!("Using EM100pro {} at {}", em100.identity(), em100.usb);
+            }
+            if let Some(blank @ DeviceSelector::BlankSerial(..)) = &selector {
+                if !em100.has_blank_serial() {
+                    eprintln!(
+                        "Error: Device {} has serial number {}, select it with -x {}",
+                        blank,
+                        em100.serial_string(),
+                        em100.serial_string()
+                    );
+                    exit(1);
+                }
+            }
+            log_event(&format!(
+                "opened EM100Pro {} at {}",
+                em100.identity(),
+                em100.usb
+            ));
+            if let Some(command) = &args.notify_state_changes {
+                *STATE_HOOK.lock().unwrap() = Some(StateHook {
+                    command: command.clone(),
+                    serial: em100.identity(),
+                });
+            }
+            em100.conservative_timing = args.conservative_timing;
+            if let Some(ms) = args.usb_timeout_ms {
+                em100.set_usb_timeout(Duration::from_millis(ms));
+            }
+            em100
+        }
+        Err(Error::PermissionDenied) => {
+            eprintln!("Error: {}\n", Error::PermissionDenied);
+            eprintln!("{}", permission_help());
+            eprintln!("\nOr let rem100 install it: sudo rem100 --install-udev-rule");
+            exit(1);
+        }
+        Err(Error::MultipleDevices(candidates)) => {
+            eprintln!("Error: Several EM100pro devices are connected:");
+            for candidate in candidates {
+                eprintln!("  {}", candidate);
+            }
+            eprintln!("Select one with -x (see --list-devices), or use --any-device.");
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+
+    // Per-device defaults; options given on the command line win
+    if args.save_profile {
+        let profile = cli_profile(&args, em100.identity());
+        match save_profile(profile.clone()) {
+            Ok(path) => {
+                outln!("Saved profile {} to {}", profile, path.display());
+                log_event(&format!("saved profile {}", profile));
+            }
+            Err(e) => {
+                eprintln!("Error: Can't save profile: {}", e);
+                exit(1);
+            }
+        }
+    } else if !args.no_profile {
+        match find_profile(&em100.identity()) {
+            Ok(Some(profile)) => {
+                apply_profile(&mut args, &profile);
+                if let Err((_, msg)) = validate_combinations(&args) {
+                    eprintln!("Error: {} (with profile {})", msg, profile);
+                    exit(1);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: Ignoring {}: {}", PROFILES_FILE, e),
+        }
+    }
+
+    // Load chip database
+    let chip_db = ChipDatabase::load().ok();
+
+    // Setup chips if requested
+    let chip = if let Some(chip_name) = &args.chip {
+        match chip_db.as_ref() {
+            Some(db) => match db.find_chip(chip_name) {
+                Ok(chip) => Some(chip),
+                Err(_) => {
+                    outln!("Supported chips:\n");
+                    print_chips(db, &ChipFilter::default());
+                    outln!(
+                        "\nCould not find a chip matching '{}' to be emulated.",
+                        chip_name
+                    );
+                    exit(1);
+                }
+            },
+            None => {
+                eprintln!("Can't find chip configs. Please run: rem100 --update-files");
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Set up signal handler
+    let exit_requested = Arc::new(AtomicBool::new(false));
+    let exit_clone = exit_requested.clone();
+    let cancel = em100.cancel.clone();
+    ctrlc::set_handler(move || {
+        exit_clone.store(true, Ordering::SeqCst);
+        cancel.cancel();
+    })
+    .ok();
+
+    // Print device info
+    print_device_info(&em100, args.status, args.compat_c);
+    if let Some(db) = &chip_db {
+        outln!("SPI flash database: {}", db.version);
+    }
+
+    // Print current state
+    match em100.get_state() {
+        Ok(running) => outln!(
+            "EM100Pro currently {}",
+            if running { "running" } else { "stopped" }
+        ),
+        Err(_) => outln!("EM100Pro state unknown"),
+    }
+
+    if let Ok(state) = em100.get_hold_pin_state() {
+        outln!("EM100Pro hold pin currently {}", state);
+    }
+    outln!();
+
+    if args.status {
+        exit(0);
+    }
+
+    if args.identify {
+        if let Err(e) = em100.identify() {
+            eprintln!("Identify error: {}", e);
+        }
+    }
+
+    let mut session = ReplSession {
+        last_download: None,
+    };
+    run_operations(
+        &args,
+        &mut em100,
+        chip,
+        color,
+        milestones,
+        trace_filter,
+        &exit_requested,
+    );
+
+    if let (Some(path), Some(commands)) = (&args.script, &script) {
+        if !run_script(
+            &mut em100,
+            chip_db.as_ref(),
+            &mut session,
+            &exit_requested,
+            path,
+            commands,
+        ) {
+            exit(1);
+        }
+    }
+    if args.interactive {
+        interactive(&mut em100, chip_db.as_ref(), &mut session, &exit_requested);
+    }
+
+    // Debug mode runs last so it can't disturb the requested operations
+    if args.debug {
+        if let Err(e) = print_debug_info(&em100) {
+            eprintln!("Debug error: {}", e);
+        }
+    }
+
+    let counters = em100.usb_counters();
+    if !counters.is_clean() {
+        eprintln!(
+            "Warning: USB trouble during this run ({}). \
+             Check the cable and avoid unpowered hubs.",
+            counters
+        );
+        log_event(&format!("usb trouble: {}", counters));
+    } else if args.debug {
+        outln!("USB: {}", counters);
+    }
+}
+
+/// Print MCU/FPGA versions, serial number and USB location
+///
+/// Firmware quirks are shown when there are any, or always with `all_quirks`.
+/// With `compat_c` only the lines of the C em100 tool are printed, in its format.
+fn print_device_info(em100: &Em100, all_quirks: bool, compat_c: bool) {
+    let info = em100.get_info();
+    if JSON_OUTPUT.load(Ordering::Relaxed) {
+        print_json(&serde_json::json!({
+            "mcu_version": info.mcu_version,
+            "fpga_version": info.fpga_version,
+            "hw_version": info.hw_version.to_string(),
+            "serial": info.serial,
+            "fpga_voltage": info.fpga_voltage,
+            "quirks": info.quirks.to_string(),
+            "usb": {
+                "bus": info.usb.bus,
+                "device": info.usb.address,
+                "port": info.usb.port_path(),
+                "speed": info.usb.speed_name(),
+            },
+        }));
+        return;
+    }
+    outln!("MCU version: {}", info.mcu_version);
+    outln!("FPGA version: {}", info.fpga_version);
+    if compat_c {
+        outln!("Hardware version: {}", info.hw_version as u8);
+        outln!("Serial number: {}", info.serial);
+        return;
+    }
+    outln!("Hardware version: {:?}", info.hw_version);
+    outln!("Serial number: {}", info.serial);
+    outln!("USB: {}", info.usb);
+    if all_quirks || !info.quirks.is_empty() {
+        outln!("Firmware quirks: {}", info.quirks);
+    }
+}
+
+/// Print voltages and FPGA registers
+fn print_debug_info(em100: &Em100) -> Result<(), Error> {
+    let info = em100.get_debug_info()?;
+
+    outln!("Voltages:");
+    outln!("  1.2V:        {}mV", info.voltages.v1_2);
+    outln!("  E_VCC:       {}mV", info.voltages.e_vcc);
+    outln!("  REF+:        {}mV", info.voltages.ref_plus);
+    outln!("  REF-:        {}mV", info.voltages.ref_minus);
+    outln!("  Buffer VCC:  {}mV", info.voltages.buffer_vcc);
+    outln!("  Trig VCC:    {}mV", info.voltages.trig_vcc);
+    outln!("  RST VCC:     {}mV", info.voltages.rst_vcc);
+    outln!("  3.3V:        {}mV", info.voltages.v3_3);
+    outln!("  Buffer 3.3V: {}mV", info.voltages.buffer_v3_3);
+    outln!("  5V:          {}mV", info.voltages.v5);
+
+    outln!("\nFPGA registers:");
+    for (i, value) in info.fpga_registers.iter().enumerate() {
+        if i % 8 == 0 {
+            out!("\n  {:04x}: ", i * 2);
+        }
+        out!("{:04x} ", value);
+    }
+    outln!();
+
+    Ok(())
+}
+
+/// Write the udev rule, or explain how to when we lack the privileges
+fn install_udev_rule(group: Option<&str>) {
+    let rule = match group {
+        Some(group) => udev_rule_for_group(group),
+        None => udev_rule(),
+    };
+    match device::install_udev_rule(Path::new(UDEV_RULE_PATH), &rule) {
+        Ok(UdevChange::NeedsRoot(commands)) => {
+            outln!(
+                "Not allowed to write {}. Run this as root, or install the rule by hand:\n",
+                UDEV_RULE_PATH
+            );
+            outln!("{}", commands);
+            exit(1);
+        }
+        Ok(_) => {
+            outln!("Installed {}", UDEV_RULE_PATH);
+            log_event(&format!("installed udev rule {}", UDEV_RULE_PATH));
+        }
+        Err(e) => fail(&format!("Error: Can't write {}: {}", UDEV_RULE_PATH, e)),
+    }
+    reload_udev_rules();
+    outln!("Unplug and replug the EM100Pro to apply it.");
+}
+
+/// Remove the udev rule installed by --install-udev-rule
+fn uninstall_udev_rule() {
+    match device::uninstall_udev_rule(Path::new(UDEV_RULE_PATH)) {
+        Ok(UdevChange::Done) => {
+            outln!("Removed {}", UDEV_RULE_PATH);
+            log_event(&format!("removed udev rule {}", UDEV_RULE_PATH));
+        }
+        Ok(UdevChange::NotInstalled) => {
+            outln!("No udev rule installed at {}", UDEV_RULE_PATH);
+            return;
+        }
+        Ok(UdevChange::NeedsRoot(commands)) => {
+            outln!(
+                "Not allowed to remove {}. Run this as root, or remove it by hand:\n",
+                UDEV_RULE_PATH
+            );
+            outln!("{}", commands);
+            exit(1);
+        }
+        Err(e) => fail(&format!("Error: Can't remove {}: {}", UDEV_RULE_PATH, e)),
+    }
+    reload_udev_rules();
+}
+
+/// Have udev pick up changed rules, if udevadm is installed
+fn reload_udev_rules() {
+    for args in [&["control", "--reload-rules"][..], &["trigger"][..]] {
+        match Command::new("udevadm").args(args).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!("Warning: udevadm {} {}", args.join(" "), status);
+                return;
+            }
+            Err(_) => {
+                outln!("udevadm not found, reload the udev rules by hand.");
+                return;
+            }
+        }
+    }
+}
+
+/// Print the chip database as "vendor name" lines
+fn print_chips(db: &ChipDatabase, filter: &ChipFilter) {
+    for chip in db.search(filter) {
+        outln!("  - {} {}", chip.vendor, chip.name);
+    }
+}
+
+/// Print the header of the DPFW file at `path`, returning whether it is valid
+fn print_firmware_info(path: &Path) -> bool {
+    let fw = match std::fs::read(path) {
+        Ok(fw) => fw,
+        Err(e) => {
+            eprintln!("Error: Can't read {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    let header = match DpfwHeader::parse(&fw) {
+        Ok(header) => header,
+        Err(e) => {
+            eprintln!("Error: {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    outln!("File:         {} (0x{:x} bytes)", path.display(), fw.len());
+    outln!("Target:       {}", header.target);
+    outln!("MCU version:  {}", header.mcu_version);
+    outln!("FPGA version: {}", header.fpga_version);
+    outln!(
+        "MCU section:  0x{:08x}, 0x{:x} bytes",
+        header.mcu_offset,
+        header.mcu_len
+    );
+    outln!(
+        "FPGA section: 0x{:08x}, 0x{:x} bytes",
+        header.fpga_offset,
+        header.fpga_len
+    );
+    match header.check_sections(fw.len()) {
+        Ok(()) => {
+            outln!("Consistent:   yes");
+            true
+        }
+        Err(e) => {
+            outln!("Consistent:   no, {}", e);
+            false
+        }
+    }
+}
+
+/// Bash glue completing -c/--set from the installed chip database
+const BASH_CHIP_COMPLETION: &str = r#"
+_rem100_with_chips() {
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "$prev" == "-c" || "$prev" == "--set" ]]; then
+        COMPREPLY=($(compgen -W "$(rem100 --list-chips --names-only 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}"))
+        return 0
+    fi
+    _rem100 "$@"
+}
+complete -F _rem100_with_chips -o bashdefault -o default rem100
+"#;
+
+/// Zsh completion function for chip names, used in place of `_default`
+const ZSH_CHIP_COMPLETION: &str = r#"
+_rem100_chips() {
+    local -a chips
+    chips=(${(f)"$(rem100 --list-chips --names-only 2>/dev/null)"})
+    compadd -a chips
+}
+"#;
+
+/// Fish rule completing -c/--set from the installed chip database
+const FISH_CHIP_COMPLETION: &str =
+    "complete -c rem100 -s c -l set -x -a '(rem100 --list-chips --names-only 2>/dev/null)'\n";
+
+/// Print a completion script for `shell`
+///
+/// clap_complete only knows the options, so for bash, zsh and fish the
+/// script is extended to complete chip names by running
+/// `rem100 --list-chips --names-only`.
+fn print_completions(shell: Shell) {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Args::command(), "rem100", &mut script);
+    let mut script = String::from_utf8_lossy(&script).into_owned();
+
+    match shell {
+        Shell::Bash => script.push_str(BASH_CHIP_COMPLETION),
+        Shell::Zsh => {
+            // Define the chip function before the script registers itself
+            script = script.replace(":CHIP:_default'", ":CHIP:_rem100_chips'");
+            let at = script
+                .rfind("\nif [ \"$funcstack[1]\"")
+                .unwrap_or(script.len());
+            script.insert_str(at, ZSH_CHIP_COMPLETION);
+        }
+        Shell::Fish => script.push_str(FISH_CHIP_COMPLETION),
+        _ => {}
+    }
+    out!("{}", script);
+}
+
+/// Write a --snapshot of `length` bytes to `file`, described in `file`.json
+fn take_snapshot(
+    em100: &Em100,
+    file: &Path,
+    length: usize,
+    consistent: bool,
+) -> Result<(), String> {
+    let out = File::create(file).map_err(|e| format!("Could not open snapshot file: {}", e))?;
+    let mut out = BufWriter::new(out);
+    let snapshot = with_transfer_bar(length, |progress| {
+        rem100::snapshot::take_snapshot(
+            em100,
+            0,
+            length,
+            rem100::snapshot::DEFAULT_CHUNK_SIZE,
+            consistent,
+            &mut out,
+            progress,
+        )
+    })
+    .map_err(|e| e.to_string())?;
+    out.flush()
+        .map_err(|e| format!("Error writing file: {}", e))?;
+
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(".json");
+    let sidecar = PathBuf::from(sidecar);
+    File::create(&sidecar)
+        .and_then(|mut f| snapshot.write_json(&mut f))
+        .map_err(|e| format!("Error writing {}: {}", sidecar.display(), e))?;
+
+    let span = snapshot.span().as_millis();
+    match snapshot.mode {
+        SnapshotMode::Stopped => outln!(
+            "Snapshot of {} bytes written to {} (emulation was stopped)",
+            length,
+            file.display()
+        ),
+        SnapshotMode::Live => outln!(
+            "Snapshot of {} bytes written to {}, read over {} ms while emulating; \
+             chunks may be from different points in time",
+            length,
+            file.display(),
+            span
+        ),
+        SnapshotMode::Consistent => outln!(
+            "Snapshot of {} bytes written to {} in {} chunks, emulation stopped for up to {} ms each",
+            length,
+            file.display(),
+            snapshot.chunks.len(),
+            snapshot.max_pause().unwrap_or_default().as_millis()
+        ),
+    }
+    log_event(&format!(
+        "took {} snapshot of {} bytes to {}",
+        snapshot.mode,
+        length,
+        file.display()
+    ));
+    Ok(())
+}
+
+/// Write the --boot-report and print a summary of it
+fn write_boot_report(profile: &BootProfile, path: &Path) {
+    if let Err(e) = File::create(path).and_then(|mut f| profile.write_json(&mut f)) {
+        eprintln!("Error: Can't write boot report {}: {}", path.display(), e);
+    }
+
+    outln!(
+        "\nBoot report: {} bytes read ({} unique) from {} regions over {:.3} s",
+        profile.total_bytes(),
+        profile.unique_bytes(),
+        profile.regions().len(),
+        profile.duration()
+    );
+    for (start, region) in profile.hottest(10) {
+        outln!(
+            "  0x{:08x}  {:>6} reads  {:>9} bytes  first at +{:.6} s",
+            start,
+            region.reads,
+            region.bytes,
+            profile.since_first(region.first_access)
+        );
+    }
+}
+
+/// Forward stdin to the returned channel from a background thread
+///
+/// The thread ends at the end of input; while stdin stays open it lives as
+/// long as the process.
+fn read_stdin_in_background() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; trace::DFIFO_SIZE];
+        let mut stdin = std::io::stdin().lock();
+        while let Ok(n @ 1..) = stdin.read(&mut buf) {
+            if tx.send(buf[..n].to_vec()).is_err() {
+                break;
             }
         }
-        return;
+    });
+    rx
+}
+
+/// Terminal settings of stdin, restored when dropped
+///
+/// While enabled, typed bytes are passed on one by one without echo.
+/// CTRL-C still raises SIGINT, so the usual handler ends the session.
+#[cfg(unix)]
+struct RawStdin(libc::termios);
+
+#[cfg(unix)]
+impl RawStdin {
+    /// Switch a terminal on stdin to non-canonical mode without echo
+    fn enable() -> Option<Self> {
+        use std::io::IsTerminal;
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+        // SAFETY: termios is plain data, filled in by tcgetattr
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+            return None;
+        }
+        let mut raw = saved;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+        Some(Self(saved))
     }
+}
 
-    // Handle --update-files
-    if args.update_files {
-        if let Err(e) = update_all_files() {
-            eprintln!("Error updating files: {}", e);
-            std::process::exit(1);
-        }
-        return;
+#[cfg(unix)]
+impl Drop for RawStdin {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
     }
+}
 
-    // Parse device selection
-    let (bus, device, serial) = args
-        .device
-        .as_ref()
-        .map(|d| parse_device(d))
-        .unwrap_or((None, None, None));
+/// Start emulation for --start, exiting if the device doesn't start
+fn start_emulation(em100: &Em100) {
+    if let Err(e) = em100.set_state(true) {
+        eprintln!("Error starting emulation: {}", e);
+        exit(1);
+    } else {
+        outln!("Started EM100Pro");
+        state_changed(StateChange::Started);
+    }
+}
 
-    // Open device
-    let mut em100 = match Em100::open(bus, device, serial) {
-        Ok(em100) => em100,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
+/// Pattern given with --fill; exits if it is invalid
+fn fill_pattern(args: &Args) -> Option<Vec<u8>> {
+    let pattern = args.fill.as_deref()?;
+    match parse_pattern(pattern) {
+        Some(bytes) => Some(bytes),
+        None => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("Invalid --fill pattern '{}', expected hex bytes", pattern),
+            )
+            .exit(),
+    }
+}
+
+/// Fill SDRAM with the --fill pattern; exits on failure
+fn fill_memory(args: &Args, em100: &Em100, chip: &Option<ChipDesc>, pattern: &[u8]) {
+    let memory_size = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
+    let (address, length) = match args.fill_range.as_deref().map(parse_range) {
+        Some(Some((start, len))) => (start as u32, Some(len)),
+        Some(None) => {
+            eprintln!("Error: Can't parse fill range, use START:LEN");
+            exit(1);
         }
+        None => (0, None),
     };
-
-    // Load chip database
-    let chip_db = ChipDatabase::load().ok();
-
-    // Setup chips if requested
-    let chip = if let Some(chip_name) = &args.chip {
-        match chip_db.as_ref() {
-            Some(db) => match db.find_chip(chip_name) {
-                Ok(chip) => Some(chip),
-                Err(_) => {
-                    println!("Supported chips:\n");
-                    for chip in db.list_chips() {
-                        println!("  - {} {}", chip.vendor, chip.name);
-                    }
-                    println!(
-                        "\nCould not find a chip matching '{}' to be emulated.",
-                        chip_name
-                    );
-                    std::process::exit(1);
-                }
-            },
-            None => {
-                eprintln!("Can't find chip configs. Please run: rem100 --update-files");
-                std::process::exit(1);
-            }
+    let length = match sdram_region_len(address, length, memory_size) {
+        Ok(length) => length,
+        Err(e) => {
+            eprintln!("Fill error: {}", e);
+            exit(1);
         }
-    } else {
-        None
     };
 
-    // Set up signal handler
-    let exit_requested = Arc::new(AtomicBool::new(false));
-    let exit_clone = exit_requested.clone();
-    ctrlc::set_handler(move || {
-        exit_clone.store(true, Ordering::SeqCst);
-    })
-    .ok();
-
-    // Print device info
-    em100.print_info();
-    if let Some(db) = &chip_db {
-        println!("SPI flash database: {}", db.version);
+    let started = std::time::Instant::now();
+    let filled = with_transfer_bar(length, |progress| {
+        rem100::sdram::fill_sdram_pattern(em100, address, length, pattern, progress)
+    });
+    if let Err(e) = filled {
+        check_cancelled(em100, "Fill", false);
+        eprintln!("Fill error: {}", e);
+        exit(1);
     }
+    let elapsed = started.elapsed().as_secs_f64();
+    outln!(
+        "Filled {} bytes at 0x{:08x} with 0x{} in {:.1}s ({:.1} MB/s)",
+        length,
+        address,
+        format_pattern(pattern),
+        elapsed,
+        length as f64 / (1024.0 * 1024.0) / elapsed.max(f64::EPSILON)
+    );
+    log_event(&format!(
+        "filled {} bytes at 0x{:08x} with 0x{}",
+        length,
+        address,
+        format_pattern(pattern)
+    ));
+}
 
-    // Print current state
-    match em100.get_state() {
-        Ok(running) => println!(
-            "EM100Pro currently {}",
-            if running { "running" } else { "stopped" }
-        ),
-        Err(_) => println!("EM100Pro state unknown"),
-    }
+fn format_pattern(pattern: &[u8]) -> String {
+    pattern.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    match em100.get_hold_pin_state() {
-        Ok(state) => println!("EM100Pro hold pin currently {}", state),
-        Err(_) => {}
+/// Algorithm given with --checksum; exits if it is invalid
+fn checksum_algorithm(args: &Args) -> Option<DigestAlgorithm> {
+    match args.checksum.as_deref()?.parse() {
+        Ok(algorithm) => Some(algorithm),
+        Err(e) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
     }
-    println!();
+}
 
-    // Debug mode
-    if args.debug {
-        if let Err(e) = em100.debug() {
-            eprintln!("Debug error: {}", e);
+/// Print the --checksum line: algorithm, digest, address, length and chip
+fn print_checksum(args: &Args, em100: &Em100, chip: &Option<ChipDesc>, algorithm: DigestAlgorithm) {
+    let memory_size = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
+    let (address, length) = match args.checksum_range.as_deref().map(parse_range) {
+        Some(Some((start, len))) => (start as u32, Some(len)),
+        Some(None) => {
+            eprintln!("Error: Can't parse checksum range, use START:LEN");
+            exit(1);
+        }
+        None => (0, None),
+    };
+    let length = match sdram_region_len(address, length, memory_size) {
+        Ok(length) => length,
+        Err(e) => {
+            eprintln!("Checksum error: {}", e);
+            exit(1);
+        }
+    };
+
+    let digest = match with_transfer_bar(length, |progress| {
+        em100.content_digest(algorithm, address, length, progress)
+    }) {
+        Ok(digest) => digest,
+        Err(e) => {
+            check_cancelled(em100, "Checksum", false);
+            eprintln!("Checksum error: {}", e);
+            exit(1);
         }
+    };
+    outln!(
+        "{} {} 0x{:08x} {} {}",
+        digest.algorithm,
+        digest.hex(),
+        digest.address,
+        digest.length,
+        chip.as_ref().map(|c| c.name.as_str()).unwrap_or("-")
+    );
+    log_event(&format!(
+        "{} of {} bytes at 0x{:08x}: {}",
+        digest.algorithm,
+        digest.length,
+        digest.address,
+        digest.hex()
+    ));
+}
+
+/// Part of the firmware -f and -g dump, from --firmware-dump-part
+fn firmware_part(args: &Args) -> FirmwarePart {
+    match args
+        .firmware_dump_part
+        .as_deref()
+        .map(str::parse::<FirmwarePart>)
+    {
+        Some(Ok(part)) => part,
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => FirmwarePart::default(),
+    }
+}
+
+/// Output format of -t, from --trace-format
+fn trace_format(args: &Args) -> TraceFormat {
+    match args.trace_format.as_deref().map(str::parse::<TraceFormat>) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        None => TraceFormat::default(),
     }
+}
 
+/// Carry out the requested operations on an opened device
+fn run_operations(
+    args: &Args,
+    em100: &mut Em100,
+    chip: Option<ChipDesc>,
+    color: ColorMode,
+    milestones: Vec<Milestone>,
+    trace_filter: TraceFilter,
+    exit_requested: &AtomicBool,
+) {
     // Firmware update
     if let Some(firmware_in) = &args.firmware_update {
-        if let Err(e) = firmware_update(&em100, firmware_in, args.verify) {
-            eprintln!("Firmware update error: {}", e);
-            std::process::exit(1);
+        match firmware_update(em100, firmware_in, args.verify, args.force) {
+            Ok(true) => log_event(&format!("firmware updated from {}", firmware_in)),
+            Ok(false) => {}
+            Err(Error::Cancelled) => {
+                eprintln!(
+                    "Firmware update cancelled. The firmware is incomplete, run the update again \
+                     before disconnecting the EM100pro."
+                );
+                log_event("firmware update cancelled");
+                exit(EXIT_CANCELLED);
+            }
+            Err(e) => {
+                eprintln!("Firmware update error: {}", e);
+                exit(1);
+            }
         }
         return;
     }
 
     // Firmware dump
     if let Some(firmware_out) = &args.firmware_dump {
-        if let Err(e) = firmware_dump(&em100, firmware_out, false) {
-            eprintln!("Firmware dump error: {}", e);
-            std::process::exit(1);
+        let range = args.firmware_dump_range.as_ref().map(|s| {
+            parse_range(s).unwrap_or_else(|| {
+                Args::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!("Invalid range '{}', expected START:LEN", s),
+                    )
+                    .exit()
+            })
+        });
+        if firmware_part(args) != FirmwarePart::All {
+            if let Err(e) = firmware_dump(em100, firmware_out, false, firmware_part(args)) {
+                check_cancelled(em100, "Firmware dump", false);
+                eprintln!("Firmware dump error: {}", e);
+                exit(1);
+            }
+            log_event(&format!(
+                "firmware {} part dumped to {}",
+                firmware_part(args),
+                firmware_out
+            ));
+            return;
+        }
+        match firmware_dump_range(
+            em100,
+            firmware_out,
+            range,
+            args.resume,
+            args.ignore_read_errors,
+        ) {
+            Ok(report) if !report.bad_pages.is_empty() => {
+                eprintln!(
+                    "Warning: {} of {} pages could not be read and are filled with {}:",
+                    report.bad_pages.len(),
+                    report.pages_read + report.bad_pages.len(),
+                    BAD_PAGE_PATTERN
+                        .iter()
+                        .map(|b| format!("{:02X}", b))
+                        .collect::<String>()
+                );
+                for page in &report.bad_pages {
+                    eprintln!("  0x{:08x}", page);
+                }
+                eprintln!(
+                    "They are listed in {}; --resume retries them.",
+                    bad_pages_path(firmware_out)
+                );
+                log_event(&format!(
+                    "firmware dumped to {} with {} unreadable pages",
+                    firmware_out,
+                    report.bad_pages.len()
+                ));
+                return;
+            }
+            Ok(_) => {}
+            Err(Error::PartialDump { bad_pages }) => {
+                eprintln!("Firmware dump incomplete, unreadable pages:");
+                for page in &bad_pages {
+                    eprintln!("  0x{:08x}", page);
+                }
+                eprintln!(
+                    "Run again with --resume to retry them, or with --ignore-read-errors to accept the dump."
+                );
+                log_event(&format!(
+                    "firmware dump to {} missing {} pages",
+                    firmware_out,
+                    bad_pages.len()
+                ));
+                exit(1);
+            }
+            Err(e) => {
+                check_cancelled(em100, "Firmware dump", false);
+                eprintln!("Firmware dump error: {}", e);
+                exit(1);
+            }
         }
+        log_event(&format!("firmware dumped to {}", firmware_out));
         return;
     }
 
     // Firmware write (DPFW format)
     if let Some(firmware_out) = &args.firmware_write {
-        if let Err(e) = firmware_dump(&em100, firmware_out, true) {
+        if let Err(e) = firmware_dump(em100, firmware_out, true, firmware_part(args)) {
+            check_cancelled(em100, "Firmware write", false);
             eprintln!("Firmware write error: {}", e);
-            std::process::exit(1);
+            exit(1);
         }
+        log_event(&format!("firmware written to {}", firmware_out));
         return;
     }
 
     // Set serial number
     if let Some(serialno) = &args.set_serialno {
-        let mut s = serialno.as_str();
-        if s.to_uppercase().starts_with("DP") || s.to_uppercase().starts_with("EM") {
-            s = &s[2..];
-        }
-        match s.parse::<u32>() {
-            Ok(serial) => {
-                if let Err(e) = em100.set_serial_no(serial) {
+        match parse_serialno(serialno) {
+            Some(serial) => {
+                if let Err(e) = em100.set_serial_no(serial, args.allow_protected_region) {
                     eprintln!("Error setting serial number: {}", e);
-                    std::process::exit(1);
+                    if matches!(e, Error::InvalidArgument(_)) && !args.allow_protected_region {
+                        eprintln!(
+                            "The bootloader magic page could not be read; \
+                             add --allow-protected-region to replace the serial number anyway."
+                        );
+                    }
+                    exit(1);
                 }
+                log_event(&format!("serial number set to {}", serial));
             }
-            Err(_) => {
+            None => {
                 eprintln!("Error: Can't parse serial number '{}'", serialno);
-                std::process::exit(1);
+                exit(1);
             }
         }
         return;
     }
 
-    // Stop emulation
-    if args.stop {
+    // Tracing a freshly downloaded image starts emulation only once the trace
+    // is armed: stop, set chip, download, reset and arm the trace, start
+    let tracing = args.trace || args.terminal || args.traceconsole;
+    let trace_from_start = tracing && args.download.is_some();
+
+    // Stop emulation; a fill rewrites what the host may be reading
+    if args.stop || trace_from_start || args.fill.is_some() {
         if let Err(e) = em100.set_state(false) {
             eprintln!("Error stopping emulation: {}", e);
         } else {
-            println!("Stopped EM100Pro");
+            outln!("Stopped EM100Pro");
+            state_changed(StateChange::Stopped);
         }
     }
 
-    // Set chip type
+    // Set chip type (and address mode along with it)
     if let Some(chip) = &chip {
-        println!("Configuring SPI flash chip emulation.");
-        if let Err(e) = em100.set_chip_type(chip) {
-            eprintln!("Failed configuring chip type: {}", e);
-            std::process::exit(1);
-        }
-        println!("Chip set to {} {}.", chip.vendor, chip.name);
-
-        // Auto-enable 4-byte mode for large chips
-        if args.address_mode.is_none() && chip.size > 16 * 1024 * 1024 {
-            if let Err(e) = em100.set_address_mode(4) {
-                eprintln!("Warning: {}", e);
-            } else {
-                println!("Enabled 4 byte address mode");
+        let policy = args
+            .address_mode
+            .map(AddressModePolicy::Force)
+            .unwrap_or_default();
+
+        outln!("Configuring SPI flash chip emulation.");
+        let readback = match em100.set_chip_type(chip, policy) {
+            Ok(readback) => readback,
+            Err(e) => {
+                eprintln!("Failed configuring chip type: {}", e);
+                exit(1);
             }
+        };
+        outln!("Chip set to {} {}.", chip.vendor, chip.name);
+        if args.debug || readback.retried {
+            outln!("FPGA registers: {}", readback);
         }
-    }
+        log_event(&format!("chip set to {} {}", chip.vendor, chip.name));
 
-    // Set address mode
-    if let Some(mode) = args.address_mode {
+        let mode = policy.resolve(chip);
+        if mode == 4 || args.address_mode.is_some() {
+            outln!("Enabled {} byte address mode", mode);
+        }
+        log_event(&format!("address mode set to {} bytes", mode));
+    } else if let Some(mode) = args.address_mode {
+        // Set address mode
         if let Err(e) = em100.set_address_mode(mode) {
             eprintln!("Error: {}", e);
-            std::process::exit(1);
+            exit(1);
         }
-        println!("Enabled {} byte address mode", mode);
+        outln!("Enabled {} byte address mode", mode);
+        log_event(&format!("address mode set to {} bytes", mode));
     }
 
     // Set voltage (obsolete)
@@ -362,19 +2658,20 @@ fn main() {
             "1.8" => 18,
             _ => {
                 eprintln!("Invalid voltage, use 1.8 or 3.3.");
-                std::process::exit(1);
+                exit(1);
             }
         };
 
-        println!("Setting the voltage on the command line is known to cause problems.");
-        println!("Please report to the coreboot mailing list why this is necessary.");
+        outln!("Setting the voltage on the command line is known to cause problems.");
+        outln!("Please report to the coreboot mailing list why this is necessary.");
 
-        if args.debug {
-            println!("Setting anyways on your own risk (debug mode enabled)");
+        if args.force {
+            outln!("Setting anyways on your own risk (--force given)");
             if em100.set_fpga_voltage(voltage_code).is_err() {
                 eprintln!("Failed configuring FPGA voltage.");
-                std::process::exit(1);
+                exit(1);
             }
+            log_event(&format!("FPGA voltage set to {}", voltage));
         }
     }
 
@@ -384,235 +2681,661 @@ fn main() {
             Ok(state) => {
                 if let Err(e) = em100.set_hold_pin_state(state) {
                     eprintln!("Failed configuring hold pin state: {}", e);
-                    std::process::exit(1);
+                    exit(1);
                 }
-                println!("Hold pin state set to {}", state);
+                outln!("Hold pin state set to {}", state);
+                state_changed(StateChange::HoldPin(state));
             }
             Err(e) => {
                 eprintln!("{}", e);
-                std::process::exit(1);
+                exit(1);
             }
         }
     }
 
-    // Upload from device
-    if let Some(upload_file) = &args.upload {
-        let maxlen = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
+    // Fill SDRAM with a pattern
+    if let Some(pattern) = fill_pattern(args) {
+        fill_memory(args, em100, &chip, &pattern);
+    }
 
-        match em100.upload(0, maxlen) {
-            Ok(data) => {
-                let mut file = match File::create(upload_file) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        eprintln!("Could not open download file: {}", e);
-                        std::process::exit(1);
-                    }
-                };
-                if let Err(e) = file.write_all(&data) {
-                    eprintln!("Error writing file: {}", e);
-                    std::process::exit(1);
+    // Back up and/or flash the SDRAM contents
+    transfer_images(args, em100, &chip);
+
+    // Simulate a chip erase
+    if args.simulate_erase {
+        let Some(chip) = &chip else {
+            eprintln!("Error: --simulate-erase needs a chip, use --set CHIP.");
+            exit(1);
+        };
+
+        if !args.force && em100.get_state().unwrap_or(true) {
+            eprintln!(
+                "Error: Refusing to erase SDRAM while emulation is running. Use --stop or --force."
+            );
+            exit(1);
+        }
+
+        match em100.simulate_erase(chip) {
+            Ok(status_modeled) => {
+                outln!("Simulated chip erase of {} {}.", chip.vendor, chip.name);
+                if !status_modeled {
+                    outln!(
+                        "No status register reset values for this chip, only memory was erased."
+                    );
                 }
+                log_event(&format!(
+                    "simulated chip erase of {} {}",
+                    chip.vendor, chip.name
+                ));
             }
             Err(e) => {
-                eprintln!("Upload error: {}", e);
-                std::process::exit(1);
+                eprintln!("Erase error: {}", e);
+                exit(1);
             }
         }
     }
 
-    // Download to device
-    if let Some(download_file) = &args.download {
-        let spi_start_address = args
-            .start_address
-            .as_ref()
-            .and_then(|s| parse_hex(s))
-            .unwrap_or(0) as u32;
-
-        if spi_start_address != 0 {
-            println!("SPI address: 0x{:08x}", spi_start_address);
-        }
-
-        let maxlen = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
-
-        let mut file = match File::open(download_file) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Can't open file '{}': {}", download_file, e);
-                std::process::exit(1);
+    // Patch SDRAM
+    if let Some(poke) = &args.poke {
+        let (address, bytes) = match parse_poke(poke) {
+            Some(p) => p,
+            None => {
+                eprintln!(
+                    "Error: Can't parse poke '{}', use ADDR=BYTE[,BYTE...]",
+                    poke
+                );
+                exit(1);
             }
         };
 
-        let mut data = Vec::new();
-        if let Err(e) = file.read_to_end(&mut data) {
-            eprintln!("Error reading file: {}", e);
-            std::process::exit(1);
-        }
-
-        if data.is_empty() {
-            eprintln!("FATAL: No file to upload.");
-            std::process::exit(1);
+        let memory_size = chip.as_ref().map_or(MAX_MEMORY_SIZE, |c| c.size as usize);
+        if let Err(e) = sdram_region_len(address, Some(bytes.len()), memory_size) {
+            eprintln!("Poke error: {}", e);
+            exit(1);
         }
 
-        if data.len() > maxlen {
-            eprintln!("FATAL: file size exceeds maximum");
-            std::process::exit(1);
+        if !args.force && em100.get_state().unwrap_or(true) {
+            eprintln!(
+                "Error: Refusing to patch SDRAM while emulation is running. Use --stop or --force."
+            );
+            exit(1);
         }
 
-        // When a chip is specified, validate that file size matches expected size
-        if chip.is_some() {
-            let expected_size = maxlen - spi_start_address as usize;
-            if data.len() != expected_size {
-                eprintln!(
-                    "FATAL: file size ({}) does not match chip size minus start address ({}).",
-                    data.len(),
-                    expected_size
+        match rem100::sdram::patch_bytes(em100, address, &bytes) {
+            Ok((old, new)) => {
+                let line = format!(
+                    "0x{:08x}: {} -> {}",
+                    address,
+                    format_bytes(&old),
+                    format_bytes(&new)
                 );
-                std::process::exit(1);
+                outln!("{}", line);
+                log_event(&format!("patched {}", line));
+            }
+            Err(e) => {
+                eprintln!("Poke error: {}", e);
+                exit(1);
             }
         }
+    }
 
-        // Apply image auto-correction if requested
-        if args.compatible {
-            autocorrect_image(&em100, &mut data).ok();
-        }
+    // Compare SDRAM with a file
+    if let Some(file) = &args.verify_only {
+        verify_only(args, em100, file);
+    }
 
-        // Handle start address
-        if spi_start_address != 0 {
-            // Read existing data and merge
-            match em100.upload(0, maxlen) {
-                Ok(mut existing) => {
-                    let start = spi_start_address as usize;
-                    let end = start + data.len();
-                    if end <= existing.len() {
-                        existing[start..end].copy_from_slice(&data);
-                        if let Err(e) = em100.download(&existing, 0) {
-                            eprintln!("Download error: {}", e);
-                            std::process::exit(1);
-                        }
-                    }
-                }
-                Err(e) => {
-                    eprintln!("SDRAM readback failed: {}", e);
-                    std::process::exit(1);
-                }
+    // Digest of SDRAM
+    if let Some(algorithm) = checksum_algorithm(args) {
+        print_checksum(args, em100, &chip, algorithm);
+    }
+
+    // Read back SDRAM
+    if let Some(peek) = &args.peek {
+        let (address, length) = match parse_peek(peek) {
+            Some(p) => p,
+            None => {
+                eprintln!("Error: Can't parse peek '{}', use ADDR[:LEN]", peek);
+                exit(1);
             }
-        } else if let Err(e) = em100.download(&data, 0) {
-            eprintln!("Download error: {}", e);
-            std::process::exit(1);
+        };
+        let memory_size = chip.as_ref().map_or(MAX_MEMORY_SIZE, |c| c.size as usize);
+        if let Err(e) = sdram_region_len(address, Some(length), memory_size) {
+            eprintln!("Peek error: {}", e);
+            exit(1);
         }
 
-        // Verify
-        if args.verify {
-            match em100.upload(spi_start_address, data.len()) {
-                Ok(readback) => {
-                    if readback == data {
-                        println!("Verify: PASS");
-                    } else {
-                        println!("Verify: FAIL");
-                        std::process::exit(1);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Verification error: {}", e);
-                    std::process::exit(1);
+        match rem100::sdram::read_sdram_with_progress(em100, address, length, None) {
+            Ok(data) => {
+                for (i, line) in data.chunks(16).enumerate() {
+                    outln!(
+                        "0x{:08x}: {}",
+                        address as usize + i * 16,
+                        format_bytes(line)
+                    );
                 }
             }
+            Err(e) => {
+                eprintln!("Peek error: {}", e);
+                exit(1);
+            }
         }
     }
 
-    // Start emulation
-    if args.start {
-        if let Err(e) = em100.set_state(true) {
-            eprintln!("Error starting emulation: {}", e);
-        } else {
-            println!("Started EM100Pro");
+    // Read a snapshot of the live SDRAM
+    if let Some(file) = &args.snapshot {
+        let length = chip.as_ref().map(|c| c.size as usize).unwrap_or(0x4000000);
+        if let Err(e) = take_snapshot(em100, file, length, args.snapshot_consistent) {
+            check_cancelled(em100, "Snapshot", false);
+            eprintln!("Snapshot error: {}", e);
+            exit(1);
+        }
+    }
+
+    // Start emulation; when tracing, only after the trace is armed
+    if args.start && !tracing {
+        start_emulation(em100);
+    }
+
+    // Serve SDRAM over FUSE until unmounted
+    #[cfg(feature = "fuse")]
+    if let Some(dir) = &args.mount {
+        let size = chip.as_ref().map(|c| c.size as u64).unwrap_or(0x4000000);
+        outln!(
+            "Serving {} bytes as {}. Unmount with 'fusermount -u {}' to continue.",
+            size,
+            dir.join(rem100::fuse::FILE_NAME).display(),
+            dir.display()
+        );
+        if let Err(e) = rem100::fuse::mount(em100, size, dir) {
+            eprintln!("Mount error: {}", e);
+            exit(1);
         }
     }
 
     // Trace/terminal mode
-    if args.trace || args.terminal || args.traceconsole {
+    if tracing {
         const MAX_USB_ERRORS: u32 = 10;
+        // Errors in a row after which the device is taken to have dropped off
+        const RECONNECT_AFTER: u32 = 3;
+
+        // Keep --list-devices in other terminals from probing this device
+        let _lock = match DeviceLock::acquire(&em100.usb, "tracing") {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                None
+            }
+        };
 
         // Set hold pin to input if not explicitly set
         if args.holdpin.is_none() {
             if let Err(e) = em100.set_hold_pin_state(HoldPinState::Input) {
                 eprintln!("Error: Failed to set EM100 to input: {}", e);
-                std::process::exit(1);
+                exit(1);
             }
+            state_changed(StateChange::HoldPin(HoldPinState::Input));
         }
 
-        // Start emulation if not explicitly started or stopped
-        if !args.start && !args.stop {
-            em100.set_state(true).ok();
+        // Arm the trace before starting emulation, so the host's first
+        // transaction is captured
+        if args.trace || args.traceconsole {
+            trace::reset_spi_trace(em100).ok();
+        }
+        if args.terminal {
+            trace::init_spi_terminal(em100).ok();
         }
 
-        print!("Starting ");
+        // Start emulation if asked to, or if not explicitly stopped
+        if args.start {
+            start_emulation(em100);
+        } else if !args.stop && em100.set_state(true).is_ok() {
+            state_changed(StateChange::Started);
+        }
 
+        out!("Starting ");
         if args.trace || args.traceconsole {
-            trace::reset_spi_trace(&em100).ok();
-            print!("trace{}", if args.terminal { " & " } else { "" });
+            out!("trace{}", if args.terminal { " & " } else { "" });
         }
-
         if args.terminal {
-            trace::init_spi_terminal(&em100).ok();
-            print!("terminal");
+            out!("terminal");
         }
-
-        println!(". Press CTRL-C to exit.\n");
+        outln!(". Press CTRL-C to exit.\n");
         std::io::stdout().flush().ok();
 
-        let address_offset = args.offset.as_ref().and_then(|s| parse_hex(s)).unwrap_or(0);
+        let address_offset = args
+            .offset
+            .as_ref()
+            .and_then(|s| parse_hex_val(s))
+            .unwrap_or(0);
 
         if address_offset != 0 {
-            println!("Address offset: 0x{:08x}", address_offset);
+            outln!("Address offset: 0x{:08x}", address_offset);
+        }
+
+        let address_length = args
+            .length
+            .as_ref()
+            .and_then(|s| parse_hex_val(s))
+            .unwrap_or(0);
+
+        // Decode in the mode the emulation is actually in: a chip over 16MB
+        // was switched to 4-byte mode by --set even without -m
+        let address_mode = args.address_mode.unwrap_or_else(|| {
+            em100.get_address_mode().unwrap_or_else(|_| {
+                em100
+                    .chip
+                    .as_ref()
+                    .map_or(3, |c| AddressModePolicy::default().resolve(c))
+            })
+        });
+        if args.trace || args.traceconsole {
+            outln!("Decoding trace in {} byte address mode", address_mode);
         }
 
-        let address_length = args.length.as_ref().and_then(|s| parse_hex(s)).unwrap_or(0);
+        let mut trace_state = TraceState::new(args.brief, address_mode)
+            .with_data_max(args.trace_data_max)
+            .with_coalescing(!args.no_coalesce)
+            .with_color(color.enabled())
+            .with_milestones(milestones)
+            .with_filter(trace_filter)
+            .with_format(trace_format(args), args.trace_data)
+            .with_hw_version(em100.hw_version)
+            .with_quirks(em100.quirks)
+            .with_status_decoding(!args.no_decode_status);
+        if let Some(commands) = args.console_write_cmds.as_deref().and_then(parse_commands) {
+            trace_state = trace_state.with_console_write_commands(commands);
+        }
+        if args.boot_report.is_some() {
+            trace_state = trace_state.with_boot_profile();
+        }
+        if let Some(trigger) = trace_trigger(args).filter(TraceTrigger::is_set) {
+            trace_state = trace_state.with_trigger(trigger);
+        }
+        if let Some(path) = &args.terminal_lut {
+            match trace::terminal::load_lookup_table(path) {
+                Ok(table) => trace_state = trace_state.with_terminal_lookup_table(table),
+                Err(e) => {
+                    eprintln!("Error: Can't load lookup table {}: {}", path.display(), e);
+                    exit(1);
+                }
+            }
+        }
+        if !args.no_trace_dump {
+            trace_state = trace_state.with_post_mortem_dir(get_em100_file("traces").ok());
+        }
+        if let Some(path) = &args.trace_file {
+            // A VCD file is no use on the terminal
+            let sink = if trace_format(args) == TraceFormat::Vcd {
+                TraceSink::file(path, false)
+            } else {
+                TraceSink::terminal().tee(path, args.trace_append)
+            };
+            match sink {
+                Ok(sink) => trace_state = trace_state.with_sink(sink),
+                Err(e) => {
+                    eprintln!("Error: Can't open trace file {}: {}", path.display(), e);
+                    exit(1);
+                }
+            }
+        }
 
-        let mut trace_state = TraceState::new(args.brief, args.address_mode.unwrap_or(3));
+        // Bytes still to be sent to the host through the dFIFO
+        let mut to_host = Vec::new();
+        if let Some(path) = &args.terminal_send {
+            match std::fs::read(path) {
+                Ok(data) => to_host = data,
+                Err(e) => {
+                    eprintln!("Error: Can't read {}: {}", path.display(), e);
+                    exit(1);
+                }
+            }
+        }
+        let typed = args.terminal_interactive.then(read_stdin_in_background);
+        #[cfg(unix)]
+        let _raw_stdin = typed.as_ref().and_then(|_| RawStdin::enable());
+
+        // What to put back if the device has to be reopened
+        let restore = em100.device_state().unwrap_or_else(|_| DeviceState {
+            chip: em100.chip.clone(),
+            ..Default::default()
+        });
         let mut usb_errors = 0u32;
+        let mut consecutive_errors = 0u32;
+        let mut mode_sync = if args.sync_address_mode {
+            AddressModeSync::start(em100)
+        } else {
+            None
+        };
 
         while !exit_requested.load(Ordering::SeqCst) && usb_errors < MAX_USB_ERRORS {
             let ret = if args.traceconsole {
                 trace::read_spi_trace_console(
-                    &em100,
+                    em100,
                     &mut trace_state,
                     address_offset,
                     address_length,
                 )
             } else if args.trace {
-                trace::read_spi_trace(&em100, &mut trace_state, args.terminal, address_offset)
+                trace::read_spi_trace(em100, &mut trace_state, args.terminal, address_offset)
+            } else if args.terminal_interactive || args.terminal_send.is_some() {
+                if let Some(typed) = &typed {
+                    to_host.extend(typed.try_iter().flatten());
+                }
+                trace::exchange_spi_terminal(em100, &mut trace_state, &mut to_host)
             } else if args.terminal {
-                trace::read_spi_terminal(&em100, false)
+                trace::read_spi_terminal(em100, &mut trace_state)
             } else {
                 Ok(true)
             };
 
             match ret {
-                Ok(false) => usb_errors += 1,
-                Err(_) => break,
-                _ => {}
+                Ok(false) => {
+                    usb_errors += 1;
+                    consecutive_errors += 1;
+                }
+                Err(Error::Io(e)) => {
+                    eprintln!("Error: Can't write trace file: {}", e);
+                    break;
+                }
+                Err(e) => {
+                    // Realign the command stream rather than reading stale responses
+                    usb_errors += 1;
+                    consecutive_errors += 1;
+                    match usb::resync(em100) {
+                        Ok(()) => trace_state.resync(),
+                        Err(resync_err) if args.no_reconnect => {
+                            eprintln!("Error: {} (resync failed: {})", e, resync_err);
+                            break;
+                        }
+                        // The device is gone, don't wait for more errors
+                        Err(_) => consecutive_errors = RECONNECT_AFTER,
+                    }
+                }
+                Ok(true) => consecutive_errors = 0,
+            }
+
+            if consecutive_errors >= RECONNECT_AFTER && !args.no_reconnect {
+                if !reconnect_trace(args, em100, &restore, &mut trace_state) {
+                    break;
+                }
+                usb_errors = 0;
+                consecutive_errors = 0;
+            }
+
+            if let Some(sync) = mode_sync.as_mut() {
+                sync.update(em100, trace_state.address_mode());
+            }
+
+            // Limits end the trace like CTRL-C, checked after each read
+            let timed_out = args.trace_seconds.is_some_and(|secs| {
+                trace_state
+                    .first_data()
+                    .is_some_and(|start| start.elapsed() >= Duration::from_secs(secs))
+            });
+            if timed_out
+                || args
+                    .trace_count
+                    .is_some_and(|n| trace_state.commands() >= n)
+            {
+                break;
             }
         }
 
+        if let Some(sync) = mode_sync {
+            sync.finish(em100);
+        }
+
         if usb_errors >= MAX_USB_ERRORS {
             eprintln!("Error: Bailed out with too many USB errors.");
         }
 
+        if trace_state.terminal_overflows() > 0 {
+            eprintln!(
+                "Warning: the host's messages overflowed the uFIFO {} times, some were lost.",
+                trace_state.terminal_overflows()
+            );
+        }
+
+        if trace_state.buffer_errors() > 0 {
+            eprintln!(
+                "Warning: dropped {} trace buffers with a corrupt header.",
+                trace_state.buffer_errors()
+            );
+        }
+
+        let finished = trace_state.finish();
+        let finished = if args.json {
+            print_milestones_json(&trace_state);
+            finished
+        } else if args.trace && trace_format(args) == TraceFormat::Text {
+            finished.and_then(|_| trace_state.print_milestones())
+        } else {
+            finished
+        };
+        if let Err(e) = finished {
+            eprintln!("Error: Can't write trace file: {}", e);
+        }
+
+        if let (Some(path), Some(profile)) = (&args.boot_report, trace_state.boot_profile()) {
+            write_boot_report(profile, path);
+        }
+
         // Stop emulation if not explicitly started or stopped
-        if !args.start && !args.stop {
-            em100.set_state(false).ok();
+        if !args.start && !args.stop && em100.set_state(false).is_ok() {
+            state_changed(StateChange::Stopped);
         }
 
         if args.trace {
-            trace::reset_spi_trace(&em100).ok();
+            trace::reset_spi_trace(em100).ok();
         }
 
         // Reset hold pin to float
         if args.holdpin.is_none() {
             if let Err(e) = em100.set_hold_pin_state(HoldPinState::Float) {
                 eprintln!("Error: Failed to set EM100 to float: {}", e);
+            } else {
+                state_changed(StateChange::HoldPin(HoldPinState::Float));
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rem100::mock::{MockTransport, Step};
+    use rem100::protocol;
+
+    pub(crate) fn args(argv: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("rem100").chain(argv.iter().copied())).unwrap()
+    }
+
+    /// A 4KB chip, small enough to script its download
+    fn small_chip() -> ChipDesc {
+        let mut chip = ChipDesc {
+            vendor: "Test".to_string(),
+            name: "4K".to_string(),
+            size: 0x1000,
+            ..Default::default()
+        };
+        chip.init[0] = [0x11, 0x04, 0x0c, 0xe4];
+        chip.init_len = 1;
+        chip
+    }
+
+    #[test]
+    fn download_and_trace_arm_the_trace_before_starting() {
+        let chip = small_chip();
+        let image: Vec<u8> = (0..chip.size).map(|i| i as u8).collect();
+        let path = std::env::temp_dir().join(format!("rem100-{}-trace.bin", std::process::id()));
+        std::fs::write(&path, &image).unwrap();
+
+        let state = protocol::FPGA_REG_STATE;
+        let hold = protocol::FPGA_REG_HOLD_PIN;
+        let float = HoldPinState::Float as u16;
+        let input = HoldPinState::Input as u16;
+        let mock = MockTransport::stock()
+            // Stop, set the chip and download the image
+            .write_register(state, 0)
+            .read_register(state, 0)
+            .chip_setup(&chip, 3)
+            .command(protocol::write_sdram_cmd(0, image.len()))
+            .step(Step::BulkWrite(image.clone()))
+            // Hold pin to input, reset the trace, and only then start
+            .read_register(hold, float)
+            .write_register(hold, 4 | float)
+            .read_register(hold, float)
+            .write_register(hold, input)
+            .read_register(hold, input)
+            .command(protocol::command(0xbd, &[]))
+            .write_register(state, 1)
+            .read_register(state, 1)
+            // State to restore on reconnect
+            .read_register(protocol::FPGA_REG_ADDRESS_MODE, 0)
+            .read_register(hold, input)
+            .read_register(state, 1)
+            // Tracing ends right away: stop, clear the trace, float the hold pin
+            .write_register(state, 0)
+            .read_register(state, 0)
+            .command(protocol::command(0xbd, &[]))
+            .read_register(hold, input)
+            .write_register(hold, 4 | input)
+            .read_register(hold, input)
+            .write_register(hold, float)
+            .read_register(hold, float);
+
+        let file = path.to_str().unwrap();
+        let args = args(&["-d", file, "-t", "-m", "3", "--no-trace-dump"]);
+        let mut em100 = mock.open().unwrap();
+        run_operations(
+            &args,
+            &mut em100,
+            Some(chip),
+            ColorMode::Never,
+            Vec::new(),
+            TraceFilter::default(),
+            &AtomicBool::new(true),
+        );
+        std::fs::remove_file(&path).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn c_tool_invocations_parse_alike() {
+        // Command lines of scripts written for the C em100 tool
+        let valid: [&[&str]; 8] = [
+            &[
+                "--stop",
+                "--set",
+                "M25P80",
+                "-d",
+                "file.bin",
+                "-v",
+                "--start",
+                "-t",
+                "-O",
+                "0xfff00000",
+            ],
+            &["-s", "-c", "W25Q64CV", "-d", "coreboot.rom", "-v"],
+            &["-c", "MX25L12835F", "-d", "image.rom", "-r", "-p", "low"],
+            &["-rt", "-O", "fff00000"],
+            &["-R", "-O", "7ff000", "-L", "1000"],
+            &["-x", "EM123456", "-T"],
+            &["-x", "1:3", "-S", "DP000042"],
+            &["-F", "auto", "-x", "dp000042"],
+        ];
+        for argv in valid {
+            let args = args(argv);
+            assert_eq!(
+                validate_combinations(&args).map_err(|e| e.1),
+                Ok(()),
+                "{:?}",
+                argv
+            );
+        }
+
+        // -O and -L are hex, with or without 0x
+        for argv in [["-t", "-O", "0xfff00000"], ["-t", "-O", "fff00000"]] {
+            let offset = args(&argv).offset.unwrap();
+            assert_eq!(parse_hex_val(&offset), Some(0xfff00000));
+        }
+        assert_eq!(parse_hex_val("1000"), Some(0x1000));
+
+        // Combined short flags
+        let combined = args(&["-rtb"]);
+        assert!(combined.start && combined.trace && combined.brief);
+
+        // Hold pin states ignore case
+        for state in ["low", "LOW", "Low"] {
+            assert_eq!(state.parse::<HoldPinState>().unwrap(), HoldPinState::Low);
+        }
+
+        // Serial numbers with and without their prefix
+        for serial in ["DP000042", "dp000042", "EM000042", "em42", "42"] {
+            assert_eq!(parse_serialno(serial), Some(42), "{}", serial);
+        }
+        assert_eq!(parse_serialno("XY42"), None);
+        for device in ["EM123456", "em123456", "DP123456"] {
+            assert_eq!(
+                device.parse::<DeviceSelector>().unwrap(),
+                DeviceSelector::Serial(123456)
+            );
+        }
+        assert_eq!(
+            "1:3".parse::<DeviceSelector>().unwrap(),
+            DeviceSelector::BusDevice(1, 3)
+        );
+    }
+
+    #[test]
+    fn peek_and_poke_stay_within_the_sdram() {
+        assert_eq!(parse_peek("0x1000"), Some((0x1000, 1)));
+        assert_eq!(parse_peek("0x1000:16"), Some((0x1000, 16)));
+        assert_eq!(parse_peek("0x3fffff0:0x10"), Some((0x3fffff0, 16)));
+        assert_eq!(parse_poke("0x3fffffe=1,2"), Some((0x3fffffe, vec![1, 2])));
+        for peek in [
+            "0x1000:0",
+            "0x3fffff0:17",
+            "0x4000000",
+            "0xffffffff:2",
+            "0:18446744073709551615",
+        ] {
+            assert_eq!(parse_peek(peek), None, "{}", peek);
+        }
+        for poke in [
+            "0x3ffffff=1,2",
+            "0x4000000=1",
+            "0xffffffff=1,2",
+            "0x1000=256",
+            "0x1000",
+        ] {
+            assert_eq!(parse_poke(poke), None, "{}", poke);
+        }
+    }
+
+    #[test]
+    fn firmware_info_checks_the_file_alone() {
+        let header = DpfwHeader {
+            target: rem100::firmware::DpfwTarget::Em100ProG2,
+            mcu_version: "2.27".to_string(),
+            fpga_version: "1.10".to_string(),
+            fpga_offset: DpfwHeader::SIZE,
+            fpga_len: 0x100,
+            mcu_offset: DpfwHeader::SIZE + 0x100,
+            mcu_len: 0x100,
+        };
+        let mut fw = header.to_bytes().to_vec();
+        fw.resize(DpfwHeader::SIZE + 0x200, 0xff);
+        let path = std::env::temp_dir().join(format!("rem100-{}-info.dpfw", std::process::id()));
+
+        std::fs::write(&path, &fw).unwrap();
+        assert!(print_firmware_info(&path));
+        // A section running past the end of the file
+        std::fs::write(&path, &fw[..fw.len() - 1]).unwrap();
+        assert!(!print_firmware_info(&path));
+        std::fs::write(&path, b"not a firmware file").unwrap();
+        assert!(!print_firmware_info(&path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(!print_firmware_info(&path));
+    }
+}
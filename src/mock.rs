@@ -0,0 +1,225 @@
+//! Scripted stand-in for an EM100Pro
+//!
+//! A [`MockTransport`] plays back a conversation written in advance: the
+//! commands and bulk data the host must send, and the responses the device
+//! gives. Device logic can then run through `Em100::with_transport` without
+//! hardware. Anything the host does that the script doesn't expect panics
+//! with a description of the mismatch.
+
+use crate::chips::ChipDesc;
+use crate::device::Em100;
+use crate::error::{Error, Result};
+use crate::protocol::{self, Command, HwVersion};
+use crate::usb::Transport;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// One step of a scripted conversation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// The host sends this command
+    Command(Command),
+    /// The device answers the next read with this
+    ///
+    /// Reads are rounded up to whole packets as on real hardware: bytes past
+    /// the requested length are dropped, and more than the rounded-up
+    /// length is an error.
+    Response(Vec<u8>),
+    /// The host sends this bulk data
+    BulkWrite(Vec<u8>),
+    /// The device sends this bulk data, rounded up to packets like `Response`
+    BulkRead(Vec<u8>),
+    /// The next transfer, whichever it is, times out
+    Timeout,
+}
+
+/// A transport that checks the host's side of a scripted conversation
+///
+/// Clones share the script, so a test can keep one to check that the
+/// conversation ran to the end after handing the other to an `Em100`.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    script: Arc<Mutex<VecDeque<Step>>>,
+}
+
+impl MockTransport {
+    /// An empty script
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A device answering the queries made when it is opened
+    pub fn em100pro(mcu: u16, fpga: u16, serial_no: u32, hw_version: HwVersion) -> Self {
+        let mut info_page = vec![0xff; 256];
+        info_page[1] = hw_version as u8;
+        info_page[2..6].copy_from_slice(&serial_no.to_le_bytes());
+
+        Self::new()
+            .query(protocol::spi_flash_id_cmd(), &[0x20, 0x20, 0x15])
+            .query(
+                protocol::version_cmd(),
+                &[
+                    4,
+                    (fpga >> 8) as u8,
+                    fpga as u8,
+                    (mcu >> 8) as u8,
+                    mcu as u8,
+                ],
+            )
+            .query(
+                protocol::read_spi_flash_page_cmd(protocol::INFO_PAGE),
+                &info_page,
+            )
+    }
+
+    /// An EM100Pro with firmware 2.27/0.34 and serial number EM123456, for
+    /// tests that don't depend on the device's identity
+    pub fn stock() -> Self {
+        Self::em100pro(0x0227, 0x0034, 123456, HwVersion::Em100Pro)
+    }
+
+    /// Append a step to the script
+    pub fn step(self, step: Step) -> Self {
+        self.script.lock().unwrap().push_back(step);
+        self
+    }
+
+    /// Expect the host to send `cmd`
+    pub fn command(self, cmd: Command) -> Self {
+        self.step(Step::Command(cmd))
+    }
+
+    /// Expect the host to send `cmd` and answer it with `response`
+    pub fn query(self, cmd: Command, response: &[u8]) -> Self {
+        self.command(cmd).step(Step::Response(response.to_vec()))
+    }
+
+    /// Expect an FPGA register read and answer it with `value`
+    pub fn read_register(self, reg: u8, value: u16) -> Self {
+        self.query(
+            protocol::read_fpga_register_cmd(reg),
+            &[2, (value >> 8) as u8, value as u8],
+        )
+    }
+
+    /// Expect an FPGA register write
+    pub fn write_register(self, reg: u8, value: u16) -> Self {
+        self.command(protocol::write_fpga_register_cmd(reg, value))
+    }
+
+    /// Expect emulation to be stopped and `chip` set up on an FPGA already
+    /// at its voltage
+    pub fn chip_setup(self, chip: &ChipDesc, address_mode: u8) -> Self {
+        self.write_register(protocol::FPGA_REG_STATE, 0)
+            .chip_init(chip, address_mode)
+    }
+
+    /// Expect the part of a chip setup after the FPGA voltage switch
+    ///
+    /// The init sequence is sent, the post-init registers are written and
+    /// read back intact, and `address_mode` is programmed.
+    pub fn chip_init(self, chip: &ChipDesc, address_mode: u8) -> Self {
+        let mut mock = self;
+        for entry in chip.init.iter().take(chip.init_len) {
+            let mut cmd = [0u8; protocol::CMD_LEN];
+            cmd[..entry.len()].copy_from_slice(entry);
+            mock = mock.command(cmd);
+        }
+        for reg in &protocol::POST_INIT_REGISTERS {
+            mock = mock.write_register(reg.reg, reg.value);
+        }
+        for reg in &protocol::POST_INIT_REGISTERS {
+            mock = mock.read_register(reg.reg, reg.value);
+        }
+        let mode = protocol::address_mode_value(address_mode).unwrap();
+        mock.write_register(protocol::FPGA_REG_ADDRESS_MODE, mode)
+    }
+
+    /// Open an `Em100` talking to this script
+    ///
+    /// The script has to start with the queries made when a device is
+    /// opened, as [`MockTransport::em100pro`] does.
+    pub fn open(&self) -> Result<Em100> {
+        Em100::with_transport(Box::new(self.clone()))
+    }
+
+    /// Steps the host hasn't gone through yet
+    pub fn remaining(&self) -> Vec<Step> {
+        self.script.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Panic unless the host went through the whole script
+    pub fn assert_done(&self) {
+        let remaining = self.remaining();
+        assert!(
+            remaining.is_empty(),
+            "mock: {} scripted steps left, next {:?}",
+            remaining.len(),
+            remaining[0]
+        );
+    }
+
+    /// Take the next step for `what`, or None if it is a timeout
+    fn next(&self, what: &str) -> Option<Step> {
+        match self.script.lock().unwrap().pop_front() {
+            Some(Step::Timeout) => None,
+            Some(step) => Some(step),
+            None => panic!("mock: unexpected {} after the end of the script", what),
+        }
+    }
+}
+
+/// Max packet size of the EM100Pro's high-speed bulk endpoints
+const MAX_PACKET_SIZE: usize = 512;
+
+/// What a read of `length` bytes gets when the device sends `data`
+fn completion(data: &[u8], length: usize) -> Result<&[u8]> {
+    let requested_len = protocol::round_up_to_max_packet(length, MAX_PACKET_SIZE);
+    protocol::clamp_response(data, data.len(), length, requested_len)
+}
+
+impl Transport for MockTransport {
+    fn send_cmd(&self, cmd: &[u8; 16]) -> Result<usize> {
+        match self.next("command") {
+            None => Err(Error::Timeout),
+            Some(Step::Command(expected)) if expected == *cmd => Ok(cmd.len()),
+            Some(step) => panic!("mock: expected {:?}, got command {:02x?}", step, cmd),
+        }
+    }
+
+    fn get_response(&self, length: usize) -> Result<Vec<u8>> {
+        match self.next("response read") {
+            None => Err(Error::Timeout),
+            Some(Step::Response(data)) => completion(&data, length).map(<[u8]>::to_vec),
+            Some(step) => panic!("mock: expected {:?}, got a response read", step),
+        }
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> Result<usize> {
+        match self.next("bulk write") {
+            None => Err(Error::Timeout),
+            Some(Step::BulkWrite(expected)) if expected == data => Ok(data.len()),
+            Some(step) => panic!(
+                "mock: expected {:?}, got a {} byte bulk write",
+                step,
+                data.len()
+            ),
+        }
+    }
+
+    fn bulk_read(&self, buffer: &mut [u8]) -> Result<usize> {
+        match self.next("bulk read") {
+            None => Err(Error::Timeout),
+            Some(Step::BulkRead(data)) => {
+                let data = completion(&data, buffer.len())?;
+                buffer[..data.len()].copy_from_slice(data);
+                Ok(data.len())
+            }
+            Some(step) => panic!("mock: expected {:?}, got a bulk read", step),
+        }
+    }
+
+    /// Waits are skipped, so scripted FPGA reconfigurations take no time
+    fn sleep(&self, _duration: Duration) {}
+}
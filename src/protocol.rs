@@ -0,0 +1,911 @@
+//! EM100 USB protocol: command construction and response parsing
+//!
+//! Everything here is independent of the USB transport, so the blocking
+//! native implementation and the async WebUSB implementation share it.
+
+use crate::chips::ChipDesc;
+use crate::error::{Error, Result};
+
+/// EM100 USB Vendor ID
+pub const VENDOR_ID: u16 = 0x04b4;
+/// EM100 USB Product ID
+pub const PRODUCT_ID: u16 = 0x1235;
+
+/// Length of every EM100 command packet
+pub const CMD_LEN: usize = 16;
+
+/// An EM100 command packet
+pub type Command = [u8; CMD_LEN];
+
+/// SDRAM transfers are streamed in chunks of this size (2MB)
+pub const TRANSFER_LENGTH: usize = 0x200000;
+
+/// Hardware versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HwVersion {
+    /// Early EM100Pro (hardware version 0xff)
+    Em100ProEarly = 0xff,
+    /// EM100Pro (hardware version 0x04)
+    Em100Pro = 0x04,
+    /// EM100Pro-G2 (hardware version 0x06)
+    Em100ProG2 = 0x06,
+    /// Unknown hardware version
+    Unknown = 0x00,
+}
+
+impl From<u8> for HwVersion {
+    fn from(v: u8) -> Self {
+        match v {
+            0xff => HwVersion::Em100ProEarly,
+            0x04 => HwVersion::Em100Pro,
+            0x06 => HwVersion::Em100ProG2,
+            _ => HwVersion::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for HwVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HwVersion::Em100ProEarly => write!(f, "EM100Pro (early)"),
+            HwVersion::Em100Pro => write!(f, "EM100Pro"),
+            HwVersion::Em100ProG2 => write!(f, "EM100Pro-G2"),
+            HwVersion::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+/// Hold pin states
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HoldPinState {
+    #[default]
+    Float = 0x2,
+    Low = 0x0,
+    Input = 0x3,
+}
+
+impl std::str::FromStr for HoldPinState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_uppercase().as_str() {
+            "FLOAT" => Ok(HoldPinState::Float),
+            "LOW" => Ok(HoldPinState::Low),
+            "INPUT" => Ok(HoldPinState::Input),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid hold pin state: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for HoldPinState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HoldPinState::Float => write!(f, "float"),
+            HoldPinState::Low => write!(f, "low"),
+            HoldPinState::Input => write!(f, "input"),
+        }
+    }
+}
+
+/// Build a command with the given opcode and leading argument bytes
+pub fn command(opcode: u8, args: &[u8]) -> Command {
+    let mut cmd = [0u8; CMD_LEN];
+    cmd[0] = opcode;
+    let len = args.len().min(CMD_LEN - 1);
+    cmd[1..=len].copy_from_slice(&args[..len]);
+    cmd
+}
+
+/// Read the MCU and FPGA firmware versions
+pub fn version_cmd() -> Command {
+    command(0x10, &[])
+}
+
+/// Parse the response to `version_cmd` into (MCU version, FPGA version)
+pub fn parse_version(data: &[u8]) -> Result<(u16, u16)> {
+    if data.len() == 5 && data[0] == 4 {
+        let mcu = ((data[3] as u16) << 8) | (data[4] as u16);
+        let fpga = ((data[1] as u16) << 8) | (data[2] as u16);
+        Ok((mcu, fpga))
+    } else {
+        Err(Error::InvalidResponse)
+    }
+}
+
+/// A region of the EM100Pro's own SPI flash that must not be overwritten casually
+#[derive(Debug, Clone, Copy)]
+pub struct ProtectedRegion {
+    pub name: &'static str,
+    pub start: u32,
+    pub len: u32,
+}
+
+impl ProtectedRegion {
+    /// Check whether `[address, address + len)` overlaps this region
+    pub fn overlaps(&self, address: u32, len: u32) -> bool {
+        let end = address as u64 + len as u64;
+        (address as u64) < self.start as u64 + self.len as u64 && end > self.start as u64
+    }
+}
+
+/// SPI flash part used by the EM100Pro to store its firmware
+#[derive(Debug)]
+pub struct SpiFlashPart {
+    pub id: u32,
+    pub name: &'static str,
+    pub size: usize,
+    /// 64KB sectors erased before writing firmware, from address 0
+    pub firmware_sectors: u8,
+    pub protected: &'static [ProtectedRegion],
+}
+
+impl SpiFlashPart {
+    /// Find the first protected region overlapping `[address, address + len)`
+    pub fn protected_region(&self, address: u32, len: u32) -> Option<&'static ProtectedRegion> {
+        self.protected.iter().find(|r| r.overlaps(address, len))
+    }
+}
+
+/// Regions the bootloader relies on: the update tag follows the FPGA image,
+/// and the bootloader sector (the last 64KB of the M25P16) holds the boot
+/// magic page and the info page (serial number, hardware version). Lookups
+/// return the first overlapping region, so the pages come before the sector.
+///
+/// Both accepted parts share this layout: the MX77L12850F keeps the M25P16
+/// layout in its first 2MB, so its bootloader sector is sector 0x1f rather
+/// than its own last one. The space above 2MB is not used by the firmware
+/// and is left unprotected.
+static BOOTLOADER_PROTECTED_REGIONS: &[ProtectedRegion] = &[
+    ProtectedRegion {
+        name: "update tag",
+        start: 0x100000,
+        len: 0x100,
+    },
+    ProtectedRegion {
+        name: "boot magic",
+        start: 0x1f0000,
+        len: 0x100,
+    },
+    ProtectedRegion {
+        name: "info page",
+        start: INFO_PAGE,
+        len: 0x100,
+    },
+    ProtectedRegion {
+        name: "bootloader sector",
+        start: 0x1f0000,
+        len: 0x10000,
+    },
+];
+
+/// SPI flash parts accepted as EM100Pro firmware storage
+///
+/// This lives here rather than in `spi`, which isn't built for wasm32,
+/// because the shared device identification in `commands` checks the ID.
+pub static SPI_FLASH_PARTS: &[SpiFlashPart] = &[
+    SpiFlashPart {
+        id: 0x202015,
+        name: "M25P16",
+        size: 2 * 1024 * 1024,
+        firmware_sectors: 0x1f,
+        protected: BOOTLOADER_PROTECTED_REGIONS,
+    },
+    SpiFlashPart {
+        id: 0xc27518,
+        name: "MX77L12850F",
+        size: 16 * 1024 * 1024,
+        // Firmware is laid out as on the 2MB part, the rest stays unused
+        firmware_sectors: 0x1f,
+        protected: BOOTLOADER_PROTECTED_REGIONS,
+    },
+];
+
+/// Look up an accepted SPI flash part by JEDEC ID
+pub fn find_spi_flash_part(id: u32) -> Option<&'static SpiFlashPart> {
+    SPI_FLASH_PARTS.iter().find(|p| p.id == id)
+}
+
+/// Address of the SPI flash page holding the serial number and hardware version
+pub const INFO_PAGE: u32 = 0x1fff00;
+
+/// Parse the info page into (serial number, hardware version)
+pub fn parse_info_page(data: &[u8]) -> (u32, HwVersion) {
+    let serial_no = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+    (serial_no, HwVersion::from(data[1]))
+}
+
+/// Read the SPI flash JEDEC ID
+pub fn spi_flash_id_cmd() -> Command {
+    command(0x30, &[])
+}
+
+/// Parse the response to `spi_flash_id_cmd`
+pub fn parse_spi_flash_id(data: &[u8]) -> Result<u32> {
+    if data.len() == 3 {
+        Ok(((data[0] as u32) << 16) | ((data[1] as u32) << 8) | (data[2] as u32))
+    } else {
+        Err(Error::InvalidResponse)
+    }
+}
+
+/// Read a 256-byte SPI flash page
+pub fn read_spi_flash_page_cmd(address: u32) -> Command {
+    command(0x33, &address.to_be_bytes()[1..])
+}
+
+/// Write a 256-byte SPI flash page (data follows on the OUT endpoint)
+pub fn write_spi_flash_page_cmd(address: u32) -> Command {
+    command(0x34, &address.to_be_bytes()[1..])
+}
+
+/// Read an FPGA register
+pub fn read_fpga_register_cmd(reg: u8) -> Command {
+    command(0x22, &[reg])
+}
+
+/// Parse the response to `read_fpga_register_cmd`
+pub fn parse_fpga_register(data: &[u8]) -> Result<u16> {
+    if data.len() == 3 && data[0] == 2 {
+        Ok(((data[1] as u16) << 8) | (data[2] as u16))
+    } else {
+        Err(Error::InvalidResponse)
+    }
+}
+
+/// Write an FPGA register
+pub fn write_fpga_register_cmd(reg: u8, val: u16) -> Command {
+    command(0x23, &[reg, (val >> 8) as u8, val as u8])
+}
+
+/// Reconfigure the FPGA from its SPI flash
+pub fn fpga_reconfigure_cmd() -> Command {
+    command(0x20, &[])
+}
+
+/// Switch the FPGA I/O voltage (18 for 1.8V, 33 for 3.3V)
+///
+/// Must be sent right after `fpga_reconfigure_cmd`, and the device needs 2s
+/// before it takes another command.
+pub fn set_fpga_voltage_cmd(voltage_code: u8) -> Command {
+    if voltage_code == 18 {
+        command(0x24, &[0, 7, 0x80])
+    } else {
+        command(0x24, &[])
+    }
+}
+
+/// Voltage code (18 or 33) of the FPGA I/O voltage flag in the FPGA version
+pub fn fpga_voltage_code(fpga: u16) -> u8 {
+    if fpga & 0x8000 != 0 {
+        18
+    } else {
+        33
+    }
+}
+
+/// An FPGA voltage switch needed to emulate a chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoltageSwitch {
+    /// Voltage code to switch to (18 or 33)
+    pub voltage_code: u8,
+    /// Supply voltage of the chip in millivolts
+    pub chip_mv: u16,
+}
+
+/// The FPGA voltage switch `chip` needs with the FPGA version `fpga`, if any
+///
+/// The chip's supply voltage is given by the first "set voltage" entry of
+/// its init sequence.
+pub fn required_voltage_switch(chip: &ChipDesc, fpga: u16) -> Option<VoltageSwitch> {
+    let entry = chip
+        .init
+        .iter()
+        .take(chip.init_len)
+        .find(|entry| entry[0] == 0x11 && entry[1] == 0x04)?;
+    let chip_mv = ((entry[2] as u16) << 8) | (entry[3] as u16);
+    let voltage_code = match (chip_mv, fpga_voltage_mv(fpga)) {
+        (1601 | 1800, 3300) => 18,
+        (3300, 1800) => 33,
+        _ => return None,
+    };
+    Some(VoltageSwitch {
+        voltage_code,
+        chip_mv,
+    })
+}
+
+/// FPGA register starting (1) and stopping (0) emulation
+pub const FPGA_REG_STATE: u8 = 0x28;
+/// FPGA register holding the hold pin state
+pub const FPGA_REG_HOLD_PIN: u8 = 0x2a;
+/// FPGA register selecting 4 byte (1) or 3 byte (0) addressing
+pub const FPGA_REG_ADDRESS_MODE: u8 = 0x4f;
+
+/// Value of `FPGA_REG_ADDRESS_MODE` for an address mode of 3 or 4 bytes
+pub fn address_mode_value(mode: u8) -> Result<u16> {
+    match mode {
+        3 => Ok(0),
+        4 => Ok(1),
+        _ => Err(Error::InvalidArgument(format!(
+            "Invalid address mode: {}",
+            mode
+        ))),
+    }
+}
+
+/// Address mode (3 or 4 byte) of a `FPGA_REG_ADDRESS_MODE` value
+pub fn parse_address_mode(val: u16) -> u8 {
+    if val & 1 != 0 {
+        4
+    } else {
+        3
+    }
+}
+
+/// Hold pin state of a `FPGA_REG_HOLD_PIN` value
+pub fn parse_hold_pin_state(val: u16) -> Result<HoldPinState> {
+    match val {
+        0 => Ok(HoldPinState::Low),
+        2 => Ok(HoldPinState::Float),
+        3 => Ok(HoldPinState::Input),
+        _ => Err(Error::InvalidResponse),
+    }
+}
+
+/// FPGA register enabling the emulated chip's write protection
+pub const FPGA_REG_PROTECT: u8 = 0xc4;
+/// FPGA register selecting the emulation mode
+pub const FPGA_REG_MODE: u8 = 0x10;
+/// FPGA register selecting the SDRAM bank the chip is emulated from
+pub const FPGA_REG_BANK: u8 = 0x81;
+
+/// An FPGA register written after a chip's init sequence
+pub struct PostInitRegister {
+    pub reg: u8,
+    pub name: &'static str,
+    pub value: u16,
+}
+
+/// Registers written after a chip's init sequence, in the order they are written
+///
+/// If one of these writes is lost, emulation still starts but misbehaves,
+/// so they are read back and written once more on mismatch.
+pub const POST_INIT_REGISTERS: [PostInitRegister; 3] = [
+    PostInitRegister {
+        reg: FPGA_REG_PROTECT,
+        name: "protection-enable",
+        value: 0x01,
+    },
+    PostInitRegister {
+        reg: FPGA_REG_MODE,
+        name: "mode",
+        value: 0x00,
+    },
+    PostInitRegister {
+        reg: FPGA_REG_BANK,
+        name: "bank",
+        value: 0x00,
+    },
+];
+
+/// Values of the post-init registers read back after setting a chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PostInitReadback {
+    /// Final values, in the order of `POST_INIT_REGISTERS`
+    pub values: [u16; 3],
+    /// Whether a write had to be repeated
+    pub retried: bool,
+}
+
+impl PostInitReadback {
+    /// Registers that don't hold the value written to them
+    pub fn mismatches(&self) -> impl Iterator<Item = (&'static PostInitRegister, u16)> + '_ {
+        POST_INIT_REGISTERS
+            .iter()
+            .zip(self.values)
+            .filter(|(reg, value)| reg.value != *value)
+    }
+
+    /// Fail with the mismatched registers if any write didn't stick
+    pub fn check(self) -> Result<Self> {
+        let mismatches: Vec<String> = self
+            .mismatches()
+            .map(|(reg, value)| {
+                format!(
+                    "{} register 0x{:02x} reads 0x{:04x} instead of 0x{:04x}",
+                    reg.name, reg.reg, value, reg.value
+                )
+            })
+            .collect();
+        if mismatches.is_empty() {
+            return Ok(self);
+        }
+        Err(Error::OperationFailed(format!(
+            "Chip setup didn't stick after a retry ({}), check the USB cable",
+            mismatches.join(", ")
+        )))
+    }
+}
+
+impl std::fmt::Display for PostInitReadback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (reg, value)) in POST_INIT_REGISTERS.iter().zip(self.values).enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} (0x{:02x}) = 0x{:04x}", reg.name, reg.reg, value)?;
+        }
+        if self.retried {
+            write!(f, " after a retry")?;
+        }
+        Ok(())
+    }
+}
+
+/// Write `length` bytes to SDRAM at `address` (data follows in chunks)
+pub fn write_sdram_cmd(address: u32, length: usize) -> Command {
+    sdram_cmd(0x40, address, length)
+}
+
+/// Read `length` bytes from SDRAM at `address` (data follows in chunks)
+pub fn read_sdram_cmd(address: u32, length: usize) -> Command {
+    sdram_cmd(0x41, address, length)
+}
+
+fn sdram_cmd(opcode: u8, address: u32, length: usize) -> Command {
+    let mut args = [0u8; 8];
+    args[..4].copy_from_slice(&address.to_be_bytes());
+    args[4..].copy_from_slice(&(length as u32).to_be_bytes());
+    command(opcode, &args)
+}
+
+/// Size of the next SDRAM chunk after `done` of `total` bytes
+pub fn next_chunk_len(total: usize, done: usize) -> usize {
+    (total - done).min(TRANSFER_LENGTH)
+}
+
+/// Length of an SDRAM region at `address` in `memory_size` bytes of memory
+///
+/// Without a `length`, the region runs to the end of the memory.
+pub fn sdram_region_len(address: u32, length: Option<usize>, memory_size: usize) -> Result<usize> {
+    let start = address as usize;
+    if start >= memory_size {
+        return Err(Error::InvalidArgument(format!(
+            "Address 0x{:08x} is outside the {} byte emulated memory",
+            address, memory_size
+        )));
+    }
+    let length = length.unwrap_or(memory_size - start);
+    if length == 0 {
+        return Err(Error::InvalidArgument("The length is zero".to_string()));
+    }
+    if length > memory_size - start {
+        return Err(Error::InvalidArgument(format!(
+            "{} bytes at 0x{:08x} exceed the {} byte emulated memory",
+            length, address, memory_size
+        )));
+    }
+    Ok(length)
+}
+
+/// Round up to the next multiple of max packet size for IN transfers
+///
+/// nusb 0.2 requires requested_len to be a multiple of max_packet_size.
+pub fn round_up_to_max_packet(len: usize, max_packet_size: usize) -> usize {
+    len.div_ceil(max_packet_size) * max_packet_size
+}
+
+/// The part of a completed IN transfer that a read of `length` bytes asked for
+///
+/// Reads are rounded up to whole packets, so the device may send up to
+/// `requested_len` bytes; anything past `length` is dropped with a warning.
+/// A completion claiming more than was requested, or more than its buffer
+/// holds, is reported as an error instead of being trusted.
+pub fn clamp_response(
+    buffer: &[u8],
+    actual_len: usize,
+    length: usize,
+    requested_len: usize,
+) -> Result<&[u8]> {
+    if actual_len > requested_len || actual_len > buffer.len() {
+        return Err(Error::Communication(format!(
+            "Device returned {} bytes for a {} byte read",
+            actual_len, requested_len
+        )));
+    }
+    if actual_len > length {
+        log::warn!(
+            "Discarding {} unexpected bytes after a {} byte USB read",
+            actual_len - length,
+            length
+        );
+    }
+    Ok(&buffer[..actual_len.min(length)])
+}
+
+/// Serial number of a factory-fresh or serial-wiped device
+pub const BLANK_SERIAL: u32 = 0xffffffff;
+
+/// `serial_string` of a device with a blank serial number
+pub const BLANK_SERIAL_NAME: &str = "N.A.";
+
+/// Format a serial number the way Dediprog labels the device
+pub fn serial_string(serial_no: u32, hw_version: HwVersion) -> String {
+    if serial_no == BLANK_SERIAL {
+        BLANK_SERIAL_NAME.to_string()
+    } else {
+        let prefix = if hw_version == HwVersion::Em100ProEarly {
+            "DP"
+        } else {
+            "EM"
+        };
+        format!("{}{:06}", prefix, serial_no)
+    }
+}
+
+/// Format the MCU firmware version
+pub fn mcu_version_string(mcu: u16) -> String {
+    format!("{}.{:02}", mcu >> 8, mcu & 0xff)
+}
+
+/// Format the FPGA firmware version, which is encoded per hardware version
+pub fn fpga_version_string(fpga: u16, hw_version: HwVersion) -> String {
+    match hw_version {
+        HwVersion::Em100Pro | HwVersion::Em100ProEarly => {
+            if fpga > 0x0033 {
+                format!(
+                    "{}.{:02} ({})",
+                    (fpga >> 8) & 0x7f,
+                    fpga & 0xff,
+                    if fpga & 0x8000 != 0 { "1.8V" } else { "3.3V" }
+                )
+            } else {
+                format!("{}.{:02}", fpga >> 8, fpga & 0xff)
+            }
+        }
+        HwVersion::Em100ProG2 => {
+            format!("{}.{:03}", (fpga >> 8) & 0x7f, fpga & 0xff)
+        }
+        _ => format!("{}.{}", fpga >> 8, fpga & 0xff),
+    }
+}
+
+/// FPGA I/O voltage in millivolts, encoded in the FPGA version's high bit
+pub fn fpga_voltage_mv(fpga: u16) -> u16 {
+    if fpga & 0x8000 != 0 {
+        1800
+    } else {
+        3300
+    }
+}
+
+/// Protocol quirks of old firmware that other code has to work around
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// The FPGA version doesn't carry the I/O voltage in bit 15, so the
+    /// voltage can't be read back
+    pub no_voltage_flag: bool,
+    /// Trace report buffers never count more packets than they hold
+    pub legacy_trace_framing: bool,
+}
+
+impl Quirks {
+    /// Whether no quirks apply
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            no_voltage_flag: self.no_voltage_flag || other.no_voltage_flag,
+            legacy_trace_framing: self.legacy_trace_framing || other.legacy_trace_framing,
+        }
+    }
+}
+
+impl std::fmt::Display for Quirks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = Vec::new();
+        if self.no_voltage_flag {
+            names.push("no-voltage-flag");
+        }
+        if self.legacy_trace_framing {
+            names.push("legacy-trace-framing");
+        }
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Firmware versions with known quirks, up to and including `max_fpga`
+pub struct QuirkyFirmware {
+    pub max_fpga: u16,
+    pub quirks: Quirks,
+    pub description: &'static str,
+}
+
+/// Firmware recommendations for one hardware version
+///
+/// FPGA versions are compared without the voltage flag in bit 15.
+pub struct Compatibility {
+    pub hw_version: HwVersion,
+    /// Minimum recommended MCU firmware, if any
+    pub min_mcu: Option<u16>,
+    /// Minimum recommended FPGA firmware, if any
+    pub min_fpga: Option<u16>,
+    /// Quirks that apply regardless of the firmware version
+    pub quirks: Quirks,
+    pub quirky_fpga: &'static [QuirkyFirmware],
+}
+
+const OLD_EM100PRO_FPGA: QuirkyFirmware = QuirkyFirmware {
+    max_fpga: 0x0033,
+    quirks: Quirks {
+        no_voltage_flag: true,
+        legacy_trace_framing: true,
+    },
+    description: "no voltage flag in the version, old trace framing",
+};
+
+/// Known-good firmware per hardware version
+pub const COMPATIBILITY: &[Compatibility] = &[
+    Compatibility {
+        hw_version: HwVersion::Em100ProEarly,
+        min_mcu: None,
+        min_fpga: Some(0x0034),
+        quirks: Quirks {
+            no_voltage_flag: false,
+            legacy_trace_framing: true,
+        },
+        quirky_fpga: &[OLD_EM100PRO_FPGA],
+    },
+    Compatibility {
+        hw_version: HwVersion::Em100Pro,
+        min_mcu: None,
+        min_fpga: Some(0x0034),
+        quirks: Quirks {
+            no_voltage_flag: false,
+            legacy_trace_framing: false,
+        },
+        quirky_fpga: &[OLD_EM100PRO_FPGA],
+    },
+];
+
+/// Look up the compatibility entry for a hardware version
+pub fn compatibility(hw_version: HwVersion) -> Option<&'static Compatibility> {
+    COMPATIBILITY.iter().find(|c| c.hw_version == hw_version)
+}
+
+/// Quirks of this hardware and firmware combination
+pub fn firmware_quirks(hw_version: HwVersion, fpga: u16) -> Quirks {
+    let Some(compat) = compatibility(hw_version) else {
+        return Quirks::default();
+    };
+    compat
+        .quirky_fpga
+        .iter()
+        .filter(|q| fpga & 0x7fff <= q.max_fpga)
+        .fold(compat.quirks, |quirks, q| quirks.union(q.quirks))
+}
+
+/// Describe why the firmware is below the recommended versions, if it is
+pub fn firmware_warning(hw_version: HwVersion, mcu: u16, fpga: u16) -> Option<String> {
+    let compat = compatibility(hw_version)?;
+    let mut outdated = Vec::new();
+    if let Some(min) = compat.min_mcu.filter(|&min| mcu < min) {
+        outdated.push(format!(
+            "MCU {} (recommended {})",
+            mcu_version_string(mcu),
+            mcu_version_string(min)
+        ));
+    }
+    if let Some(min) = compat.min_fpga.filter(|&min| fpga & 0x7fff < min) {
+        outdated.push(format!(
+            "FPGA {} (recommended {}.{:02})",
+            fpga_version_string(fpga, hw_version),
+            min >> 8,
+            min & 0xff
+        ));
+    }
+    if outdated.is_empty() {
+        return None;
+    }
+    let known: Vec<&str> = compat
+        .quirky_fpga
+        .iter()
+        .filter(|q| fpga & 0x7fff <= q.max_fpga)
+        .map(|q| q.description)
+        .collect();
+    let mut warning = format!(
+        "{} firmware is older than recommended: {}",
+        hw_version,
+        outdated.join(", ")
+    );
+    if !known.is_empty() {
+        warning.push_str(&format!("; known issues: {}", known.join(", ")));
+    }
+    Some(warning)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test as test;
+
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
+    fn region(part: &SpiFlashPart, address: u32, len: u32) -> Option<&'static str> {
+        part.protected_region(address, len).map(|r| r.name)
+    }
+
+    #[test]
+    fn bootloader_regions_are_protected_on_both_parts() {
+        for part in SPI_FLASH_PARTS {
+            assert_eq!(region(part, 0x100000, 256), Some("update tag"));
+            assert_eq!(region(part, 0x1f0000, 256), Some("boot magic"));
+            assert_eq!(region(part, INFO_PAGE, 256), Some("info page"));
+            assert_eq!(region(part, 0x1f8000, 256), Some("bootloader sector"));
+            assert_eq!(region(part, 0x1f0000, 0x10000), Some("boot magic"));
+        }
+    }
+
+    #[test]
+    fn firmware_images_are_not_protected() {
+        for part in SPI_FLASH_PARTS {
+            // FPGA image, MCU image after the update tag, last firmware sector
+            assert_eq!(region(part, 0, 0x10000), None);
+            assert_eq!(region(part, 0x100100, 256), None);
+            assert_eq!(region(part, 0x1e0000, 0x10000), None);
+            assert_eq!(region(part, 0xff00, 0x100), None);
+        }
+        let mx77 = find_spi_flash_part(0xc27518).unwrap();
+        assert_eq!(region(mx77, 0x200000, 0x10000), None);
+        assert_eq!(region(mx77, 0xff0000, 0x10000), None);
+    }
+
+    #[test]
+    fn commands_are_padded_to_16_bytes() {
+        assert_eq!(
+            command(0x10, &[]),
+            [0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(command(0x23, &[1, 2, 3])[..5], [0x23, 1, 2, 3, 0]);
+        // Arguments past the 15 that fit are dropped
+        assert_eq!(command(0x01, &[0xaa; 20])[15], 0xaa);
+        assert_eq!(
+            read_spi_flash_page_cmd(0x1fff00)[..4],
+            [0x33, 0x1f, 0xff, 0x00]
+        );
+        assert_eq!(
+            write_fpga_register_cmd(0x28, 0x1234)[..4],
+            [0x23, 0x28, 0x12, 0x34]
+        );
+        assert_eq!(set_fpga_voltage_cmd(18)[..4], [0x24, 0, 7, 0x80]);
+        assert_eq!(set_fpga_voltage_cmd(33)[..4], [0x24, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sdram_commands_carry_address_and_length() {
+        assert_eq!(
+            write_sdram_cmd(0x0012_3456, 0x200000)[..9],
+            [0x40, 0x00, 0x12, 0x34, 0x56, 0x00, 0x20, 0x00, 0x00]
+        );
+        assert_eq!(
+            read_sdram_cmd(0x0100_0000, 256)[..9],
+            [0x41, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn responses_are_parsed() {
+        assert_eq!(
+            parse_version(&[4, 0x80, 0x34, 0x02, 0x27]).unwrap(),
+            (0x0227, 0x8034)
+        );
+        assert!(matches!(
+            parse_version(&[4, 0, 0x34, 2]),
+            Err(Error::InvalidResponse)
+        ));
+        assert!(matches!(
+            parse_version(&[3, 0, 0x34, 2, 0x27]),
+            Err(Error::InvalidResponse)
+        ));
+        assert_eq!(parse_spi_flash_id(&[0xc2, 0x75, 0x18]).unwrap(), 0xc27518);
+        assert!(matches!(
+            parse_spi_flash_id(&[0xc2, 0x75]),
+            Err(Error::InvalidResponse)
+        ));
+        assert_eq!(parse_fpga_register(&[2, 0x12, 0x34]).unwrap(), 0x1234);
+        assert!(matches!(
+            parse_fpga_register(&[1, 0x12, 0x34]),
+            Err(Error::InvalidResponse)
+        ));
+        assert_eq!(
+            parse_info_page(&[0, 0x06, 0x40, 0xe2, 0x01, 0x00]),
+            (123456, HwVersion::Em100ProG2)
+        );
+        assert_eq!(
+            parse_info_page(&[0, 0x42, 0xff, 0xff, 0xff, 0xff]).1,
+            HwVersion::Unknown
+        );
+    }
+
+    #[test]
+    fn device_info_is_formatted_per_hardware_version() {
+        assert_eq!(serial_string(123456, HwVersion::Em100Pro), "EM123456");
+        assert_eq!(serial_string(42, HwVersion::Em100ProEarly), "DP000042");
+        assert_eq!(
+            serial_string(BLANK_SERIAL, HwVersion::Em100ProG2),
+            BLANK_SERIAL_NAME
+        );
+        assert_eq!(mcu_version_string(0x0227), "2.39");
+        assert_eq!(fpga_version_string(0x0033, HwVersion::Em100Pro), "0.51");
+        assert_eq!(
+            fpga_version_string(0x8034, HwVersion::Em100Pro),
+            "0.52 (1.8V)"
+        );
+        assert_eq!(
+            fpga_version_string(0x0034, HwVersion::Em100ProEarly),
+            "0.52 (3.3V)"
+        );
+        assert_eq!(fpga_version_string(0x0105, HwVersion::Em100ProG2), "1.005");
+        assert_eq!(fpga_version_string(0x0105, HwVersion::Unknown), "1.5");
+        assert_eq!(fpga_voltage_mv(0x8034), 1800);
+        assert_eq!(fpga_voltage_mv(0x0034), 3300);
+    }
+
+    #[test]
+    fn sdram_transfers_are_split_into_chunks() {
+        let total = 2 * TRANSFER_LENGTH + 5;
+        assert_eq!(next_chunk_len(total, 0), TRANSFER_LENGTH);
+        assert_eq!(next_chunk_len(total, TRANSFER_LENGTH), TRANSFER_LENGTH);
+        assert_eq!(next_chunk_len(total, 2 * TRANSFER_LENGTH), 5);
+        assert_eq!(next_chunk_len(100, 40), 60);
+
+        assert_eq!(round_up_to_max_packet(1, 512), 512);
+        assert_eq!(round_up_to_max_packet(512, 512), 512);
+        assert_eq!(round_up_to_max_packet(513, 64), 576);
+    }
+
+    #[test]
+    fn sdram_regions_stay_inside_the_memory() {
+        const MEMORY: usize = 0x1000;
+        assert_eq!(sdram_region_len(0, None, MEMORY).unwrap(), MEMORY);
+        assert_eq!(sdram_region_len(0xf00, None, MEMORY).unwrap(), 0x100);
+        assert_eq!(sdram_region_len(0xf00, Some(0x100), MEMORY).unwrap(), 0x100);
+        for (address, length) in [(0x1000, None), (0xf00, Some(0x101)), (0, Some(0))] {
+            assert!(matches!(
+                sdram_region_len(address, length, MEMORY),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn completions_are_clamped_to_the_read() {
+        let buffer = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(clamp_response(&buffer, 4, 6, 8).unwrap(), [1, 2, 3, 4]);
+        assert_eq!(
+            clamp_response(&buffer, 8, 6, 8).unwrap(),
+            [1, 2, 3, 4, 5, 6]
+        );
+        // More than the transfer asked for, or than the buffer holds
+        assert!(matches!(
+            clamp_response(&buffer, 9, 6, 16),
+            Err(Error::Communication(_))
+        ));
+        assert!(matches!(
+            clamp_response(&buffer, 8, 6, 7),
+            Err(Error::Communication(_))
+        ));
+    }
+}
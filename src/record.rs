@@ -0,0 +1,458 @@
+//! Recording and replay of the USB traffic with an EM100Pro
+//!
+//! With recording on (`set_record_path`, or the `EM100_USB_RECORD`
+//! environment variable), every device opened afterwards writes its
+//! commands, responses and bulk transfer lengths to a file. A
+//! [`ReplayTransport`] plays such a file back, so a session that went wrong
+//! on a user's hardware can be reproduced without it.
+//!
+//! The file starts with `RECORD_MAGIC`, followed by frames of a kind byte,
+//! a little-endian u32 payload length and the payload:
+//!
+//! | kind | frame      | payload                                      |
+//! |------|------------|----------------------------------------------|
+//! | 1    | command    | the 16 command bytes                         |
+//! | 2    | response   | the response bytes                           |
+//! | 3    | bulk write | requested and sent length (u32 each)         |
+//! | 4    | bulk read  | requested and received length (u32 each)     |
+//! | 5    | timeout    | none                                         |
+//! | 6    | error      | the error message                            |
+//!
+//! Bulk data itself isn't recorded, as SDRAM transfers run to 64MB; bulk
+//! reads replay as zeros.
+
+use crate::error::{Error, Result};
+use crate::protocol::{Command, CMD_LEN};
+use crate::usb::Transport;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Environment variable naming a file to record USB traffic to
+pub const RECORD_ENV: &str = "EM100_USB_RECORD";
+
+/// First bytes of a recording, the last one being the format version
+pub const RECORD_MAGIC: &[u8; 8] = b"EM100RC\x01";
+
+/// Recording file set with `set_record_path`
+static RECORD_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Record the USB traffic of devices opened from now on to `path`
+///
+/// This takes precedence over `EM100_USB_RECORD`.
+pub fn set_record_path(path: Option<PathBuf>) {
+    *RECORD_PATH.lock().unwrap() = path;
+}
+
+/// File USB traffic is recorded to, if recording is on
+pub fn record_path() -> Option<PathBuf> {
+    RECORD_PATH
+        .lock()
+        .unwrap()
+        .clone()
+        .or_else(|| std::env::var_os(RECORD_ENV).map(PathBuf::from))
+}
+
+/// One recorded event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// The host sent a command
+    Command(Command),
+    /// The device answered with a response
+    Response(Vec<u8>),
+    /// Bulk data was sent
+    BulkWrite { requested: u32, actual: u32 },
+    /// Bulk data was received
+    BulkRead { requested: u32, actual: u32 },
+    /// The transfer timed out
+    Timeout,
+    /// The transfer failed
+    Error(String),
+}
+
+impl Frame {
+    /// The frame as stored in a recording
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Frame::Command(cmd) => (1, cmd.to_vec()),
+            Frame::Response(data) => (2, data.clone()),
+            Frame::BulkWrite { requested, actual } => (3, lengths(*requested, *actual)),
+            Frame::BulkRead { requested, actual } => (4, lengths(*requested, *actual)),
+            Frame::Timeout => (5, Vec::new()),
+            Frame::Error(message) => (6, message.as_bytes().to_vec()),
+        };
+        let mut frame = Vec::with_capacity(5 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// The frame recorded for a failed transfer
+    fn from_error(e: &Error) -> Self {
+        match e {
+            Error::Timeout => Frame::Timeout,
+            e => Frame::Error(e.to_string()),
+        }
+    }
+
+    /// The error a replayed failure frame stands for
+    fn to_error(&self) -> Option<Error> {
+        match self {
+            Frame::Timeout => Some(Error::Timeout),
+            Frame::Error(message) => Some(Error::Communication(message.clone())),
+            _ => None,
+        }
+    }
+}
+
+fn lengths(requested: u32, actual: u32) -> Vec<u8> {
+    let mut payload = requested.to_le_bytes().to_vec();
+    payload.extend_from_slice(&actual.to_le_bytes());
+    payload
+}
+
+/// Parse a recording into its frames
+pub fn parse_recording(data: &[u8]) -> Result<Vec<Frame>> {
+    let invalid = |msg: String| Error::InvalidConfig(format!("USB recording: {}", msg));
+    let mut rest = data
+        .strip_prefix(RECORD_MAGIC.as_slice())
+        .ok_or_else(|| invalid("not a recording of this version".to_string()))?;
+
+    let mut frames = Vec::new();
+    while !rest.is_empty() {
+        let offset = data.len() - rest.len();
+        if rest.len() < 5 {
+            return Err(invalid(format!("truncated frame at offset {}", offset)));
+        }
+        let len = u32::from_le_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        let payload = rest
+            .get(5..5 + len)
+            .ok_or_else(|| invalid(format!("truncated frame at offset {}", offset)))?;
+        let u32_at = |i: usize| {
+            u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+        };
+        let frame = match (rest[0], len) {
+            (1, CMD_LEN) => Frame::Command(payload.try_into().unwrap()),
+            (2, _) => Frame::Response(payload.to_vec()),
+            (3, 8) => Frame::BulkWrite {
+                requested: u32_at(0),
+                actual: u32_at(4),
+            },
+            (4, 8) => Frame::BulkRead {
+                requested: u32_at(0),
+                actual: u32_at(4),
+            },
+            (5, 0) => Frame::Timeout,
+            (6, _) => Frame::Error(String::from_utf8_lossy(payload).into_owned()),
+            (kind, _) => {
+                return Err(invalid(format!(
+                    "bad frame of kind {} and length {} at offset {}",
+                    kind, len, offset
+                )))
+            }
+        };
+        frames.push(frame);
+        rest = &rest[5 + len..];
+    }
+    Ok(frames)
+}
+
+/// A transport that records the traffic of another one to a file
+///
+/// Frames are written as they happen, unbuffered, so the recording is
+/// complete up to the last transfer even if the process exits abruptly.
+/// Failures to write the recording are logged once and otherwise ignored.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    file: File,
+    failed: Mutex<bool>,
+}
+
+impl RecordingTransport {
+    /// Record the traffic of `inner` to a new recording at `path`
+    pub fn create(inner: Box<dyn Transport>, path: &Path) -> Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(RECORD_MAGIC)?;
+        Ok(Self::new(inner, file))
+    }
+
+    /// Record the traffic of `inner` at the end of the recording at `path`
+    pub fn append(inner: Box<dyn Transport>, path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self::new(inner, file))
+    }
+
+    fn new(inner: Box<dyn Transport>, file: File) -> Self {
+        RecordingTransport {
+            inner,
+            file,
+            failed: Mutex::new(false),
+        }
+    }
+
+    fn write(&self, frame: Frame) {
+        if let Err(e) = (&self.file).write_all(&frame.encode()) {
+            let mut failed = self.failed.lock().unwrap();
+            if !*failed {
+                log::warn!("Can't write the USB recording: {}", e);
+                *failed = true;
+            }
+        }
+    }
+
+    /// Record the failure of a transfer
+    fn outcome<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.write(Frame::from_error(e));
+        }
+        result
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send_cmd(&self, cmd: &[u8; 16]) -> Result<usize> {
+        self.write(Frame::Command(*cmd));
+        self.outcome(self.inner.send_cmd(cmd))
+    }
+
+    fn get_response(&self, length: usize) -> Result<Vec<u8>> {
+        let data = self.outcome(self.inner.get_response(length))?;
+        self.write(Frame::Response(data.clone()));
+        Ok(data)
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> Result<usize> {
+        let actual = self.outcome(self.inner.bulk_write(data))?;
+        self.write(Frame::BulkWrite {
+            requested: data.len() as u32,
+            actual: actual as u32,
+        });
+        Ok(actual)
+    }
+
+    fn bulk_read(&self, buffer: &mut [u8]) -> Result<usize> {
+        let actual = self.outcome(self.inner.bulk_read(buffer))?;
+        self.write(Frame::BulkRead {
+            requested: buffer.len() as u32,
+            actual: actual as u32,
+        });
+        Ok(actual)
+    }
+
+    fn drain(&self) {
+        self.inner.drain()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.inner.sleep(duration)
+    }
+}
+
+/// A transport playing back a recording
+///
+/// The host must repeat the recorded session: a command or bulk transfer
+/// that differs from the recording panics, like a deviation from a
+/// [`crate::mock::MockTransport`] script. Clones share the recording, so
+/// one can be kept to check that the session was played back to the end.
+#[derive(Debug, Clone)]
+pub struct ReplayTransport {
+    frames: Arc<Mutex<VecDeque<Frame>>>,
+}
+
+impl ReplayTransport {
+    /// Play back the recording in `data`
+    pub fn new(data: &[u8]) -> Result<Self> {
+        Ok(ReplayTransport {
+            frames: Arc::new(Mutex::new(parse_recording(data)?.into())),
+        })
+    }
+
+    /// Play back the recording at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::new(&std::fs::read(path)?)
+    }
+
+    /// Frames not played back yet
+    pub fn remaining(&self) -> usize {
+        self.frames.lock().unwrap().len()
+    }
+
+    fn next(&self, what: &str) -> Frame {
+        self.frames
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("replay: {} after the end of the recording", what))
+    }
+
+    /// The recorded failure of the transfer just replayed, if it failed
+    fn recorded_failure(&self) -> Option<Error> {
+        let mut frames = self.frames.lock().unwrap();
+        let error = frames.front().and_then(Frame::to_error)?;
+        frames.pop_front();
+        Some(error)
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send_cmd(&self, cmd: &[u8; 16]) -> Result<usize> {
+        match self.next("command") {
+            Frame::Command(recorded) if recorded == *cmd => {}
+            frame => panic!("replay: recorded {:?}, got command {:02x?}", frame, cmd),
+        }
+        match self.recorded_failure() {
+            Some(e) => Err(e),
+            None => Ok(cmd.len()),
+        }
+    }
+
+    fn get_response(&self, _length: usize) -> Result<Vec<u8>> {
+        match self.next("response read") {
+            Frame::Response(data) => Ok(data),
+            frame => Err(frame
+                .to_error()
+                .unwrap_or_else(|| panic!("replay: recorded {:?}, got a response read", frame))),
+        }
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> Result<usize> {
+        match self.next("bulk write") {
+            Frame::BulkWrite { requested, actual } if requested as usize == data.len() => {
+                Ok(actual as usize)
+            }
+            frame => Err(frame.to_error().unwrap_or_else(|| {
+                panic!(
+                    "replay: recorded {:?}, got a {} byte bulk write",
+                    frame,
+                    data.len()
+                )
+            })),
+        }
+    }
+
+    fn bulk_read(&self, buffer: &mut [u8]) -> Result<usize> {
+        match self.next("bulk read") {
+            Frame::BulkRead { requested, actual } if requested as usize == buffer.len() => {
+                let actual = (actual as usize).min(buffer.len());
+                buffer[..actual].fill(0);
+                Ok(actual)
+            }
+            frame => Err(frame.to_error().unwrap_or_else(|| {
+                panic!(
+                    "replay: recorded {:?}, got a {} byte bulk read",
+                    frame,
+                    buffer.len()
+                )
+            })),
+        }
+    }
+
+    /// The recorded session already waited
+    fn sleep(&self, _duration: Duration) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::{AddressModePolicy, ChipDesc};
+    use crate::device::Em100;
+    use crate::protocol::{self, HwVersion};
+
+    /// Opening an EM100Pro (MCU 2.27, FPGA 0.52, EM123456), a version
+    /// query, setting up a 32MB chip and starting emulation
+    const SET_CHIP: &[u8] = include_bytes!("../tests/fixtures/set_chip.em100rec");
+
+    fn w25q256() -> ChipDesc {
+        let mut chip = ChipDesc {
+            vendor: "Winbond".to_string(),
+            name: "W25Q256FV".to_string(),
+            size: 0x2000000,
+            ..Default::default()
+        };
+        chip.init[0] = [0x11, 0x04, 0x0c, 0xe4];
+        chip.init[1] = [0x23, 0xef, 0x40, 0x19];
+        chip.init[2] = [0x23, 0x00, 0x00, 0x00];
+        chip.init_len = 3;
+        chip
+    }
+
+    fn open(replay: &ReplayTransport) -> Em100 {
+        Em100::with_transport(Box::new(replay.clone())).unwrap()
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let replay = ReplayTransport::new(SET_CHIP).unwrap();
+        let mut em100 = open(&replay);
+        assert_eq!((em100.mcu, em100.fpga), (0x0227, 0x0034));
+        assert_eq!(em100.serial_string(), "EM123456");
+        assert_eq!(em100.hw_version, HwVersion::Em100Pro);
+        assert_eq!(
+            crate::system::get_version(&em100).unwrap(),
+            (0x0227, 0x0034)
+        );
+        let readback = em100
+            .set_chip_type(&w25q256(), AddressModePolicy::Auto)
+            .unwrap();
+        assert!(!readback.retried);
+        em100.set_state(true).unwrap();
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "replay: recorded Command")]
+    fn divergent_session_panics() {
+        let replay = ReplayTransport::new(SET_CHIP).unwrap();
+        let mut em100 = open(&replay);
+        crate::system::get_version(&em100).unwrap();
+        em100
+            .set_chip_type(&w25q256(), AddressModePolicy::Force(3))
+            .unwrap();
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![
+            Frame::Command(protocol::version_cmd()),
+            Frame::Response(vec![4, 0, 0x34, 2, 0x27]),
+            Frame::BulkWrite {
+                requested: 0x200000,
+                actual: 0x200000,
+            },
+            Frame::BulkRead {
+                requested: 8192,
+                actual: 512,
+            },
+            Frame::Timeout,
+            Frame::Error("pipe error".to_string()),
+        ];
+        let mut data = RECORD_MAGIC.to_vec();
+        for frame in &frames {
+            data.extend_from_slice(&frame.encode());
+        }
+        assert_eq!(parse_recording(&data).unwrap(), frames);
+    }
+
+    #[test]
+    fn malformed_recordings_are_rejected() {
+        let mut truncated = RECORD_MAGIC.to_vec();
+        truncated.extend_from_slice(&Frame::Command(protocol::version_cmd()).encode());
+        truncated.pop();
+        let mut bad_kind = RECORD_MAGIC.to_vec();
+        bad_kind.extend_from_slice(&[9, 0, 0, 0, 0]);
+        for data in [&b"EM100RC\x02"[..], &truncated, &bad_kind] {
+            assert!(matches!(
+                parse_recording(data),
+                Err(Error::InvalidConfig(_))
+            ));
+        }
+    }
+}
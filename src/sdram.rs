@@ -1,70 +1,77 @@
 //! SDRAM related operations
 
+use crate::commands;
 use crate::device::Em100;
 use crate::error::{Error, Result};
+use crate::protocol;
 use crate::usb;
-use nusb::transfer::Buffer;
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Transfer chunk size (2MB)
-const TRANSFER_LENGTH: usize = 0x200000;
+/// Bumped on every SDRAM write made by this process
+static WRITE_GENERATION: AtomicU64 = AtomicU64::new(0);
 
-/// Default timeout for USB transfers
-const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
-
-/// Round up to the next multiple of max packet size for IN transfers
-fn round_up_to_max_packet(len: usize, max_packet_size: usize) -> usize {
-    len.div_ceil(max_packet_size) * max_packet_size
+/// Counter that changes whenever this process writes to SDRAM
+///
+/// Caches of SDRAM contents compare it to detect that they are stale.
+pub fn write_generation() -> u64 {
+    WRITE_GENERATION.load(Ordering::SeqCst)
 }
 
 /// Progress callback type for reporting transfer progress
 /// Arguments: (bytes_transferred, total_bytes)
 pub type ProgressCallback<'a> = Option<&'a mut dyn FnMut(usize, usize)>;
 
+/// Refuse transfers whose chunk addresses would not fit the 32-bit address
+fn check_span(address: u32, length: usize) -> Result<()> {
+    if (address as u64).saturating_add(length as u64) > 1 << 32 {
+        return Err(Error::InvalidArgument(format!(
+            "{} bytes at 0x{:08x} run past the end of the address space",
+            length, address
+        )));
+    }
+    Ok(())
+}
+
 /// Read data from SDRAM with optional progress callback
 pub fn read_sdram_with_progress(
     em100: &Em100,
     address: u32,
     length: usize,
-    mut progress: ProgressCallback,
+    progress: ProgressCallback,
 ) -> Result<Vec<u8>> {
-    let cmd = [
-        0x41u8,
-        ((address >> 24) & 0xff) as u8,
-        ((address >> 16) & 0xff) as u8,
-        ((address >> 8) & 0xff) as u8,
-        (address & 0xff) as u8,
-        ((length >> 24) & 0xff) as u8,
-        ((length >> 16) & 0xff) as u8,
-        ((length >> 8) & 0xff) as u8,
-        (length & 0xff) as u8,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-
-    usb::send_cmd(em100, &cmd)?;
-
-    let mut data = vec![0u8; length];
+    let mut data = Vec::with_capacity(length);
+    read_sdram_to(em100, address, length, &mut data, progress)?;
+    Ok(data)
+}
+
+/// Read data from SDRAM into `out` as the chunks arrive
+///
+/// Nothing is buffered beyond one chunk, so `out` can stream the data to a
+/// file or compressor. On error `out` holds whatever was read until then.
+/// Each chunk is read with its own command, so a cancellation through
+/// `em100.cancel` leaves no data in flight.
+pub fn read_sdram_to<W: Write + ?Sized>(
+    em100: &Em100,
+    address: u32,
+    length: usize,
+    out: &mut W,
+    mut progress: ProgressCallback,
+) -> Result<()> {
+    check_span(address, length)?;
     let mut bytes_read = 0;
 
     while bytes_read < length {
-        let bytes_to_read = std::cmp::min(length - bytes_read, TRANSFER_LENGTH);
-
-        let mut ep = em100.endpoint_in.borrow_mut();
-        let max_packet_size = ep.max_packet_size();
-        let requested_len = round_up_to_max_packet(bytes_to_read, max_packet_size);
-        let mut buf = Buffer::new(requested_len);
-        buf.set_requested_len(requested_len);
-        let completion = ep.transfer_blocking(buf, DEFAULT_TIMEOUT);
-        completion.status?;
-        let actual = std::cmp::min(completion.actual_len, bytes_to_read);
-
-        data[bytes_read..bytes_read + actual].copy_from_slice(&completion.buffer[..actual]);
+        em100.cancel.check()?;
+        let bytes_to_read = protocol::next_chunk_len(length, bytes_read);
+        let chunk = usb::block_on(commands::read_sdram_chunk(
+            &mut usb::Blocking(em100),
+            address + bytes_read as u32,
+            bytes_to_read,
+        ))?;
+        let actual = chunk.len();
+
+        out.write_all(&chunk)?;
         bytes_read += actual;
 
         if let Some(ref mut cb) = progress {
@@ -83,7 +90,7 @@ pub fn read_sdram_with_progress(
         )));
     }
 
-    Ok(data)
+    Ok(())
 }
 
 /// Read data from SDRAM (convenience wrapper with CLI progress bar)
@@ -110,6 +117,7 @@ pub fn read_sdram(em100: &Em100, address: u32, length: usize) -> Result<Vec<u8>>
 
     match &result {
         Ok(_) => pb.finish_with_message("Read complete"),
+        Err(Error::Cancelled) => pb.abandon_with_message("Read cancelled"),
         Err(_) => pb.abandon_with_message("Read failed"),
     }
 
@@ -123,47 +131,45 @@ pub fn read_sdram(em100: &Em100, address: u32, length: usize) -> Result<Vec<u8>>
 }
 
 /// Write data to SDRAM with optional progress callback
+///
+/// Like reads, writes use one command per chunk and can be cancelled
+/// between chunks through `em100.cancel`.
 pub fn write_sdram_with_progress(
     em100: &Em100,
     data: &[u8],
     address: u32,
-    mut progress: ProgressCallback,
+    progress: ProgressCallback,
 ) -> Result<()> {
-    let length = data.len();
+    write_sdram_from_reader(em100, &mut &data[..], data.len(), address, progress)
+}
 
-    let cmd = [
-        0x40u8,
-        ((address >> 24) & 0xff) as u8,
-        ((address >> 16) & 0xff) as u8,
-        ((address >> 8) & 0xff) as u8,
-        (address & 0xff) as u8,
-        ((length >> 24) & 0xff) as u8,
-        ((length >> 16) & 0xff) as u8,
-        ((length >> 8) & 0xff) as u8,
-        (length & 0xff) as u8,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-
-    usb::send_cmd(em100, &cmd)?;
+/// Write `length` bytes from `reader` to SDRAM at `address`
+///
+/// Only one chunk is held in memory at a time, so an image can be streamed
+/// from a file. Each chunk is read from `reader` before its command is
+/// sent, so a read error leaves no transfer half done.
+pub fn write_sdram_from_reader<R: Read + ?Sized>(
+    em100: &Em100,
+    reader: &mut R,
+    length: usize,
+    address: u32,
+    mut progress: ProgressCallback,
+) -> Result<()> {
+    check_span(address, length)?;
+    WRITE_GENERATION.fetch_add(1, Ordering::SeqCst);
 
     let mut bytes_sent = 0;
 
     while bytes_sent < length {
-        let bytes_to_send = std::cmp::min(length - bytes_sent, TRANSFER_LENGTH);
-
-        let buf = Buffer::from(data[bytes_sent..bytes_sent + bytes_to_send].to_vec());
-        let completion = em100
-            .endpoint_out
-            .borrow_mut()
-            .transfer_blocking(buf, DEFAULT_TIMEOUT);
-        completion.status?;
-        let actual = completion.actual_len;
+        em100.cancel.check()?;
+        let bytes_to_send = protocol::next_chunk_len(length, bytes_sent);
+        let mut chunk = vec![0; bytes_to_send];
+        reader.read_exact(&mut chunk)?;
+        let actual = usb::block_on(commands::write_sdram_chunk(
+            &mut usb::Blocking(em100),
+            address + bytes_sent as u32,
+            &chunk,
+        ))?;
 
         bytes_sent += actual;
 
@@ -211,6 +217,7 @@ pub fn write_sdram(em100: &Em100, data: &[u8], address: u32) -> Result<()> {
 
     match &result {
         Ok(_) => pb.finish_with_message("Transfer complete"),
+        Err(Error::Cancelled) => pb.abandon_with_message("Transfer cancelled"),
         Err(_) => pb.abandon_with_message("Transfer failed"),
     }
 
@@ -222,3 +229,100 @@ pub fn write_sdram(em100: &Em100, data: &[u8], address: u32) -> Result<()> {
 pub fn write_sdram(em100: &Em100, data: &[u8], address: u32) -> Result<()> {
     write_sdram_with_progress(em100, data, address, None)
 }
+
+/// Overwrite a small region of SDRAM in place
+///
+/// Only the bytes covered by `data` are read and rewritten; the region has
+/// to lie within the emulated memory. Returns the contents of the region
+/// before and after the write, as read back from the device.
+pub fn patch_bytes(em100: &Em100, address: u32, data: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    if data.is_empty() {
+        return Err(Error::InvalidArgument("No bytes to patch".to_string()));
+    }
+    protocol::sdram_region_len(address, Some(data.len()), em100.memory_size())?;
+
+    let old = read_sdram_with_progress(em100, address, data.len(), None)?;
+    write_sdram_with_progress(em100, data, address, None)?;
+    let new = read_sdram_with_progress(em100, address, data.len(), None)?;
+    Ok((old, new))
+}
+
+/// Fill a region of SDRAM with a single byte value
+pub fn fill_sdram(em100: &Em100, address: u32, length: usize, value: u8) -> Result<()> {
+    fill_sdram_pattern(em100, address, length, &[value], None)
+}
+
+/// Fill a region of SDRAM with `pattern` repeated from `address` on
+///
+/// The chunks are generated as they are sent, so no buffer the size of the
+/// region is allocated.
+pub fn fill_sdram_pattern(
+    em100: &Em100,
+    address: u32,
+    length: usize,
+    pattern: &[u8],
+    progress: ProgressCallback,
+) -> Result<()> {
+    if pattern.is_empty() {
+        return Err(Error::InvalidArgument(
+            "The fill pattern is empty".to_string(),
+        ));
+    }
+    let mut reader = PatternReader { pattern, pos: 0 };
+    write_sdram_from_reader(em100, &mut reader, length, address, progress)
+}
+
+/// Endless repetition of a byte pattern
+struct PatternReader<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+}
+
+impl Read for PatternReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        for byte in buf.iter_mut() {
+            *byte = self.pattern[self.pos];
+            self.pos = (self.pos + 1) % self.pattern.len();
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTransport, Step};
+
+    #[test]
+    fn patch_bytes_reads_the_region_back() {
+        let mock = MockTransport::stock()
+            .command(protocol::read_sdram_cmd(0x1000, 2))
+            .step(Step::BulkRead(vec![0x12, 0x34]))
+            .command(protocol::write_sdram_cmd(0x1000, 2))
+            .step(Step::BulkWrite(vec![0xaa, 0xbb]))
+            .command(protocol::read_sdram_cmd(0x1000, 2))
+            .step(Step::BulkRead(vec![0xaa, 0xbf]));
+        let em100 = mock.open().unwrap();
+        let (old, new) = patch_bytes(&em100, 0x1000, &[0xaa, 0xbb]).unwrap();
+        assert_eq!(old, [0x12, 0x34]);
+        assert_eq!(new, [0xaa, 0xbf]);
+        mock.assert_done();
+    }
+
+    #[test]
+    fn patch_bytes_stays_within_the_memory() {
+        let mock = MockTransport::stock();
+        let em100 = mock.open().unwrap();
+        for address in [0x3ffffff, 0x4000000, u32::MAX] {
+            assert!(matches!(
+                patch_bytes(&em100, address, &[0, 0]),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            write_sdram_with_progress(&em100, &[0, 0], u32::MAX, None),
+            Err(Error::InvalidArgument(_))
+        ));
+        mock.assert_done();
+    }
+}
@@ -0,0 +1,49 @@
+//! Append-only log of state-changing operations on the device
+
+use crate::error::Result;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Session log file
+///
+/// Each entry is a single line prefixed with the wall-clock time in seconds
+/// since the Unix epoch. The file is opened for appending so several sessions
+/// can share one log.
+pub struct SessionLog {
+    file: File,
+}
+
+impl SessionLog {
+    /// Open (or create) a log file for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Create a new log file named after the current time in `dir`
+    pub fn create_in(dir: &Path) -> Result<Self> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self::open(&dir.join(format!("rem100-{}.log", secs)))
+    }
+
+    /// Append a timestamped entry
+    pub fn record(&mut self, message: &str) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.file,
+            "[{}.{:03}] {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            message
+        )?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
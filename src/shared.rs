@@ -0,0 +1,184 @@
+//! Sharing one EM100Pro between the GUI and background sessions
+
+use crate::device::Em100;
+use crate::error::Result;
+use crate::usb;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+/// Pause between session steps that lets queued control operations run
+pub const SESSION_YIELD: Duration = Duration::from_millis(5);
+
+/// An EM100Pro shared between threads
+///
+/// The device has a single OUT/IN endpoint pair, so a command and its
+/// response must never be split by another thread's command. Every access
+/// goes through one lock that is held for a complete operation:
+///
+/// - Control operations (`with`, `try_with`) hold it for the closure, which
+///   should be one or a few short `Em100` calls.
+/// - Long-running sessions such as tracing use `run_session`, which takes the
+///   lock for one step at a time and releases it for `SESSION_YIELD` between
+///   steps. `std::sync::Mutex` is not fair on its own; the pause is what lets
+///   control operations waiting on the lock get the device before the next
+///   step.
+/// - A bulk transfer is a single operation: a 64MB download keeps the lock
+///   until it is done. UI code that polls status should use `try_with` so it
+///   doesn't block meanwhile.
+#[derive(Clone)]
+pub struct SharedEm100 {
+    inner: Arc<Mutex<Em100>>,
+}
+
+impl SharedEm100 {
+    pub fn new(em100: Em100) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(em100)),
+        }
+    }
+
+    /// Take the lock, resynchronizing the USB stream if a previous holder
+    /// panicked in the middle of an exchange
+    fn lock(&self) -> MutexGuard<'_, Em100> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                let guard = poisoned.into_inner();
+                usb::resync(&guard).ok();
+                self.inner.clear_poison();
+                guard
+            }
+        }
+    }
+
+    /// Run one operation with exclusive access to the device
+    pub fn with<T>(&self, f: impl FnOnce(&mut Em100) -> T) -> T {
+        f(&mut self.lock())
+    }
+
+    /// Like `with`, but returns None instead of waiting if the device is busy
+    pub fn try_with<T>(&self, f: impl FnOnce(&mut Em100) -> T) -> Option<T> {
+        match self.inner.try_lock() {
+            Ok(mut guard) => Some(f(&mut guard)),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => Some(self.with(f)),
+        }
+    }
+
+    /// Run a long session one step at a time
+    ///
+    /// Stops when `stop` is set, when `step` returns `Ok(false)` or on the
+    /// first error. The lock is released between steps.
+    pub fn run_session(
+        &self,
+        stop: &AtomicBool,
+        mut step: impl FnMut(&Em100) -> Result<bool>,
+    ) -> Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            if !self.with(|em100| step(em100))? {
+                break;
+            }
+            thread::sleep(SESSION_YIELD);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fpga;
+    use crate::mock::MockTransport;
+    use crate::protocol;
+    use std::sync::atomic::AtomicUsize;
+
+    const SESSION_STEPS: usize = 20;
+    const CONTROL_THREADS: usize = 2;
+    const CONTROL_OPS: usize = 10;
+
+    /// A device answering `reads` state register reads
+    ///
+    /// Every read is a command followed by its response, so a command from
+    /// another thread between the two breaks the script.
+    fn em100(reads: usize) -> (SharedEm100, MockTransport) {
+        let mut mock = MockTransport::stock();
+        for _ in 0..reads {
+            mock = mock.read_register(protocol::FPGA_REG_STATE, 1);
+        }
+        (SharedEm100::new(mock.open().unwrap()), mock)
+    }
+
+    fn read_state(em100: &Em100) -> Result<u16> {
+        fpga::read_fpga_register(em100, protocol::FPGA_REG_STATE)
+    }
+
+    #[test]
+    fn control_operations_get_the_device_between_session_steps() {
+        let (shared, mock) = em100(SESSION_STEPS + CONTROL_THREADS * CONTROL_OPS);
+        let stop = AtomicBool::new(false);
+        let steps = AtomicUsize::new(0);
+
+        let steps_seen = thread::scope(|s| {
+            s.spawn(|| {
+                shared
+                    .run_session(&stop, |em100| {
+                        read_state(em100)?;
+                        Ok(steps.fetch_add(1, Ordering::SeqCst) + 1 < SESSION_STEPS)
+                    })
+                    .unwrap()
+            });
+            while steps.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+            let controls: Vec<_> = (0..CONTROL_THREADS)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..CONTROL_OPS {
+                            assert_eq!(shared.with(|em100| read_state(em100)).unwrap(), 1);
+                        }
+                        steps.load(Ordering::SeqCst)
+                    })
+                })
+                .collect();
+            controls
+                .into_iter()
+                .map(|c| c.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // The yield between steps let the control threads in while the
+        // session was still running, rather than after it
+        for seen in steps_seen {
+            assert!(
+                seen < SESSION_STEPS,
+                "control operations waited for the session"
+            );
+        }
+        assert_eq!(steps.load(Ordering::SeqCst), SESSION_STEPS);
+        mock.assert_done();
+    }
+
+    #[test]
+    fn stopped_sessions_end_before_the_next_step() {
+        let (shared, mock) = em100(1);
+        let stop = AtomicBool::new(false);
+        shared
+            .run_session(&stop, |em100| {
+                read_state(em100)?;
+                stop.store(true, Ordering::SeqCst);
+                Ok(true)
+            })
+            .unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn busy_devices_are_not_waited_for() {
+        let (shared, _mock) = em100(0);
+        let busy = shared.with(|_| thread::scope(|s| s.spawn(|| shared.try_with(|_| ())).join()));
+        assert_eq!(busy.unwrap(), None);
+        assert_eq!(shared.try_with(|_| 42), Some(42));
+    }
+}
@@ -0,0 +1,204 @@
+//! Best-effort snapshots of SDRAM while the emulation keeps running
+//!
+//! The host may write the emulated flash while it is read back, so a live
+//! snapshot can tear: every chunk is a copy of its range at the time it was
+//! read, but different chunks come from different points in time. Each
+//! chunk's read time is recorded so the result can be judged afterwards.
+//!
+//! In consistent mode the emulation is stopped for every chunk instead, so
+//! each chunk is read while the host can't access the flash. The host sees
+//! one short outage per chunk rather than one long one.
+
+use crate::device::Em100;
+use crate::error::{Error, Result};
+use crate::sdram;
+use std::fmt;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Default size of the chunks a snapshot is read in
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How a snapshot was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotMode {
+    /// Emulation was stopped already, the snapshot can't tear
+    Stopped,
+    /// Read while emulating; chunks may be from different points in time
+    Live,
+    /// Emulation was stopped around every chunk
+    Consistent,
+}
+
+impl fmt::Display for SnapshotMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotMode::Stopped => write!(f, "stopped"),
+            SnapshotMode::Live => write!(f, "live"),
+            SnapshotMode::Consistent => write!(f, "consistent"),
+        }
+    }
+}
+
+/// One chunk of a snapshot
+#[derive(Debug, Clone)]
+pub struct SnapshotChunk {
+    pub address: u32,
+    pub length: usize,
+    /// When the read started, relative to the start of the snapshot
+    pub offset: Duration,
+    /// Time the read took
+    pub duration: Duration,
+    /// How long emulation was stopped for this chunk, in consistent mode
+    pub pause: Option<Duration>,
+}
+
+/// Description of a finished snapshot
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub mode: SnapshotMode,
+    pub address: u32,
+    pub length: usize,
+    /// Wall-clock start of the snapshot, since the Unix epoch
+    pub started: Duration,
+    pub chunks: Vec<SnapshotChunk>,
+}
+
+impl Snapshot {
+    /// Time from the start of the first chunk to the end of the last one
+    ///
+    /// In live mode this is the window the contents may have been written in.
+    pub fn span(&self) -> Duration {
+        self.chunks
+            .last()
+            .map(|c| c.offset + c.duration)
+            .unwrap_or_default()
+    }
+
+    /// Longest time the emulation was stopped for a chunk
+    pub fn max_pause(&self) -> Option<Duration> {
+        self.chunks.iter().filter_map(|c| c.pause).max()
+    }
+
+    /// Write the snapshot description as a JSON document
+    pub fn write_json<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"mode\": \"{}\",", self.mode)?;
+        writeln!(
+            w,
+            "  \"torn\": {},",
+            self.mode == SnapshotMode::Live && self.chunks.len() > 1
+        )?;
+        writeln!(w, "  \"address\": {},", self.address)?;
+        writeln!(w, "  \"length\": {},", self.length)?;
+        writeln!(w, "  \"started_unix_us\": {},", self.started.as_micros())?;
+        writeln!(w, "  \"span_us\": {},", self.span().as_micros())?;
+        match self.max_pause() {
+            Some(pause) => writeln!(w, "  \"max_pause_us\": {},", pause.as_micros())?,
+            None => writeln!(w, "  \"max_pause_us\": null,")?,
+        }
+        writeln!(w, "  \"chunks\": [")?;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let pause = chunk
+                .pause
+                .map(|p| p.as_micros().to_string())
+                .unwrap_or_else(|| "null".to_string());
+            writeln!(
+                w,
+                "    {{\"address\": {}, \"length\": {}, \"offset_us\": {}, \"duration_us\": {}, \"pause_us\": {}}}{}",
+                chunk.address,
+                chunk.length,
+                chunk.offset.as_micros(),
+                chunk.duration.as_micros(),
+                pause,
+                if i + 1 < self.chunks.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+}
+
+/// Read `length` bytes of SDRAM at `address` into `out` in `chunk_size` chunks
+///
+/// If emulation is running it keeps running unless `consistent` is set, in
+/// which case it is stopped before and restarted after every chunk. A failed
+/// read still restarts the emulation before the error is returned.
+pub fn take_snapshot<W: Write + ?Sized>(
+    em100: &Em100,
+    address: u32,
+    length: usize,
+    chunk_size: usize,
+    consistent: bool,
+    out: &mut W,
+    mut progress: sdram::ProgressCallback,
+) -> Result<Snapshot> {
+    if chunk_size == 0 {
+        return Err(Error::InvalidArgument(
+            "Snapshot chunk size must not be 0".to_string(),
+        ));
+    }
+    let mode = match (em100.get_state()?, consistent) {
+        (false, _) => SnapshotMode::Stopped,
+        (true, false) => SnapshotMode::Live,
+        (true, true) => SnapshotMode::Consistent,
+    };
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let start = Instant::now();
+    let mut chunks = Vec::new();
+
+    let mut done = 0;
+    while done < length {
+        let chunk_address = address + done as u32;
+        let chunk_len = chunk_size.min(length - done);
+        let (chunk, read) = if mode == SnapshotMode::Consistent {
+            let stopped = Instant::now();
+            em100.set_state(false)?;
+            let read_start = Instant::now();
+            let read = sdram::read_sdram_to(em100, chunk_address, chunk_len, out, None);
+            let duration = read_start.elapsed();
+            if let Err(e) = em100.set_state(true) {
+                return Err(Error::CommandFailed(format!(
+                    "Could not restart emulation after reading 0x{:08x}, it is stopped: {}",
+                    chunk_address, e
+                )));
+            }
+            let chunk = SnapshotChunk {
+                address: chunk_address,
+                length: chunk_len,
+                offset: read_start - start,
+                duration,
+                pause: Some(stopped.elapsed()),
+            };
+            (chunk, read)
+        } else {
+            let read_start = Instant::now();
+            let read = sdram::read_sdram_to(em100, chunk_address, chunk_len, out, None);
+            let chunk = SnapshotChunk {
+                address: chunk_address,
+                length: chunk_len,
+                offset: read_start - start,
+                duration: read_start.elapsed(),
+                pause: None,
+            };
+            (chunk, read)
+        };
+        read?;
+        chunks.push(chunk);
+        done += chunk_len;
+
+        if let Some(ref mut cb) = progress {
+            cb(done, length);
+        }
+    }
+
+    Ok(Snapshot {
+        mode,
+        address,
+        length,
+        started,
+        chunks,
+    })
+}
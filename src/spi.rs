@@ -2,27 +2,25 @@
 
 use crate::device::Em100;
 use crate::error::{Error, Result};
+use crate::protocol;
+use crate::sdram::ProgressCallback;
 use crate::usb;
-use nusb::transfer::Buffer;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-/// Default timeout for USB transfers
-const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+pub use crate::protocol::{find_spi_flash_part, ProtectedRegion, SpiFlashPart, SPI_FLASH_PARTS};
+
+/// FNV-1a hash of a flash page, used to log protected-region writes
+fn page_hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5u32, |h, &b| {
+        (h ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
 
 /// Get SPI flash ID
 pub fn get_spi_flash_id(em100: &Em100) -> Result<u32> {
-    let cmd = [0x30u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 512)?;
-
-    if data.len() == 3 {
-        let id = ((data[0] as u32) << 16) | ((data[1] as u32) << 8) | (data[2] as u32);
-        Ok(id)
-    } else {
-        Err(Error::InvalidResponse)
-    }
+    let data = usb::exchange(em100, &protocol::spi_flash_id_cmd(), 512)?;
+    protocol::parse_spi_flash_id(&data)
 }
 
 /// Erase entire SPI flash
@@ -30,17 +28,47 @@ pub fn erase_spi_flash(em100: &Em100) -> Result<()> {
     let cmd = [0x31u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     usb::send_cmd(em100, &cmd)?;
 
-    // Specification says to wait 5s before issuing another USB command
-    thread::sleep(Duration::from_secs(5));
-    Ok(())
+    wait_for_erase(em100, "SPI flash")
+}
+
+/// Interval between SPI flash status polls while an erase runs
+const ERASE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Specification says to wait 5s after an erase before issuing another USB
+/// command; polling gives up after the same time
+const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for an erase of `what` to finish
+///
+/// Polls the flash status every `ERASE_POLL_INTERVAL`, or with
+/// `em100.conservative_timing` sleeps the full `ERASE_TIMEOUT` instead.
+fn wait_for_erase(em100: &Em100, what: &str) -> Result<()> {
+    if em100.conservative_timing {
+        thread::sleep(ERASE_TIMEOUT);
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    loop {
+        thread::sleep(ERASE_POLL_INTERVAL);
+        if poll_spi_flash_status(em100)? {
+            log::debug!("Erased {} in {:?}", what, start.elapsed());
+            return Ok(());
+        }
+        if start.elapsed() >= ERASE_TIMEOUT {
+            return Err(Error::CommandFailed(format!(
+                "Erasing {} did not finish within {}s",
+                what,
+                ERASE_TIMEOUT.as_secs()
+            )));
+        }
+    }
 }
 
 /// Poll SPI flash status
 pub fn poll_spi_flash_status(em100: &Em100) -> Result<bool> {
     let cmd = [0x32u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 1)?;
+    let data = usb::exchange(em100, &cmd, 1)?;
 
     if data.len() == 1 && data[0] == 1 {
         Ok(true) // ready
@@ -57,27 +85,7 @@ pub fn read_spi_flash_page(em100: &Em100, address: u32, buffer: &mut [u8]) -> Re
         ));
     }
 
-    let cmd = [
-        0x33u8,
-        ((address >> 16) & 0xff) as u8,
-        ((address >> 8) & 0xff) as u8,
-        (address & 0xff) as u8,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 256)?;
+    let data = usb::exchange(em100, &protocol::read_spi_flash_page_cmd(address), 256)?;
 
     if data.len() == 256 {
         buffer[..256].copy_from_slice(&data);
@@ -87,6 +95,56 @@ pub fn read_spi_flash_page(em100: &Em100, address: u32, buffer: &mut [u8]) -> Re
     }
 }
 
+/// Read consecutive 256-byte pages of SPI flash into `buffer`
+///
+/// Each page is tried three times, resynchronizing the USB stream between
+/// attempts. With `skip_filled`, pages whose contents in `buffer` are not all
+/// zero are taken as already read. Returns the addresses of pages that could
+/// not be read; those are left zeroed.
+pub fn read_spi_flash_region(
+    em100: &Em100,
+    address: u32,
+    buffer: &mut [u8],
+    skip_filled: bool,
+    mut progress: ProgressCallback,
+) -> Result<Vec<u32>> {
+    if !address.is_multiple_of(256) || !buffer.len().is_multiple_of(256) {
+        return Err(Error::InvalidArgument(
+            "SPI flash region must be 256-byte aligned".to_string(),
+        ));
+    }
+
+    let total = buffer.len();
+    let mut bad_pages = Vec::new();
+
+    for (i, page) in buffer.chunks_exact_mut(256).enumerate() {
+        em100.cancel.check()?;
+        let page_address = address + (i * 256) as u32;
+        if !(skip_filled && page.iter().any(|&b| b != 0)) {
+            let mut ok = false;
+            for retry in 0..3 {
+                if read_spi_flash_page(em100, page_address, page).is_ok() {
+                    ok = true;
+                    break;
+                }
+                if retry < 2 {
+                    usb::count(em100, |c| c.retries += 1);
+                    usb::resync(em100).ok();
+                }
+            }
+            if !ok {
+                page.fill(0);
+                bad_pages.push(page_address);
+            }
+        }
+        if let Some(ref mut cb) = progress {
+            cb((i + 1) * 256, total);
+        }
+    }
+
+    Ok(bad_pages)
+}
+
 /// Write a 256-byte page to SPI flash
 pub fn write_spi_flash_page(em100: &Em100, address: u32, data: &[u8]) -> Result<()> {
     if data.len() > 256 {
@@ -95,37 +153,13 @@ pub fn write_spi_flash_page(em100: &Em100, address: u32, data: &[u8]) -> Result<
         ));
     }
 
-    let cmd = [
-        0x34u8,
-        ((address >> 16) & 0xff) as u8,
-        ((address >> 8) & 0xff) as u8,
-        (address & 0xff) as u8,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-    usb::send_cmd(em100, &cmd)?;
+    usb::send_cmd(em100, &protocol::write_spi_flash_page_cmd(address))?;
 
     // Pad data to 256 bytes if needed
     let mut page = [0xffu8; 256];
     page[..data.len()].copy_from_slice(data);
 
-    let buf = Buffer::from(page.to_vec());
-    let completion = em100
-        .endpoint_out
-        .borrow_mut()
-        .transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    let bytes_sent = completion.actual_len;
+    let bytes_sent = usb::bulk_write(em100, &page)?;
 
     if bytes_sent != 256 {
         return Err(Error::Communication(format!(
@@ -137,6 +171,67 @@ pub fn write_spi_flash_page(em100: &Em100, address: u32, data: &[u8]) -> Result<
     Ok(())
 }
 
+/// Write a page to SPI flash, refusing protected regions unless allowed
+///
+/// Writes that do touch a protected region are logged to stderr with hashes
+/// of the page before and after the write.
+pub fn write_spi_flash_page_guarded(
+    em100: &Em100,
+    part: &SpiFlashPart,
+    address: u32,
+    data: &[u8],
+    allow_protected: bool,
+) -> Result<()> {
+    let Some(region) = part.protected_region(address, data.len() as u32) else {
+        return write_spi_flash_page(em100, address, data);
+    };
+    if !allow_protected {
+        return Err(Error::InvalidArgument(format!(
+            "Refusing to write {} region at 0x{:06x} of {}",
+            region.name, region.start, part.name
+        )));
+    }
+
+    let page_address = address & !0xff;
+    let mut page = [0u8; 256];
+    read_spi_flash_page(em100, page_address, &mut page)?;
+    let before = page_hash(&page);
+    write_spi_flash_page(em100, address, data)?;
+    read_spi_flash_page(em100, page_address, &mut page)?;
+    log::warn!(
+        "Wrote protected {} page 0x{:06x}: {:08x} -> {:08x}",
+        region.name,
+        page_address,
+        before,
+        page_hash(&page)
+    );
+    Ok(())
+}
+
+/// Erase a 64KB SPI flash sector, refusing protected regions unless allowed
+pub fn erase_spi_flash_sector_guarded(
+    em100: &Em100,
+    part: &SpiFlashPart,
+    sector: u8,
+    allow_protected: bool,
+) -> Result<()> {
+    let address = (sector as u32) << 16;
+    if let Some(region) = part.protected_region(address, 0x10000) {
+        if !allow_protected {
+            return Err(Error::InvalidArgument(format!(
+                "Refusing to erase {} region at 0x{:06x} of {}",
+                region.name, region.start, part.name
+            )));
+        }
+        log::warn!(
+            "Erasing sector 0x{:06x} containing protected {} region",
+            address,
+            region.name
+        );
+    }
+    erase_spi_flash_sector(em100, sector)
+}
+
 /// Unlock SPI flash
 pub fn unlock_spi_flash(em100: &Em100) -> Result<()> {
     let cmd = [0x36u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
@@ -144,12 +239,12 @@ pub fn unlock_spi_flash(em100: &Em100) -> Result<()> {
     Ok(())
 }
 
-/// Erase a 64KB SPI flash sector
+/// Erase a 64KB SPI flash sector, returning once the erase has finished
 ///
 /// Note: The specification says to wait 5s before issuing another USB command,
-/// but the original C implementation does not actually wait. Omitting the wait
-/// here for compatibility and performance (firmware updates would otherwise
-/// take 155+ seconds for 31 sectors).
+/// and the original C implementation does not wait at all. Polling the flash
+/// status keeps firmware updates fast (a flat 5s would take 155+ seconds for
+/// 31 sectors) without writing to a sector that is still being erased.
 pub fn erase_spi_flash_sector(em100: &Em100, sector: u8) -> Result<()> {
     if sector > 31 {
         return Err(Error::InvalidArgument(format!(
@@ -161,7 +256,7 @@ pub fn erase_spi_flash_sector(em100: &Em100, sector: u8) -> Result<()> {
     let cmd = [0x37u8, sector, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     usb::send_cmd(em100, &cmd)?;
 
-    Ok(())
+    wait_for_erase(em100, &format!("sector 0x{:02x}", sector))
 }
 
 // SPI Hyper Terminal related operations
@@ -188,9 +283,7 @@ pub const DFIFO_EMPTY: u8 = 1 << 6;
 /// Read HT register
 pub fn read_ht_register(em100: &Em100, reg: HtRegister) -> Result<u8> {
     let cmd = [0x50u8, reg as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 2)?;
+    let data = usb::exchange(em100, &cmd, 2)?;
 
     if data.len() == 2 && data[0] == 1 {
         Ok(data[1])
@@ -237,13 +330,7 @@ pub fn write_dfifo(em100: &Em100, data: &[u8], timeout: u16) -> Result<()> {
     ];
     usb::send_cmd(em100, &cmd)?;
 
-    let buf = Buffer::from(data.to_vec());
-    let completion = em100
-        .endpoint_out
-        .borrow_mut()
-        .transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    let bytes_sent = completion.actual_len;
+    let bytes_sent = usb::bulk_write(em100, data)?;
 
     let response = usb::get_response(em100, 512)?;
 
@@ -296,3 +383,68 @@ pub fn read_ufifo(em100: &Em100, length: usize, timeout: u16) -> Result<Vec<u8>>
         Err(Error::InvalidResponse)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+
+    fn m25p16() -> &'static SpiFlashPart {
+        find_spi_flash_part(0x202015).unwrap()
+    }
+
+    #[test]
+    fn protected_writes_are_refused_by_default() {
+        let mock = MockTransport::stock();
+        let em100 = mock.open().unwrap();
+        for address in [0x100000, 0x1f0000, 0x1f8000, protocol::INFO_PAGE] {
+            let result = write_spi_flash_page_guarded(&em100, m25p16(), address, &[0; 256], false);
+            assert!(
+                matches!(result, Err(Error::InvalidArgument(_))),
+                "0x{:06x}",
+                address
+            );
+        }
+        mock.assert_done();
+    }
+
+    #[test]
+    fn protected_erases_are_refused_by_default() {
+        let mock = MockTransport::stock();
+        let em100 = mock.open().unwrap();
+        for sector in [0x10, 0x1f] {
+            let result = erase_spi_flash_sector_guarded(&em100, m25p16(), sector, false);
+            assert!(
+                matches!(result, Err(Error::InvalidArgument(_))),
+                "0x{:02x}",
+                sector
+            );
+        }
+        mock.assert_done();
+    }
+
+    #[test]
+    fn unprotected_writes_go_straight_through() {
+        let page = [0x5a; 256];
+        let mock = MockTransport::stock()
+            .command(protocol::write_spi_flash_page_cmd(0x100100))
+            .step(crate::mock::Step::BulkWrite(page.to_vec()));
+        let em100 = mock.open().unwrap();
+        write_spi_flash_page_guarded(&em100, m25p16(), 0x100100, &page, false).unwrap();
+        mock.assert_done();
+    }
+
+    #[test]
+    fn allowed_protected_writes_are_read_back() {
+        let page = [0x5a; 256];
+        let read = protocol::read_spi_flash_page_cmd(protocol::INFO_PAGE);
+        let mock = MockTransport::stock()
+            .query(read, &[0xff; 256])
+            .command(protocol::write_spi_flash_page_cmd(protocol::INFO_PAGE))
+            .step(crate::mock::Step::BulkWrite(page.to_vec()))
+            .query(read, &page);
+        let em100 = mock.open().unwrap();
+        write_spi_flash_page_guarded(&em100, m25p16(), protocol::INFO_PAGE, &page, true).unwrap();
+        mock.assert_done();
+    }
+}
@@ -2,6 +2,7 @@
 
 use crate::device::Em100;
 use crate::error::{Error, Result};
+use crate::protocol;
 use crate::usb;
 
 /// Channels for setting voltage
@@ -45,18 +46,8 @@ pub enum LedState {
 ///
 /// Returns (MCU version, FPGA version)
 pub fn get_version(em100: &Em100) -> Result<(u16, u16)> {
-    let cmd = [0x10u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 512)?;
-
-    if data.len() == 5 && data[0] == 4 {
-        let mcu = ((data[3] as u16) << 8) | (data[4] as u16);
-        let fpga = ((data[1] as u16) << 8) | (data[2] as u16);
-        Ok((mcu, fpga))
-    } else {
-        Err(Error::InvalidResponse)
-    }
+    let data = usb::exchange(em100, &protocol::version_cmd(), 512)?;
+    protocol::parse_version(&data)
 }
 
 /// Set voltage on a channel
@@ -109,9 +100,7 @@ pub fn get_voltage(em100: &Em100, channel: GetVoltageChannel) -> Result<u32> {
         0,
         0,
     ];
-    usb::send_cmd(em100, &cmd)?;
-
-    let data = usb::get_response(em100, 512)?;
+    let data = usb::exchange(em100, &cmd, 512)?;
 
     if data.len() == 3 && data[0] == 2 {
         let raw_voltage = ((data[1] as u32) << 8) | (data[2] as u32);
@@ -1,16 +1,52 @@
 //! SPI trace related operations
+//!
+//! `TraceDecoder` turns the raw report buffers into `TraceEvent`s. The
+//! `read_spi_trace*` functions print those to a `TraceSink`, stdout unless
+//! told otherwise; diagnostics go through `log`.
 
-use crate::device::Em100;
+use crate::device::{Em100, HwVersion, Quirks};
 use crate::error::{Error, Result};
 use crate::fpga;
 use crate::spi;
 use crate::usb;
-use std::io::{self, Write};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub use decoder::TraceDecoder;
+pub use profile::BootProfile;
+pub use sink::TraceSink;
+pub use status::StatusDecoder;
+pub use terminal::TerminalState;
+
+pub mod decoder;
+pub mod export;
+pub mod profile;
+pub mod sink;
+pub mod status;
+pub mod terminal;
+pub mod vcd;
 
 /// Report buffer length
-const REPORT_BUFFER_LENGTH: usize = 8192;
+pub const REPORT_BUFFER_LENGTH: usize = 8192;
 /// Number of report buffers
 const REPORT_BUFFER_COUNT: usize = 8;
+/// Packets that fit in a report buffer after the 2-byte count header
+const REPORT_BUFFER_PACKETS: usize = (REPORT_BUFFER_LENGTH - 2) / 8;
+
+/// All report buffers returned by one trace read
+type ReportData = [[u8; REPORT_BUFFER_LENGTH]; REPORT_BUFFER_COUNT];
+
+/// Number of raw report batches kept for post-mortem dumps
+const POST_MORTEM_BATCHES: usize = 3;
+
+/// Dumps written per trace session, so a persistent problem can't flood the disk
+const MAX_POST_MORTEM_DUMPS: u32 = 5;
+
+/// Older dumps are deleted to keep the dump directory below this size
+const MAX_POST_MORTEM_BYTES: u64 = 16 * 1024 * 1024;
 
 /// EM100 specific command
 pub const EM100_SPECIFIC_CMD: u8 = 0x11;
@@ -33,6 +69,9 @@ struct SpiCmdValues {
     name: &'static str,
     cmd: u8,
     address_type: AddressType,
+    /// Bytes between the address and the data, counted at the data phase's
+    /// bus width: mode bits plus dummy clocks times the number of data lanes,
+    /// over 8 (e.g. 8 dummy clocks are 1 byte for 0x0b, 2 for 0x3b, 4 for 0x6b)
     pad_bytes: u8,
 }
 
@@ -41,7 +80,7 @@ static SPI_COMMAND_LIST: &[SpiCmdValues] = &[
         name: "read SFDP",
         cmd: 0x5a,
         address_type: AddressType::NoOff3B,
-        pad_bytes: 0,
+        pad_bytes: 1,
     },
     SpiCmdValues {
         name: "write status register",
@@ -131,13 +170,13 @@ static SPI_COMMAND_LIST: &[SpiCmdValues] = &[
         name: "quad I/O read",
         cmd: 0xeb,
         address_type: AddressType::Dynamic,
-        pad_bytes: 0,
+        pad_bytes: 3,
     },
     SpiCmdValues {
         name: "quad read",
         cmd: 0x6b,
         address_type: AddressType::Dynamic,
-        pad_bytes: 0,
+        pad_bytes: 4,
     },
     SpiCmdValues {
         name: "quad I/O dt read",
@@ -185,31 +224,31 @@ static SPI_COMMAND_LIST: &[SpiCmdValues] = &[
         name: "fast read 4b",
         cmd: 0x0c,
         address_type: AddressType::Addr4B,
-        pad_bytes: 0,
+        pad_bytes: 1,
     },
     SpiCmdValues {
         name: "dual I/O read 4b",
         cmd: 0xbc,
         address_type: AddressType::Addr4B,
-        pad_bytes: 0,
+        pad_bytes: 2,
     },
     SpiCmdValues {
         name: "dual out read 4b",
         cmd: 0x3c,
         address_type: AddressType::Addr4B,
-        pad_bytes: 0,
+        pad_bytes: 2,
     },
     SpiCmdValues {
         name: "quad I/O read 4b",
         cmd: 0xec,
         address_type: AddressType::Addr4B,
-        pad_bytes: 0,
+        pad_bytes: 3,
     },
     SpiCmdValues {
         name: "quad out read 4b",
         cmd: 0x6c,
         address_type: AddressType::Addr4B,
-        pad_bytes: 0,
+        pad_bytes: 4,
     },
     SpiCmdValues {
         name: "quad I/O dt read 4b",
@@ -267,6 +306,142 @@ static SPI_COMMAND_LIST: &[SpiCmdValues] = &[
     },
 ];
 
+/// When to colorize trace output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colorize when stdout is a terminal and NO_COLOR is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Resolve the mode against the environment
+    pub fn enabled(self) -> bool {
+        self.resolve(
+            std::env::var_os("NO_COLOR").as_deref(),
+            io::stdout().is_terminal(),
+        )
+    }
+
+    /// Resolve the mode given NO_COLOR and whether stdout is a terminal
+    fn resolve(self, no_color: Option<&std::ffi::OsStr>, terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => no_color.is_none_or(|v| v.is_empty()) && terminal,
+        }
+    }
+}
+
+impl std::str::FromStr for ColorMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid color mode: {} (expected auto, always or never)",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for ColorMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorMode::Auto => write!(f, "auto"),
+            ColorMode::Always => write!(f, "always"),
+            ColorMode::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Output format of a trace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Human readable, data printed as it arrives
+    #[default]
+    Text,
+    /// One CSV row per transaction, after a header line
+    Csv,
+    /// One JSON object per transaction and line
+    Jsonl,
+    /// Value Change Dump for waveform viewers
+    Vcd,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(TraceFormat::Text),
+            "csv" => Ok(TraceFormat::Csv),
+            "jsonl" => Ok(TraceFormat::Jsonl),
+            "vcd" => Ok(TraceFormat::Vcd),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid trace format: {} (expected text, csv, jsonl or vcd)",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFormat::Text => write!(f, "text"),
+            TraceFormat::Csv => write!(f, "csv"),
+            TraceFormat::Jsonl => write!(f, "jsonl"),
+            TraceFormat::Vcd => write!(f, "vcd"),
+        }
+    }
+}
+
+/// Severity class of a traced SPI command, used for highlighting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Read,
+    Program,
+    Erase,
+    Status,
+    Em100,
+    Error,
+}
+
+impl Severity {
+    /// Classify an SPI command byte
+    pub fn classify(command: u8) -> Self {
+        match command {
+            0x02 | 0x12 | 0x38 | 0x3e => Severity::Program,
+            0x20 | 0x21 | 0x52 | 0x5c | 0x60 | 0xc7 | 0xd8 | 0xdc => Severity::Erase,
+            0x01 | 0x04 | 0x05 | 0x06 | 0x35 | 0xb7 | 0xe9 | 0xf5 => Severity::Status,
+            EM100_SPECIFIC_CMD => Severity::Em100,
+            _ if get_command_vals(command).cmd != command => Severity::Error,
+            _ => Severity::Read,
+        }
+    }
+
+    /// ANSI SGR sequence for this class, `None` for the default color
+    fn ansi(self) -> Option<&'static str> {
+        match self {
+            Severity::Read => None,
+            Severity::Program => Some("\x1b[33m"),
+            Severity::Erase => Some("\x1b[31m"),
+            Severity::Status => Some("\x1b[2m"),
+            Severity::Em100 => Some("\x1b[36m"),
+            Severity::Error => Some("\x1b[1;31m"),
+        }
+    }
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+
 fn get_command_vals(command: u8) -> &'static SpiCmdValues {
     SPI_COMMAND_LIST
         .iter()
@@ -274,45 +449,890 @@ fn get_command_vals(command: u8) -> &'static SpiCmdValues {
         .unwrap_or(&SPI_COMMAND_LIST[SPI_COMMAND_LIST.len() - 1])
 }
 
+/// Device trace timestamps count ticks of this frequency, unless the
+/// decoder knows better for the hardware
+const TIMESTAMP_HZ: u64 = 100_000_000;
+
+/// Seconds, to 10ns, of `ticks` counted at `hz`
+fn format_timestamp(ticks: u64, hz: u64) -> String {
+    format!(
+        "{:06}.{:08}",
+        ticks / hz,
+        (ticks % hz) as u128 * 100_000_000 / hz as u128
+    )
+}
+
+/// A decoded SPI transaction
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    /// Device timestamp of the transaction
+    pub timestamp: u64,
+    /// SPI command byte
+    pub command: u8,
+    /// Command mnemonic
+    pub name: &'static str,
+    /// Flash address, for commands that carry one
+    pub address: Option<u64>,
+    /// Data bytes following the command and address
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Time: {} : 0x{:02x} - {}",
+            format_timestamp(self.timestamp, TIMESTAMP_HZ),
+            self.command,
+            self.name
+        )?;
+        if let Some(address) = self.address {
+            write!(f, " @ 0x{:08x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects the transactions a trace prints
+///
+/// Both conditions have to hold. Filtered transactions are still decoded,
+/// so address mode switches and milestones are tracked as usual.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    /// SPI commands to print, all if empty
+    pub commands: Vec<u8>,
+    /// Inclusive address range to print, compared after the `-O` offset;
+    /// transactions without an address are dropped when set
+    pub range: Option<(u64, u64)>,
+}
+
+impl TraceFilter {
+    /// Whether `event` is printed when tracing with `addr_offset`
+    pub fn matches(&self, event: &TraceEvent, addr_offset: u64) -> bool {
+        if !self.commands.is_empty() && !self.commands.contains(&event.command) {
+            return false;
+        }
+        let Some((start, end)) = self.range else {
+            return true;
+        };
+        traced_address(event, addr_offset).is_some_and(|a| a >= start && a <= end)
+    }
+}
+
+/// Address of `event` as printed when tracing with `addr_offset`
+fn traced_address(event: &TraceEvent, addr_offset: u64) -> Option<u64> {
+    match get_command_vals(event.command).address_type {
+        AddressType::NoOff3B => event.address,
+        _ => event.address.map(|a| a + addr_offset),
+    }
+}
+
+/// Condition a trace waits for before printing anything
+///
+/// Transactions before it are still decoded, so address mode switches are
+/// followed, but dropped. Either condition fires the trigger.
+#[derive(Debug, Clone, Default)]
+pub struct TraceTrigger {
+    /// Fire on the first transaction with this SPI command
+    pub command: Option<u8>,
+    /// Fire on the first transaction accessing this address, compared after
+    /// the `-O` offset
+    pub address: Option<u64>,
+    /// Transactions before the trigger to print once it fires
+    pub pretrigger: usize,
+}
+
+impl TraceTrigger {
+    /// Whether any condition is set; a trace without one starts right away
+    pub fn is_set(&self) -> bool {
+        self.command.is_some() || self.address.is_some()
+    }
+
+    /// Whether `event` fires the trigger when tracing with `addr_offset`
+    pub fn matches(&self, event: &TraceEvent, addr_offset: u64) -> bool {
+        if self.command == Some(event.command) {
+            return true;
+        }
+        let (Some(target), Some(address)) = (self.address, traced_address(event, addr_offset))
+        else {
+            return false;
+        };
+        target >= address && target - address < (event.data.len() as u64).max(1)
+    }
+}
+
+/// A named flash region whose first access time is recorded during a trace
+#[derive(Debug, Clone)]
+pub struct Milestone {
+    pub name: String,
+    pub start: u64,
+    pub len: u64,
+    /// Device timestamp of the first transaction hitting the region
+    pub hit: Option<u64>,
+}
+
+impl Milestone {
+    pub fn new(name: &str, start: u64, len: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            len,
+            hit: None,
+        }
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        address >= self.start && address - self.start < self.len
+    }
+}
+
+/// A reached milestone, with its deltas in microseconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct MilestoneHit {
+    pub name: String,
+    /// Device timestamp of the first transaction hitting the region
+    pub timestamp: u64,
+    /// Time since the first traced flash access
+    pub from_first_us: f64,
+    /// Time since the previous milestone, or the first access
+    pub from_previous_us: f64,
+}
+
 /// SPI trace state
+///
+/// Decodes report buffers with a `TraceDecoder` and prints the transactions
+/// as they arrive.
 pub struct TraceState {
+    decoder: TraceDecoder,
+    printer: TracePrinter,
+    terminal: TerminalState,
+    trigger: TraceTrigger,
+    /// Whether the trigger fired, or there is none
+    triggered: bool,
+    /// The last transactions before the trigger fired
+    pretrigger: VecDeque<TraceEvent>,
+    profile: Option<BootProfile>,
+    history: VecDeque<Box<ReportData>>,
+    post_mortem_dir: Option<PathBuf>,
+    post_mortem_dumps: u32,
+    commands: u64,
+    first_data: Option<Instant>,
+}
+
+/// Page program opcodes firmware may write its trace console with
+pub const CONSOLE_WRITE_COMMANDS: [u8; 4] = [0x02, 0x12, 0x38, 0x3e];
+
+/// Sequential accesses merged into one line in brief mode
+struct AccessRun {
+    command: u8,
+    name: &'static str,
+    start: u64,
+    /// Address following the last data byte
+    end: u64,
+    commands: u64,
+}
+
+/// Formatting state of the printed trace
+struct TracePrinter {
     counter: u32,
-    curpos: u8,
-    cmdid: u8,
-    address_mode: u8,
-    outbytes: usize,
-    additional_pad_bytes: usize,
-    address: u64,
-    timestamp: u64,
     start_timestamp: u64,
     brief: bool,
+    data_max: Option<usize>,
+    color: bool,
+    milestones: Vec<Milestone>,
+    /// Rate of the device timestamps, from the decoder
+    timestamp_hz: u64,
+    first_access: Option<u64>,
+    filter: TraceFilter,
+    /// Opcodes whose data is printed in trace console mode
+    console_write_cmds: Vec<u8>,
+    format: TraceFormat,
+    /// Include data bytes in CSV and JSON records
+    record_data: bool,
+    header_written: bool,
+    vcd: vcd::VcdWriter,
+    /// Merge sequential accesses in brief mode
+    coalesce: bool,
+    run: Option<AccessRun>,
+    addr_offset: u64,
+    status: Option<StatusDecoder>,
+    /// Data bytes already printed of the transaction being received
+    shown: Option<usize>,
+    /// Same for the console buffer writes printed by the console mode
+    console_shown: Option<usize>,
+    out: TraceSink,
 }
 
 impl Default for TraceState {
     fn default() -> Self {
         Self {
-            counter: 0,
-            curpos: 0,
-            cmdid: 0xff, // timestamp, never a valid command id
-            address_mode: 3,
-            outbytes: 0,
-            additional_pad_bytes: 0,
-            address: 0,
-            timestamp: 0,
-            start_timestamp: 0,
-            brief: false,
+            decoder: TraceDecoder::new(3),
+            printer: TracePrinter {
+                counter: 0,
+                start_timestamp: 0,
+                brief: false,
+                data_max: None,
+                color: false,
+                milestones: Vec::new(),
+                timestamp_hz: TIMESTAMP_HZ,
+                first_access: None,
+                filter: TraceFilter::default(),
+                console_write_cmds: CONSOLE_WRITE_COMMANDS.to_vec(),
+                format: TraceFormat::Text,
+                record_data: false,
+                header_written: false,
+                vcd: vcd::VcdWriter::new(),
+                coalesce: true,
+                run: None,
+                addr_offset: 0,
+                status: Some(StatusDecoder::new()),
+                shown: None,
+                console_shown: None,
+                out: TraceSink::terminal(),
+            },
+            terminal: TerminalState::new(),
+            trigger: TraceTrigger::default(),
+            triggered: true,
+            pretrigger: VecDeque::new(),
+            profile: None,
+            history: VecDeque::new(),
+            post_mortem_dir: None,
+            post_mortem_dumps: 0,
+            commands: 0,
+            first_data: None,
         }
     }
 }
 
 impl TraceState {
     pub fn new(brief: bool, address_mode: u8) -> Self {
-        Self {
-            brief,
-            address_mode,
+        let mut state = Self {
+            decoder: TraceDecoder::new(address_mode),
             ..Default::default()
+        };
+        state.printer.brief = brief;
+        state
+    }
+
+    /// Limit the number of data bytes printed per transaction
+    pub fn with_data_max(mut self, data_max: Option<usize>) -> Self {
+        self.printer.data_max = data_max;
+        self
+    }
+
+    /// Write the decoded trace to `sink` instead of stdout
+    pub fn with_sink(mut self, sink: TraceSink) -> Self {
+        self.printer.out = sink;
+        self
+    }
+
+    /// The sink the decoded trace is written to
+    pub fn sink(&mut self) -> &mut TraceSink {
+        &mut self.printer.out
+    }
+
+    /// End the last transaction and flush the trace output, once tracing stops
+    pub fn finish(&mut self) -> Result<()> {
+        let last = self.decoder.finish().into_iter().collect();
+        for event in self.admit(last) {
+            self.printer.end(&event)?;
+        }
+        self.printer.end_run()?;
+        self.printer.vcd.finish(&mut self.printer.out)?;
+        self.printer.out.flush()?;
+        Ok(())
+    }
+
+    /// Write the trace in `format`, with data bytes in CSV/JSON records if `data`
+    ///
+    /// The records are written once a transaction is complete and carry at
+    /// most `with_data_max` data bytes.
+    pub fn with_format(mut self, format: TraceFormat, data: bool) -> Self {
+        self.printer.format = format;
+        self.printer.record_data = data;
+        self
+    }
+
+    /// In brief mode, merge sequential accesses into one line (on by default)
+    ///
+    /// Commands with the same opcode, each starting where the previous one's
+    /// data ended, are printed as a single address range once the run ends.
+    pub fn with_coalescing(mut self, enabled: bool) -> Self {
+        self.printer.coalesce = enabled;
+        self
+    }
+
+    /// Print the data of these opcodes in trace console mode
+    ///
+    /// Defaults to `CONSOLE_WRITE_COMMANDS`. Their address width comes from
+    /// the command table, as for any traced command.
+    pub fn with_console_write_commands(mut self, commands: Vec<u8>) -> Self {
+        self.printer.console_write_cmds = commands;
+        self
+    }
+
+    /// Print the host's lookup table messages as the strings in `table`
+    pub fn with_terminal_lookup_table(mut self, table: terminal::LookupTable) -> Self {
+        self.terminal = self.terminal.with_lookup_table(table);
+        self
+    }
+
+    /// Only start printing once `trigger` fires
+    pub fn with_trigger(mut self, trigger: TraceTrigger) -> Self {
+        self.triggered = !trigger.is_set();
+        self.trigger = trigger;
+        self
+    }
+
+    /// Count the reads of the trace in a `BootProfile`
+    pub fn with_boot_profile(mut self) -> Self {
+        self.profile = Some(BootProfile::new());
+        self
+    }
+
+    /// The boot profile, if enabled
+    pub fn boot_profile(&self) -> Option<&BootProfile> {
+        self.profile.as_ref()
+    }
+
+    /// Whether the trigger fired, always true without one
+    pub fn triggered(&self) -> bool {
+        self.triggered
+    }
+
+    /// Only print the transactions passing `filter`
+    pub fn with_filter(mut self, filter: TraceFilter) -> Self {
+        self.printer.filter = filter;
+        self
+    }
+
+    /// Highlight command lines by severity using ANSI colors
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.printer.color = color;
+        self
+    }
+
+    /// Decode report buffers according to this hardware's quirks
+    pub fn with_hw_version(mut self, hw_version: HwVersion) -> Self {
+        self.decoder = self.decoder.with_hw_version(hw_version);
+        self.printer.timestamp_hz = self.decoder.timestamp_hz();
+        self
+    }
+
+    /// Decode report buffers according to the firmware's quirks
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.decoder = self.decoder.with_quirks(quirks);
+        self
+    }
+
+    /// Annotate status register writes with the bits they change (on by default)
+    pub fn with_status_decoding(mut self, enabled: bool) -> Self {
+        self.printer.status = enabled.then(StatusDecoder::new);
+        self
+    }
+
+    /// Save the last raw report buffers to `dir` when decoding goes wrong
+    ///
+    /// Each dump is the raw contents of up to the last three trace reads,
+    /// oldest first, so the decoder can be debugged offline.
+    pub fn with_post_mortem_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.post_mortem_dir = dir;
+        self
+    }
+
+    /// Forget any partially decoded command after the USB stream was resynchronized
+    pub fn resync(&mut self) {
+        let last = self.decoder.resync().into_iter().collect();
+        for event in self.admit(last) {
+            self.printer.end(&event).ok();
+        }
+        self.printer.end_run().ok();
+        self.printer.console_shown = None;
+    }
+
+    /// Address mode (3 or 4) the host is currently using, as far as decoded
+    pub fn address_mode(&self) -> u8 {
+        self.decoder.address_mode()
+    }
+
+    /// Number of report buffers dropped because of a corrupt header
+    pub fn buffer_errors(&self) -> u32 {
+        self.decoder.buffer_errors()
+    }
+
+    /// Number of SPI commands decoded so far
+    pub fn commands(&self) -> u64 {
+        self.commands
+    }
+
+    /// Number of times the host's messages overflowed the uFIFO
+    pub fn terminal_overflows(&self) -> u32 {
+        self.terminal.overflows()
+    }
+
+    /// When the first report buffer with any packets was read
+    pub fn first_data(&self) -> Option<Instant> {
+        self.first_data
+    }
+
+    /// Decode one report buffer, keeping count of the decoded commands
+    fn decode(&mut self, report: &[u8; REPORT_BUFFER_LENGTH]) -> Vec<TraceEvent> {
+        if self.first_data.is_none() && (report[0] != 0 || report[1] != 0) {
+            self.first_data = Some(Instant::now());
+        }
+        let events = self.decoder.push_report(report);
+        let events = self.admit(events);
+        self.commands += events.len() as u64;
+        events
+    }
+
+    /// Decode one report buffer and print what it completed or added
+    fn print_report(&mut self, report: &[u8; REPORT_BUFFER_LENGTH]) -> io::Result<()> {
+        let text = self.printer.format == TraceFormat::Text;
+        for event in self.decode(report) {
+            if text {
+                self.printer.show(&event)?;
+            }
+            self.printer.end(&event)?;
+        }
+        // Nothing is shown before the trigger fired
+        if let Some(event) = self.decoder.pending().filter(|_| text && self.triggered) {
+            self.printer.show(event)?;
+        }
+        self.printer.out.flush_terminal();
+        Ok(())
+    }
+
+    /// Pass completed transactions through the trigger and into the profile
+    fn admit(&mut self, events: Vec<TraceEvent>) -> Vec<TraceEvent> {
+        let events = self.gate(events);
+        if let Some(profile) = self.profile.as_mut() {
+            for event in &events {
+                profile.record(event, self.printer.addr_offset);
+            }
+        }
+        events
+    }
+
+    /// Hold back transactions until the trigger fires
+    ///
+    /// The transaction firing it is returned after the kept pretrigger ones.
+    fn gate(&mut self, events: Vec<TraceEvent>) -> Vec<TraceEvent> {
+        if self.triggered {
+            return events;
+        }
+        let mut events = events.into_iter();
+        while let Some(event) = events.next() {
+            if self.trigger.matches(&event, self.printer.addr_offset) {
+                self.triggered = true;
+                let mut released: Vec<TraceEvent> = self.pretrigger.drain(..).collect();
+                released.push(event);
+                released.extend(events);
+                return released;
+            }
+            if self.trigger.pretrigger > 0 {
+                if self.pretrigger.len() == self.trigger.pretrigger {
+                    self.pretrigger.pop_front();
+                }
+                self.pretrigger.push_back(event);
+            }
+        }
+        Vec::new()
+    }
+
+    /// Record the first access to each of these regions
+    pub fn with_milestones(mut self, milestones: Vec<Milestone>) -> Self {
+        self.printer.milestones = milestones;
+        self
+    }
+
+    /// Recorded milestones, in the order they were given
+    pub fn milestones(&self) -> &[Milestone] {
+        &self.printer.milestones
+    }
+
+    /// Reached milestones, ordered by time of first access
+    ///
+    /// Deltas are converted to microseconds at the decoder's timestamp rate
+    /// for the hardware.
+    pub fn milestone_hits(&self) -> Vec<MilestoneHit> {
+        let hz = self.decoder.timestamp_hz();
+        let micros = |ticks: u64| ticks as f64 * 1_000_000.0 / hz as f64;
+        let first = self.printer.first_access.unwrap_or(0);
+        let mut hits: Vec<(&Milestone, u64)> = self
+            .printer
+            .milestones
+            .iter()
+            .filter_map(|m| Some((m, m.hit?)))
+            .collect();
+        hits.sort_by_key(|&(_, hit)| hit);
+
+        let mut previous = first;
+        hits.into_iter()
+            .map(|(m, hit)| {
+                let since_previous = hit - previous;
+                previous = hit;
+                MilestoneHit {
+                    name: m.name.clone(),
+                    timestamp: hit,
+                    from_first_us: micros(hit - first),
+                    from_previous_us: micros(since_previous),
+                }
+            })
+            .collect()
+    }
+
+    /// Print the milestone table, ordered by time of first access
+    pub fn print_milestones(&mut self) -> Result<()> {
+        if self.printer.milestones.is_empty() {
+            return Ok(());
+        }
+        let hz = self.decoder.timestamp_hz();
+        let hits = self.milestone_hits();
+
+        let out = &mut self.printer.out;
+        writeln!(out, "\nMilestones:")?;
+        writeln!(
+            out,
+            "{:<20} {:>15} {:>15} {:>15}",
+            "name", "timestamp", "from first", "from previous"
+        )?;
+        for hit in hits {
+            writeln!(
+                out,
+                "{:<20} {:>15} {:>12.3} us {:>12.3} us",
+                hit.name,
+                format_timestamp(hit.timestamp, hz),
+                hit.from_first_us,
+                hit.from_previous_us
+            )?;
+        }
+        for m in self.printer.milestones.iter().filter(|m| m.hit.is_none()) {
+            writeln!(out, "{:<20} {:>15}", m.name, "not reached")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Keep a raw batch for post-mortem dumps
+    fn remember(&mut self, reportdata: &ReportData) {
+        if self.post_mortem_dir.is_none() {
+            return;
+        }
+        if self.history.len() == POST_MORTEM_BATCHES {
+            self.history.pop_front();
+        }
+        self.history.push_back(Box::new(*reportdata));
+    }
+
+    /// Dump the kept batches if an anomaly was seen while decoding the last one
+    fn dump_post_mortem(&mut self) {
+        let Some(reason) = self.decoder.take_anomaly() else {
+            return;
+        };
+        let Some(dir) = &self.post_mortem_dir else {
+            return;
+        };
+        if self.post_mortem_dumps >= MAX_POST_MORTEM_DUMPS {
+            return;
+        }
+        self.post_mortem_dumps += 1;
+        match write_post_mortem(dir, &self.history) {
+            Ok(path) => log::warn!(
+                "Trace decode anomaly ({}), raw report buffers saved to {}",
+                reason,
+                path.display()
+            ),
+            Err(e) => log::warn!(
+                "Trace decode anomaly ({}), could not save raw report buffers: {}",
+                reason,
+                e
+            ),
+        }
+        self.history.clear();
+    }
+}
+
+impl TracePrinter {
+    /// Print the part of `event` not printed yet
+    ///
+    /// The command line is printed the first time a transaction is seen, its
+    /// data bytes as they arrive.
+    fn show(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let addr_offset = self.addr_offset;
+        if !self.filter.matches(event, addr_offset) {
+            self.record_access(event.timestamp, event.address);
+            return Ok(());
+        }
+        let shown = match self.shown {
+            Some(shown) => shown,
+            // Coalesced lines are printed once the transaction is complete
+            None if self.brief && self.coalesce => 0,
+            None => {
+                self.begin(event)?;
+                0
+            }
+        };
+        self.shown = Some(event.data.len());
+        if self.brief {
+            return Ok(());
+        }
+
+        let address_type = get_command_vals(event.command).address_type;
+        let max = self.data_max.unwrap_or(usize::MAX);
+        for (n, byte) in event.data.iter().enumerate().take(max).skip(shown) {
+            if n % 16 == 0 {
+                let address = event.address.unwrap_or(0) + n as u64;
+                match address_type {
+                    AddressType::Dynamic | AddressType::Addr3B | AddressType::Addr4B => {
+                        write!(self.out, "\n{:08x} : ", addr_offset + address)?;
+                    }
+                    AddressType::NoOff3B => {
+                        write!(self.out, "\n{:08x} : ", address)?;
+                    }
+                    AddressType::None => {
+                        write!(self.out, "\n         : ")?;
+                    }
+                }
+            }
+            write!(self.out, "{:02x} ", byte)?;
+        }
+        Ok(())
+    }
+
+    /// Print the command line of a new transaction
+    fn begin(&mut self, event: &TraceEvent) -> io::Result<()> {
+        if self.counter == 0 {
+            self.start_timestamp = event.timestamp;
+        }
+        self.record_access(event.timestamp, event.address);
+
+        let severity = Severity::classify(event.command);
+        if self.brief {
+            let line = match event.address {
+                Some(address) => format!(
+                    "0x{:02x} @ 0x{:08x} ({})",
+                    event.command, address, event.name
+                ),
+                None => format!("0x{:02x} ({})", event.command, event.name),
+            };
+            self.paint(severity, &line)?;
+            writeln!(self.out)
+        } else {
+            self.counter += 1;
+            let rel_time = event.timestamp - self.start_timestamp;
+            let line = format!(
+                "Time: {} command # {:<6} : 0x{:02x} - {}",
+                format_timestamp(rel_time, self.timestamp_hz),
+                self.counter,
+                event.command,
+                event.name
+            );
+            writeln!(self.out)?;
+            self.paint(severity, &line)
+        }
+    }
+
+    /// Finish printing a completed transaction
+    ///
+    /// Notes for suppressed data bytes and decoded status register writes
+    /// follow its last data line. In CSV and JSON the whole transaction is
+    /// written here.
+    fn end(&mut self, event: &TraceEvent) -> io::Result<()> {
+        if self.format != TraceFormat::Text {
+            return self.record(event);
+        }
+        if self.shown.take().is_none() {
+            return Ok(());
+        }
+        if self.brief && self.coalesce {
+            return self.coalesce(event);
+        }
+        if self.brief {
+            return Ok(());
+        }
+        let suppressed = event
+            .data
+            .len()
+            .saturating_sub(self.data_max.unwrap_or(usize::MAX));
+        if suppressed > 0 {
+            self.paint(Severity::Error, &format!("… (+{} bytes)", suppressed))?;
+        }
+        let note = self
+            .status
+            .as_mut()
+            .filter(|_| StatusDecoder::handles(event.command))
+            .and_then(|decoder| decoder.decode(event.command, &event.data));
+        if let Some(text) = note {
+            self.paint(Severity::Status, &format!("  ; {}", text))?;
+        }
+        Ok(())
+    }
+
+    /// Add a completed transaction to the current run of sequential accesses
+    ///
+    /// A transaction not continuing the run ends it; transactions without
+    /// address or data are printed right away.
+    fn coalesce(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let len = event.data.len() as u64;
+        let continues = self.run.as_ref().is_some_and(|run| {
+            run.command == event.command && Some(run.end) == event.address && len > 0
+        });
+        if continues {
+            self.record_access(event.timestamp, event.address);
+            if let Some(run) = self.run.as_mut() {
+                run.end += len;
+                run.commands += 1;
+            }
+            return Ok(());
+        }
+        self.end_run()?;
+        match event.address {
+            Some(address) if len > 0 => {
+                self.record_access(event.timestamp, event.address);
+                self.run = Some(AccessRun {
+                    command: event.command,
+                    name: event.name,
+                    start: address,
+                    end: address + len,
+                    commands: 1,
+                });
+                Ok(())
+            }
+            _ => self.begin(event),
+        }
+    }
+
+    /// Print the current run of sequential accesses, if any
+    fn end_run(&mut self) -> io::Result<()> {
+        let Some(run) = self.run.take() else {
+            return Ok(());
+        };
+        let line = if run.commands == 1 {
+            format!("0x{:02x} @ 0x{:08x} ({})", run.command, run.start, run.name)
+        } else {
+            format!(
+                "0x{:02x} {} 0x{:08x}..0x{:08x} ({} commands, {} bytes)",
+                run.command,
+                run.name,
+                run.start,
+                run.end,
+                run.commands,
+                run.end - run.start
+            )
+        };
+        self.paint(Severity::classify(run.command), &line)?;
+        writeln!(self.out)
+    }
+
+    /// Write a completed transaction as a CSV or JSON record
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        self.record_access(event.timestamp, event.address);
+        if !self.filter.matches(event, self.addr_offset) {
+            return Ok(());
+        }
+        let max = self.data_max.unwrap_or(usize::MAX);
+        let payload = &event.data[..event.data.len().min(max)];
+        match self.format {
+            TraceFormat::Csv => {
+                if !self.header_written {
+                    self.header_written = true;
+                    export::write_csv_header(&mut self.out)?;
+                }
+                let payload = if self.record_data { payload } else { &[] };
+                export::write_csv_row(&mut self.out, event, payload)
+            }
+            TraceFormat::Jsonl => {
+                export::write_jsonl_row(&mut self.out, event, self.record_data.then_some(payload))
+            }
+            TraceFormat::Vcd => self.vcd.write_event(&mut self.out, event),
+            TraceFormat::Text => Ok(()),
+        }
+    }
+
+    /// Print the console buffer writes in `event` not printed yet
+    fn show_console(
+        &mut self,
+        event: &TraceEvent,
+        addr_offset: u64,
+        addr_len: u64,
+    ) -> io::Result<()> {
+        let in_buffer = event
+            .address
+            .is_some_and(|a| a >= addr_offset && a <= addr_offset + addr_len);
+        if !self.console_write_cmds.contains(&event.command) || !in_buffer {
+            return Ok(());
+        }
+        let shown = self.console_shown.unwrap_or(0);
+        for byte in &event.data[shown..] {
+            write!(self.out, "{}", *byte as char)?;
+        }
+        self.console_shown = Some(event.data.len());
+        Ok(())
+    }
+
+    /// Note a transaction at `address` for milestone timing
+    fn record_access(&mut self, timestamp: u64, address: Option<u64>) {
+        self.first_access.get_or_insert(timestamp);
+        if let Some(address) = address {
+            for m in self.milestones.iter_mut() {
+                if m.hit.is_none() && m.contains(address) {
+                    m.hit = Some(timestamp);
+                }
+            }
         }
     }
+
+    /// Write `text` in the color for `severity` if colorizing is enabled
+    fn paint(&mut self, severity: Severity, text: &str) -> io::Result<()> {
+        let color = severity.ansi().filter(|_| self.color);
+        self.out.write_painted(color, text)
+    }
+}
+
+/// Write raw report batches to a new timestamped file in `dir`
+fn write_post_mortem(dir: &Path, history: &VecDeque<Box<ReportData>>) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let size = (history.len() * REPORT_BUFFER_COUNT * REPORT_BUFFER_LENGTH) as u64;
+    prune_post_mortems(dir, size)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = dir.join(format!(
+        "trace-{}.{:03}.bin",
+        now.as_secs(),
+        now.subsec_millis()
+    ));
+    let mut file = File::create(&path)?;
+    for report in history.iter().flat_map(|batch| batch.iter()) {
+        file.write_all(report)?;
+    }
+    Ok(path)
+}
+
+/// Delete the oldest dumps until `incoming` more bytes fit the size limit
+fn prune_post_mortems(dir: &Path, incoming: u64) -> Result<()> {
+    let mut dumps = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("trace-") && name.ends_with(".bin") {
+            dumps.push((name, entry.metadata()?.len()));
+        }
+    }
+    // Names are timestamps, so sorting them sorts by age
+    dumps.sort();
+
+    let mut total: u64 = dumps.iter().map(|(_, len)| len).sum();
+    for (name, len) in dumps {
+        if total + incoming <= MAX_POST_MORTEM_BYTES {
+            break;
+        }
+        fs::remove_file(dir.join(name))?;
+        total -= len;
+    }
+    Ok(())
 }
 
 /// Reset SPI trace buffer
@@ -323,7 +1343,7 @@ pub fn reset_spi_trace(em100: &Em100) -> Result<()> {
 }
 
 /// Read report buffer from device
-fn read_report_buffer(em100: &Em100) -> Result<[[u8; REPORT_BUFFER_LENGTH]; REPORT_BUFFER_COUNT]> {
+fn read_report_buffer(em100: &Em100) -> Result<ReportData> {
     let mut cmd = [0u8; 16];
     cmd[0] = 0xbc; // read SPI trace buffer
     cmd[4] = REPORT_BUFFER_COUNT as u8;
@@ -333,7 +1353,7 @@ fn read_report_buffer(em100: &Em100) -> Result<[[u8; REPORT_BUFFER_LENGTH]; REPO
 
     let mut reportdata = [[0u8; REPORT_BUFFER_LENGTH]; REPORT_BUFFER_COUNT];
 
-    for report in 0..REPORT_BUFFER_COUNT {
+    for report in reportdata.iter_mut() {
         let data = usb::get_response(em100, REPORT_BUFFER_LENGTH)?;
         if data.len() != REPORT_BUFFER_LENGTH {
             return Err(Error::Communication(format!(
@@ -342,7 +1362,7 @@ fn read_report_buffer(em100: &Em100) -> Result<[[u8; REPORT_BUFFER_LENGTH]; REPO
                 REPORT_BUFFER_LENGTH
             )));
         }
-        reportdata[report][..].copy_from_slice(&data);
+        report.copy_from_slice(&data);
     }
 
     Ok(reportdata)
@@ -356,150 +1376,19 @@ pub fn read_spi_trace(
     addr_offset: u64,
 ) -> Result<bool> {
     let reportdata = read_report_buffer(em100)?;
+    state.remember(&reportdata);
 
-    for report in 0..REPORT_BUFFER_COUNT {
-        let data = &reportdata[report];
-        let count = ((data[0] as usize) << 8) | (data[1] as usize);
-        if count == 0 {
-            continue;
-        }
-        let count = count.min(1023);
-
-        for i in 0..count {
-            let mut j = state.additional_pad_bytes;
-            state.additional_pad_bytes = 0;
-            let cmd = data[2 + i * 8];
-
-            if cmd == 0x00 {
-                // Packet without valid data
-                continue;
-            }
-            if cmd == 0xff {
-                // Timestamp
-                state.timestamp = (data[2 + i * 8 + 2] as u64) << 40
-                    | (data[2 + i * 8 + 3] as u64) << 32
-                    | (data[2 + i * 8 + 4] as u64) << 24
-                    | (data[2 + i * 8 + 5] as u64) << 16
-                    | (data[2 + i * 8 + 6] as u64) << 8
-                    | (data[2 + i * 8 + 7] as u64);
-                if display_terminal {
-                    read_spi_terminal(em100, true)?;
-                }
-                continue;
-            }
-
-            // Data packet
-            if cmd != state.cmdid {
-                let spi_command = data[i * 8 + 4];
-                let spi_cmd_vals = get_command_vals(spi_command);
-
-                state.cmdid = cmd;
-                if state.counter == 0 {
-                    state.start_timestamp = state.timestamp;
-                }
+    state.printer.addr_offset = addr_offset;
 
-                // Special commands
-                match spi_command {
-                    0xb7 => state.address_mode = 4,
-                    0xe9 => state.address_mode = 3,
-                    _ => {}
-                }
-
-                j = 1; // Skip command byte
-
-                let address_bytes = match spi_cmd_vals.address_type {
-                    AddressType::Dynamic => state.address_mode,
-                    AddressType::NoOff3B | AddressType::Addr3B => 3,
-                    AddressType::Addr4B => 4,
-                    AddressType::None => 0,
-                };
-
-                if address_bytes == 3 {
-                    state.address = ((data[i * 8 + 5] as u64) << 16)
-                        | ((data[i * 8 + 6] as u64) << 8)
-                        | (data[i * 8 + 7] as u64);
-                } else if address_bytes == 4 {
-                    state.address = ((data[i * 8 + 5] as u64) << 24)
-                        | ((data[i * 8 + 6] as u64) << 16)
-                        | ((data[i * 8 + 7] as u64) << 8)
-                        | (data[i * 8 + 8] as u64);
-                }
+    for report in &reportdata {
+        let timestamp = state.decoder.timestamp();
+        state.print_report(report)?;
 
-                state.address &= 0xffffffff;
-
-                j += address_bytes as usize + spi_cmd_vals.pad_bytes as usize;
-
-                const MAX_TRACE_BLOCKLENGTH: usize = 6;
-                if j > MAX_TRACE_BLOCKLENGTH {
-                    state.additional_pad_bytes = j - MAX_TRACE_BLOCKLENGTH;
-                    j = MAX_TRACE_BLOCKLENGTH;
-                }
-
-                if state.brief {
-                    if state.start_timestamp != 0 {
-                        state.start_timestamp = 0;
-                    }
-                    if spi_cmd_vals.address_type != AddressType::None {
-                        println!(
-                            "0x{:02x} @ 0x{:08x} ({})",
-                            spi_command, state.address, spi_cmd_vals.name
-                        );
-                    } else {
-                        println!("0x{:02x} ({})", spi_command, spi_cmd_vals.name);
-                    }
-                } else {
-                    state.counter += 1;
-                    let rel_time = state.timestamp - state.start_timestamp;
-                    print!(
-                        "\nTime: {:06}.{:08} command # {:<6} : 0x{:02x} - {}",
-                        rel_time / 100000000,
-                        rel_time % 100000000,
-                        state.counter,
-                        spi_command,
-                        spi_cmd_vals.name
-                    );
-                }
-
-                state.curpos = 0;
-                state.outbytes = 0;
-            }
-
-            if state.brief {
-                if state.outbytes > 0 {
-                    state.outbytes += 1;
-                }
-            } else {
-                let blocklen = ((data[2 + i * 8 + 1].wrapping_sub(state.curpos)) / 8) as usize;
-                let spi_cmd_vals = get_command_vals(data[i * 8 + 4]);
-
-                while j < blocklen {
-                    if state.outbytes == 0 {
-                        match spi_cmd_vals.address_type {
-                            AddressType::Dynamic | AddressType::Addr3B | AddressType::Addr4B => {
-                                print!("\n{:08x} : ", addr_offset + state.address);
-                            }
-                            AddressType::NoOff3B => {
-                                print!("\n{:08x} : ", state.address);
-                            }
-                            AddressType::None => {
-                                print!("\n         : ");
-                            }
-                        }
-                    }
-                    print!("{:02x} ", data[i * 8 + 4 + j]);
-                    state.outbytes += 1;
-                    if state.outbytes == 16 {
-                        state.outbytes = 0;
-                        state.address += 16;
-                    }
-                    j += 1;
-                }
-            }
-
-            state.curpos = data[2 + i * 8 + 1].wrapping_add(0x10);
-            io::stdout().flush().ok();
+        if display_terminal && state.decoder.timestamp() != timestamp {
+            read_spi_terminal_to(em100, &mut state.terminal, true, &mut state.printer.out)?;
         }
     }
+    state.dump_post_mortem();
 
     Ok(true)
 }
@@ -519,72 +1408,73 @@ pub enum HtMsgType {
 
 const UFIFO_SIZE: usize = 512;
 
-use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+/// Read SPI terminal messages, writing them to the trace's sink
+pub fn read_spi_terminal(em100: &Em100, state: &mut TraceState) -> Result<bool> {
+    read_spi_terminal_to(em100, &mut state.terminal, false, &mut state.printer.out)
+}
 
-static MSG_COUNTER: AtomicU32 = AtomicU32::new(1);
+/// Read SPI terminal messages, writing them to `out`
+///
+/// With `show_counter` every message is put on its own, numbered line. A
+/// uFIFO overflow since the last read is reported with a marker line.
+pub fn read_spi_terminal_to(
+    em100: &Em100,
+    terminal: &mut TerminalState,
+    show_counter: bool,
+    out: &mut TraceSink,
+) -> Result<bool> {
+    check_ht_status(em100, terminal, out)?;
+    drain_ufifo(em100, terminal, show_counter, out)
+}
 
-/// Read SPI terminal messages
-pub fn read_spi_terminal(em100: &Em100, show_counter: bool) -> Result<bool> {
-    let data = spi::read_ufifo(em100, UFIFO_SIZE, 0)?;
+/// Largest chunk of data the dFIFO takes at once
+pub const DFIFO_SIZE: usize = 512;
 
-    // First two bytes are the amount of valid data
-    let data_length = ((data[0] as usize) << 8) | (data[1] as usize);
-    if data_length == 0 {
-        return Ok(true);
+/// Exchange SPI terminal messages in both directions
+///
+/// Messages from the host are printed like by `read_spi_terminal`, but the
+/// uFIFO is only read when its status says it holds data. Once the dFIFO is
+/// empty, up to `DFIFO_SIZE` bytes of `input` are sent to the host and
+/// removed from `input`, so the host's buffer can't overflow.
+pub fn exchange_spi_terminal(
+    em100: &Em100,
+    state: &mut TraceState,
+    input: &mut Vec<u8>,
+) -> Result<bool> {
+    let status = check_ht_status(em100, &mut state.terminal, &mut state.printer.out)?;
+    if status & spi::UFIFO_EMPTY == 0 {
+        drain_ufifo(em100, &mut state.terminal, false, &mut state.printer.out)?;
     }
+    if status & spi::DFIFO_EMPTY != 0 && !input.is_empty() {
+        let len = input.len().min(DFIFO_SIZE);
+        spi::write_dfifo(em100, &input[..len], 0)?;
+        input.drain(..len);
+    }
+    Ok(true)
+}
 
-    // Actual data starts after the length
-    let data_start = 2;
-    let mut j = 0;
-
-    while j < data_length && j + 6 < UFIFO_SIZE - data_start {
-        let offset = data_start + j;
-
-        // Check for signature
-        let sig = ((data[offset] as u32) << 24)
-            | ((data[offset + 1] as u32) << 16)
-            | ((data[offset + 2] as u32) << 8)
-            | (data[offset + 3] as u32);
-
-        if sig == EM100_MSG_SIGNATURE {
-            let data_type = data[offset + 4];
-            let msg_len = data[offset + 5] as usize;
-
-            if show_counter {
-                print!("\nHT{:06}: ", MSG_COUNTER.load(AtomicOrdering::Relaxed));
-            }
-
-            // Print message bytes according to format
-            for k in 0..msg_len {
-                if offset + 6 + k >= data.len() {
-                    break;
-                }
-                if offset + 6 + k >= data_start + data_length {
-                    break;
-                }
-
-                let byte = data[offset + 6 + k];
-                match data_type {
-                    0x01..=0x04 | 0x06 => print!("{:02x} ", byte),
-                    0x05 => print!("{}", byte as char),
-                    0x07 => {
-                        // Lookup table - not fully supported
-                        if k + 1 < msg_len && offset + 6 + k + 1 < data.len() {
-                            print!("Lookup: {:02x}{:02x}", byte, data[offset + 6 + k + 1]);
-                        }
-                    }
-                    _ => print!("{:02x} ", byte),
-                }
-            }
-
-            j += 6 + msg_len;
-            MSG_COUNTER.fetch_add(1, AtomicOrdering::Relaxed);
-            io::stdout().flush().ok();
-        } else {
-            j += 1;
-        }
+/// Read the HT status register, reporting and acknowledging a uFIFO overflow
+fn check_ht_status(em100: &Em100, terminal: &mut TerminalState, out: &mut TraceSink) -> Result<u8> {
+    let status = spi::read_ht_register(em100, spi::HtRegister::Status)?;
+    if terminal.note_status(status) {
+        writeln!(out, "\n<overflow, messages may be lost>")?;
+        out.flush_terminal();
+        // Writing the status as set up by init_spi_terminal clears the flag
+        spi::write_ht_register(em100, spi::HtRegister::Status, spi::START_SPI_EMULATION)?;
     }
+    Ok(status)
+}
 
+/// Read the uFIFO and print the messages in it
+fn drain_ufifo(
+    em100: &Em100,
+    terminal: &mut TerminalState,
+    show_counter: bool,
+    out: &mut TraceSink,
+) -> Result<bool> {
+    let data = spi::read_ufifo(em100, UFIFO_SIZE, 0)?;
+    terminal.decode(&data, show_counter, out)?;
+    out.flush_terminal();
     Ok(true)
 }
 
@@ -601,108 +1491,433 @@ pub fn init_spi_terminal(em100: &Em100) -> Result<()> {
 }
 
 /// Read SPI trace in console mode
+///
+/// The console buffer is `addr_len` bytes at `addr_offset`. Both must be
+/// non-zero and the buffer must end within the 32-bit address space; the
+/// caller validates this once before tracing.
 pub fn read_spi_trace_console(
     em100: &Em100,
     state: &mut TraceState,
     addr_offset: u64,
     addr_len: u64,
 ) -> Result<bool> {
-    if addr_offset == 0 {
-        return Err(Error::InvalidArgument(
-            "Address offset for console buffer required".to_string(),
-        ));
+    let reportdata = read_report_buffer(em100)?;
+    state.remember(&reportdata);
+
+    for report in &reportdata {
+        for event in state.decode(report) {
+            state.printer.show_console(&event, addr_offset, addr_len)?;
+            state.printer.console_shown = None;
+        }
+        if let Some(event) = state.decoder.pending().filter(|_| state.triggered) {
+            state.printer.show_console(event, addr_offset, addr_len)?;
+        }
+        state.printer.out.flush_terminal();
     }
-    if addr_len == 0 {
-        return Err(Error::InvalidArgument(
-            "Console buffer length required".to_string(),
-        ));
+    state.dump_post_mortem();
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trace packet; `bits` is the transaction's bit count up to the end
+    /// of `payload`, plus 0x10 per earlier packet of it
+    fn packet(id: u8, bits: u8, payload: &[u8]) -> [u8; 8] {
+        let mut packet = [0u8; 8];
+        packet[0] = id;
+        packet[1] = bits;
+        packet[2..2 + payload.len()].copy_from_slice(payload);
+        packet
     }
 
-    let reportdata = read_report_buffer(em100)?;
+    /// A report buffer holding `packets`
+    fn report(packets: &[[u8; 8]]) -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        let mut report = Box::new([0u8; REPORT_BUFFER_LENGTH]);
+        report[1] = packets.len() as u8;
+        for (i, packet) in packets.iter().enumerate() {
+            report[2 + i * 8..10 + i * 8].copy_from_slice(packet);
+        }
+        report
+    }
 
-    for report in 0..REPORT_BUFFER_COUNT {
-        let data = &reportdata[report];
-        let count = ((data[0] as usize) << 8) | (data[1] as usize);
-        if count == 0 {
-            continue;
+    /// Decode `reports` with `state` and return everything it printed
+    fn traced(
+        name: &str,
+        state: TraceState,
+        reports: &[Box<[u8; REPORT_BUFFER_LENGTH]>],
+    ) -> String {
+        let path = std::env::temp_dir().join(format!("rem100-{}-{}", std::process::id(), name));
+        let mut state = state.with_sink(TraceSink::file(&path, false).unwrap());
+        for report in reports {
+            state.print_report(report).unwrap();
         }
-        let count = count.min(1023);
+        state.finish().unwrap();
+        drop(state);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        text
+    }
 
-        let mut do_write = false;
+    /// A read of eight bytes at 0x1000, still running when tracing stops
+    fn read_8_bytes() -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        report(&[
+            packet(1, 48, &[0x03, 0x00, 0x10, 0x00, 0xa0, 0xa1]),
+            packet(1, 64 + 48, &[0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7]),
+        ])
+    }
 
-        for i in 0..count {
-            let mut j = state.additional_pad_bytes;
-            state.additional_pad_bytes = 0;
-            let cmd = data[2 + i * 8];
+    #[test]
+    fn color_mode_resolution() {
+        use std::ffi::OsStr;
+        let set = Some(OsStr::new("1"));
+        let empty = Some(OsStr::new(""));
+        assert!(ColorMode::Auto.resolve(None, true));
+        assert!(ColorMode::Auto.resolve(empty, true));
+        assert!(!ColorMode::Auto.resolve(set, true));
+        assert!(!ColorMode::Auto.resolve(None, false));
+        for (no_color, terminal) in [(None, false), (set, true), (set, false)] {
+            assert!(ColorMode::Always.resolve(no_color, terminal));
+        }
+        for (no_color, terminal) in [(None, true), (empty, true), (None, false)] {
+            assert!(!ColorMode::Never.resolve(no_color, terminal));
+        }
+    }
 
-            if cmd != state.cmdid {
-                let spi_command = data[i * 8 + 4];
-                let spi_cmd_vals = get_command_vals(spi_command);
+    #[test]
+    fn color_mode_parses_and_prints() {
+        for mode in [ColorMode::Auto, ColorMode::Always, ColorMode::Never] {
+            assert_eq!(mode.to_string().parse::<ColorMode>().unwrap(), mode);
+        }
+        assert_eq!("ALWAYS".parse::<ColorMode>().unwrap(), ColorMode::Always);
+        assert!(matches!(
+            "sometimes".parse::<ColorMode>(),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
 
-                state.cmdid = cmd;
+    #[test]
+    fn commands_are_classified_by_severity() {
+        let cases = [
+            (0x03, Severity::Read),
+            (0x0b, Severity::Read),
+            (0x9f, Severity::Read),
+            (0x02, Severity::Program),
+            (0x12, Severity::Program),
+            (0x20, Severity::Erase),
+            (0xd8, Severity::Erase),
+            (0xc7, Severity::Erase),
+            (0x05, Severity::Status),
+            (0x01, Severity::Status),
+            (0xb7, Severity::Status),
+            (EM100_SPECIFIC_CMD, Severity::Em100),
+            (0x77, Severity::Error),
+        ];
+        for (command, severity) in cases {
+            assert_eq!(Severity::classify(command), severity, "0x{:02x}", command);
+        }
+    }
 
-                match spi_command {
-                    0xb7 => state.address_mode = 4,
-                    0xe9 => state.address_mode = 3,
-                    _ => {}
-                }
+    #[test]
+    fn timestamps_are_formatted_at_the_given_rate() {
+        assert_eq!(
+            format_timestamp(150_000_000, TIMESTAMP_HZ),
+            "000001.50000000"
+        );
+        assert_eq!(format_timestamp(3, 50_000_000), "000000.00000006");
+    }
 
-                j = 1;
-
-                let address_bytes = match spi_cmd_vals.address_type {
-                    AddressType::Dynamic => state.address_mode,
-                    AddressType::NoOff3B | AddressType::Addr3B => 3,
-                    AddressType::Addr4B => 4,
-                    AddressType::None => 0,
-                };
-
-                if address_bytes == 3 {
-                    state.address = ((data[i * 8 + 5] as u64) << 16)
-                        | ((data[i * 8 + 6] as u64) << 8)
-                        | (data[i * 8 + 7] as u64);
-                } else if address_bytes == 4 {
-                    state.address = ((data[i * 8 + 5] as u64) << 24)
-                        | ((data[i * 8 + 6] as u64) << 16)
-                        | ((data[i * 8 + 7] as u64) << 8)
-                        | (data[i * 8 + 8] as u64);
-                }
+    #[test]
+    fn milestones_are_timed_from_the_first_access() {
+        let mut state = TraceState::new(true, 3)
+            .with_hw_version(HwVersion::Em100ProG2)
+            .with_milestones(vec![
+                Milestone::new("late", 0x2000, 0x100),
+                Milestone::new("early", 0x1000, 0x100),
+                Milestone::new("never", 0x8000, 0x100),
+            ]);
+        let hz = state.decoder.timestamp_hz();
+        let printer = &mut state.printer;
+        printer.record_access(1000, Some(0x0));
+        printer.record_access(1000 + hz / 1000, Some(0x1010));
+        printer.record_access(1000 + hz / 100, Some(0x2000));
+        printer.record_access(1000 + hz, Some(0x1000));
+
+        let hits = state.milestone_hits();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].name, "early");
+        assert_eq!(hits[0].timestamp, 1000 + hz / 1000);
+        assert_eq!(hits[0].from_first_us, 1000.0);
+        assert_eq!(hits[0].from_previous_us, 1000.0);
+        assert_eq!(hits[1].name, "late");
+        assert_eq!(hits[1].from_first_us, 10_000.0);
+        assert_eq!(hits[1].from_previous_us, 9000.0);
+        assert!(state.milestones()[2].hit.is_none());
+    }
 
-                j += address_bytes as usize + spi_cmd_vals.pad_bytes as usize;
+    #[test]
+    fn suppressed_bytes_are_noted_when_tracing_stops() {
+        let state = TraceState::new(false, 3).with_data_max(Some(2));
+        let text = traced("data-max", state, &[read_8_bytes()]);
+        assert!(text.contains("00001000 : a0 a1 "), "{}", text);
+        assert!(!text.contains("a2"), "{}", text);
+        assert!(text.trim_end().ends_with("… (+6 bytes)"), "{}", text);
+    }
 
-                const MAX_TRACE_BLOCKLENGTH: usize = 6;
-                if j > MAX_TRACE_BLOCKLENGTH {
-                    state.additional_pad_bytes = j - MAX_TRACE_BLOCKLENGTH;
-                    j = MAX_TRACE_BLOCKLENGTH;
-                }
+    #[test]
+    fn uncapped_transactions_have_no_note() {
+        let text = traced("no-data-max", TraceState::new(false, 3), &[read_8_bytes()]);
+        assert!(text.contains("a0 a1 a2 a3 a4 a5 a6 a7"), "{}", text);
+        assert!(!text.contains("bytes)"), "{}", text);
+    }
 
-                state.curpos = 0;
-                do_write = spi_command == 0x02;
-            }
+    /// A JEDEC ID query and a read of two bytes at 0x1000
+    fn id_and_read() -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        report(&[
+            packet(0xff, 0, &[0, 0, 0, 0, 0x01, 0x00]),
+            packet(1, 32, &[0x9f, 0xef, 0x40, 0x14]),
+            packet(2, 48, &[0x03, 0x00, 0x10, 0x00, 0xa0, 0xa1]),
+        ])
+    }
 
-            if !do_write
-                || spi_cmd_vals_address_type(data[i * 8 + 4]) == AddressType::None
-                || state.address < addr_offset
-                || state.address > addr_offset + addr_len
-            {
-                state.curpos = data[2 + i * 8 + 1].wrapping_add(0x10);
-                continue;
-            }
+    #[test]
+    fn csv_rows_carry_the_decoded_fields() {
+        let state = TraceState::new(false, 3).with_format(TraceFormat::Csv, true);
+        let text = traced("csv-data", state, &[id_and_read()]);
+        let rows: Vec<Vec<&str>> = text.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(
+            rows,
+            [
+                vec!["timestamp", "command", "name", "address", "length", "data"],
+                vec!["256", "0x9f", "read JEDEC ID", "", "3", "ef4014"],
+                vec!["256", "0x03", "read", "0x00001000", "2", "a0a1"],
+            ]
+        );
+
+        // Without --trace-data the data column stays empty
+        let state = TraceState::new(false, 3).with_format(TraceFormat::Csv, false);
+        let text = traced("csv", state, &[id_and_read()]);
+        assert_eq!(text.lines().nth(2), Some("256,0x03,read,0x00001000,2,"));
+    }
 
-            let blocklen = ((data[2 + i * 8 + 1].wrapping_sub(state.curpos)) / 8) as usize;
+    #[test]
+    fn jsonl_records_carry_the_decoded_fields() {
+        let state = TraceState::new(false, 3).with_format(TraceFormat::Jsonl, true);
+        let text = traced("jsonl", state, &[id_and_read()]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"timestamp":256,"command":159,"name":"read JEDEC ID","address":null,"length":3,"data":"ef4014"}"#,
+                r#"{"timestamp":256,"command":3,"name":"read","address":4096,"length":2,"data":"a0a1"}"#,
+            ]
+        );
+    }
 
-            while j < blocklen {
-                print!("{}", data[i * 8 + 4 + j] as char);
-                j += 1;
-            }
+    /// Read `report` over a scripted device in console mode, with the
+    /// console buffer at 0x1000..=0x1100
+    fn console(name: &str, state: TraceState, report: Box<[u8; REPORT_BUFFER_LENGTH]>) -> String {
+        let mut mock = crate::mock::MockTransport::stock()
+            .command(crate::protocol::command(
+                0xbc,
+                &[0, 0, 0, REPORT_BUFFER_COUNT as u8, 0, 0, 0, 0, 0x15],
+            ))
+            .step(crate::mock::Step::Response(report.to_vec()));
+        for _ in 1..REPORT_BUFFER_COUNT {
+            mock = mock.step(crate::mock::Step::Response(vec![0; REPORT_BUFFER_LENGTH]));
+        }
+        let em100 = mock.open().unwrap();
+
+        let path = std::env::temp_dir().join(format!("rem100-{}-{}", std::process::id(), name));
+        let mut state = state
+            .with_sink(TraceSink::file(&path, false).unwrap())
+            .with_post_mortem_dir(None);
+        assert!(read_spi_trace_console(&em100, &mut state, 0x1000, 0x100).unwrap());
+        state.finish().unwrap();
+        drop(state);
+        mock.assert_done();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        text
+    }
+
+    /// Console writes with 4-byte page program, one outside the buffer,
+    /// and a 3-byte page program
+    fn console_writes() -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        report(&[
+            packet(1, 48, &[0x12, 0x00, 0x00, 0x10, 0x00, b'h']),
+            packet(1, 64 + 48, b"ello, "),
+            packet(2, 48, &[0x12, 0x00, 0x00, 0x20, 0x00, b'x']),
+            packet(3, 48, &[0x02, 0x00, 0x10, 0x08, b'o', b'k']),
+        ])
+    }
+
+    #[test]
+    fn console_shows_writes_of_every_page_program() {
+        let text = console("console", TraceState::new(false, 3), console_writes());
+        assert_eq!(text, "hello, ok");
+
+        // Only the opcodes asked for
+        let state = TraceState::new(false, 3).with_console_write_commands(vec![0x12]);
+        let text = console("console-0x12", state, console_writes());
+        assert_eq!(text, "hello, ");
+    }
 
-            state.curpos = data[2 + i * 8 + 1].wrapping_add(0x10);
-            io::stdout().flush().ok();
+    #[test]
+    fn terminal_overflows_are_marked_and_acknowledged() {
+        use crate::mock::{MockTransport, Step};
+        use crate::protocol::command;
+        let status = command(0x50, &[spi::HtRegister::Status as u8]);
+        let mut ufifo = vec![0u8, 8];
+        ufifo.extend(EM100_MSG_SIGNATURE.to_be_bytes());
+        ufifo.extend([HtMsgType::AsciiData as u8, 2, b'h', b'i']);
+        ufifo.resize(UFIFO_SIZE, 0);
+        let read_ufifo = |mock: MockTransport| {
+            mock.command(command(0x53, &[0x02, 0x00]))
+                .step(Step::Response(ufifo.clone()))
+                .step(Step::Response(vec![0; 2]))
+        };
+        let mock = MockTransport::stock()
+            .query(status, &[1, spi::START_SPI_EMULATION | spi::UFIFO_OVERFLOW])
+            .command(command(
+                0x51,
+                &[spi::HtRegister::Status as u8, spi::START_SPI_EMULATION],
+            ));
+        let mock = read_ufifo(read_ufifo(mock).query(status, &[1, spi::START_SPI_EMULATION]));
+        let em100 = mock.open().unwrap();
+
+        let path = std::env::temp_dir().join(format!("rem100-{}-overflow", std::process::id()));
+        let mut out = TraceSink::file(&path, false).unwrap();
+        let mut terminal = TerminalState::new();
+        for _ in 0..2 {
+            assert!(read_spi_terminal_to(&em100, &mut terminal, false, &mut out).unwrap());
         }
+        drop(out);
+        mock.assert_done();
+        assert_eq!(terminal.overflows(), 1);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text, "\n<overflow, messages may be lost>\nhihi");
     }
 
-    Ok(true)
-}
+    /// Two-byte reads at each of `addresses`, then a sector erase of
+    /// 0x8000 and a final status read
+    fn reads_then_erase(addresses: &[u32]) -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        let mut packets: Vec<[u8; 8]> = addresses
+            .iter()
+            .zip(1..)
+            .map(|(address, id)| {
+                let [_, a2, a1, a0] = address.to_be_bytes();
+                packet(id, 48, &[0x03, a2, a1, a0, 0xa0, 0xa1])
+            })
+            .collect();
+        let id = packets.len() as u8;
+        packets.push(packet(id + 1, 32, &[0x20, 0x00, 0x80, 0x00]));
+        packets.push(packet(id + 2, 16, &[0x05, 0x00]));
+        report(&packets)
+    }
+
+    /// Commands and addresses of the transactions `state` lets through
+    fn admitted(
+        mut state: TraceState,
+        report: &[u8; REPORT_BUFFER_LENGTH],
+    ) -> Vec<(u8, Option<u64>)> {
+        let mut events = state.decode(report);
+        let last = state.decoder.finish();
+        events.extend(state.admit(last.into_iter().collect()));
+        events.iter().map(|e| (e.command, e.address)).collect()
+    }
 
-fn spi_cmd_vals_address_type(cmd: u8) -> AddressType {
-    get_command_vals(cmd).address_type
+    #[test]
+    fn command_trigger_drops_what_came_before() {
+        let trigger = TraceTrigger {
+            command: Some(0x20),
+            ..Default::default()
+        };
+        let state = TraceState::new(false, 3).with_trigger(trigger.clone());
+        assert!(!state.triggered());
+        let report = reads_then_erase(&[0x1000, 0x2000, 0x3000]);
+        assert_eq!(
+            admitted(state, &report),
+            [(0x20, Some(0x8000)), (0x05, None)]
+        );
+
+        // The pretrigger keeps the last transactions before it
+        let state = TraceState::new(false, 3).with_trigger(TraceTrigger {
+            pretrigger: 2,
+            ..trigger
+        });
+        assert_eq!(
+            admitted(state, &report),
+            [
+                (0x03, Some(0x2000)),
+                (0x03, Some(0x3000)),
+                (0x20, Some(0x8000)),
+                (0x05, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn address_trigger_fires_on_data_covering_it() {
+        let at = |address| TraceTrigger {
+            address: Some(address),
+            ..Default::default()
+        };
+        let report = reads_then_erase(&[0x1000, 0x2000]);
+        // The second byte of the read at 0x2000
+        let state = TraceState::new(false, 3).with_trigger(at(0x2001));
+        assert_eq!(admitted(state, &report)[0], (0x03, Some(0x2000)));
+        // Past the data read there, the erase is the first to touch it
+        let state = TraceState::new(false, 3).with_trigger(at(0x8000));
+        assert_eq!(admitted(state, &report)[0], (0x20, Some(0x8000)));
+        let state = TraceState::new(false, 3).with_trigger(at(0x2002));
+        assert!(admitted(state, &report).is_empty());
+
+        // Compared after the -O offset
+        let event = TraceEvent {
+            timestamp: 0,
+            command: 0x03,
+            name: "read",
+            address: Some(0x1000),
+            data: vec![0; 4],
+        };
+        assert!(at(0xff001003).matches(&event, 0xff000000));
+        assert!(!at(0xff001004).matches(&event, 0xff000000));
+        assert!(!at(0x1000).matches(&event, 0xff000000));
+    }
+
+    #[test]
+    fn address_mode_is_followed_before_the_trigger() {
+        let state = TraceState::new(false, 3).with_trigger(TraceTrigger {
+            command: Some(0x03),
+            ..Default::default()
+        });
+        let report = report(&[
+            packet(1, 8, &[0xb7]),
+            packet(2, 48, &[0x03, 0x01, 0x00, 0x00, 0x00, 0xa0]),
+            packet(3, 16, &[0x05, 0x00]),
+        ]);
+        assert_eq!(
+            admitted(state, &report),
+            [(0x03, Some(0x01000000)), (0x05, None)]
+        );
+    }
+
+    #[test]
+    fn status_writes_are_annotated_unless_turned_off() {
+        // Write enable, then write status register with SR1 and SR2
+        let wrsr = || report(&[packet(1, 8, &[0x06]), packet(2, 24, &[0x01, 0x00, 0x02])]);
+        let text = traced("wrsr", TraceState::new(false, 3), &[wrsr()]);
+        assert!(
+            text.contains("; SR1=0x00 (none set), SR2=0x02 (QE)"),
+            "{}",
+            text
+        );
+        let state = TraceState::new(false, 3).with_status_decoding(false);
+        let text = traced("wrsr-off", state, &[wrsr()]);
+        assert!(!text.contains("SR1="), "{}", text);
+    }
 }
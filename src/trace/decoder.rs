@@ -0,0 +1,458 @@
+//! Decoding of raw trace report buffers into SPI transactions
+
+use super::{
+    get_command_vals, AddressType, TraceEvent, REPORT_BUFFER_LENGTH, REPORT_BUFFER_PACKETS,
+    TIMESTAMP_HZ,
+};
+use crate::device::{HwVersion, Quirks};
+
+/// Bytes of payload in a packet; command, address and padding that don't
+/// fit carry over into the next packet
+const MAX_TRACE_BLOCKLENGTH: usize = 6;
+
+/// Decoder turning the report buffers read from the EM100 into SPI transactions
+///
+/// A transaction can span several packets and report buffers, so it is only
+/// returned once the next one starts. `pending` shows the transaction still
+/// being received, `finish` ends it when tracing stops.
+pub struct TraceDecoder {
+    curpos: u8,
+    cmdid: u8,
+    address_mode: u8,
+    additional_pad_bytes: usize,
+    timestamp: u64,
+    hw_version: HwVersion,
+    quirks: Quirks,
+    buffer_errors: u32,
+    mode_switch_seen: bool,
+    mode_warned: bool,
+    anomaly: Option<String>,
+    current: Option<TraceEvent>,
+}
+
+impl TraceDecoder {
+    /// Decoder for a host starting in 3- or 4-byte `address_mode`
+    pub fn new(address_mode: u8) -> Self {
+        Self {
+            curpos: 0,
+            cmdid: 0xff, // timestamp, never a valid command id
+            address_mode,
+            additional_pad_bytes: 0,
+            timestamp: 0,
+            hw_version: HwVersion::Unknown,
+            quirks: Quirks::default(),
+            buffer_errors: 0,
+            mode_switch_seen: false,
+            mode_warned: false,
+            anomaly: None,
+            current: None,
+        }
+    }
+
+    /// Decode report buffers according to this hardware's quirks
+    pub fn with_hw_version(mut self, hw_version: HwVersion) -> Self {
+        self.hw_version = hw_version;
+        self
+    }
+
+    /// Decode report buffers according to the firmware's quirks
+    pub fn with_quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Decode one report buffer, returning the transactions it completed
+    pub fn push_report(&mut self, data: &[u8; REPORT_BUFFER_LENGTH]) -> Vec<TraceEvent> {
+        let mut events = Vec::new();
+        let Some(count) = self.packet_count(data) else {
+            events.extend(self.resync());
+            return events;
+        };
+
+        for i in 0..count {
+            let cmd = data[2 + i * 8];
+
+            if cmd == 0x00 {
+                // Packet without valid data
+                continue;
+            }
+            if cmd == 0xff {
+                self.timestamp = data[2 + i * 8 + 2..2 + i * 8 + 8]
+                    .iter()
+                    .fold(0u64, |t, b| (t << 8) | *b as u64);
+                continue;
+            }
+
+            // Data packet; padding left over from the previous one is
+            // skipped first, empty and timestamp packets don't carry any
+            let mut j = self.additional_pad_bytes;
+            self.additional_pad_bytes = 0;
+            if cmd != self.cmdid {
+                let spi_command = data[i * 8 + 4];
+                let spi_cmd_vals = get_command_vals(spi_command);
+
+                self.cmdid = cmd;
+                self.track_address_mode(spi_command, spi_cmd_vals.address_type);
+
+                let address_bytes = match spi_cmd_vals.address_type {
+                    AddressType::Dynamic => self.address_mode as usize,
+                    AddressType::NoOff3B | AddressType::Addr3B => 3,
+                    AddressType::Addr4B => 4,
+                    AddressType::None => 0,
+                };
+                let address = (address_bytes != 0).then(|| {
+                    data[i * 8 + 5..i * 8 + 5 + address_bytes]
+                        .iter()
+                        .fold(0u64, |a, b| (a << 8) | *b as u64)
+                        & 0xffffffff
+                });
+
+                // Skip command byte, address and padding
+                j = 1 + address_bytes + spi_cmd_vals.pad_bytes as usize;
+
+                events.extend(self.current.replace(TraceEvent {
+                    timestamp: self.timestamp,
+                    command: spi_command,
+                    name: spi_cmd_vals.name,
+                    address,
+                    data: Vec::new(),
+                }));
+                self.curpos = 0;
+            }
+
+            let blocklen = ((data[2 + i * 8 + 1].wrapping_sub(self.curpos)) / 8) as usize;
+            let blocklen = blocklen.min(MAX_TRACE_BLOCKLENGTH);
+            if j > blocklen {
+                // Quad reads can have more padding than one packet holds
+                self.additional_pad_bytes = j - blocklen;
+                j = blocklen;
+            }
+            if let Some(event) = self.current.as_mut().filter(|_| j < blocklen) {
+                event
+                    .data
+                    .extend_from_slice(&data[i * 8 + 4 + j..i * 8 + 4 + blocklen]);
+            }
+
+            self.curpos = data[2 + i * 8 + 1].wrapping_add(0x10);
+        }
+
+        events
+    }
+
+    /// The transaction still being received, if any
+    pub fn pending(&self) -> Option<&TraceEvent> {
+        self.current.as_ref()
+    }
+
+    /// End the transaction still being received, once no more data follows
+    pub fn finish(&mut self) -> Option<TraceEvent> {
+        self.current.take()
+    }
+
+    /// Forget any partially decoded command after the USB stream was resynchronized
+    ///
+    /// Returns the transaction that was being received, which ends here.
+    pub fn resync(&mut self) -> Option<TraceEvent> {
+        self.cmdid = 0xff;
+        self.curpos = 0;
+        self.additional_pad_bytes = 0;
+        self.current.take()
+    }
+
+    /// Address mode (3 or 4) the host is currently using, as far as decoded
+    pub fn address_mode(&self) -> u8 {
+        self.address_mode
+    }
+
+    /// Rate the device timestamps count at on this hardware, in Hz
+    ///
+    /// Every revision seen so far, the early EM100Pro included, counts at
+    /// 100MHz; unknown hardware is assumed to do the same.
+    pub fn timestamp_hz(&self) -> u64 {
+        match self.hw_version {
+            HwVersion::Em100ProEarly
+            | HwVersion::Em100Pro
+            | HwVersion::Em100ProG2
+            | HwVersion::Unknown => TIMESTAMP_HZ,
+        }
+    }
+
+    /// Device timestamp of the last timestamp packet
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Number of report buffers dropped because of a corrupt header
+    pub fn buffer_errors(&self) -> u32 {
+        self.buffer_errors
+    }
+
+    /// The first decode anomaly seen since the last call
+    pub(crate) fn take_anomaly(&mut self) -> Option<String> {
+        self.anomaly.take()
+    }
+
+    /// Note a decode anomaly; the first one is reported
+    fn note_anomaly(&mut self, reason: String) {
+        self.anomaly.get_or_insert(reason);
+    }
+
+    /// Follow host switches between 3- and 4-byte mode
+    ///
+    /// A 4-byte-only opcode before the host ever switched modes suggests the
+    /// decoder started in the wrong mode, which is reported once.
+    fn track_address_mode(&mut self, spi_command: u8, address_type: AddressType) {
+        match spi_command {
+            0xb7 => self.address_mode = 4,
+            0xe9 => self.address_mode = 3,
+            _ => {
+                if address_type == AddressType::Addr4B
+                    && self.address_mode == 3
+                    && !self.mode_switch_seen
+                    && !self.mode_warned
+                {
+                    self.mode_warned = true;
+                    self.note_anomaly(format!(
+                        "4-byte opcode 0x{:02x} in 3-byte mode",
+                        spi_command
+                    ));
+                    log::warn!(
+                        "4-byte opcode 0x{:02x} seen while decoding in 3-byte address mode, \
+                         addresses may be wrong. Use -m 4 if the chip starts in 4-byte mode.",
+                        spi_command
+                    );
+                }
+                return;
+            }
+        }
+        self.mode_switch_seen = true;
+    }
+
+    /// Number of packets to decode from a report buffer
+    ///
+    /// Early EM100Pro hardware and old FPGAs never report more packets than
+    /// fit in the buffer, so a larger count there means a corrupt header: the
+    /// buffer is dropped (`None`) and decoding resynchronizes on the next
+    /// command. Later FPGAs may report the total number of packets seen, of
+    /// which only the buffered ones can be decoded.
+    fn packet_count(&mut self, data: &[u8]) -> Option<usize> {
+        let count = ((data[0] as usize) << 8) | (data[1] as usize);
+        if count <= REPORT_BUFFER_PACKETS {
+            return Some(count);
+        }
+        if self.hw_version == HwVersion::Em100ProEarly || self.quirks.legacy_trace_framing {
+            self.note_anomaly(format!("report buffer count {} out of range", count));
+            self.buffer_errors += 1;
+            return None;
+        }
+        self.note_anomaly(format!(
+            "report buffer overflow, {} packets for {} slots",
+            count, REPORT_BUFFER_PACKETS
+        ));
+        Some(REPORT_BUFFER_PACKETS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A trace packet; `bits` is the transaction's bit count up to the end
+    /// of `payload`, plus 0x10 per earlier packet of it
+    fn packet(id: u8, bits: u8, payload: &[u8]) -> [u8; 8] {
+        let mut packet = [0u8; 8];
+        packet[0] = id;
+        packet[1] = bits;
+        packet[2..2 + payload.len()].copy_from_slice(payload);
+        packet
+    }
+
+    /// A report buffer holding `packets` under a header counting `count`
+    fn report(count: u16, packets: &[[u8; 8]]) -> Box<[u8; REPORT_BUFFER_LENGTH]> {
+        let mut report = Box::new([0u8; REPORT_BUFFER_LENGTH]);
+        report[..2].copy_from_slice(&count.to_be_bytes());
+        for (i, packet) in packets.iter().enumerate() {
+            report[2 + i * 8..10 + i * 8].copy_from_slice(packet);
+        }
+        report
+    }
+
+    /// A 3-byte read of 0x001000 with two data bytes
+    fn read() -> [u8; 8] {
+        packet(1, 48, &[0x03, 0x00, 0x10, 0x00, 0xa0, 0xa1])
+    }
+
+    fn decoder(hw_version: HwVersion, quirks: Quirks) -> TraceDecoder {
+        TraceDecoder::new(3)
+            .with_hw_version(hw_version)
+            .with_quirks(quirks)
+    }
+
+    /// The fields a test cares about
+    fn fields(event: &TraceEvent) -> (u64, u8, Option<u64>, &[u8]) {
+        (event.timestamp, event.command, event.address, &event.data)
+    }
+
+    #[test]
+    fn events_are_decoded_from_a_report() {
+        let data = report(
+            8,
+            &[
+                packet(0xff, 0, &[0, 0, 0, 0, 0x12, 0x34]),
+                packet(1, 32, &[0x9f, 0xef, 0x40, 0x14]),
+                packet(2, 16, &[0x05, 0x00]),
+                // Fast read: address, one dummy byte, then data
+                packet(3, 48, &[0x0b, 0x00, 0x20, 0x00, 0x00, 0xd0]),
+                packet(3, 64 + 48, &[0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6]),
+                packet(0xff, 0, &[0, 0, 0, 0, 0x56, 0x78]),
+                packet(4, 8, &[0xb7]),
+                packet(5, 48, &[0x03, 0x01, 0x00, 0x00, 0x00, 0xe0]),
+            ],
+        );
+        let mut decoder = TraceDecoder::new(3);
+        let events = decoder.push_report(&data);
+        let events: Vec<_> = events.iter().map(fields).collect();
+        assert_eq!(
+            events,
+            [
+                (0x1234, 0x9f, None, &[0xef, 0x40, 0x14][..]),
+                (0x1234, 0x05, None, &[0x00][..]),
+                (
+                    0x1234,
+                    0x0b,
+                    Some(0x2000),
+                    &[0xd0, 0xd1, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6][..]
+                ),
+                (0x5678, 0xb7, None, &[][..]),
+            ]
+        );
+        assert_eq!(decoder.pending().map(|event| event.command), Some(0x03));
+        // The read after entering 4-byte mode has a 4-byte address
+        assert_eq!(decoder.address_mode(), 4);
+        let last = decoder.finish().unwrap();
+        assert_eq!(fields(&last), (0x5678, 0x03, Some(0x01000000), &[0xe0][..]));
+        assert_eq!(decoder.timestamp(), 0x5678);
+    }
+
+    #[test]
+    fn transactions_continue_across_reports() {
+        let mut decoder = TraceDecoder::new(3);
+        assert!(decoder.push_report(&report(1, &[read()])).is_empty());
+        let more = packet(1, 64 + 48, &[0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7]);
+        let events = decoder.push_report(&report(2, &[more, packet(2, 8, &[0x06])]));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            fields(&events[0]),
+            (
+                0,
+                0x03,
+                Some(0x1000),
+                &[0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7][..]
+            )
+        );
+        assert_eq!(decoder.finish().unwrap().command, 0x06);
+        assert!(decoder.finish().is_none());
+    }
+
+    /// Data bytes 0xd0, 0xd1, ... of a read, six per packet, after `pad`
+    /// dummy bytes at the start of the first packet
+    fn read_data(id: u8, pad: usize, packets: u8) -> Vec<[u8; 8]> {
+        let mut bytes = vec![0u8; pad];
+        bytes.extend((0..6 * packets as usize - pad).map(|i| 0xd0 + i as u8));
+        bytes
+            .chunks(6)
+            .enumerate()
+            .map(|(n, chunk)| packet(id, 64 * (n as u8 + 1) + 48, chunk))
+            .collect()
+    }
+
+    #[test]
+    fn dual_output_read_data_starts_after_two_dummy_bytes() {
+        // Command, address and both dummy bytes fill the first packet
+        let mut packets = vec![packet(1, 48, &[0x3b, 0x00, 0x10, 0x00, 0xff, 0xff])];
+        packets.extend(read_data(1, 0, 1));
+        let mut decoder = TraceDecoder::new(3);
+        assert!(decoder.push_report(&report(2, &packets)).is_empty());
+        let event = decoder.finish().unwrap();
+        assert_eq!(event.address, Some(0x1000));
+        assert_eq!(event.data, [0xd0, 0xd1, 0xd2, 0xd3, 0xd4, 0xd5]);
+
+        // With a 4-byte address the second dummy byte is in the next packet
+        let mut packets = vec![packet(1, 48, &[0x3b, 0x01, 0x00, 0x10, 0x00, 0xff])];
+        packets.extend(read_data(1, 1, 1));
+        let mut decoder = TraceDecoder::new(4);
+        assert!(decoder.push_report(&report(2, &packets)).is_empty());
+        let event = decoder.finish().unwrap();
+        assert_eq!(event.address, Some(0x01001000));
+        assert_eq!(event.data, [0xd0, 0xd1, 0xd2, 0xd3, 0xd4]);
+    }
+
+    #[test]
+    fn quad_io_read_padding_carries_across_reports() {
+        // Command, address and two of the three mode/dummy bytes fill the
+        // last packet of a report buffer; the third dummy byte opens the
+        // next report, after a timestamp
+        let first = packet(1, 48, &[0xeb, 0x00, 0x20, 0x00, 0xa5, 0xff]);
+        let mut decoder = TraceDecoder::new(3);
+        let events = decoder.push_report(&report(2, &[packet(2, 8, &[0x06]), first]));
+        assert_eq!(events.len(), 1);
+        assert!(decoder.pending().unwrap().data.is_empty());
+
+        let mut packets = vec![packet(0xff, 0, &[0, 0, 0, 0, 0x10, 0x00])];
+        packets.extend(read_data(1, 1, 2));
+        assert!(decoder.push_report(&report(3, &packets)).is_empty());
+        let event = decoder.finish().unwrap();
+        assert_eq!((event.command, event.address), (0xeb, Some(0x2000)));
+        let expected: Vec<u8> = (0xd0..0xdb).collect();
+        assert_eq!(event.data, expected);
+    }
+
+    #[test]
+    fn counts_up_to_the_buffer_size_are_taken_as_is() {
+        for hw_version in [HwVersion::Em100ProEarly, HwVersion::Em100ProG2] {
+            let mut decoder = decoder(hw_version, Quirks::default());
+            let data = report(0, &[]);
+            assert_eq!(decoder.packet_count(&data[..]), Some(0));
+            let data = report(REPORT_BUFFER_PACKETS as u16, &[]);
+            assert_eq!(decoder.packet_count(&data[..]), Some(REPORT_BUFFER_PACKETS));
+            assert_eq!(decoder.buffer_errors(), 0);
+            assert_eq!(decoder.take_anomaly(), None);
+        }
+    }
+
+    #[test]
+    fn later_fpgas_decode_the_buffered_part_of_an_overflow() {
+        for hw_version in [HwVersion::Em100Pro, HwVersion::Em100ProG2] {
+            let mut decoder = decoder(hw_version, Quirks::default());
+            assert!(decoder.push_report(&report(0x1000, &[read()])).is_empty());
+            let event = decoder.finish().unwrap();
+            assert_eq!((event.command, event.address), (0x03, Some(0x1000)));
+            assert_eq!(event.data, [0xa0, 0xa1]);
+            assert_eq!(decoder.buffer_errors(), 0);
+            let anomaly = decoder.take_anomaly().unwrap();
+            assert!(anomaly.contains("overflow, 4096 packets"), "{}", anomaly);
+        }
+    }
+
+    #[test]
+    fn legacy_framing_drops_a_buffer_with_an_impossible_count() {
+        let legacy = Quirks {
+            legacy_trace_framing: true,
+            ..Default::default()
+        };
+        for mut decoder in [
+            decoder(HwVersion::Em100ProEarly, Quirks::default()),
+            decoder(HwVersion::Em100Pro, legacy),
+        ] {
+            assert!(decoder.push_report(&report(1, &[read()])).is_empty());
+            // The transaction in progress ends with the corrupt buffer
+            let ended = decoder.push_report(&report(0x1000, &[read()]));
+            assert_eq!(ended.len(), 1);
+            assert_eq!(ended[0].data, [0xa0, 0xa1]);
+            assert!(decoder.pending().is_none());
+            assert_eq!(decoder.buffer_errors(), 1);
+            let anomaly = decoder.take_anomaly().unwrap();
+            assert!(anomaly.contains("count 4096 out of range"), "{}", anomaly);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Export decoded trace events to files
+
+use super::{StatusDecoder, TraceEvent};
+use std::io::{self, Write};
+
+/// Write events in the same layout as the interactive trace
+///
+/// Status register writes are annotated with the bits they change.
+pub fn write_text<W: Write>(w: &mut W, events: &[TraceEvent]) -> io::Result<()> {
+    let mut status = StatusDecoder::new();
+    for event in events {
+        writeln!(w, "{}", event)?;
+        let note = status.decode(event.command, &event.data);
+        let lines = event.data.chunks(16).count();
+        for (i, line) in event.data.chunks(16).enumerate() {
+            match event.address {
+                Some(address) => write!(w, "{:08x} :", address + i as u64 * 16)?,
+                None => write!(w, "         :")?,
+            }
+            for byte in line {
+                write!(w, " {:02x}", byte)?;
+            }
+            if let (Some(note), true) = (&note, i + 1 == lines) {
+                write!(w, "  ; {}", note)?;
+            }
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write events as CSV, one transaction per row
+pub fn write_csv<W: Write>(w: &mut W, events: &[TraceEvent]) -> io::Result<()> {
+    write_csv_header(w)?;
+    for event in events {
+        write_csv_row(w, event, &event.data)?;
+    }
+    Ok(())
+}
+
+/// Write the CSV header line
+pub fn write_csv_header<W: Write>(w: &mut W) -> io::Result<()> {
+    writeln!(w, "timestamp,command,name,address,length,data")
+}
+
+/// Write one transaction as a CSV row
+///
+/// `length` is the transaction's full data length; `payload`, which may
+/// be a prefix of the data or empty, goes into the data column as hex.
+pub fn write_csv_row<W: Write>(w: &mut W, event: &TraceEvent, payload: &[u8]) -> io::Result<()> {
+    let address = event
+        .address
+        .map(|a| format!("0x{:08x}", a))
+        .unwrap_or_default();
+    writeln!(
+        w,
+        "{},0x{:02x},{},{},{},{}",
+        event.timestamp,
+        event.command,
+        event.name,
+        address,
+        event.data.len(),
+        hex(payload)
+    )
+}
+
+/// Write one transaction as a JSON object on its own line
+///
+/// The `data` member holds `payload` as hex and is left out if `payload`
+/// is `None`.
+pub fn write_jsonl_row<W: Write>(
+    w: &mut W,
+    event: &TraceEvent,
+    payload: Option<&[u8]>,
+) -> io::Result<()> {
+    let address = event
+        .address
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "null".to_string());
+    write!(
+        w,
+        "{{\"timestamp\":{},\"command\":{},\"name\":\"{}\",\"address\":{},\"length\":{}",
+        event.timestamp,
+        event.command,
+        event.name,
+        address,
+        event.data.len()
+    )?;
+    if let Some(payload) = payload {
+        write!(w, ",\"data\":\"{}\"", hex(payload))?;
+    }
+    writeln!(w, "}}")
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
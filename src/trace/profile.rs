@@ -0,0 +1,150 @@
+//! Boot profile: which parts of the flash a host reads, how much and when
+
+use super::{traced_address, Severity, TraceEvent, TIMESTAMP_HZ};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Size of the regions reads are counted in
+pub const REGION_SIZE: u64 = 64 * 1024;
+
+/// Reads of one region
+#[derive(Debug, Clone)]
+pub struct RegionStats {
+    /// Read transactions touching the region
+    pub reads: u64,
+    /// Bytes read from the region, counting rereads
+    pub bytes: u64,
+    /// Device timestamp of the first read
+    pub first_access: u64,
+    /// Bytes of the region read at least once, one bit each
+    seen: Vec<u64>,
+}
+
+impl RegionStats {
+    fn new(first_access: u64) -> Self {
+        Self {
+            reads: 0,
+            bytes: 0,
+            first_access,
+            seen: vec![0; REGION_SIZE as usize / 64],
+        }
+    }
+
+    /// Bytes of the region read at least once
+    pub fn unique_bytes(&self) -> u64 {
+        self.seen.iter().map(|w| w.count_ones() as u64).sum()
+    }
+
+    fn mark(&mut self, offset: u64, len: u64) {
+        for i in offset..offset + len {
+            self.seen[(i / 64) as usize] |= 1 << (i % 64);
+        }
+    }
+}
+
+/// Accumulator of the read transactions of a trace, per `REGION_SIZE` region
+///
+/// Addresses include the `-O` offset, so regions line up with the host's
+/// memory map.
+#[derive(Debug, Clone, Default)]
+pub struct BootProfile {
+    regions: BTreeMap<u64, RegionStats>,
+    first: Option<u64>,
+    last: u64,
+}
+
+impl BootProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `event` if it reads data
+    pub fn record(&mut self, event: &TraceEvent, addr_offset: u64) {
+        if Severity::classify(event.command) != Severity::Read || event.data.is_empty() {
+            return;
+        }
+        let Some(mut address) = traced_address(event, addr_offset) else {
+            return;
+        };
+        self.first.get_or_insert(event.timestamp);
+        self.last = event.timestamp;
+
+        let mut remaining = event.data.len() as u64;
+        while remaining > 0 {
+            let offset = address % REGION_SIZE;
+            let len = remaining.min(REGION_SIZE - offset);
+            let region = self
+                .regions
+                .entry(address - offset)
+                .or_insert_with(|| RegionStats::new(event.timestamp));
+            region.reads += 1;
+            region.bytes += len;
+            region.mark(offset, len);
+            address += len;
+            remaining -= len;
+        }
+    }
+
+    /// Regions read, by start address
+    pub fn regions(&self) -> &BTreeMap<u64, RegionStats> {
+        &self.regions
+    }
+
+    /// The `n` regions the most bytes were read from, most first
+    pub fn hottest(&self, n: usize) -> Vec<(u64, &RegionStats)> {
+        let mut regions: Vec<_> = self.regions.iter().map(|(a, r)| (*a, r)).collect();
+        regions.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(&b.0)));
+        regions.truncate(n);
+        regions
+    }
+
+    /// Bytes read in total, counting rereads
+    pub fn total_bytes(&self) -> u64 {
+        self.regions.values().map(|r| r.bytes).sum()
+    }
+
+    /// Bytes read at least once
+    pub fn unique_bytes(&self) -> u64 {
+        self.regions.values().map(RegionStats::unique_bytes).sum()
+    }
+
+    /// Device time from the first to the last read, in seconds
+    pub fn duration(&self) -> f64 {
+        self.since_first(self.last)
+    }
+
+    /// Device time of `timestamp` after the first read, in seconds
+    pub fn since_first(&self, timestamp: u64) -> f64 {
+        let first = self.first.unwrap_or(timestamp);
+        timestamp.saturating_sub(first) as f64 / TIMESTAMP_HZ as f64
+    }
+
+    /// Write the profile as a JSON document
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"region_size\": {},", REGION_SIZE)?;
+        writeln!(w, "  \"total_bytes\": {},", self.total_bytes())?;
+        writeln!(w, "  \"unique_bytes\": {},", self.unique_bytes())?;
+        match self.first {
+            Some(first) => writeln!(w, "  \"first_timestamp\": {},", first)?,
+            None => writeln!(w, "  \"first_timestamp\": null,")?,
+        }
+        writeln!(w, "  \"duration_s\": {:.8},", self.duration())?;
+        writeln!(w, "  \"regions\": [")?;
+        for (i, (start, region)) in self.regions.iter().enumerate() {
+            writeln!(
+                w,
+                "    {{\"start\": {}, \"reads\": {}, \"bytes\": {}, \"unique_bytes\": {}, \"first_access\": {}, \"first_access_s\": {:.8}}}{}",
+                start,
+                region.reads,
+                region.bytes,
+                region.unique_bytes(),
+                region.first_access,
+                self.since_first(region.first_access),
+                if i + 1 < self.regions.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+}
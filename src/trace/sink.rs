@@ -0,0 +1,100 @@
+//! Destinations for decoded trace output
+
+use crate::error::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Where decoded trace output goes: the terminal, a file, or both
+///
+/// Colors are only sent to the terminal, the file gets plain text. Errors
+/// writing the terminal are ignored like `print!` would, errors writing the
+/// file are returned so a full disk doesn't silently truncate the trace.
+pub struct TraceSink {
+    terminal: bool,
+    file: Option<BufWriter<File>>,
+}
+
+impl Default for TraceSink {
+    fn default() -> Self {
+        Self::terminal()
+    }
+}
+
+impl TraceSink {
+    /// Write to stdout only
+    pub fn terminal() -> Self {
+        Self {
+            terminal: true,
+            file: None,
+        }
+    }
+
+    /// Write to `path` only
+    pub fn file(path: &Path, append: bool) -> Result<Self> {
+        Ok(Self {
+            terminal: false,
+            file: Some(open(path, append)?),
+        })
+    }
+
+    /// Also write to `path`, truncating it unless `append` is set
+    pub fn tee(mut self, path: &Path, append: bool) -> Result<Self> {
+        self.file = Some(open(path, append)?);
+        Ok(self)
+    }
+
+    /// Write `text`, wrapped in the ANSI sequence `color` on the terminal
+    pub(crate) fn write_painted(&mut self, color: Option<&str>, text: &str) -> io::Result<()> {
+        if self.terminal {
+            let mut stdout = io::stdout().lock();
+            let _ = match color {
+                Some(code) => write!(stdout, "{}{}{}", code, text, super::ANSI_RESET),
+                None => stdout.write_all(text.as_bytes()),
+            };
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(text.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Show what was written so far on the terminal
+    ///
+    /// The file is left buffered; it is flushed by `flush` and on drop.
+    pub(crate) fn flush_terminal(&mut self) {
+        if self.terminal {
+            io::stdout().flush().ok();
+        }
+    }
+}
+
+impl Write for TraceSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.terminal {
+            io::stdout().write_all(buf).ok();
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_terminal();
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn open(path: &Path, append: bool) -> io::Result<BufWriter<File>> {
+    let mut options = OpenOptions::new();
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).truncate(true);
+    }
+    Ok(BufWriter::new(options.create(true).open(path)?))
+}
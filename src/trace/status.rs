@@ -0,0 +1,173 @@
+//! Annotate write status register transactions with the bits they change
+
+/// Bit names of one status register, LSB first; `None` for reserved bits
+type BitNames = [Option<&'static str>; 8];
+
+/// Generic JEDEC layout, as used by Winbond, GigaDevice and most others
+const SR1_BITS: BitNames = [
+    Some("BUSY"),
+    Some("WEL"),
+    Some("BP0"),
+    Some("BP1"),
+    Some("BP2"),
+    Some("TB"),
+    Some("SEC"),
+    Some("SRP"),
+];
+const SR2_BITS: BitNames = [
+    Some("SRL"),
+    Some("QE"),
+    None,
+    Some("LB1"),
+    Some("LB2"),
+    Some("LB3"),
+    Some("CMP"),
+    Some("SUS"),
+];
+const SR3_BITS: BitNames = [
+    Some("ADS"),
+    Some("ADP"),
+    Some("WPS"),
+    None,
+    None,
+    Some("DRV0"),
+    Some("DRV1"),
+    None,
+];
+
+/// Decoder for write status register (0x01) and write SR2 (0x31) commands
+///
+/// 0x01 writes SR1, followed by SR2 and SR3 on parts that accept 2 or 3
+/// bytes. The write SR3 opcode (0x11) is not decoded because the EM100
+/// uses it for its own messages. The decoder remembers the last value
+/// written to each register, so later writes are shown as the bits they
+/// set (+) and clear (-).
+#[derive(Debug, Clone, Default)]
+pub struct StatusDecoder {
+    last: [Option<u8>; 3],
+}
+
+impl StatusDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `command` writes status registers
+    pub fn handles(command: u8) -> bool {
+        matches!(command, 0x01 | 0x31)
+    }
+
+    /// Describe a status write, e.g. "SR1=0x1c (+BP0 +BP1 +BP2)"
+    pub fn decode(&mut self, command: u8, data: &[u8]) -> Option<String> {
+        let first = match command {
+            0x01 => 0,
+            0x31 => 1,
+            _ => return None,
+        };
+        let parts: Vec<String> = data
+            .iter()
+            .take(3 - first)
+            .enumerate()
+            .map(|(i, &value)| self.describe(first + i, value))
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    fn describe(&mut self, register: usize, value: u8) -> String {
+        let names = [&SR1_BITS, &SR2_BITS, &SR3_BITS][register];
+        let bits = match self.last[register].replace(value) {
+            Some(previous) => {
+                let changes: Vec<String> = (0..8)
+                    .filter(|bit| (previous ^ value) & (1 << bit) != 0)
+                    .map(|bit| {
+                        let sign = if value & (1 << bit) != 0 { '+' } else { '-' };
+                        format!("{}{}", sign, bit_name(names, bit))
+                    })
+                    .collect();
+                if changes.is_empty() {
+                    "unchanged".to_string()
+                } else {
+                    changes.join(" ")
+                }
+            }
+            None => {
+                let set: Vec<String> = (0..8)
+                    .filter(|bit| value & (1 << bit) != 0)
+                    .map(|bit| bit_name(names, bit))
+                    .collect();
+                if set.is_empty() {
+                    "none set".to_string()
+                } else {
+                    set.join(" ")
+                }
+            }
+        };
+        format!("SR{}=0x{:02x} ({})", register + 1, value, bits)
+    }
+}
+
+fn bit_name(names: &BitNames, bit: usize) -> String {
+    names[bit]
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("bit{}", bit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_byte_writes_show_the_bits_of_sr1() {
+        let mut decoder = StatusDecoder::new();
+        assert_eq!(
+            decoder.decode(0x01, &[0x1c]).unwrap(),
+            "SR1=0x1c (BP0 BP1 BP2)"
+        );
+        assert_eq!(decoder.decode(0x01, &[0x9c]).unwrap(), "SR1=0x9c (+SRP)");
+        assert_eq!(
+            decoder.decode(0x01, &[0x80]).unwrap(),
+            "SR1=0x80 (-BP0 -BP1 -BP2)"
+        );
+        assert_eq!(
+            decoder.decode(0x01, &[0x80]).unwrap(),
+            "SR1=0x80 (unchanged)"
+        );
+    }
+
+    #[test]
+    fn two_byte_writes_include_sr2() {
+        let mut decoder = StatusDecoder::new();
+        assert_eq!(
+            decoder.decode(0x01, &[0x00, 0x02]).unwrap(),
+            "SR1=0x00 (none set), SR2=0x02 (QE)"
+        );
+        // Write SR2 continues from what the two byte write left
+        assert_eq!(
+            decoder.decode(0x31, &[0x46]).unwrap(),
+            "SR2=0x46 (+bit2 +CMP)"
+        );
+    }
+
+    #[test]
+    fn three_byte_writes_include_sr3() {
+        let mut decoder = StatusDecoder::new();
+        assert_eq!(
+            decoder.decode(0x01, &[0x80, 0x42, 0x60]).unwrap(),
+            "SR1=0x80 (SRP), SR2=0x42 (QE CMP), SR3=0x60 (DRV0 DRV1)"
+        );
+        // Bytes past SR3 are not status registers
+        assert_eq!(
+            decoder.decode(0x31, &[0x02, 0x20, 0xff]).unwrap(),
+            "SR2=0x02 (-CMP), SR3=0x20 (-DRV1)"
+        );
+    }
+
+    #[test]
+    fn other_commands_and_empty_writes_are_not_decoded() {
+        let mut decoder = StatusDecoder::new();
+        assert!(!StatusDecoder::handles(0x11));
+        assert_eq!(decoder.decode(0x11, &[0x60]), None);
+        assert_eq!(decoder.decode(0x02, &[0x00]), None);
+        assert_eq!(decoder.decode(0x01, &[]), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! Decoding of the host messages (HT) the EM100 collects in its uFIFO
+//!
+//! Firmware on the host sends messages with the EM100 specific SPI command.
+//! Each starts with `EM100_MSG_SIGNATURE`, a type byte and a length byte.
+//! Multi-byte values are taken in the order the host sent them, most
+//! significant byte first.
+
+use super::{HtMsgType, EM100_MSG_SIGNATURE};
+use crate::error::{Error, Result};
+use crate::spi::UFIFO_OVERFLOW;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Strings for the IDs of lookup table messages
+pub type LookupTable = HashMap<u32, String>;
+
+/// Load a lookup table file
+///
+/// Each line holds an ID, decimal or hex with 0x, followed by whitespace and
+/// the string to print for it. Empty lines and lines starting with `#` are
+/// ignored.
+pub fn load_lookup_table(path: &Path) -> Result<LookupTable> {
+    let text = fs::read_to_string(path)?;
+    let mut table = LookupTable::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (id, string) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let id = match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => id.parse(),
+        }
+        .map_err(|_| {
+            Error::Parse(format!(
+                "{}:{}: invalid lookup table ID '{}'",
+                path.display(),
+                n + 1,
+                id
+            ))
+        })?;
+        table.insert(id, string.trim().to_string());
+    }
+    Ok(table)
+}
+
+impl HtMsgType {
+    /// The message type for a type byte
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(HtMsgType::Checkpoint1Byte),
+            0x02 => Some(HtMsgType::Checkpoint2Bytes),
+            0x03 => Some(HtMsgType::Checkpoint4Bytes),
+            0x04 => Some(HtMsgType::HexadecimalData),
+            0x05 => Some(HtMsgType::AsciiData),
+            0x06 => Some(HtMsgType::TimestampData),
+            0x07 => Some(HtMsgType::LookupTable),
+            _ => None,
+        }
+    }
+}
+
+/// State of the terminal carried from one uFIFO read to the next
+#[derive(Debug)]
+pub struct TerminalState {
+    counter: u32,
+    last_timestamp: Option<u64>,
+    lookup_table: LookupTable,
+    overflows: u32,
+}
+
+impl Default for TerminalState {
+    fn default() -> Self {
+        Self {
+            counter: 1,
+            last_timestamp: None,
+            lookup_table: LookupTable::new(),
+            overflows: 0,
+        }
+    }
+}
+
+impl TerminalState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve lookup table messages against `table`
+    pub fn with_lookup_table(mut self, table: LookupTable) -> Self {
+        self.lookup_table = table;
+        self
+    }
+
+    /// Note the HT status register, returning whether the uFIFO overflowed
+    ///
+    /// The EM100 only flags that messages were dropped, not how many.
+    pub fn note_status(&mut self, status: u8) -> bool {
+        let overflow = status & UFIFO_OVERFLOW != 0;
+        if overflow {
+            self.overflows += 1;
+        }
+        overflow
+    }
+
+    /// Number of uFIFO overflows seen
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+
+    /// Print the messages in a uFIFO buffer
+    ///
+    /// The buffer starts with the big-endian number of valid bytes. With
+    /// `show_counter` every message goes on its own line behind a running
+    /// number; otherwise only the decoded, non-ASCII messages do.
+    pub fn decode<W: Write + ?Sized>(
+        &mut self,
+        data: &[u8],
+        show_counter: bool,
+        out: &mut W,
+    ) -> io::Result<()> {
+        if data.len() < 2 {
+            return Ok(());
+        }
+        // First two bytes are the amount of valid data
+        let end = (2 + (((data[0] as usize) << 8) | (data[1] as usize))).min(data.len());
+
+        let mut offset = 2;
+        while offset + 6 <= end {
+            let sig = u32::from_be_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ]);
+            if sig != EM100_MSG_SIGNATURE {
+                offset += 1;
+                continue;
+            }
+
+            let data_type = data[offset + 4];
+            let msg_len = data[offset + 5] as usize;
+            let payload = &data[offset + 6..(offset + 6 + msg_len).min(end)];
+
+            if show_counter {
+                write!(out, "\nHT{:06}: ", self.counter)?;
+            }
+            match self.format(data_type, payload) {
+                Some(text) if show_counter => write!(out, "{}", text)?,
+                Some(text) => writeln!(out, "{}", text)?,
+                None if data_type == HtMsgType::AsciiData as u8 => {
+                    for &byte in payload {
+                        write!(out, "{}", byte as char)?;
+                    }
+                }
+                None => {
+                    for byte in payload {
+                        write!(out, "{:02x} ", byte)?;
+                    }
+                }
+            }
+
+            offset += 6 + msg_len;
+            self.counter += 1;
+        }
+        Ok(())
+    }
+
+    /// Decoded text of a message, `None` for ASCII and hex data
+    ///
+    /// Messages of an unknown type or with a payload that doesn't fit their
+    /// type are printed as hex, like hexadecimal data.
+    fn format(&mut self, data_type: u8, payload: &[u8]) -> Option<String> {
+        let value = || {
+            (!payload.is_empty() && payload.len() <= 8)
+                .then(|| payload.iter().fold(0u64, |v, b| (v << 8) | *b as u64))
+        };
+        match (HtMsgType::from_byte(data_type)?, payload.len()) {
+            (HtMsgType::Checkpoint1Byte, 1) => Some(format!("CP8: 0x{:02x}", value()?)),
+            (HtMsgType::Checkpoint2Bytes, 2) => Some(format!("CP16: 0x{:04x}", value()?)),
+            (HtMsgType::Checkpoint4Bytes, 4) => Some(format!("CP32: 0x{:08x}", value()?)),
+            (HtMsgType::TimestampData, _) => {
+                let timestamp = value()?;
+                let delta = match self.last_timestamp.replace(timestamp) {
+                    Some(last) if timestamp >= last => format!("+{}", timestamp - last),
+                    // The host restarted its counter
+                    Some(_) => "reset".to_string(),
+                    None => "first".to_string(),
+                };
+                Some(format!("TS: {} ({})", timestamp, delta))
+            }
+            (HtMsgType::LookupTable, 1..=4) => {
+                let id = value()? as u32;
+                Some(match self.lookup_table.get(&id) {
+                    Some(string) => string.clone(),
+                    None => format!("LUT: 0x{:0width$x}", id, width = payload.len() * 2),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host message of type `kind`
+    fn msg(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut msg = EM100_MSG_SIGNATURE.to_be_bytes().to_vec();
+        msg.extend([kind, payload.len() as u8]);
+        msg.extend_from_slice(payload);
+        msg
+    }
+
+    /// A uFIFO buffer holding `messages`, padded like the device does
+    fn ufifo(messages: &[Vec<u8>]) -> Vec<u8> {
+        let data = messages.concat();
+        let mut buffer = (data.len() as u16).to_be_bytes().to_vec();
+        buffer.extend(data);
+        buffer.resize(64, 0xff);
+        buffer
+    }
+
+    fn decoded(terminal: &mut TerminalState, messages: &[Vec<u8>], show_counter: bool) -> String {
+        let mut out = Vec::new();
+        terminal
+            .decode(&ufifo(messages), show_counter, &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn checkpoints_are_printed_at_their_width() {
+        let text = decoded(
+            &mut TerminalState::new(),
+            &[
+                msg(0x01, &[0x42]),
+                msg(0x02, &[0x12, 0x34]),
+                msg(0x03, &[0xde, 0xad, 0xbe, 0xef]),
+                // Payloads that don't fit the type stay hex
+                msg(0x02, &[0x12, 0x34, 0x56]),
+            ],
+            false,
+        );
+        assert_eq!(text, "CP8: 0x42\nCP16: 0x1234\nCP32: 0xdeadbeef\n12 34 56 ");
+    }
+
+    #[test]
+    fn timestamps_show_the_time_since_the_last_one() {
+        let mut terminal = TerminalState::new();
+        let text = decoded(
+            &mut terminal,
+            &[msg(0x06, &[0x00, 0x10]), msg(0x06, &[0x01, 0x00])],
+            false,
+        );
+        assert_eq!(text, "TS: 16 (first)\nTS: 256 (+240)\n");
+        // Carried over to the next read; a smaller value restarted the counter
+        let text = decoded(&mut terminal, &[msg(0x06, &[0x05])], false);
+        assert_eq!(text, "TS: 5 (reset)\n");
+    }
+
+    #[test]
+    fn lookup_table_ids_are_resolved() {
+        let mut table = LookupTable::new();
+        table.insert(0x1234, "memory init done".to_string());
+        let mut terminal = TerminalState::new().with_lookup_table(table);
+        let text = decoded(
+            &mut terminal,
+            &[msg(0x07, &[0x12, 0x34]), msg(0x07, &[0x00, 0x99])],
+            false,
+        );
+        assert_eq!(text, "memory init done\nLUT: 0x0099\n");
+    }
+
+    #[test]
+    fn ascii_hex_and_unknown_messages_are_printed_raw() {
+        let messages = [
+            msg(0x05, b"boot"),
+            msg(0x04, &[0x01, 0xab]),
+            msg(0x7f, &[0x02]),
+        ];
+        let text = decoded(&mut TerminalState::new(), &messages, false);
+        assert_eq!(text, "boot01 ab 02 ");
+
+        let text = decoded(&mut TerminalState::new(), &messages, true);
+        assert_eq!(text, "\nHT000001: boot\nHT000002: 01 ab \nHT000003: 02 ");
+    }
+
+    #[test]
+    fn lookup_table_files_are_parsed() {
+        let path = std::env::temp_dir().join(format!("rem100-{}-lut.txt", std::process::id()));
+        fs::write(&path, "# ID string\n\n1 one\n0x10  sixteen words \n7\n").unwrap();
+        let table = load_lookup_table(&path).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table[&1], "one");
+        assert_eq!(table[&0x10], "sixteen words");
+        assert_eq!(table[&7], "");
+
+        fs::write(&path, "1 one\nzz two\n").unwrap();
+        match load_lookup_table(&path) {
+            Err(Error::Parse(msg)) => assert!(msg.ends_with(":2: invalid lookup table ID 'zz'")),
+            other => panic!("{:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overflows_are_read_from_the_status_register() {
+        use crate::spi::{DFIFO_EMPTY, START_SPI_EMULATION, UFIFO_EMPTY};
+        let mut terminal = TerminalState::new();
+        for status in [0, START_SPI_EMULATION | UFIFO_EMPTY | DFIFO_EMPTY] {
+            assert!(!terminal.note_status(status));
+        }
+        assert_eq!(terminal.overflows(), 0);
+        assert!(terminal.note_status(UFIFO_OVERFLOW));
+        assert!(terminal.note_status(START_SPI_EMULATION | UFIFO_OVERFLOW));
+        assert_eq!(terminal.overflows(), 2);
+    }
+}
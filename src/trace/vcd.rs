@@ -0,0 +1,186 @@
+//! Value Change Dump output for waveform viewers like GTKWave or PulseView
+//!
+//! Each transaction pulls the active-low `cs_n` signal low and sets the
+//! `command` and `address` buses. The EM100 only timestamps its packets
+//! now and then, so transactions often share a timestamp; they are spread
+//! one tick apart to keep every boundary visible.
+
+use super::{TraceEvent, TIMESTAMP_HZ};
+use std::io::{self, Write};
+
+const CS_ID: char = '!';
+const COMMAND_ID: char = '"';
+const ADDRESS_ID: char = '#';
+
+/// Streaming VCD writer for trace events
+///
+/// Times are relative to the first event, in device timestamp ticks.
+#[derive(Debug, Default)]
+pub struct VcdWriter {
+    start: Option<u64>,
+    /// Earliest time the next change may be written at
+    next_time: u64,
+    /// Time of the last `#time` line
+    time: u64,
+    selected: bool,
+}
+
+impl VcdWriter {
+    /// Writer for a new file; the header is written with the first event
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the transaction `event`, preceded by the header for the first one
+    pub fn write_event<W: Write>(&mut self, w: &mut W, event: &TraceEvent) -> io::Result<()> {
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                write_header(w)?;
+                *self.start.insert(event.timestamp)
+            }
+        };
+        let mut time = event.timestamp.saturating_sub(start).max(self.next_time);
+        if self.selected {
+            self.at(w, time)?;
+            writeln!(w, "1{}", CS_ID)?;
+            time += 1;
+        }
+        self.at(w, time)?;
+        writeln!(w, "0{}", CS_ID)?;
+        writeln!(w, "b{:b} {}", event.command, COMMAND_ID)?;
+        match event.address {
+            Some(address) => writeln!(w, "b{:b} {}", address, ADDRESS_ID)?,
+            None => writeln!(w, "bx {}", ADDRESS_ID)?,
+        }
+        self.next_time = time + 1;
+        self.selected = true;
+        Ok(())
+    }
+
+    /// End the last transaction
+    pub fn finish<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        if self.selected {
+            self.selected = false;
+            self.at(w, self.next_time)?;
+            writeln!(w, "1{}", CS_ID)?;
+        }
+        Ok(())
+    }
+
+    /// Start the changes at `time`, unless they already are
+    fn at<W: Write>(&mut self, w: &mut W, time: u64) -> io::Result<()> {
+        if time != self.time {
+            self.time = time;
+            writeln!(w, "#{}", time)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write events as a complete VCD file
+pub fn write_vcd<W: Write>(w: &mut W, events: &[TraceEvent]) -> io::Result<()> {
+    let mut vcd = VcdWriter::new();
+    if events.is_empty() {
+        write_header(w)?;
+    }
+    for event in events {
+        vcd.write_event(w, event)?;
+    }
+    vcd.finish(w)
+}
+
+fn write_header<W: Write>(w: &mut W) -> io::Result<()> {
+    writeln!(w, "$version rem100 {} $end", env!("CARGO_PKG_VERSION"))?;
+    writeln!(w, "$timescale {}ns $end", 1_000_000_000 / TIMESTAMP_HZ)?;
+    writeln!(w, "$scope module em100 $end")?;
+    writeln!(w, "$var wire 1 {} cs_n $end", CS_ID)?;
+    writeln!(w, "$var wire 8 {} command $end", COMMAND_ID)?;
+    writeln!(w, "$var wire 32 {} address $end", ADDRESS_ID)?;
+    writeln!(w, "$upscope $end")?;
+    writeln!(w, "$enddefinitions $end")?;
+    writeln!(w, "#0")?;
+    writeln!(w, "$dumpvars")?;
+    writeln!(w, "1{}", CS_ID)?;
+    writeln!(w, "bx {}", COMMAND_ID)?;
+    writeln!(w, "bx {}", ADDRESS_ID)?;
+    writeln!(w, "$end")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64, command: u8, address: Option<u64>) -> TraceEvent {
+        TraceEvent {
+            timestamp,
+            command,
+            name: "",
+            address,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn header_declares_the_signals_at_the_timestamp_rate() {
+        let mut out = Vec::new();
+        write_vcd(&mut out, &[]).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("$version rem100 "));
+        assert_eq!(
+            lines[1..],
+            [
+                "$timescale 10ns $end",
+                "$scope module em100 $end",
+                "$var wire 1 ! cs_n $end",
+                "$var wire 8 \" command $end",
+                "$var wire 32 # address $end",
+                "$upscope $end",
+                "$enddefinitions $end",
+                "#0",
+                "$dumpvars",
+                "1!",
+                "bx \"",
+                "bx #",
+                "$end",
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_toggle_chip_select_and_set_the_buses() {
+        let events = [
+            event(1000, 0x9f, None),
+            // Same timestamp as the one before, so moved a tick later
+            event(1000, 0x03, Some(0x1000)),
+            event(1500, 0x05, None),
+        ];
+        let mut out = Vec::new();
+        write_vcd(&mut out, &events).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let body: Vec<&str> = text.split("$end\n").last().unwrap().lines().collect();
+        assert_eq!(
+            body,
+            [
+                "0!",
+                "b10011111 \"",
+                "bx #",
+                "#1",
+                "1!",
+                "#2",
+                "0!",
+                "b11 \"",
+                "b1000000000000 #",
+                "#500",
+                "1!",
+                "#501",
+                "0!",
+                "b101 \"",
+                "bx #",
+                "#502",
+                "1!",
+            ]
+        );
+    }
+}
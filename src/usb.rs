@@ -1,17 +1,197 @@
 //! Low-level USB communication functions
 
+use crate::commands::AsyncTransport;
 use crate::device::Em100;
 use crate::error::{Error, Result};
-use nusb::transfer::Buffer;
+use crate::protocol::{self, round_up_to_max_packet};
+use nusb::transfer::{Buffer, Bulk, In, Out, TransferError};
+use nusb::{Endpoint, MaybeFuture};
+use std::cell::RefCell;
+use std::future::Future;
 use std::time::Duration;
 
-/// Default timeout for USB transfers
-const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+/// Counts of USB trouble seen on a device, for flaky cable or hub diagnosis
+///
+/// A healthy setup keeps all of these at zero; operations that recovered
+/// from errors still succeed, so these are the only trace of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsbCounters {
+    /// Exchanges repeated after a failure
+    pub retries: u32,
+    /// Transfers that timed out
+    pub timeouts: u32,
+    /// Transfers moving fewer bytes than requested
+    pub short_transfers: u32,
+    /// Command/response stream resynchronizations
+    pub resyncs: u32,
+    /// Times the device was opened again after dropping off the bus
+    pub reconnects: u32,
+}
+
+impl UsbCounters {
+    /// Whether no trouble was seen
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for UsbCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} retries, {} timeouts, {} short transfers, {} resyncs, {} reconnects",
+            self.retries, self.timeouts, self.short_transfers, self.resyncs, self.reconnects
+        )
+    }
+}
+
+/// Update the USB trouble counters of `em100`
+pub(crate) fn count(em100: &Em100, update: impl FnOnce(&mut UsbCounters)) {
+    let mut counters = em100.usb_counters.get();
+    update(&mut counters);
+    em100.usb_counters.set(counters);
+}
+
+/// Moves EM100 commands and data over USB
+///
+/// `Em100` reaches the device only through this trait, so the device logic
+/// can run against [`crate::mock::MockTransport`] instead of hardware.
+/// Transfers that run out of time fail with [`Error::Timeout`]; the
+/// functions below count those and short transfers for the device.
+pub trait Transport: Send {
+    /// Send a command packet, returning how many bytes the device took
+    fn send_cmd(&self, cmd: &[u8; 16]) -> Result<usize>;
+
+    /// Read a response of up to `length` bytes
+    fn get_response(&self, length: usize) -> Result<Vec<u8>>;
+
+    /// Send bulk data, returning how much was sent
+    fn bulk_write(&self, data: &[u8]) -> Result<usize>;
+
+    /// Receive bulk data into `buffer`, returning how much arrived
+    fn bulk_read(&self, buffer: &mut [u8]) -> Result<usize>;
+
+    /// Discard anything the device still has queued for the host
+    fn drain(&self) {}
+
+    /// Set the timeout of later transfers
+    fn set_timeout(&mut self, _timeout: Duration) {}
+
+    /// Wait for the device, e.g. while the FPGA reconfigures
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The bulk endpoints of a claimed EM100 interface
+pub struct NusbTransport {
+    endpoint_out: RefCell<Endpoint<Bulk, Out>>,
+    endpoint_in: RefCell<Endpoint<Bulk, In>>,
+    timeout: Duration,
+}
+
+/// USB endpoint addresses
+const ENDPOINT_OUT: u8 = 0x01;
+const ENDPOINT_IN: u8 = 0x82;
+
+/// Timeout for reads that drain stale responses
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Upper bound on packets drained before giving up
+const MAX_DRAIN_PACKETS: usize = 64;
+
+impl NusbTransport {
+    /// Open `device` and claim its EM100 interface
+    pub fn open(device: &nusb::DeviceInfo, timeout: Duration) -> Result<Self> {
+        let interface = device.open().wait()?.claim_interface(0).wait()?;
+        Ok(NusbTransport {
+            endpoint_out: RefCell::new(interface.endpoint::<Bulk, Out>(ENDPOINT_OUT)?),
+            endpoint_in: RefCell::new(interface.endpoint::<Bulk, In>(ENDPOINT_IN)?),
+            timeout,
+        })
+    }
+
+    fn write(&self, data: Vec<u8>) -> Result<usize> {
+        let completion = self
+            .endpoint_out
+            .borrow_mut()
+            .transfer_blocking(Buffer::from(data), self.timeout);
+        transfer_result(completion.status)?;
+        Ok(completion.actual_len)
+    }
+
+    fn read(&self, length: usize) -> Result<Vec<u8>> {
+        let mut ep = self.endpoint_in.borrow_mut();
+        let requested_len = round_up_to_max_packet(length, ep.max_packet_size());
+        let mut buf = Buffer::new(requested_len);
+        buf.set_requested_len(requested_len);
+        let completion = ep.transfer_blocking(buf, self.timeout);
+        transfer_result(completion.status)?;
+        let data = protocol::clamp_response(
+            &completion.buffer,
+            completion.actual_len,
+            length,
+            requested_len,
+        )?;
+        Ok(data.to_vec())
+    }
+}
+
+/// Turn the status of a finished transfer into a result
+///
+/// nusb reports a transfer that ran out of time as cancelled.
+fn transfer_result(status: std::result::Result<(), TransferError>) -> Result<()> {
+    match status {
+        Ok(()) => Ok(()),
+        Err(TransferError::Cancelled) => Err(Error::Timeout),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl Transport for NusbTransport {
+    fn send_cmd(&self, cmd: &[u8; 16]) -> Result<usize> {
+        self.write(cmd.to_vec())
+    }
+
+    fn get_response(&self, length: usize) -> Result<Vec<u8>> {
+        self.read(length)
+    }
+
+    fn bulk_write(&self, data: &[u8]) -> Result<usize> {
+        self.write(data.to_vec())
+    }
+
+    fn bulk_read(&self, buffer: &mut [u8]) -> Result<usize> {
+        let data = self.read(buffer.len())?;
+        buffer[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Read until the IN endpoint is empty
+    fn drain(&self) {
+        let mut ep = self.endpoint_in.borrow_mut();
+        let max_packet_size = ep.max_packet_size();
+        for _ in 0..MAX_DRAIN_PACKETS {
+            let mut buf = Buffer::new(max_packet_size);
+            buf.set_requested_len(max_packet_size);
+            let completion = ep.transfer_blocking(buf, DRAIN_TIMEOUT);
+            if completion.status.is_err() || completion.actual_len == 0 {
+                break;
+            }
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
 
-/// Round up to the next multiple of max packet size for IN transfers
-/// nusb 0.2 requires requested_len to be a multiple of max_packet_size
-fn round_up_to_max_packet(len: usize, max_packet_size: usize) -> usize {
-    len.div_ceil(max_packet_size) * max_packet_size
+/// Count a transfer of `em100` that timed out
+fn counted<T>(em100: &Em100, result: Result<T>) -> Result<T> {
+    if let Err(Error::Timeout) = result {
+        count(em100, |c| c.timeouts += 1);
+    }
+    result
 }
 
 /// Send a 16-byte command to the EM100
@@ -20,15 +200,9 @@ pub fn send_cmd(em100: &Em100, data: &[u8]) -> Result<()> {
     let len = std::cmp::min(data.len(), 16);
     cmd[..len].copy_from_slice(&data[..len]);
 
-    let buf = Buffer::from(cmd.to_vec());
-    let completion = em100
-        .endpoint_out
-        .borrow_mut()
-        .transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    let written = completion.actual_len;
-
+    let written = counted(em100, em100.transport.send_cmd(&cmd))?;
     if written != 16 {
+        count(em100, |c| c.short_transfers += 1);
         return Err(Error::Communication(format!(
             "Expected to send 16 bytes, sent {}",
             written
@@ -40,39 +214,189 @@ pub fn send_cmd(em100: &Em100, data: &[u8]) -> Result<()> {
 
 /// Get a response from the EM100
 pub fn get_response(em100: &Em100, length: usize) -> Result<Vec<u8>> {
-    let mut ep = em100.endpoint_in.borrow_mut();
-    let max_packet_size = ep.max_packet_size();
-    let requested_len = round_up_to_max_packet(length, max_packet_size);
-    let mut buf = Buffer::new(requested_len);
-    buf.set_requested_len(requested_len);
-    let completion = ep.transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    // Return only the bytes actually requested (up to actual_len)
-    let actual = std::cmp::min(completion.actual_len, length);
-    Ok(completion.buffer[..actual].to_vec())
+    let data = counted(em100, em100.transport.get_response(length))?;
+    if data.len() < length {
+        count(em100, |c| c.short_transfers += 1);
+    }
+    Ok(data)
+}
+
+/// Send a command and read its response, retrying once after a timeout
+///
+/// Before the retry the stream is resynchronized, so a late response to the
+/// first attempt isn't taken for the second one. Only use this for commands
+/// that merely query the device, as the command is sent again.
+pub fn exchange(em100: &Em100, cmd: &[u8], length: usize) -> Result<Vec<u8>> {
+    let attempt = || {
+        send_cmd(em100, cmd)?;
+        get_response(em100, length)
+    };
+    match attempt() {
+        Err(e) if e.is_retryable() => {
+            if resync(em100).is_err() {
+                return Err(e);
+            }
+            count(em100, |c| c.retries += 1);
+            attempt()
+        }
+        result => result,
+    }
 }
 
 /// Send a bulk transfer (for large data transfers)
 pub fn bulk_write(em100: &Em100, data: &[u8]) -> Result<usize> {
-    let buf = Buffer::from(data.to_vec());
-    let completion = em100
-        .endpoint_out
-        .borrow_mut()
-        .transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    Ok(completion.actual_len)
+    let written = counted(em100, em100.transport.bulk_write(data))?;
+    if written < data.len() {
+        count(em100, |c| c.short_transfers += 1);
+    }
+    Ok(written)
 }
 
 /// Receive a bulk transfer (for large data transfers)
 pub fn bulk_read(em100: &Em100, buffer: &mut [u8]) -> Result<usize> {
-    let mut ep = em100.endpoint_in.borrow_mut();
-    let max_packet_size = ep.max_packet_size();
-    let requested_len = round_up_to_max_packet(buffer.len(), max_packet_size);
-    let mut buf = Buffer::new(requested_len);
-    buf.set_requested_len(requested_len);
-    let completion = ep.transfer_blocking(buf, DEFAULT_TIMEOUT);
-    completion.status?;
-    let received = std::cmp::min(completion.actual_len, buffer.len());
-    buffer[..received].copy_from_slice(&completion.buffer[..received]);
-    Ok(received)
+    let read = counted(em100, em100.transport.bulk_read(buffer))?;
+    if read < buffer.len() {
+        count(em100, |c| c.short_transfers += 1);
+    }
+    Ok(read)
+}
+
+/// Re-synchronize the command/response stream after a failed exchange
+///
+/// A response that arrives after its read timed out is picked up by the next
+/// command, leaving every later exchange off by one. This drains the IN
+/// endpoint until it is empty and then checks that a benign command (read SPI
+/// flash ID) gets a well-formed reply.
+pub fn resync(em100: &Em100) -> Result<()> {
+    count(em100, |c| c.resyncs += 1);
+    em100.transport.drain();
+
+    send_cmd(em100, &protocol::spi_flash_id_cmd())?;
+    let data = get_response(em100, 512)?;
+    protocol::parse_spi_flash_id(&data).map(|_| ())
+}
+
+/// The blocking transfers of an `Em100` as an [`AsyncTransport`]
+///
+/// Every operation has completed by the time its future is first polled,
+/// so the shared command sequences run on it with [`block_on`]. Queries
+/// keep the timeout retry of [`exchange`].
+pub struct Blocking<'a>(pub &'a Em100);
+
+impl AsyncTransport for Blocking<'_> {
+    async fn send_cmd(&mut self, cmd: &[u8]) -> Result<()> {
+        send_cmd(self.0, cmd)
+    }
+
+    async fn get_response(&mut self, length: usize) -> Result<Vec<u8>> {
+        get_response(self.0, length)
+    }
+
+    async fn exchange(&mut self, cmd: &[u8], length: usize) -> Result<Vec<u8>> {
+        exchange(self.0, cmd, length)
+    }
+
+    async fn bulk_write(&mut self, data: &[u8]) -> Result<usize> {
+        bulk_write(self.0, data)
+    }
+
+    async fn bulk_read(&mut self, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        let actual = bulk_read(self.0, &mut data)?;
+        data.truncate(actual);
+        Ok(data)
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        self.0.transport.sleep(duration);
+    }
+
+    fn count_retry(&mut self) {
+        count(self.0, |c| c.retries += 1);
+    }
+}
+
+/// Run a command sequence on a [`Blocking`] transport to completion
+pub fn block_on<T>(sequence: impl Future<Output = T>) -> T {
+    futures_lite::future::block_on(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands;
+    use crate::mock::{MockTransport, Step};
+
+    #[test]
+    fn oversized_completions_are_clamped_to_the_request() {
+        // A register read answered with a whole packet
+        let mut register = vec![2, 0x12, 0x34];
+        register.resize(64, 0xee);
+        let mock = MockTransport::stock()
+            .query(protocol::read_fpga_register_cmd(0x28), &register)
+            .command(protocol::read_sdram_cmd(0, 100))
+            .step(Step::BulkRead(vec![0x5a; 512]));
+        let em100 = mock.open().unwrap();
+        let counters = em100.usb_counters();
+
+        let value = block_on(commands::read_fpga_register(&mut Blocking(&em100), 0x28)).unwrap();
+        assert_eq!(value, 0x1234);
+        send_cmd(&em100, &protocol::read_sdram_cmd(0, 100)).unwrap();
+        let mut buffer = [0; 100];
+        assert_eq!(bulk_read(&em100, &mut buffer).unwrap(), 100);
+        assert_eq!(buffer, [0x5a; 100]);
+
+        assert_eq!(
+            em100.usb_counters().short_transfers,
+            counters.short_transfers
+        );
+        mock.assert_done();
+    }
+
+    #[test]
+    fn completions_past_the_transfer_are_errors() {
+        let mock = MockTransport::stock()
+            .command(protocol::read_fpga_register_cmd(0x28))
+            .step(Step::Response(vec![2; 513]))
+            .command(protocol::read_sdram_cmd(0, 512))
+            .step(Step::BulkRead(vec![0; 1024]));
+        let em100 = mock.open().unwrap();
+
+        send_cmd(&em100, &protocol::read_fpga_register_cmd(0x28)).unwrap();
+        match get_response(&em100, 3) {
+            Err(Error::Communication(msg)) => {
+                assert_eq!(msg, "Device returned 513 bytes for a 512 byte read")
+            }
+            other => panic!("{:?}", other),
+        }
+        send_cmd(&em100, &protocol::read_sdram_cmd(0, 512)).unwrap();
+        assert!(matches!(
+            bulk_read(&em100, &mut [0; 512]),
+            Err(Error::Communication(_))
+        ));
+        mock.assert_done();
+    }
+
+    #[test]
+    fn undersized_completions_are_counted_and_rejected() {
+        let mock = MockTransport::stock()
+            .query(protocol::version_cmd(), &[4, 0x00, 0x34])
+            .command(protocol::read_sdram_cmd(0, 16))
+            .step(Step::BulkRead(vec![0; 10]));
+        let em100 = mock.open().unwrap();
+        let counters = em100.usb_counters();
+
+        assert!(matches!(
+            block_on(commands::get_version(&mut Blocking(&em100))),
+            Err(Error::InvalidResponse)
+        ));
+        send_cmd(&em100, &protocol::read_sdram_cmd(0, 16)).unwrap();
+        assert_eq!(bulk_read(&em100, &mut [0; 16]).unwrap(), 10);
+
+        assert_eq!(
+            em100.usb_counters().short_transfers,
+            counters.short_transfers + 2
+        );
+        mock.assert_done();
+    }
 }
@@ -0,0 +1,370 @@
+//! Verification of SDRAM contents against an image
+//!
+//! A full verify reads everything back and compares it byte by byte. Hash
+//! mode reads as much but compares SHA-256 digests, which can be logged.
+//! Sample mode only reads a few 4KB blocks picked from a seed, so a failure
+//! can be reproduced by verifying with the same seed.
+
+use crate::device::Em100;
+use crate::error::{Error, Result};
+use crate::hexdump::DiffReport;
+use crate::image::diff::{self, DiffMap};
+use crate::protocol;
+use crate::sdram::ProgressCallback;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Size of the blocks sample mode compares
+pub const SAMPLE_BLOCK_SIZE: usize = 4096;
+
+/// Blocks sample mode compares unless told otherwise
+pub const DEFAULT_SAMPLES: usize = 64;
+
+/// How contents are verified
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Read everything back and compare it
+    #[default]
+    Full,
+    /// Read everything back and compare SHA-256 digests
+    Hash,
+    /// Compare `samples` blocks of `SAMPLE_BLOCK_SIZE` picked from `seed`
+    Sample { samples: usize, seed: u64 },
+}
+
+impl VerifyMode {
+    /// Sample mode with a seed from the clock
+    pub fn sample(samples: usize) -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        VerifyMode::Sample { samples, seed }
+    }
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(VerifyMode::Full),
+            "hash" => Ok(VerifyMode::Hash),
+            "sample" => Ok(VerifyMode::sample(DEFAULT_SAMPLES)),
+            _ => Err(Error::InvalidArgument(format!(
+                "Invalid verify mode: {} (expected full, hash or sample)",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for VerifyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyMode::Full => write!(f, "full"),
+            VerifyMode::Hash => write!(f, "hash"),
+            VerifyMode::Sample { .. } => write!(f, "sample"),
+        }
+    }
+}
+
+/// Outcome of verifying one region
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub mode: VerifyMode,
+    /// Address of the region
+    pub address: u32,
+    /// Bytes read back and compared
+    pub bytes_checked: usize,
+    /// SHA-256 of the expected contents and of the readback, in hash mode
+    pub hashes: Option<([u8; 32], [u8; 32])>,
+    /// Addresses of the blocks compared, in sample mode
+    pub sampled: Vec<u32>,
+    /// Where the contents first differed, if they did
+    pub mismatch: Option<DiffReport>,
+    /// All differing ranges, for a failed full or hash verify
+    pub diff: Option<DiffMap>,
+}
+
+impl VerifyReport {
+    pub fn passed(&self) -> bool {
+        self.mismatch.is_none()
+    }
+
+    /// Write the report as a JSON document
+    pub fn write_json<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"mode\": \"{}\",", self.mode)?;
+        writeln!(w, "  \"address\": {},", self.address)?;
+        writeln!(w, "  \"passed\": {},", self.passed())?;
+        writeln!(w, "  \"bytes_checked\": {},", self.bytes_checked)?;
+        match &self.hashes {
+            Some((expected, actual)) => writeln!(
+                w,
+                "  \"sha256\": {{\"expected\": \"{}\", \"actual\": \"{}\"}},",
+                hex_digest(expected),
+                hex_digest(actual)
+            )?,
+            None => writeln!(w, "  \"sha256\": null,")?,
+        }
+        if let VerifyMode::Sample { seed, .. } = self.mode {
+            writeln!(w, "  \"seed\": {},", seed)?;
+            let sampled: Vec<_> = self.sampled.iter().map(|a| a.to_string()).collect();
+            writeln!(w, "  \"sampled\": [{}],", sampled.join(", "))?;
+        }
+        match &self.mismatch {
+            Some(mismatch) => writeln!(w, "  \"first_difference\": {},", mismatch.first)?,
+            None => writeln!(w, "  \"first_difference\": null,")?,
+        }
+        self.diff
+            .clone()
+            .unwrap_or_default()
+            .write_json_fields(w, self.address as u64)?;
+        writeln!(w, "}}")
+    }
+}
+
+/// Lower-case hex of a digest
+pub fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SplitMix64, a small PRNG that is enough to spread samples
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Offsets of the blocks sample mode compares in `len` bytes, in order
+///
+/// The same `len`, `samples` and `seed` always give the same offsets. With
+/// at least as many samples as blocks, every block is compared.
+pub fn sample_offsets(len: usize, samples: usize, seed: u64) -> Vec<usize> {
+    let blocks = len.div_ceil(SAMPLE_BLOCK_SIZE);
+    if samples >= blocks {
+        return (0..blocks).map(|b| b * SAMPLE_BLOCK_SIZE).collect();
+    }
+    let mut state = seed;
+    let mut picked = BTreeSet::new();
+    while picked.len() < samples {
+        picked.insert((splitmix64(&mut state) % blocks as u64) as usize);
+    }
+    picked.into_iter().map(|b| b * SAMPLE_BLOCK_SIZE).collect()
+}
+
+/// Verify that SDRAM at `address` holds `expected`
+///
+/// Progress is reported in bytes read back.
+pub fn verify_region(
+    em100: &Em100,
+    address: u32,
+    expected: &[u8],
+    mode: VerifyMode,
+    progress: ProgressCallback,
+) -> Result<VerifyReport> {
+    let mut report = VerifyReport {
+        mode,
+        address,
+        bytes_checked: 0,
+        hashes: None,
+        sampled: Vec::new(),
+        mismatch: None,
+        diff: None,
+    };
+
+    match mode {
+        VerifyMode::Full | VerifyMode::Hash => {
+            let readback = em100.upload_with_progress(address, expected.len(), progress)?;
+            report.bytes_checked = readback.len();
+            if mode == VerifyMode::Hash {
+                let hashes = (
+                    Sha256::digest(expected).into(),
+                    Sha256::digest(&readback).into(),
+                );
+                report.hashes = Some(hashes);
+                if hashes.0 == hashes.1 {
+                    return Ok(report);
+                }
+            }
+            report.mismatch = DiffReport::compare(expected, &readback, address as usize);
+            if report.mismatch.is_some() {
+                report.diff = Some(diff::diff(expected, &readback));
+            }
+        }
+        VerifyMode::Sample { samples, seed } => {
+            verify_samples(
+                em100,
+                &mut report,
+                expected.len(),
+                samples,
+                seed,
+                progress,
+                |o, n| Ok(expected[o..o + n].to_vec()),
+            )?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Verify that SDRAM at `address` holds the `len` bytes `expected` reads
+///
+/// Like [`verify_region`], but only one chunk of each side is held in
+/// memory at a time. When a full or hash verify fails, both sides are read
+/// again in full to report where they differ.
+pub fn verify_reader<R: Read + Seek + ?Sized>(
+    em100: &Em100,
+    address: u32,
+    expected: &mut R,
+    len: usize,
+    mode: VerifyMode,
+    mut progress: ProgressCallback,
+) -> Result<VerifyReport> {
+    let start = expected.stream_position()?;
+    let mut report = VerifyReport {
+        mode,
+        address,
+        bytes_checked: 0,
+        hashes: None,
+        sampled: Vec::new(),
+        mismatch: None,
+        diff: None,
+    };
+
+    if let VerifyMode::Sample { samples, seed } = mode {
+        verify_samples(em100, &mut report, len, samples, seed, progress, |o, n| {
+            let mut block = vec![0; n];
+            expected.seek(SeekFrom::Start(start + o as u64))?;
+            expected.read_exact(&mut block)?;
+            Ok(block)
+        })?;
+        return Ok(report);
+    }
+
+    let (mut expected_hash, mut actual_hash) = (Sha256::new(), Sha256::new());
+    let mut chunk = Vec::new();
+    let mut matched = true;
+    while report.bytes_checked < len {
+        let n = protocol::next_chunk_len(len, report.bytes_checked);
+        chunk.resize(n, 0);
+        expected.read_exact(&mut chunk)?;
+        let readback =
+            em100.upload_with_progress(address + report.bytes_checked as u32, n, None)?;
+        report.bytes_checked += n;
+        if let Some(ref mut cb) = progress {
+            cb(report.bytes_checked, len);
+        }
+        if mode == VerifyMode::Hash {
+            expected_hash.update(&chunk);
+            actual_hash.update(&readback);
+        } else if readback != chunk {
+            matched = false;
+            break;
+        }
+    }
+    if mode == VerifyMode::Hash {
+        let hashes = (
+            expected_hash.finalize().into(),
+            actual_hash.finalize().into(),
+        );
+        report.hashes = Some(hashes);
+        matched = hashes.0 == hashes.1;
+    }
+    if matched {
+        return Ok(report);
+    }
+
+    let mut contents = vec![0; len];
+    expected.seek(SeekFrom::Start(start))?;
+    expected.read_exact(&mut contents)?;
+    verify_region(em100, address, &contents, mode, None)
+}
+
+/// Compare the blocks sample mode picks in `len` bytes at `report.address`
+///
+/// `expected_block(offset, len)` returns the expected contents of a block.
+fn verify_samples(
+    em100: &Em100,
+    report: &mut VerifyReport,
+    len: usize,
+    samples: usize,
+    seed: u64,
+    mut progress: ProgressCallback,
+    mut expected_block: impl FnMut(usize, usize) -> Result<Vec<u8>>,
+) -> Result<()> {
+    let offsets = sample_offsets(len, samples, seed);
+    let total = offsets
+        .iter()
+        .map(|&o| SAMPLE_BLOCK_SIZE.min(len - o))
+        .sum();
+    for offset in offsets {
+        let block = expected_block(offset, SAMPLE_BLOCK_SIZE.min(len - offset))?;
+        let block_address = report.address + offset as u32;
+        let readback = em100.upload_with_progress(block_address, block.len(), None)?;
+        report.sampled.push(block_address);
+        report.bytes_checked += block.len();
+        if let Some(ref mut cb) = progress {
+            cb(report.bytes_checked, total);
+        }
+        if report.mismatch.is_none() {
+            report.mismatch = DiffReport::compare(&block, &readback, block_address as usize);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTransport, Step};
+    use std::io::Cursor;
+
+    #[test]
+    fn streamed_verify_reads_back_from_the_start_address() {
+        let data: Vec<u8> = (0..0x300).map(|i| i as u8).collect();
+        let mut stale = data.clone();
+        stale[0x2ff] = 0;
+        let mock = MockTransport::stock()
+            .command(protocol::read_sdram_cmd(0x4000, data.len()))
+            .step(Step::BulkRead(data.clone()))
+            // A mismatch is read once more for the report
+            .command(protocol::read_sdram_cmd(0x4000, data.len()))
+            .step(Step::BulkRead(stale.clone()))
+            .command(protocol::read_sdram_cmd(0x4000, data.len()))
+            .step(Step::BulkRead(stale));
+        let em100 = mock.open().unwrap();
+
+        let mut file = Cursor::new(&data);
+        let report = verify_reader(
+            &em100,
+            0x4000,
+            &mut file,
+            data.len(),
+            VerifyMode::Full,
+            None,
+        )
+        .unwrap();
+        assert!(report.passed());
+        assert_eq!((report.address, report.bytes_checked), (0x4000, data.len()));
+
+        file.set_position(0);
+        let report = verify_reader(
+            &em100,
+            0x4000,
+            &mut file,
+            data.len(),
+            VerifyMode::Full,
+            None,
+        )
+        .unwrap();
+        let mismatch = report.mismatch.unwrap();
+        assert_eq!((mismatch.first, mismatch.count), (0x42ff, 1));
+        mock.assert_done();
+    }
+}
@@ -2,21 +2,32 @@
 //!
 //! This module provides a web-based GUI that mirrors the CLI functionality.
 
-use crate::chips::ChipDesc;
-use crate::device::{list_devices, DeviceInfo, Em100, HoldPinState};
-use crate::sdram::{read_sdram_with_progress, write_sdram_with_progress};
+use crate::chips::{size_name, AddressModePolicy, ChipDesc, ChipFilter};
+use crate::device::{
+    list_devices, udev_rule_commands, DeviceInfo, Em100, FlashOptions, HoldPinState, ListedDevice,
+    MAX_MEMORY_SIZE,
+};
+use crate::error::Error;
+use crate::protocol::sdram_region_len;
+use crate::sdram::read_sdram_with_progress;
+use crate::session_log::SessionLog;
+use crate::shared::SharedEm100;
+use crate::trace::TraceEvent;
 use egui::{Color32, RichText};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+
+/// Storage key for the session log directory setting
+const LOG_DIR_KEY: &str = "log_dir";
 
 /// Application state
 #[derive(Default)]
 pub struct Em100App {
     /// Connected device
-    device: Option<Arc<Mutex<Em100>>>,
+    device: Option<SharedEm100>,
     /// Device info
     device_info: Option<DeviceInfo>,
     /// Available devices list
-    available_devices: Vec<(u8, u8, String)>,
+    available_devices: Vec<ListedDevice>,
     /// Selected device index
     selected_device: Option<usize>,
     /// Current emulation state
@@ -25,20 +36,30 @@ pub struct Em100App {
     hold_pin_state: HoldPinState,
     /// Selected chip
     selected_chip: Option<ChipDesc>,
-    /// Chip search query
-    chip_search: String,
+    /// Chip search query and size/voltage filters
+    chip_filter: ChipFilter,
     /// Available chips (loaded from embedded data or fetched)
     available_chips: Vec<ChipDesc>,
+    /// Distinct sizes of the available chips, for the size filter
+    chip_sizes: Vec<u32>,
     /// Chip database version
     chip_db_version: String,
     /// File data to upload to device
     upload_file_data: Option<Vec<u8>>,
     /// Upload filename
     upload_filename: String,
+    /// Path typed into the upload path field
+    upload_path: String,
     /// Start address for upload
     start_address: String,
     /// Address mode (3 or 4)
     address_mode: u8,
+    /// Address mode was chosen by the user rather than derived from the chip
+    address_mode_explicit: bool,
+    /// Start address for download
+    download_address: String,
+    /// Length to download, empty for up to the end of the memory
+    download_length: String,
     /// Data downloaded from device
     download_data: Option<Vec<u8>>,
     /// Operation progress (0.0 - 1.0)
@@ -51,8 +72,16 @@ pub struct Em100App {
     status_is_error: bool,
     /// Debug info
     debug_info: Option<crate::device::DebugInfo>,
-    /// Trace output buffer
-    trace_buffer: String,
+    /// Captured trace events, kept structured so they can be exported losslessly
+    trace_buffer: Vec<TraceEvent>,
+    /// Directory session logs are written to
+    log_dir: Option<PathBuf>,
+    /// Log directory being edited in the settings panel
+    log_dir_input: String,
+    /// Session log, open while a log directory is configured
+    session_log: Option<SessionLog>,
+    /// Show the udev rule help after a permission-denied open
+    show_permission_help: bool,
     /// Current panel
     current_panel: Panel,
 }
@@ -65,22 +94,54 @@ enum Panel {
     Trace,
     Firmware,
     Debug,
+    Settings,
 }
 
 impl Em100App {
     /// Create a new application instance
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
+    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // Load chip database
         let chip_db = crate::chips::ChipDatabase::load_embedded();
         let available_chips = chip_db.list_chips();
         let chip_db_version = chip_db.version.clone();
+        let mut chip_sizes: Vec<u32> = available_chips.iter().map(|c| c.size).collect();
+        chip_sizes.sort_unstable();
+        chip_sizes.dedup();
 
-        Self {
+        let mut app = Self {
             address_mode: 3,
             start_address: "0".to_string(),
+            download_address: "0".to_string(),
             available_chips,
+            chip_sizes,
             chip_db_version,
             ..Default::default()
+        };
+        if let Some(dir) = cc
+            .storage
+            .and_then(|s| s.get_string(LOG_DIR_KEY))
+            .filter(|d| !d.is_empty())
+        {
+            app.log_dir_input = dir.clone();
+            app.set_log_dir(Some(PathBuf::from(dir)));
+        }
+        app
+    }
+
+    /// Start writing a new session log in `dir`, or stop logging
+    fn set_log_dir(&mut self, dir: Option<PathBuf>) {
+        self.session_log = None;
+        self.log_dir = dir;
+        if let Some(dir) = self.log_dir.clone() {
+            match SessionLog::create_in(&dir) {
+                Ok(log) => {
+                    self.session_log = Some(log);
+                    self.set_status(&format!("Logging to {}", dir.display()), false);
+                }
+                Err(e) => {
+                    self.set_status(&format!("Failed to open session log: {}", e), true);
+                }
+            }
         }
     }
 
@@ -89,7 +150,19 @@ impl Em100App {
         match list_devices() {
             Ok(devices) => {
                 self.available_devices = devices;
-                self.set_status("Device list refreshed", false);
+                // Only connect on our own when there's no choice to make
+                match self.available_devices.as_slice() {
+                    [listed] if self.device.is_none() => {
+                        let (bus, address) = (listed.usb.bus, listed.usb.address);
+                        self.selected_device = Some(0);
+                        self.connect_device(bus, address);
+                    }
+                    [_, _, ..] if self.device.is_none() => {
+                        self.selected_device = None;
+                        self.set_status("Several devices found, select one", false);
+                    }
+                    _ => self.set_status("Device list refreshed", false),
+                }
             }
             Err(e) => {
                 self.set_status(&format!("Failed to list devices: {}", e), true);
@@ -105,10 +178,11 @@ impl Em100App {
                 self.is_running = em100.get_state().unwrap_or(false);
                 self.hold_pin_state = em100.get_hold_pin_state().unwrap_or(HoldPinState::Float);
                 self.device_info = Some(info.clone());
-                self.device = Some(Arc::new(Mutex::new(em100)));
+                self.device = Some(SharedEm100::new(em100));
                 self.set_status(&format!("Connected to {}", info.serial), false);
             }
             Err(e) => {
+                self.show_permission_help = matches!(e, Error::PermissionDenied);
                 self.set_status(&format!("Failed to connect: {}", e), true);
             }
         }
@@ -123,15 +197,10 @@ impl Em100App {
 
     /// Set emulation state
     fn set_emulation_state(&mut self, running: bool) {
-        let result = if let Some(ref device) = self.device {
-            if let Ok(em100) = device.lock() {
-                em100.set_state(running)
-            } else {
-                return;
-            }
-        } else {
+        let Some(device) = self.device.clone() else {
             return;
         };
+        let result = device.with(|em100| em100.set_state(running));
 
         match result {
             Ok(_) => {
@@ -153,15 +222,10 @@ impl Em100App {
 
     /// Set hold pin state
     fn set_hold_pin(&mut self, state: HoldPinState) {
-        let result = if let Some(ref device) = self.device {
-            if let Ok(em100) = device.lock() {
-                em100.set_hold_pin_state(state)
-            } else {
-                return;
-            }
-        } else {
+        let Some(device) = self.device.clone() else {
             return;
         };
+        let result = device.with(|em100| em100.set_hold_pin_state(state));
 
         match result {
             Ok(_) => {
@@ -176,24 +240,22 @@ impl Em100App {
 
     /// Set chip type
     fn set_chip(&mut self, chip: ChipDesc) {
-        let result = if let Some(ref device) = self.device {
-            if let Ok(mut em100) = device.lock() {
-                // Stop emulation before changing chip type (matches CLI --stop --set pattern)
-                let _ = em100.set_state(false);
-                let res = em100.set_chip_type(&chip);
-                // Auto-enable 4-byte mode for large chips
-                if res.is_ok() && chip.size > 16 * 1024 * 1024 {
-                    if em100.set_address_mode(4).is_ok() {
-                        self.address_mode = 4;
-                    }
-                }
-                res
-            } else {
-                return;
-            }
-        } else {
+        let Some(device) = self.device.clone() else {
             return;
         };
+        let policy = if self.address_mode_explicit {
+            AddressModePolicy::Force(self.address_mode)
+        } else {
+            AddressModePolicy::Auto
+        };
+        let result = device.with(|em100| {
+            // Stop emulation before changing chip type (matches CLI --stop --set pattern)
+            let _ = em100.set_state(false);
+            em100.set_chip_type(&chip, policy)
+        });
+        if result.is_ok() {
+            self.address_mode = policy.resolve(&chip);
+        }
 
         match result {
             Ok(_) => {
@@ -208,6 +270,61 @@ impl Em100App {
         }
     }
 
+    /// Read an image to upload from `path`, reporting problems in the status line
+    fn load_upload_file(&mut self, path: &Path) {
+        match std::fs::read(path) {
+            Ok(data) => {
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.display().to_string());
+                self.set_upload_data(name, data);
+            }
+            Err(e) => {
+                self.set_status(&format!("Can't read {}: {}", path.display(), e), true);
+            }
+        }
+    }
+
+    /// Use `data` as the image to upload if it fits the emulated memory
+    fn set_upload_data(&mut self, name: String, data: Vec<u8>) {
+        let max_size = self
+            .selected_chip
+            .as_ref()
+            .map(|c| c.size as usize)
+            .unwrap_or(MAX_MEMORY_SIZE);
+        if data.is_empty() {
+            self.set_status(&format!("{} is empty", name), true);
+        } else if data.len() > max_size {
+            self.set_status(
+                &format!(
+                    "{} is too large ({} bytes, at most {})",
+                    name,
+                    data.len(),
+                    max_size
+                ),
+                true,
+            );
+        } else {
+            self.set_status(&format!("Loaded {} ({} bytes)", name, data.len()), false);
+            self.upload_filename = name;
+            self.upload_file_data = Some(data);
+        }
+    }
+
+    /// Load a file dropped onto the window
+    fn handle_dropped_files(&mut self, ctx: &egui::Context) {
+        let dropped = ctx.input(|i| i.raw.dropped_files.first().cloned());
+        let Some(file) = dropped else {
+            return;
+        };
+        if let Some(path) = &file.path {
+            self.load_upload_file(path);
+        } else if let Some(bytes) = &file.bytes {
+            self.set_upload_data(file.name.clone(), bytes.to_vec());
+        }
+    }
+
     /// Upload data to device (write file to SDRAM)
     fn upload_to_device(&mut self) {
         let data = match &self.upload_file_data {
@@ -216,20 +333,22 @@ impl Em100App {
         };
         let start_addr = parse_hex(&self.start_address).unwrap_or(0) as u32;
 
-        let result = if let Some(ref device) = self.device {
-            if let Ok(em100) = device.lock() {
-                // Stop emulation before writing to memory
-                let _ = em100.set_state(false);
-                self.is_running = false;
-                self.progress = 0.0;
-                self.progress_message = "Uploading to device...".to_string();
-                write_sdram_with_progress(&em100, &data, start_addr, None)
-            } else {
-                return;
-            }
-        } else {
+        let Some(device) = self.device.clone() else {
             return;
         };
+        self.is_running = false;
+        self.progress = 0.0;
+        self.progress_message = "Uploading to device...".to_string();
+        let opts = FlashOptions {
+            start_address: start_addr,
+            memory_size: self.selected_chip.as_ref().map(|c| c.size as usize),
+            ..Default::default()
+        };
+        let result = device.with(|em100| {
+            // Stop emulation before writing to memory
+            let _ = em100.set_state(false);
+            em100.flash_image(None, &data, opts)
+        });
 
         match result {
             Ok(_) => {
@@ -247,23 +366,40 @@ impl Em100App {
 
     /// Download data from device (read SDRAM to file)
     fn download_from_device(&mut self) {
-        let size = self
+        let memory_size = self
             .selected_chip
             .as_ref()
             .map(|c| c.size as usize)
-            .unwrap_or(0x4000000);
-
-        let result = if let Some(ref device) = self.device {
-            if let Ok(em100) = device.lock() {
-                self.progress = 0.0;
-                self.progress_message = "Downloading from device...".to_string();
-                read_sdram_with_progress(&em100, 0, size, None)
-            } else {
+            .unwrap_or(MAX_MEMORY_SIZE);
+        let Some(address) = parse_hex(&self.download_address) else {
+            self.set_status("Invalid download address", true);
+            return;
+        };
+        let length = match self.download_length.trim() {
+            "" => None,
+            length => match parse_hex(length) {
+                Some(length) => Some(length as usize),
+                None => {
+                    self.set_status("Invalid download length", true);
+                    return;
+                }
+            },
+        };
+        let size = match sdram_region_len(address as u32, length, memory_size) {
+            Ok(size) => size,
+            Err(e) => {
+                self.set_status(&format!("Download failed: {}", e), true);
                 return;
             }
-        } else {
+        };
+
+        let Some(device) = self.device.clone() else {
             return;
         };
+        self.progress = 0.0;
+        self.progress_message = "Downloading from device...".to_string();
+        let result =
+            device.with(|em100| read_sdram_with_progress(em100, address as u32, size, None));
 
         match result {
             Ok(data) => {
@@ -279,15 +415,10 @@ impl Em100App {
 
     /// Refresh debug info
     fn refresh_debug_info(&mut self) {
-        let result = if let Some(ref device) = self.device {
-            if let Ok(em100) = device.lock() {
-                em100.get_debug_info()
-            } else {
-                return;
-            }
-        } else {
+        let Some(device) = self.device.clone() else {
             return;
         };
+        let result = device.with(|em100| em100.get_debug_info());
 
         match result {
             Ok(info) => {
@@ -304,6 +435,19 @@ impl Em100App {
     fn set_status(&mut self, message: &str, is_error: bool) {
         self.status_message = message.to_string();
         self.status_is_error = is_error;
+
+        // Everything shown to the user also goes to the session log
+        if let Some(log) = self.session_log.as_mut() {
+            let entry = if is_error {
+                format!("error: {}", message)
+            } else {
+                message.to_string()
+            };
+            if log.record(&entry).is_err() {
+                self.session_log = None;
+                self.status_message = format!("{} (session log write failed)", message);
+            }
+        }
     }
 
     /// Render device panel
@@ -316,26 +460,38 @@ impl Em100App {
             if ui.button("Refresh Devices").clicked() {
                 self.refresh_devices();
             }
-            if self.device.is_some() {
-                if ui.button("Disconnect").clicked() {
-                    self.disconnect_device();
-                }
+            if self.device.is_some() && ui.button("Disconnect").clicked() {
+                self.disconnect_device();
             }
         });
 
         // Collect device info first to avoid borrow issues
-        let devices: Vec<_> = self.available_devices.iter().cloned().collect();
+        let devices: Vec<_> = self.available_devices.to_vec();
 
         if !devices.is_empty() {
             ui.add_space(8.0);
             ui.label("Available devices:");
-            for (i, (bus, addr, serial)) in devices.iter().enumerate() {
-                let label = format!("Bus {:03} Device {:03}: {}", bus, addr, serial);
+            for (i, listed) in devices.iter().enumerate() {
+                let usb = &listed.usb;
+                let label = format!(
+                    "Bus {:03} Device {:03}: {} ({})",
+                    usb.bus,
+                    usb.address,
+                    listed.serial_or_status(),
+                    usb.speed_name()
+                );
                 let is_selected = self.selected_device == Some(i);
 
-                if ui.selectable_label(is_selected, &label).clicked() {
+                // Devices held by another session or refused can't be connected to
+                if ui
+                    .add_enabled(
+                        listed.is_available(),
+                        egui::SelectableLabel::new(is_selected, &label),
+                    )
+                    .clicked()
+                {
                     self.selected_device = Some(i);
-                    self.connect_device(*bus, *addr);
+                    self.connect_device(usb.bus, usb.address);
                 }
             }
         } else {
@@ -369,6 +525,18 @@ impl Em100App {
                     ui.label(&info.fpga_version);
                     ui.end_row();
 
+                    ui.label("USB Bus:");
+                    ui.label(format!("{:03}", info.usb.bus));
+                    ui.end_row();
+
+                    ui.label("USB Port:");
+                    ui.label(info.usb.port_path());
+                    ui.end_row();
+
+                    ui.label("USB Speed:");
+                    ui.label(info.usb.speed_name());
+                    ui.end_row();
+
                     ui.label("Chip DB:");
                     ui.label(&self.chip_db_version);
                     ui.end_row();
@@ -455,10 +623,9 @@ impl Em100App {
                 }
             });
             if let Some(mode) = address_mode_changed {
+                self.address_mode_explicit = true;
                 if let Some(ref device) = self.device {
-                    if let Ok(em100) = device.lock() {
-                        let _ = em100.set_address_mode(mode);
-                    }
+                    let _ = device.with(|em100| em100.set_address_mode(mode));
                 }
             }
 
@@ -474,23 +641,46 @@ impl Em100App {
                     "None selected".to_string()
                 };
 
+                let mut size = self.chip_filter.min_size;
+                egui::ComboBox::from_id_salt("chip_size_filter")
+                    .selected_text(size.map_or("Any size".to_string(), size_name))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut size, None, "Any size");
+                        for &s in &self.chip_sizes {
+                            ui.selectable_value(&mut size, Some(s), size_name(s));
+                        }
+                    });
+                self.chip_filter.min_size = size;
+                self.chip_filter.max_size = size;
+
+                egui::ComboBox::from_id_salt("chip_voltage_filter")
+                    .selected_text(match self.chip_filter.voltage {
+                        Some(1800) => "1.8V",
+                        Some(_) => "3.3V",
+                        None => "Any voltage",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.chip_filter.voltage, None, "Any voltage");
+                        ui.selectable_value(&mut self.chip_filter.voltage, Some(1800), "1.8V");
+                        ui.selectable_value(&mut self.chip_filter.voltage, Some(3300), "3.3V");
+                    });
+
                 egui::ComboBox::from_id_salt("chip_selector")
                     .width(500.0)
                     .selected_text(selected_text)
                     .show_ui(ui, |ui| {
                         // Add search filter
-                        ui.text_edit_singleline(&mut self.chip_search);
+                        ui.text_edit_singleline(&mut self.chip_filter.text);
                         ui.separator();
 
                         // Filter and display chips
-                        let search_lower = self.chip_search.to_lowercase();
                         egui::ScrollArea::vertical()
                             .max_height(500.0)
                             .show(ui, |ui| {
                                 for chip in &self.available_chips {
                                     let chip_name = format!("{} {}", chip.vendor, chip.name);
-                                    if search_lower.is_empty()
-                                        || chip_name.to_lowercase().contains(&search_lower)
+                                    if self.chip_filter.matches_text(&chip_name)
+                                        && self.chip_filter.matches_attributes(chip)
                                     {
                                         let is_selected = self
                                             .selected_chip
@@ -532,20 +722,22 @@ impl Em100App {
             #[cfg(all(not(target_arch = "wasm32"), feature = "rfd"))]
             if ui.button("Browse...").clicked() {
                 if let Some(path) = rfd::FileDialog::new().pick_file() {
-                    if let Ok(data) = std::fs::read(&path) {
-                        self.upload_filename = path
-                            .file_name()
-                            .map(|n| n.to_string_lossy().to_string())
-                            .unwrap_or_default();
-                        self.upload_file_data = Some(data);
-                    }
+                    self.load_upload_file(&path);
                 }
             }
-            #[cfg(any(target_arch = "wasm32", not(feature = "rfd")))]
-            {
-                ui.label("(File dialogs not available - use drag and drop)");
+        });
+
+        // Typed path, for builds without file dialogs
+        ui.horizontal(|ui| {
+            ui.label("Path:");
+            let response = ui.text_edit_singleline(&mut self.upload_path);
+            let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            let path = self.upload_path.trim().to_string();
+            if (ui.button("Load").clicked() || submitted) && !path.is_empty() {
+                self.load_upload_file(Path::new(&path));
             }
         });
+        ui.label("Or drop a file onto the window.");
 
         ui.horizontal(|ui| {
             ui.label("Start Address:");
@@ -567,6 +759,17 @@ impl Em100App {
 
         // Download from Device section
         ui.heading("Download from Device");
+        ui.horizontal(|ui| {
+            ui.label("Start Address:");
+            ui.text_edit_singleline(&mut self.download_address);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Length:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.download_length)
+                    .hint_text("to the end of the memory"),
+            );
+        });
         ui.horizontal(|ui| {
             if ui.button("Download").clicked() {
                 self.download_from_device();
@@ -678,20 +881,60 @@ impl Em100App {
             if ui.button("Clear").clicked() {
                 self.trace_buffer.clear();
             }
+            ui.separator();
+            #[cfg(all(not(target_arch = "wasm32"), feature = "rfd"))]
+            {
+                if ui.button("Save as Text...").clicked() {
+                    self.export_trace("txt", crate::trace::export::write_text);
+                }
+                if ui.button("Save as CSV...").clicked() {
+                    self.export_trace("csv", crate::trace::export::write_csv);
+                }
+            }
+            #[cfg(any(target_arch = "wasm32", not(feature = "rfd")))]
+            {
+                ui.label("(File dialogs not available)");
+            }
         });
 
         ui.add_space(8.0);
         egui::ScrollArea::vertical()
             .stick_to_bottom(true)
             .show(ui, |ui| {
-                ui.add(
-                    egui::TextEdit::multiline(&mut self.trace_buffer.as_str())
-                        .font(egui::TextStyle::Monospace)
-                        .desired_width(f32::INFINITY),
-                );
+                for event in &self.trace_buffer {
+                    ui.label(RichText::new(event.to_string()).monospace());
+                }
             });
     }
 
+    /// Save the captured trace with one of the `trace::export` writers
+    #[cfg(all(not(target_arch = "wasm32"), feature = "rfd"))]
+    fn export_trace(
+        &mut self,
+        extension: &str,
+        write: fn(&mut std::fs::File, &[TraceEvent]) -> std::io::Result<()>,
+    ) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter(extension, &[extension])
+            .save_file()
+        else {
+            return;
+        };
+        let result =
+            std::fs::File::create(&path).and_then(|mut f| write(&mut f, &self.trace_buffer));
+        match result {
+            Ok(()) => self.set_status(
+                &format!(
+                    "Saved {} trace events to {}",
+                    self.trace_buffer.len(),
+                    path.display()
+                ),
+                false,
+            ),
+            Err(e) => self.set_status(&format!("Failed to save trace: {}", e), true),
+        }
+    }
+
     /// Render firmware panel
     fn firmware_panel(&mut self, ui: &mut egui::Ui) {
         ui.heading("Firmware");
@@ -716,10 +959,83 @@ impl Em100App {
             }
         });
     }
+
+    /// Render settings panel
+    fn settings_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Settings");
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("Session log directory:");
+            match &self.log_dir {
+                Some(dir) => ui.label(RichText::new(dir.display().to_string()).monospace()),
+                None => ui.label("(disabled)"),
+            };
+        });
+
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.log_dir_input);
+            if ui.button("Apply").clicked() && !self.log_dir_input.trim().is_empty() {
+                let dir = PathBuf::from(self.log_dir_input.trim());
+                self.set_log_dir(Some(dir));
+            }
+            #[cfg(all(not(target_arch = "wasm32"), feature = "rfd"))]
+            if ui.button("Browse...").clicked() {
+                if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                    self.log_dir_input = dir.display().to_string();
+                    self.set_log_dir(Some(dir));
+                }
+            }
+            if self.log_dir.is_some() && ui.button("Disable").clicked() {
+                self.set_status("Session logging disabled", false);
+                self.set_log_dir(None);
+            }
+        });
+    }
+
+    /// Explain how to grant access after opening the device was denied
+    fn permission_help_window(&mut self, ctx: &egui::Context) {
+        let mut open = true;
+        egui::Window::new("Device permissions")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label("The EM100Pro was found, but this user is not allowed to access it.");
+                ui.label("On Linux, install a udev rule by running:");
+                ui.add_space(4.0);
+                ui.label(RichText::new(udev_rule_commands()).monospace());
+                ui.add_space(4.0);
+                ui.label("Then unplug and replug the EM100Pro and connect again.");
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Copy commands").clicked() {
+                        ui.ctx().copy_text(udev_rule_commands());
+                        self.set_status("udev commands copied to clipboard", false);
+                    }
+                    if ui.button("Close").clicked() {
+                        self.show_permission_help = false;
+                    }
+                });
+            });
+        if !open {
+            self.show_permission_help = false;
+        }
+    }
 }
 
 impl eframe::App for Em100App {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        let dir = self
+            .log_dir
+            .as_ref()
+            .map(|d| d.display().to_string())
+            .unwrap_or_default();
+        storage.set_string(LOG_DIR_KEY, dir);
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.handle_dropped_files(ctx);
+
         // Top panel with navigation
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
@@ -731,6 +1047,7 @@ impl eframe::App for Em100App {
                 ui.selectable_value(&mut self.current_panel, Panel::Trace, "Trace");
                 ui.selectable_value(&mut self.current_panel, Panel::Firmware, "Firmware");
                 ui.selectable_value(&mut self.current_panel, Panel::Debug, "Debug");
+                ui.selectable_value(&mut self.current_panel, Panel::Settings, "Settings");
             });
         });
 
@@ -746,6 +1063,10 @@ impl eframe::App for Em100App {
             });
         });
 
+        if self.show_permission_help {
+            self.permission_help_window(ctx);
+        }
+
         // Central panel
         egui::CentralPanel::default().show(ctx, |ui| match self.current_panel {
             Panel::Device => self.device_panel(ui),
@@ -753,6 +1074,7 @@ impl eframe::App for Em100App {
             Panel::Trace => self.trace_panel(ui),
             Panel::Firmware => self.firmware_panel(ui),
             Panel::Debug => self.debug_panel(ui),
+            Panel::Settings => self.settings_panel(ui),
         });
     }
 }
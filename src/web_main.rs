@@ -12,8 +12,9 @@ fn main() -> eframe::Result<()> {
 #[cfg(target_arch = "wasm32")]
 mod wasm_app {
     use egui::Color32;
-    use rem100::chips::{ChipDatabase, ChipDesc};
-    use rem100::web_device::{DeviceInfo, Em100Async, HoldPinState};
+    use rem100::chips::{size_name, AddressModePolicy, ChipDatabase, ChipDesc, ChipFilter};
+    use rem100::protocol::sdram_region_len;
+    use rem100::async_device::{DeviceInfo, Em100Async, HoldPinState};
     use std::cell::RefCell;
     use std::rc::Rc;
     use wasm_bindgen::closure::Closure;
@@ -86,22 +87,32 @@ mod wasm_app {
         available_chips: Vec<ChipInfo>,
         /// Selected chip
         selected_chip: Option<Rc<ChipDesc>>,
-        /// Chip search query
-        chip_search: String,
+        /// Chip search query and size/voltage filters
+        chip_filter: ChipFilter,
+        /// Distinct sizes of the available chips, for the size filter
+        chip_sizes: Vec<u32>,
         /// File data to upload to device
         upload_file_data: Option<Vec<u8>>,
         /// Upload filename
         upload_filename: String,
         /// Start address for upload
         start_address: String,
+        /// Start address for download
+        download_address: String,
+        /// Length to download, empty for up to the end of the memory
+        download_length: String,
         /// Address mode (3 or 4)
         address_mode: u8,
+        /// Address mode was chosen by the user rather than derived from the chip
+        address_mode_explicit: bool,
         /// Current panel
         current_panel: Panel,
         /// Status message
         status_message: String,
         /// Status is error
         status_is_error: bool,
+        /// Browser supports WebUSB
+        webusb_available: bool,
     }
 
     #[derive(Default, PartialEq, Clone, Copy)]
@@ -118,7 +129,7 @@ mod wasm_app {
 
             // Wrap chips in Rc and pre-compute display names
             let available_chips: Vec<ChipInfo> = chip_db
-                .chips
+                .list_chips()
                 .into_iter()
                 .map(|chip| {
                     let display_name = format!("{} {}", chip.vendor, chip.name);
@@ -128,19 +139,34 @@ mod wasm_app {
                     }
                 })
                 .collect();
+            let mut chip_sizes: Vec<u32> = available_chips.iter().map(|c| c.chip.size).collect();
+            chip_sizes.sort_unstable();
+            chip_sizes.dedup();
+
+            let webusb_available = Em100Async::webusb_available();
+            let status_message = if webusb_available {
+                "Click 'Connect Device' to connect via WebUSB".to_string()
+            } else {
+                rem100::error::Error::WebUsbUnavailable.to_string()
+            };
 
             Self {
                 state: Rc::new(RefCell::new(SharedState::default())),
                 available_chips,
                 selected_chip: None,
-                chip_search: String::new(),
+                chip_filter: ChipFilter::default(),
+                chip_sizes,
                 upload_file_data: None,
                 upload_filename: String::new(),
                 start_address: "0".to_string(),
+                download_address: "0".to_string(),
+                download_length: String::new(),
                 address_mode: 3,
+                address_mode_explicit: false,
                 current_panel: Panel::Device,
-                status_message: "Click 'Connect Device' to connect via WebUSB".to_string(),
-                status_is_error: false,
+                status_message,
+                status_is_error: !webusb_available,
+                webusb_available,
             }
         }
 
@@ -323,6 +349,12 @@ mod wasm_app {
         fn set_chip(&mut self, chip: Rc<ChipDesc>) {
             let state = self.state.clone();
             let chip_for_async = chip.clone();
+            let policy = if self.address_mode_explicit {
+                AddressModePolicy::Force(self.address_mode)
+            } else {
+                AddressModePolicy::Auto
+            };
+            let resolved_mode = policy.resolve(&chip);
             state.borrow_mut().async_op =
                 AsyncOp::InProgress(format!("Setting chip to {} {}...", chip.vendor, chip.name));
 
@@ -334,7 +366,7 @@ mod wasm_app {
                 };
 
                 let (result, device) = if let Some(mut dev) = device {
-                    let res = dev.set_chip_type(&*chip_for_async).await;
+                    let res = dev.set_chip_type(&*chip_for_async, policy).await;
                     (Some(res), Some(dev))
                 } else {
                     (None, None)
@@ -347,14 +379,9 @@ mod wasm_app {
                     Some(Ok(_)) => {
                         // set_chip_type stops emulation, so update is_running
                         s.is_running = false;
-                        let mode_msg = if chip_for_async.size > 16 * 1024 * 1024 {
-                            " (4-byte address mode enabled)"
-                        } else {
-                            ""
-                        };
                         s.async_op = AsyncOp::Success(format!(
-                            "Chip set to {} {}{}",
-                            chip_for_async.vendor, chip_for_async.name, mode_msg
+                            "Chip set to {} {} ({}-byte address mode)",
+                            chip_for_async.vendor, chip_for_async.name, resolved_mode
                         ));
                     }
                     Some(Err(e)) => {
@@ -366,6 +393,7 @@ mod wasm_app {
                 }
             });
 
+            self.address_mode = resolved_mode;
             self.selected_chip = Some(chip);
         }
 
@@ -474,11 +502,30 @@ mod wasm_app {
         }
 
         fn download_from_device(&mut self) {
-            let size = self
+            let memory_size = self
                 .selected_chip
                 .as_ref()
                 .map(|c| c.size as usize)
                 .unwrap_or(0x4000000);
+            let address = parse_hex(&self.download_address);
+            let length = match self.download_length.trim() {
+                "" => Some(None),
+                length => parse_hex(length).map(|l| Some(l as usize)),
+            };
+            let (Some(address), Some(length)) = (address, length) else {
+                self.state.borrow_mut().async_op =
+                    AsyncOp::Error("Invalid download address or length".to_string());
+                return;
+            };
+            let address = address as u32;
+            let size = match sdram_region_len(address, length, memory_size) {
+                Ok(size) => size,
+                Err(e) => {
+                    self.state.borrow_mut().async_op =
+                        AsyncOp::Error(format!("Download failed: {}", e));
+                    return;
+                }
+            };
 
             let state = self.state.clone();
 
@@ -498,7 +545,7 @@ mod wasm_app {
                 };
 
                 let (result, device) = if let Some(mut dev) = device {
-                    let res = dev.upload(0, size).await;
+                    let res = dev.upload(address, size).await;
                     (Some(res), Some(dev))
                 } else {
                     (None, None)
@@ -571,7 +618,7 @@ mod wasm_app {
             ui.horizontal(|ui| {
                 if ui
                     .add_enabled(
-                        !is_connected && !is_connecting,
+                        self.webusb_available && !is_connected && !is_connecting,
                         egui::Button::new("Connect Device"),
                     )
                     .clicked()
@@ -590,6 +637,17 @@ mod wasm_app {
                 }
             });
 
+            if !self.webusb_available {
+                ui.label(
+                    egui::RichText::new("This browser does not support WebUSB.")
+                        .color(Color32::RED),
+                );
+                ui.label(
+                    "Use a Chromium-based browser (Chrome, Edge or Opera) on a page served over \
+                     https or from localhost. Firefox and Safari don't implement WebUSB.",
+                );
+            }
+
             // Connection status
             let state = self.state.borrow();
             match &state.connection_state {
@@ -727,6 +785,7 @@ mod wasm_app {
                     }
                 });
                 if let Some(mode) = address_mode_to_set {
+                    self.address_mode_explicit = true;
                     self.set_address_mode(mode);
                 }
 
@@ -734,6 +793,31 @@ mod wasm_app {
 
                 // Chip selection
                 ui.label("Chip:");
+                ui.horizontal(|ui| {
+                    let mut size = self.chip_filter.min_size;
+                    egui::ComboBox::from_id_salt("chip_size_filter")
+                        .selected_text(size.map_or("Any size".to_string(), size_name))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut size, None, "Any size");
+                            for &s in &self.chip_sizes {
+                                ui.selectable_value(&mut size, Some(s), size_name(s));
+                            }
+                        });
+                    self.chip_filter.min_size = size;
+                    self.chip_filter.max_size = size;
+
+                    egui::ComboBox::from_id_salt("chip_voltage_filter")
+                        .selected_text(match self.chip_filter.voltage {
+                            Some(1800) => "1.8V",
+                            Some(_) => "3.3V",
+                            None => "Any voltage",
+                        })
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.chip_filter.voltage, None, "Any voltage");
+                            ui.selectable_value(&mut self.chip_filter.voltage, Some(1800), "1.8V");
+                            ui.selectable_value(&mut self.chip_filter.voltage, Some(3300), "3.3V");
+                        });
+                });
 
                 let mut chip_to_set: Option<Rc<ChipDesc>> = None;
                 let popup_id = ui.make_persistent_id("chip_selector_popup");
@@ -764,7 +848,7 @@ mod wasm_app {
 
                         // Search filter - always request focus so it's ready for typing
                         let search_response = ui.add(
-                            egui::TextEdit::singleline(&mut self.chip_search)
+                            egui::TextEdit::singleline(&mut self.chip_filter.text)
                                 .id(search_field_id)
                                 .hint_text("Search chips..."),
                         );
@@ -772,16 +856,12 @@ mod wasm_app {
                         ui.separator();
 
                         // Filter and display chips using pre-computed names
-                        let search_lower = self.chip_search.to_lowercase();
                         egui::ScrollArea::vertical()
                             .max_height(500.0)
                             .show(ui, |ui| {
                                 for chip_info in &self.available_chips {
-                                    if search_lower.is_empty()
-                                        || chip_info
-                                            .display_name
-                                            .to_lowercase()
-                                            .contains(&search_lower)
+                                    if self.chip_filter.matches_text(&chip_info.display_name)
+                                        && self.chip_filter.matches_attributes(&chip_info.chip)
                                     {
                                         let is_selected = self
                                             .selected_chip
@@ -860,6 +940,17 @@ mod wasm_app {
 
                 // Download from Device
                 ui.label("Download from Device:");
+                ui.horizontal(|ui| {
+                    ui.label("Start Address:");
+                    ui.text_edit_singleline(&mut self.download_address);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Length:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.download_length)
+                            .hint_text("to the end of the memory"),
+                    );
+                });
                 ui.horizontal(|ui| {
                     if ui
                         .add_enabled(!is_busy, egui::Button::new("Download"))